@@ -0,0 +1 @@
+DROP TABLE deferrable_jobs;
@@ -0,0 +1,11 @@
+CREATE TABLE deferrable_jobs (
+    id SERIAL PRIMARY KEY,
+    processor TEXT NOT NULL,
+    switch_name TEXT NOT NULL,
+    power_w INTEGER NOT NULL,
+    runtime_s BIGINT NOT NULL,
+    earliest_start TIMESTAMP NOT NULL,
+    latest_finish TIMESTAMP NOT NULL,
+    started TIMESTAMP,
+    finished TIMESTAMP
+);
@@ -0,0 +1 @@
+DROP TABLE switch_rules;
@@ -0,0 +1,11 @@
+CREATE TABLE switch_rules (
+    id SERIAL PRIMARY KEY,
+    processor TEXT NOT NULL,
+    name TEXT NOT NULL,
+    enabled BOOLEAN NOT NULL DEFAULT TRUE,
+    schedule TEXT NOT NULL,
+    calendar TEXT NOT NULL,
+    target_type TEXT NOT NULL,
+    target_name TEXT NOT NULL,
+    action TEXT NOT NULL
+);
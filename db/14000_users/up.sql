@@ -0,0 +1,15 @@
+CREATE TABLE users (
+    id SERIAL PRIMARY KEY,
+    name TEXT NOT NULL UNIQUE,
+    hashed_password TEXT NOT NULL,
+    role TEXT NOT NULL
+);
+
+CREATE TABLE audit_log (
+    id SERIAL PRIMARY KEY,
+    time TIMESTAMP NOT NULL,
+    username TEXT NOT NULL,
+    mutation TEXT NOT NULL,
+    input TEXT NOT NULL
+);
+CREATE INDEX audit_log_time_idx ON audit_log (time);
@@ -0,0 +1,8 @@
+CREATE TABLE api_tokens (
+    id SERIAL PRIMARY KEY,
+    name TEXT NOT NULL,
+    username TEXT NOT NULL,
+    role TEXT NOT NULL,
+    token_hash TEXT NOT NULL UNIQUE,
+    created TIMESTAMP NOT NULL
+);
@@ -0,0 +1,7 @@
+CREATE TABLE config_versions (
+    id SERIAL PRIMARY KEY,
+    time TIMESTAMP NOT NULL,
+    username TEXT NOT NULL,
+    comment TEXT NOT NULL,
+    config TEXT NOT NULL
+);
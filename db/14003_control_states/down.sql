@@ -0,0 +1 @@
+DROP TABLE control_states;
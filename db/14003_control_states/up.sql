@@ -0,0 +1,7 @@
+CREATE TABLE control_states (
+    processor TEXT NOT NULL,
+    key TEXT NOT NULL,
+    value TEXT NOT NULL,
+    time TIMESTAMP NOT NULL,
+    PRIMARY KEY (processor, key)
+);
@@ -25,7 +25,7 @@ use tokio_modbus::{
         tcp::{connect, connect_slave},
         Context,
     },
-    prelude::Reader,
+    prelude::{Reader, Writer},
 };
 
 fn read_err_msg<S>(reg: u16, e: S) -> String
@@ -35,6 +35,13 @@ where
     format!("Could not read register {reg}: {e}")
 }
 
+fn write_err_msg<S>(reg: u16, e: S) -> String
+where
+    S: std::fmt::Display,
+{
+    format!("Could not write register {reg}: {e}")
+}
+
 #[derive(Debug)]
 pub struct SunspecClient {
     addr: SocketAddr,
@@ -55,6 +62,12 @@ impl SunspecClient {
     const SUNSPEC_INVERTER_YIELD_SCALE: u16 = 26;
     const SUNSPEC_INVERTER_YIELD_SCALE_SIZE: u16 = 1;
 
+    const SUNSPEC_CONTROLS: u16 = 123;
+    const SUNSPEC_CONTROLS_WMAXLIMPCT: u16 = 5;
+    const SUNSPEC_CONTROLS_WMAXLIMPCT_RVRTTMS: u16 = 7;
+    const SUNSPEC_CONTROLS_WMAXLIM_ENA: u16 = 9;
+    const SUNSPEC_CONTROLS_WMAXLIMPCT_SF: u16 = 23;
+
     pub fn new(
         addr: SocketAddr,
         id: Option<u8>,
@@ -158,6 +171,76 @@ impl SunspecClient {
         Ok(total_energy as f64 * 10_f64.powf(scale as f64))
     }
 
+    /// Limits the inverter output power to the given percentage of its
+    /// nominal power. The inverter reverts the limit after the given
+    /// timeout in seconds if it is not refreshed. Zero disables the timeout.
+    pub async fn set_power_limit(
+        &self,
+        context: &mut Context,
+        percent: f64,
+        revert_timeout: u16,
+    ) -> Result<(), String> {
+        let scale = Self::validate_result_i16(
+            "SUNSPEC_CONTROLS_WMAXLIMPCT_SF",
+            self.read_register(
+                context,
+                Self::SUNSPEC_CONTROLS,
+                Self::SUNSPEC_CONTROLS_WMAXLIMPCT_SF,
+                1,
+            )
+            .await,
+        )?;
+
+        let value = (percent.clamp(0.0, 100.0) / 10_f64.powf(scale as f64))
+            .round() as u16;
+        self.write_register(
+            context,
+            Self::SUNSPEC_CONTROLS,
+            Self::SUNSPEC_CONTROLS_WMAXLIMPCT,
+            &[value],
+        )
+        .await?;
+        self.write_register(
+            context,
+            Self::SUNSPEC_CONTROLS,
+            Self::SUNSPEC_CONTROLS_WMAXLIMPCT_RVRTTMS,
+            &[revert_timeout],
+        )
+        .await?;
+        self.write_register(
+            context,
+            Self::SUNSPEC_CONTROLS,
+            Self::SUNSPEC_CONTROLS_WMAXLIM_ENA,
+            &[1],
+        )
+        .await
+    }
+
+    fn model_base(&self, model: u16) -> Result<u16, String> {
+        match self.models.get(&model) {
+            Some(x) => Ok(*x),
+            None => {
+                Err(format!("The device does not support model {}", &model))
+            }
+        }
+    }
+
+    async fn write_register(
+        &self,
+        context: &mut Context,
+        model: u16,
+        register: u16,
+        data: &[u16],
+    ) -> Result<(), String> {
+        let addr = self.model_base(model)? + register;
+
+        context
+            .write_multiple_registers(addr, data)
+            .await
+            .map_err(|e| write_err_msg(addr, e))?
+            .map_err(|e| write_err_msg(addr, e))
+    }
+
     async fn read_register(
         &self,
         context: &mut Context,
@@ -165,16 +248,7 @@ impl SunspecClient {
         register: u16,
         size: u16,
     ) -> Result<Vec<u16>, String> {
-        let model_base = match self.models.get(&model) {
-            Some(x) => x,
-            None => {
-                return Err(format!(
-                    "The device does not support model {}",
-                    &model
-                ))
-            }
-        };
-        let addr = model_base + register;
+        let addr = self.model_base(model)? + register;
 
         context
             .read_holding_registers(addr, size)
@@ -26,6 +26,7 @@ mod mig11000_fix_heatpump_heat;
 mod mig12001_calc_rain_acc;
 mod mig4000_convert_battery_charge;
 mod mig9000_to_postgres;
+mod users;
 
 use mig11000_fix_heatpump_heat::{mig11000_fix_heatpump_heat, Mig11000Args};
 use mig12001_calc_rain_acc::{mig12001_calc_rain_acc, Mig12001Args};
@@ -33,6 +34,7 @@ use mig4000_convert_battery_charge::{
     mig4000_convert_battery_charge, Mig4000Args,
 };
 use mig9000_to_postgres::{mig9000_to_postgres, Mig9000Args};
+use users::{manage_users, UserArgs};
 
 /// Common migration command line arguments.
 #[derive(Debug, Parser)]
@@ -60,6 +62,9 @@ enum Migration {
     /// Calculate accumulated rain
     #[clap(name = "12001_calc_rain_acc")]
     Mig12001(Mig12001Args),
+    /// Manage API users
+    #[clap(name = "user")]
+    User(UserArgs),
 }
 
 #[tokio::main]
@@ -78,6 +83,7 @@ async fn main() -> Result<(), String> {
         Migration::Mig12001(args) => {
             mig12001_calc_rain_acc(settings, args).await?
         }
+        Migration::User(args) => manage_users(settings, args).await?,
     }
 
     Ok(())
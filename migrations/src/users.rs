@@ -0,0 +1,144 @@
+/******************************************************************************\
+    empowerd - empowers the offline smart home
+    Copyright (C) 2019 - 2026 Max Maisel
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU Affero General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU Affero General Public License for more details.
+
+    You should have received a copy of the GNU Affero General Public License
+    along with this program.  If not, see <https://www.gnu.org/licenses/>.
+\******************************************************************************/
+use clap::{Args, Subcommand};
+use diesel_async::{
+    pooled_connection::{deadpool::Pool, AsyncDieselConnectionManager},
+    AsyncPgConnection,
+};
+use libempowerd::{
+    error::Error,
+    models::{postgres::run_migrations, User},
+    session_manager::Role,
+    settings::Settings,
+};
+use std::io::{self, BufRead, Write};
+
+#[derive(Args, Clone, Debug)]
+pub struct UserArgs {
+    #[command(subcommand)]
+    command: UserCommand,
+}
+
+#[derive(Clone, Debug, Subcommand)]
+enum UserCommand {
+    /// List all users
+    List,
+    /// Add a new user, the password is read from stdin
+    Add {
+        /// Name of the user
+        name: String,
+        /// One of Viewer, Operator or Admin
+        #[arg(short, long, default_value("Viewer"))]
+        role: String,
+    },
+    /// Change the password of a user, the password is read from stdin
+    Passwd {
+        /// Name of the user
+        name: String,
+    },
+    /// Change the role of a user
+    Role {
+        /// Name of the user
+        name: String,
+        /// One of Viewer, Operator or Admin
+        role: String,
+    },
+    /// Delete a user
+    Delete {
+        /// Name of the user
+        name: String,
+    },
+}
+
+fn read_password() -> Result<String, String> {
+    eprint!("Password: ");
+    io::stderr().flush().map_err(|e| e.to_string())?;
+    let mut password = String::new();
+    io::stdin()
+        .lock()
+        .read_line(&mut password)
+        .map_err(|e| format!("Reading password failed: {e}"))?;
+
+    let password = password.trim_end_matches(['\r', '\n']).to_string();
+    if password.is_empty() {
+        return Err("Password must not be empty".into());
+    }
+    Ok(password)
+}
+
+pub async fn manage_users(
+    settings: Settings,
+    args: UserArgs,
+) -> Result<(), String> {
+    let pg_url = format!(
+        "postgres://{}:{}@{}/{}",
+        settings.database.user,
+        settings.database.password,
+        settings.database.url,
+        settings.database.name,
+    );
+    tokio::task::block_in_place(|| run_migrations(&pg_url))?;
+
+    let pool_cfg =
+        AsyncDieselConnectionManager::<AsyncPgConnection>::new(pg_url);
+    let pool = Pool::builder(pool_cfg).build().map_err(|e| e.to_string())?;
+    let mut conn = pool.get().await.map_err(|e| e.to_string())?;
+
+    match args.command {
+        UserCommand::List => {
+            for user in User::all(&mut conn).await? {
+                println!("{}\t{}", user.name, user.role);
+            }
+        }
+        UserCommand::Add { name, role } => {
+            let role = role.parse::<Role>()?;
+            let mut user = User::new(name, &read_password()?, role)?;
+            user.insert(&mut conn).await?;
+        }
+        UserCommand::Passwd { name } => {
+            let mut user = find_user(&mut conn, &name).await?;
+            user.hashed_password = User::hash_password(&read_password()?)?;
+            user.save_changes(&mut conn).await?;
+        }
+        UserCommand::Role { name, role } => {
+            let mut user = find_user(&mut conn, &name).await?;
+            user.role = role.parse()?;
+            user.save_changes(&mut conn).await?;
+        }
+        UserCommand::Delete { name } => {
+            match User::delete(&mut conn, &name).await {
+                Err(Error::NotFound) => {
+                    return Err(format!("User '{name}' does not exist"))
+                }
+                x => x?,
+            }
+        }
+    }
+
+    Ok(())
+}
+
+async fn find_user(
+    conn: &mut AsyncPgConnection,
+    name: &str,
+) -> Result<User, String> {
+    match User::by_name(conn, name).await {
+        Err(Error::NotFound) => Err(format!("User '{name}' does not exist")),
+        x => x.map_err(|e| e.to_string()),
+    }
+}
@@ -0,0 +1,166 @@
+/******************************************************************************\
+    empowerd - empowers the offline smart home
+    Copyright (C) 2019 - 2026 Max Maisel
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU Affero General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU Affero General Public License for more details.
+
+    You should have received a copy of the GNU Affero General Public License
+    along with this program.  If not, see <https://www.gnu.org/licenses/>.
+\******************************************************************************/
+use base64::prelude::{Engine, BASE64_STANDARD};
+use hyper::{
+    body::Incoming, header, server::conn::http1, service::service_fn, Request,
+    Response, StatusCode,
+};
+use hyper_util::rt::TokioIo;
+use std::{
+    convert::Infallible,
+    io,
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+};
+use tokio::{net::TcpListener, task::JoinHandle};
+
+/// Counters of a simulated Senertec Dachs.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct DachsState {
+    /// Total generated energy in kilowatt hours.
+    pub total_energy: f64,
+    /// Total operating time in hours.
+    pub runtime: f64,
+}
+
+impl DachsState {
+    fn value(&self, key: &str) -> Option<f64> {
+        match key {
+            "Hka_Bd.ulArbeitElektr" => Some(self.total_energy),
+            "Hka_Bd.ulBetriebssekunden" => Some(self.runtime),
+            _ => None,
+        }
+    }
+}
+
+fn response(status: StatusCode, body: String) -> Response<String> {
+    let mut response = Response::new(body);
+    *response.status_mut() = status;
+    response
+}
+
+/// Answers a GLT "getKey" request with the value in the format of the
+/// device, e.g. "Hka_Bd.ulArbeitElektr=123.0 ".
+fn handle(
+    req: &Request<Incoming>,
+    authorization: &str,
+    state: &DachsState,
+) -> Response<String> {
+    let authorized = req
+        .headers()
+        .get(header::AUTHORIZATION)
+        .map_or(false, |x| x.as_bytes() == authorization.as_bytes());
+    if !authorized {
+        return response(StatusCode::UNAUTHORIZED, String::new());
+    }
+
+    let key = match (req.uri().path(), req.uri().query()) {
+        ("/getKey", Some(query)) => query.strip_prefix("k=").unwrap_or(""),
+        _ => return response(StatusCode::NOT_FOUND, String::new()),
+    };
+    match state.value(key) {
+        Some(value) => response(StatusCode::OK, format!("{key}={value:.1} \n")),
+        None => response(StatusCode::NOT_FOUND, String::new()),
+    }
+}
+
+/// HTTP server which emulates the GLT API of a Senertec Dachs. The client
+/// always connects to port 8080, so it must be started on a dedicated
+/// loopback address like 127.0.80.80:8080 to run in parallel to other
+/// services.
+pub struct DachsServer {
+    addr: SocketAddr,
+    state: Arc<Mutex<DachsState>>,
+    task: JoinHandle<()>,
+}
+
+impl DachsServer {
+    pub async fn start(
+        addr: SocketAddr,
+        password: &str,
+        state: DachsState,
+    ) -> io::Result<Self> {
+        let listener = TcpListener::bind(addr).await?;
+        let addr = listener.local_addr()?;
+        let authorization = Arc::new(format!(
+            "Basic {}",
+            BASE64_STANDARD.encode(format!("glt:{password}"))
+        ));
+        let state = Arc::new(Mutex::new(state));
+        let task_state = state.clone();
+
+        let task = tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                let authorization = authorization.clone();
+                let state = task_state.clone();
+                tokio::spawn(async move {
+                    let service = service_fn(move |req| {
+                        let response = handle(
+                            &req,
+                            &authorization,
+                            &state.lock().unwrap(),
+                        );
+                        async move { Ok::<_, Infallible>(response) }
+                    });
+                    let _ = http1::Builder::new()
+                        .serve_connection(TokioIo::new(stream), service)
+                        .await;
+                });
+            }
+        });
+
+        Ok(Self { addr, state, task })
+    }
+
+    pub fn local_addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// Changes the counters while the server is running.
+    pub fn update(&self, f: impl FnOnce(&mut DachsState)) {
+        f(&mut self.state.lock().unwrap())
+    }
+}
+
+impl Drop for DachsServer {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+#[tokio::test]
+async fn test_dachs_server() {
+    use dachs_client::DachsClient;
+
+    let _server = DachsServer::start(
+        "127.0.80.80:8080".parse().unwrap(),
+        "secret",
+        DachsState {
+            total_energy: 1234.0,
+            runtime: 2.0,
+        },
+    )
+    .await
+    .unwrap();
+
+    let client = DachsClient::new("127.0.80.80".into(), "secret".into(), None);
+    assert_eq!(Ok(1234), client.get_total_energy().await);
+    assert_eq!(Ok(7200), client.get_runtime().await);
+    let client = DachsClient::new("127.0.80.80".into(), "wrong".into(), None);
+    assert!(client.get_total_energy().await.is_err());
+}
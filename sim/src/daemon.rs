@@ -0,0 +1,74 @@
+/******************************************************************************\
+    empowerd - empowers the offline smart home
+    Copyright (C) 2019 - 2026 Max Maisel
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU Affero General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU Affero General Public License for more details.
+
+    You should have received a copy of the GNU Affero General Public License
+    along with this program.  If not, see <https://www.gnu.org/licenses/>.
+\******************************************************************************/
+use std::{
+    fs::{self, File},
+    io,
+    path::{Path, PathBuf},
+    process::{Child, Command},
+};
+use tempfile::TempDir;
+
+/// Running empowerd process with a config file in a temporary directory.
+/// The daemon runs in the foreground and is killed when it is dropped.
+pub struct Daemon {
+    dir: TempDir,
+    child: Child,
+}
+
+impl Daemon {
+    /// Writes the config to "empowerd.conf" and starts the given empowerd
+    /// binary with it.
+    pub fn start(binary: impl AsRef<Path>, config: &str) -> io::Result<Self> {
+        let dir = tempfile::Builder::new().prefix("empowerd-").tempdir()?;
+        let config_path = dir.path().join("empowerd.conf");
+        fs::write(&config_path, config)?;
+
+        let log = File::create(dir.path().join("empowerd.log"))?;
+        let child = Command::new(binary.as_ref())
+            .arg("-d")
+            .arg("-c")
+            .arg(&config_path)
+            .stdout(log.try_clone()?)
+            .stderr(log)
+            .spawn()?;
+
+        Ok(Self { dir, child })
+    }
+
+    pub fn config_path(&self) -> PathBuf {
+        self.dir.path().join("empowerd.conf")
+    }
+
+    /// Returns the output of the daemon.
+    pub fn log(&self) -> String {
+        fs::read_to_string(self.dir.path().join("empowerd.log"))
+            .unwrap_or_default()
+    }
+
+    /// Returns the exit code if the daemon has stopped.
+    pub fn exit_code(&mut self) -> io::Result<Option<i32>> {
+        Ok(self.child.try_wait()?.map(|x| x.code().unwrap_or(-1)))
+    }
+}
+
+impl Drop for Daemon {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
@@ -0,0 +1,178 @@
+/******************************************************************************\
+    empowerd - empowers the offline smart home
+    Copyright (C) 2019 - 2026 Max Maisel
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU Affero General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU Affero General Public License for more details.
+
+    You should have received a copy of the GNU Affero General Public License
+    along with this program.  If not, see <https://www.gnu.org/licenses/>.
+\******************************************************************************/
+use serde_json::json;
+use std::{
+    io,
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+};
+use tokio::{net::UdpSocket, task::JoinHandle};
+
+/// State of a simulated KEBA KeContact wallbox.
+#[derive(Clone, Debug, PartialEq)]
+pub struct KebaState {
+    /// Charging state, 3 is charging.
+    pub state: u32,
+    /// Plug state, 7 is plugged on both sides and locked.
+    pub plug: u32,
+    /// Charging is enabled by the user.
+    pub enabled: bool,
+    /// Hardware current limit in milliampere.
+    pub max_current_hw: u32,
+    /// Current limit set with "curr" in milliampere.
+    pub max_current: u32,
+    /// Phase voltages in volt.
+    pub voltages: [u32; 3],
+    /// Phase currents in milliampere.
+    pub currents: [u32; 3],
+    /// Energy of the current session in 0.1 watt hours.
+    pub e_pres: u64,
+    /// Total energy in 0.1 watt hours.
+    pub e_total: u64,
+}
+
+impl Default for KebaState {
+    fn default() -> Self {
+        Self {
+            state: 2,
+            plug: 7,
+            enabled: true,
+            max_current_hw: 32000,
+            max_current: 16000,
+            voltages: [230; 3],
+            currents: [0; 3],
+            e_pres: 0,
+            e_total: 0,
+        }
+    }
+}
+
+impl KebaState {
+    /// Returns the answer to a UDP command.
+    fn handle(&mut self, command: &str) -> String {
+        let mut parts = command.split_whitespace();
+        match (parts.next(), parts.next()) {
+            (Some("report"), Some("2")) => json!({
+                "ID": "2",
+                "State": self.state,
+                "Plug": self.plug,
+                "Error1": 0,
+                "Error2": 0,
+                "Enable user": self.enabled as u32,
+                "Curr HW": self.max_current_hw,
+                "Curr user": self.max_current,
+            })
+            .to_string(),
+            (Some("report"), Some("3")) => json!({
+                "ID": "3",
+                "U1": self.voltages[0],
+                "U2": self.voltages[1],
+                "U3": self.voltages[2],
+                "I1": self.currents[0],
+                "I2": self.currents[1],
+                "I3": self.currents[2],
+                "E pres": self.e_pres,
+                "E total": self.e_total,
+            })
+            .to_string(),
+            (Some("ena"), Some(x)) if x == "0" || x == "1" => {
+                self.enabled = x == "1";
+                "TCH-OK :done\n".into()
+            }
+            (Some("curr"), Some(x)) => match x.parse() {
+                Ok(current) if current <= self.max_current_hw => {
+                    self.max_current = current;
+                    "TCH-OK :done\n".into()
+                }
+                _ => "TCH-ERR :invalid current\n".into(),
+            },
+            _ => "TCH-ERR :unknown command\n".into(),
+        }
+    }
+}
+
+/// UDP responder which answers the commands of a KEBA KeContact wallbox.
+/// The client always binds port 7090, so the responder must use another
+/// port or address.
+pub struct KebaResponder {
+    addr: SocketAddr,
+    state: Arc<Mutex<KebaState>>,
+    task: JoinHandle<()>,
+}
+
+impl KebaResponder {
+    pub async fn start(addr: SocketAddr, state: KebaState) -> io::Result<Self> {
+        let socket = UdpSocket::bind(addr).await?;
+        let addr = socket.local_addr()?;
+        let state = Arc::new(Mutex::new(state));
+        let task_state = state.clone();
+
+        let task = tokio::spawn(async move {
+            let mut buffer = [0; 512];
+            while let Ok((len, peer)) = socket.recv_from(&mut buffer).await {
+                let command = String::from_utf8_lossy(&buffer[..len]);
+                let response = task_state.lock().unwrap().handle(&command);
+                let _ = socket.send_to(response.as_bytes(), peer).await;
+            }
+        });
+
+        Ok(Self { addr, state, task })
+    }
+
+    pub fn local_addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    pub fn state(&self) -> KebaState {
+        self.state.lock().unwrap().clone()
+    }
+
+    /// Changes the state while the responder is running.
+    pub fn update(&self, f: impl FnOnce(&mut KebaState)) {
+        f(&mut self.state.lock().unwrap())
+    }
+}
+
+impl Drop for KebaResponder {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+#[tokio::test]
+async fn test_keba_responder() {
+    use kecontact_client::KeContactClient;
+
+    let keba = KebaResponder::start(
+        "127.0.0.1:0".parse().unwrap(),
+        KebaState {
+            e_total: 123450,
+            ..Default::default()
+        },
+    )
+    .await
+    .unwrap();
+    let client = KeContactClient::new(keba.local_addr(), None);
+
+    assert_eq!(123450, client.power_report().await.unwrap().e_total);
+    client.set_max_current(10000).await.unwrap();
+    client.set_enable(false).await.unwrap();
+    assert_eq!(0, client.status_report().await.unwrap().enabled);
+    assert_eq!(10000, keba.state().max_current);
+    assert!(client.set_max_current(40000).await.is_err());
+}
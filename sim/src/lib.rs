@@ -0,0 +1,44 @@
+/******************************************************************************\
+    empowerd - empowers the offline smart home
+    Copyright (C) 2019 - 2026 Max Maisel
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU Affero General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU Affero General Public License for more details.
+
+    You should have received a copy of the GNU Affero General Public License
+    along with this program.  If not, see <https://www.gnu.org/licenses/>.
+\******************************************************************************/
+#![forbid(unsafe_code)]
+
+//! Local stand-ins for the devices which are supported by empowerd and a
+//! harness to run the daemon against them. All simulators bind to the given
+//! address, use the port from the operating system if it is zero and stop
+//! when they are dropped. Their state can be changed while they run.
+
+use std::{io, net::TcpListener};
+
+pub mod dachs;
+pub mod daemon;
+pub mod keba;
+pub mod modbus;
+pub mod postgres;
+pub mod speedwire;
+
+pub use dachs::DachsServer;
+pub use daemon::Daemon;
+pub use keba::KebaResponder;
+pub use modbus::{ModbusServer, RegisterMap};
+pub use postgres::TempPostgres;
+pub use speedwire::EnergyMeterEmitter;
+
+/// Returns a currently unused TCP port on the loopback interface.
+pub fn free_port() -> io::Result<u16> {
+    Ok(TcpListener::bind("127.0.0.1:0")?.local_addr()?.port())
+}
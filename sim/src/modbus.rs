@@ -0,0 +1,349 @@
+/******************************************************************************\
+    empowerd - empowers the offline smart home
+    Copyright (C) 2019 - 2026 Max Maisel
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU Affero General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU Affero General Public License for more details.
+
+    You should have received a copy of the GNU Affero General Public License
+    along with this program.  If not, see <https://www.gnu.org/licenses/>.
+\******************************************************************************/
+use std::{
+    collections::BTreeMap,
+    future::{self, Ready},
+    io,
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+};
+use tokio::{net::TcpListener, task::JoinHandle};
+use tokio_modbus::{
+    server::{
+        tcp::{accept_tcp_connection, Server},
+        Service,
+    },
+    ExceptionCode, Request, Response,
+};
+
+/// Splits a value into the given number of big endian registers.
+fn words(value: u64, count: u16) -> Vec<u16> {
+    (0..count)
+        .rev()
+        .map(|i| (value >> (16 * i)) as u16)
+        .collect()
+}
+
+/// Holding and input registers of a simulated Modbus device.
+#[derive(Clone, Debug, Default)]
+pub struct RegisterMap {
+    holding: BTreeMap<u16, u16>,
+    input: BTreeMap<u16, u16>,
+    mirrors: Vec<(u16, u16)>,
+}
+
+impl RegisterMap {
+    /// Address of the Lambda heat pump available power register.
+    pub const LAMBDA_AVAILABLE_POWER: u16 = 102;
+    /// Address of the Lambda heat pump current power register.
+    pub const LAMBDA_CURRENT_POWER: u16 = 103;
+    /// Address of the WMaxLimPct register of the simulated SunSpec inverter.
+    pub const SUNSPEC_WMAXLIMPCT: u16 = 40127;
+    /// Address of the WMaxLim_Ena register of the simulated SunSpec inverter.
+    pub const SUNSPEC_WMAXLIM_ENA: u16 = 40131;
+
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Registers of an SMA SunnyIsland with the given energy counters in
+    /// watt hours, state of charge in percent and capacity in watt hours.
+    pub fn sunny_island(
+        wh_in: u32,
+        wh_out: u32,
+        charge: u32,
+        capacity: u32,
+    ) -> Self {
+        let mut map = Self::new();
+        map.set_input(30595, &words(wh_in.into(), 2));
+        map.set_input(30597, &words(wh_out.into(), 2));
+        map.set_input(30845, &words(charge.into(), 2));
+        map.set_input(40187, &words(capacity.into(), 2));
+        map
+    }
+
+    /// Registers of an SMA SunnyBoyStorage with the given energy counters in
+    /// watt hours, state of charge in percent and capacity in watt hours.
+    pub fn sunny_boy_storage(
+        wh_in: u64,
+        wh_out: u64,
+        charge: u32,
+        capacity: u32,
+    ) -> Self {
+        let mut map = Self::new();
+        map.set_input(31397, &words(wh_in, 4));
+        map.set_input(31401, &words(wh_out, 4));
+        map.set_input(30845, &words(charge.into(), 2));
+        map.set_input(40187, &words(capacity.into(), 2));
+        map
+    }
+
+    /// Registers of a SunSpec inverter with the common model 1, the single
+    /// phase inverter model 101 with the given total yield in watt hours and
+    /// the immediate controls model 123.
+    pub fn sunspec_inverter(total_yield: u32) -> Self {
+        let mut map = Self::new();
+        map.set_holding(40000, &[0x5375, 0x6e53]);
+        let mut addr = 40002;
+        for (model, len) in [(1, 66), (101, 50), (123, 24)] {
+            map.set_holding(addr, &[model, len]);
+            map.set_holding(addr + 2, &vec![0; len as usize]);
+            addr += len + 2;
+        }
+        map.set_holding(addr, &[65535, 0]);
+        map.set_holding(40070 + 24, &words(total_yield.into(), 2));
+        map
+    }
+
+    /// Registers of a Lambda heat pump with the given total energy in watt
+    /// hours. The available power which is written by the E-Manager is
+    /// reported as the current power.
+    pub fn lambda_heat_pump(total_energy: u32) -> Self {
+        let mut map = Self::new();
+        map.set_holding(Self::LAMBDA_AVAILABLE_POWER, &[0]);
+        map.set_holding(Self::LAMBDA_CURRENT_POWER, &[0]);
+        map.set_holding(1002, &[0]);
+        map.set_holding(1013, &[400]);
+        map.set_holding(1020, &words(total_energy.into(), 2));
+        map.set_holding(2002, &[480]);
+        map.set_holding(3002, &[450, 350]);
+        map.mirror(Self::LAMBDA_AVAILABLE_POWER, Self::LAMBDA_CURRENT_POWER);
+        map
+    }
+
+    pub fn set_holding(&mut self, addr: u16, values: &[u16]) {
+        for (reg, value) in (addr..).zip(values) {
+            self.holding.insert(reg, *value);
+        }
+    }
+
+    pub fn set_input(&mut self, addr: u16, values: &[u16]) {
+        for (reg, value) in (addr..).zip(values) {
+            self.input.insert(reg, *value);
+        }
+    }
+
+    /// Copies values which are written to holding register `from` to the
+    /// holding register `to`.
+    pub fn mirror(&mut self, from: u16, to: u16) {
+        self.mirrors.push((from, to));
+    }
+
+    pub fn holding(&self, addr: u16, count: u16) -> Option<Vec<u16>> {
+        Self::read(&self.holding, addr, count)
+    }
+
+    pub fn input(&self, addr: u16, count: u16) -> Option<Vec<u16>> {
+        Self::read(&self.input, addr, count)
+    }
+
+    fn read(
+        registers: &BTreeMap<u16, u16>,
+        addr: u16,
+        count: u16,
+    ) -> Option<Vec<u16>> {
+        (addr..addr.checked_add(count)?)
+            .map(|reg| registers.get(&reg).copied())
+            .collect()
+    }
+
+    /// Writes to existing holding registers only, like a real device.
+    fn write(
+        &mut self,
+        addr: u16,
+        values: &[u16],
+    ) -> Result<(), ExceptionCode> {
+        let count = values.len() as u16;
+        if self.holding(addr, count).is_none() {
+            return Err(ExceptionCode::IllegalDataAddress);
+        }
+        self.set_holding(addr, values);
+        for (from, to) in self.mirrors.clone() {
+            if let Some(offset) = from.checked_sub(addr) {
+                if let Some(value) = values.get(offset as usize) {
+                    self.holding.insert(to, *value);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn call(
+        &mut self,
+        req: Request<'static>,
+    ) -> Result<Response, ExceptionCode> {
+        match req {
+            Request::ReadInputRegisters(addr, count) => self
+                .input(addr, count)
+                .map(Response::ReadInputRegisters)
+                .ok_or(ExceptionCode::IllegalDataAddress),
+            Request::ReadHoldingRegisters(addr, count) => self
+                .holding(addr, count)
+                .map(Response::ReadHoldingRegisters)
+                .ok_or(ExceptionCode::IllegalDataAddress),
+            Request::WriteSingleRegister(addr, value) => {
+                self.write(addr, &[value])?;
+                Ok(Response::WriteSingleRegister(addr, value))
+            }
+            Request::WriteMultipleRegisters(addr, values) => {
+                self.write(addr, &values)?;
+                Ok(Response::WriteMultipleRegisters(addr, values.len() as u16))
+            }
+            _ => Err(ExceptionCode::IllegalFunction),
+        }
+    }
+}
+
+struct RegisterService {
+    registers: Arc<Mutex<RegisterMap>>,
+}
+
+impl Service for RegisterService {
+    type Request = Request<'static>;
+    type Response = Response;
+    type Exception = ExceptionCode;
+    type Future = Ready<Result<Response, ExceptionCode>>;
+
+    fn call(&self, req: Self::Request) -> Self::Future {
+        future::ready(self.registers.lock().unwrap().call(req))
+    }
+}
+
+/// Modbus TCP server which serves a register map for any unit ID.
+pub struct ModbusServer {
+    addr: SocketAddr,
+    registers: Arc<Mutex<RegisterMap>>,
+    task: JoinHandle<()>,
+}
+
+impl ModbusServer {
+    pub async fn start(
+        addr: SocketAddr,
+        registers: RegisterMap,
+    ) -> io::Result<Self> {
+        let listener = TcpListener::bind(addr).await?;
+        let addr = listener.local_addr()?;
+        let registers = Arc::new(Mutex::new(registers));
+        let service = Arc::new(RegisterService {
+            registers: registers.clone(),
+        });
+
+        let task = tokio::spawn(async move {
+            let on_connected = move |stream, socket_addr| {
+                let service = service.clone();
+                future::ready(accept_tcp_connection(
+                    stream,
+                    socket_addr,
+                    move |_| Ok(Some(service.clone())),
+                ))
+            };
+            let _ = Server::new(listener).serve(&on_connected, |_| ()).await;
+        });
+
+        Ok(Self {
+            addr,
+            registers,
+            task,
+        })
+    }
+
+    pub fn local_addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// Changes the registers while the server is running.
+    pub fn update(&self, f: impl FnOnce(&mut RegisterMap)) {
+        f(&mut self.registers.lock().unwrap())
+    }
+
+    pub fn holding(&self, addr: u16, count: u16) -> Option<Vec<u16>> {
+        self.registers.lock().unwrap().holding(addr, count)
+    }
+}
+
+impl Drop for ModbusServer {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+#[tokio::test]
+async fn test_sunny_island() {
+    use sunny_storage_client::{SunnyIslandClient, SunnyStorageClient};
+
+    let server = ModbusServer::start(
+        "127.0.0.1:0".parse().unwrap(),
+        RegisterMap::sunny_island(1000, 800, 50, 10000),
+    )
+    .await
+    .unwrap();
+    let client = SunnyIslandClient::new(server.local_addr(), None).unwrap();
+
+    assert_eq!(Ok((1000, 800, 5000.0)), client.get_in_out_charge().await);
+}
+
+#[tokio::test]
+async fn test_sunspec_inverter() {
+    use sunspec_client::SunspecClient;
+
+    let server = ModbusServer::start(
+        "127.0.0.1:0".parse().unwrap(),
+        RegisterMap::sunspec_inverter(123456),
+    )
+    .await
+    .unwrap();
+    let mut client = SunspecClient::new(server.local_addr(), Some(126), None);
+    let mut context = client.open().await.unwrap();
+    client.introspect(&mut context).await.unwrap();
+
+    assert_eq!(Some(&40070), client.models().get(&101));
+    assert_eq!(Ok(123456.0), client.get_total_yield(&mut context).await);
+    client
+        .set_power_limit(&mut context, 40.0, 60)
+        .await
+        .unwrap();
+    assert_eq!(
+        Some(vec![40]),
+        server.holding(RegisterMap::SUNSPEC_WMAXLIMPCT, 1)
+    );
+    assert_eq!(
+        Some(vec![1]),
+        server.holding(RegisterMap::SUNSPEC_WMAXLIM_ENA, 1)
+    );
+}
+
+#[tokio::test]
+async fn test_lambda_heat_pump() {
+    use lambda_client::LambdaClient;
+
+    let server = ModbusServer::start(
+        "127.0.0.1:0".parse().unwrap(),
+        RegisterMap::lambda_heat_pump(70000),
+    )
+    .await
+    .unwrap();
+    let mut context =
+        LambdaClient::new(server.local_addr()).open().await.unwrap();
+
+    assert_eq!(Ok(70000), context.get_total_energy().await);
+    context.set_available_power(1500).await.unwrap();
+    assert_eq!(Ok(1500), context.get_current_power().await);
+
+    server.update(|x| x.set_holding(1020, &words(71000, 2)));
+    assert_eq!(Ok(71000), context.get_total_energy().await);
+}
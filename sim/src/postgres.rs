@@ -0,0 +1,136 @@
+/******************************************************************************\
+    empowerd - empowers the offline smart home
+    Copyright (C) 2019 - 2026 Max Maisel
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU Affero General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU Affero General Public License for more details.
+
+    You should have received a copy of the GNU Affero General Public License
+    along with this program.  If not, see <https://www.gnu.org/licenses/>.
+\******************************************************************************/
+use std::{
+    env, io,
+    io::Write,
+    path::PathBuf,
+    process::{Command, Stdio},
+};
+use tempfile::TempDir;
+
+/// Returns the path of a PostgreSQL server binary. They are taken from
+/// the directory in the environment variable PG_BINDIR, e.g.
+/// "/usr/lib/postgresql/15/bin", or the PATH.
+fn pg_binary(name: &str) -> PathBuf {
+    match env::var_os("PG_BINDIR") {
+        Some(dir) => PathBuf::from(dir).join(name),
+        None => PathBuf::from(name),
+    }
+}
+
+fn run(command: &mut Command, stdin: &str) -> io::Result<()> {
+    let mut child = command
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()?;
+    if let Some(mut pipe) = child.stdin.take() {
+        pipe.write_all(stdin.as_bytes())?;
+    }
+
+    let output = child.wait_with_output()?;
+    if output.status.success() {
+        Ok(())
+    } else {
+        Err(io::Error::new(
+            io::ErrorKind::Other,
+            format!(
+                "{:?} failed: {}{}",
+                command.get_program(),
+                String::from_utf8_lossy(&output.stdout),
+                String::from_utf8_lossy(&output.stderr)
+            ),
+        ))
+    }
+}
+
+/// Temporary PostgreSQL cluster which listens on a free port of 127.0.0.1
+/// and accepts the superuser "postgres" with any password. The cluster is
+/// stopped and deleted when it is dropped. PostgreSQL refuses to run as
+/// root.
+pub struct TempPostgres {
+    dir: TempDir,
+    port: u16,
+}
+
+impl TempPostgres {
+    /// Initializes and starts a new cluster with an empty database.
+    pub fn start(database: &str) -> io::Result<Self> {
+        let dir = tempfile::Builder::new().prefix("empowerd-pg-").tempdir()?;
+        let data = dir.path().join("data");
+        run(
+            Command::new(pg_binary("initdb"))
+                .arg("-D")
+                .arg(&data)
+                .args(["-U", "postgres", "-A", "trust", "-E", "UTF8"])
+                .arg("--no-sync"),
+            "",
+        )?;
+        run(
+            Command::new(pg_binary("postgres"))
+                .args(["--single", "-F", "-D"])
+                .arg(&data)
+                .arg("postgres"),
+            &format!("CREATE DATABASE \"{database}\";\n"),
+        )?;
+
+        let port = crate::free_port()?;
+        let postgres = Self { dir, port };
+        run(
+            Command::new(pg_binary("pg_ctl"))
+                .args(["start", "-w", "-D"])
+                .arg(&data)
+                .arg("-l")
+                .arg(postgres.dir.path().join("postgres.log"))
+                .arg("-o")
+                .arg(format!(
+                    "-F -p {port} -k {} -c listen_addresses=127.0.0.1",
+                    postgres.dir.path().display()
+                )),
+            "",
+        )?;
+
+        Ok(postgres)
+    }
+
+    /// Address and port for the *[database]* section of the config.
+    pub fn address(&self) -> String {
+        format!("127.0.0.1:{}", self.port)
+    }
+
+    pub fn url(&self, database: &str) -> String {
+        format!("postgres://postgres@127.0.0.1:{}/{database}", self.port)
+    }
+
+    /// Returns the server log.
+    pub fn log(&self) -> String {
+        std::fs::read_to_string(self.dir.path().join("postgres.log"))
+            .unwrap_or_default()
+    }
+}
+
+impl Drop for TempPostgres {
+    fn drop(&mut self) {
+        let _ = run(
+            Command::new(pg_binary("pg_ctl"))
+                .args(["stop", "-m", "immediate", "-w", "-D"])
+                .arg(self.dir.path().join("data")),
+            "",
+        );
+    }
+}
@@ -0,0 +1,159 @@
+/******************************************************************************\
+    empowerd - empowers the offline smart home
+    Copyright (C) 2019 - 2026 Max Maisel
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU Affero General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU Affero General Public License for more details.
+
+    You should have received a copy of the GNU Affero General Public License
+    along with this program.  If not, see <https://www.gnu.org/licenses/>.
+\******************************************************************************/
+use byteorder_cursor::Cursor;
+use sma_proto::{
+    energymeter::{ObisValue, SmaEmMessage},
+    SmaEndpoint, SmaSerde,
+};
+use socket2::{Domain, Socket, Type};
+use std::{
+    io,
+    net::{Ipv4Addr, SocketAddrV4},
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use tokio::{net::UdpSocket, task::JoinHandle, time};
+
+/// Totals and current power of a simulated SMA energy meter.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct MeterReading {
+    /// Grid import in watt.
+    pub power_in: u32,
+    /// Grid export in watt.
+    pub power_out: u32,
+    /// Total grid import in watt seconds.
+    pub energy_in: u64,
+    /// Total grid export in watt seconds.
+    pub energy_out: u64,
+}
+
+impl MeterReading {
+    fn payload(&self) -> Vec<ObisValue> {
+        [
+            (0x00010400, u64::from(self.power_in) * 10),
+            (0x00010800, self.energy_in),
+            (0x00020400, u64::from(self.power_out) * 10),
+            (0x00020800, self.energy_out),
+        ]
+        .into_iter()
+        .map(|(id, value)| ObisValue { id, value })
+        .collect()
+    }
+}
+
+/// Sends SMA Speedwire energy meter messages to the multicast group
+/// 239.12.255.254:9522 on the given local interface, e.g. 127.0.0.1.
+/// Multicast loopback is enabled, so receivers on the same host get them.
+pub struct EnergyMeterEmitter {
+    reading: Arc<Mutex<MeterReading>>,
+    task: JoinHandle<()>,
+}
+
+impl EnergyMeterEmitter {
+    const SMA_MCAST_IP: Ipv4Addr = Ipv4Addr::new(239, 12, 255, 254);
+    const SMA_PORT: u16 = 9522;
+
+    pub fn start(
+        interface: Ipv4Addr,
+        endpoint: SmaEndpoint,
+        interval: Duration,
+        reading: MeterReading,
+    ) -> io::Result<Self> {
+        let socket = Socket::new(Domain::IPV4, Type::DGRAM, None)?;
+        socket.bind(&SocketAddrV4::new(interface, 0).into())?;
+        socket.set_nonblocking(true)?;
+        socket.set_multicast_if_v4(&interface)?;
+        socket.set_multicast_loop_v4(true)?;
+        let socket = UdpSocket::from_std(socket.into())?;
+        let target = SocketAddrV4::new(Self::SMA_MCAST_IP, Self::SMA_PORT);
+
+        let reading = Arc::new(Mutex::new(reading));
+        let task_reading = reading.clone();
+        let task = tokio::spawn(async move {
+            let mut buffer = [0; SmaEmMessage::LENGTH_MAX];
+            let mut interval = time::interval(interval);
+            loop {
+                interval.tick().await;
+                let timestamp_ms = SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .unwrap_or_default()
+                    .as_millis() as u32;
+                let message = SmaEmMessage {
+                    src: endpoint.clone(),
+                    timestamp_ms,
+                    payload: task_reading.lock().unwrap().payload(),
+                };
+
+                let mut cursor = Cursor::new(&mut buffer[..]);
+                if message.serialize(&mut cursor).is_ok() {
+                    let len = cursor.position();
+                    let _ = socket.send_to(&buffer[..len], target).await;
+                }
+            }
+        });
+
+        Ok(Self { reading, task })
+    }
+
+    pub fn reading(&self) -> MeterReading {
+        self.reading.lock().unwrap().clone()
+    }
+
+    /// Changes the reading while the emitter is running.
+    pub fn update(&self, f: impl FnOnce(&mut MeterReading)) {
+        f(&mut self.reading.lock().unwrap())
+    }
+}
+
+impl Drop for EnergyMeterEmitter {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+#[tokio::test]
+async fn test_energy_meter_emitter() {
+    use sma_proto::client::{SmaClient, SmaSession};
+
+    let endpoint = SmaEndpoint {
+        susy_id: 270,
+        serial: 1234567,
+    };
+    let emitter = EnergyMeterEmitter::start(
+        Ipv4Addr::LOCALHOST,
+        endpoint.clone(),
+        Duration::from_millis(100),
+        MeterReading {
+            energy_in: 3600000,
+            energy_out: 7200000,
+            ..Default::default()
+        },
+    )
+    .unwrap();
+    let session = SmaSession::open_multicast(Ipv4Addr::LOCALHOST).unwrap();
+    let mut client = SmaClient::new(SmaEndpoint::dummy());
+
+    let (_, payload) = time::timeout(
+        Duration::from_secs(2),
+        client.read_em_message(&session, &endpoint),
+    )
+    .await
+    .unwrap()
+    .unwrap();
+    assert_eq!(emitter.reading().payload(), payload);
+}
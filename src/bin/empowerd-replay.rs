@@ -0,0 +1,173 @@
+/******************************************************************************\
+    empowerd - empowers the offline smart home
+    Copyright (C) 2019 - 2026 Max Maisel
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU Affero General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU Affero General Public License for more details.
+
+    You should have received a copy of the GNU Affero General Public License
+    along with this program.  If not, see <https://www.gnu.org/licenses/>.
+\******************************************************************************/
+#![forbid(unsafe_code)]
+#![allow(clippy::needless_return)]
+#![allow(clippy::redundant_field_names)]
+
+use chrono::{DateTime, Utc};
+use clap::Parser;
+use diesel_async::{AsyncConnection, AsyncPgConnection};
+use libempowerd::{
+    models::database_url,
+    replay::{self, Replay},
+    settings::Settings,
+};
+use sloggers::{
+    terminal::{Destination, TerminalLoggerBuilder},
+    types::Severity,
+    Build,
+};
+use std::{collections::BTreeMap, fs, path::PathBuf, process};
+use tokio::runtime::Builder;
+
+/// Replays recorded data through the processors of a config
+#[derive(Debug, Parser)]
+#[command(version)]
+struct Cli {
+    /// Config filename
+    #[clap(short, default_value("/etc/empowerd/empowerd.conf"))]
+    config_path: PathBuf,
+    /// Start of the replay in UTC, e.g. 2024-05-01 or "2024-05-01 12:00:00"
+    #[clap(long, value_parser = replay::parse_time)]
+    from: Option<DateTime<Utc>>,
+    /// End of the replay in UTC, defaults to now
+    #[clap(long, value_parser = replay::parse_time)]
+    to: Option<DateTime<Utc>>,
+    /// Read the records of a source from a CSV file instead of the database
+    #[clap(long, value_name = "SOURCE=FILE")]
+    csv: Vec<String>,
+    /// Write all appliance setpoints to a CSV file
+    #[clap(long, value_name = "FILE")]
+    setpoints: Option<PathBuf>,
+    /// Log the decisions of the processors
+    #[clap(short)]
+    verbose: bool,
+}
+
+fn main() {
+    let options = Cli::parse();
+    let level = if options.verbose {
+        Severity::Debug
+    } else {
+        Severity::Warning
+    };
+    let logger = TerminalLoggerBuilder::new()
+        .level(level)
+        .destination(Destination::Stderr)
+        .build()
+        .unwrap();
+
+    // The processors must handle each record before the next one is sent.
+    let result = match Builder::new_current_thread().enable_all().build() {
+        Ok(rt) => rt.block_on(run(options, logger)),
+        Err(e) => Err(format!("Failed to create tokio runtime: {}", e)),
+    };
+    if let Err(e) = result {
+        eprintln!("{}", e);
+        process::exit(1);
+    }
+}
+
+async fn run(options: Cli, logger: slog::Logger) -> Result<(), String> {
+    let config = fs::read_to_string(&options.config_path)
+        .map_err(|e| format!("Could not read config: {}", e))?;
+    let settings = Settings::parse(&config, &options.config_path)?;
+    let replay = Replay::new(&settings)?;
+
+    let mut csv = BTreeMap::new();
+    for arg in &options.csv {
+        match arg.split_once('=') {
+            Some((source, file)) => csv.insert(source, PathBuf::from(file)),
+            None => {
+                return Err(format!(
+                    "Invalid CSV argument '{}', expected SOURCE=FILE",
+                    arg
+                ))
+            }
+        };
+    }
+
+    let sources = replay.sources()?;
+    let mut conn = None;
+    if sources
+        .iter()
+        .any(|(x, _)| !csv.contains_key(x.name.as_str()))
+    {
+        let url = database_url(&settings.database);
+        conn =
+            Some(AsyncPgConnection::establish(&url).await.map_err(|e| {
+                format!("Connecting to database failed: {}", e)
+            })?);
+    }
+
+    let to = options.to.unwrap_or_else(Utc::now);
+    let mut series = BTreeMap::new();
+    for (source, series_type) in sources {
+        let records = match (csv.remove(source.name.as_str()), &mut conn) {
+            (Some(path), _) => {
+                let text = fs::read_to_string(&path).map_err(|e| {
+                    format!("Could not read {}: {}", path.display(), e)
+                })?;
+                replay::read_csv(series_type, &text)
+                    .map_err(|e| format!("{}: {}", path.display(), e))?
+                    .into_iter()
+                    .filter(|x| {
+                        let time = replay::record_time(x);
+                        !matches!(options.from, Some(from) if time < from)
+                            && time < to
+                    })
+                    .collect()
+            }
+            (None, Some(conn)) => {
+                let from = options.from.ok_or(
+                    "--from is required to replay records from the database",
+                )?;
+                replay::load_series(
+                    conn,
+                    series_type,
+                    source.series_id,
+                    from,
+                    to,
+                )
+                .await
+                .map_err(|e| {
+                    format!(
+                        "Loading records of '{}' failed: {}",
+                        source.name, e
+                    )
+                })?
+            }
+            (None, None) => {
+                return Err(format!("No records for '{}'", source.name))
+            }
+        };
+        series.insert(source.name.clone(), records);
+    }
+    if let Some(source) = csv.keys().next() {
+        return Err(format!("Source '{}' is not replayed", source));
+    }
+
+    let report = replay.run(logger, series).await?;
+    print!("{}", report);
+    if let Some(path) = &options.setpoints {
+        fs::write(path, report.setpoints_csv()).map_err(|e| {
+            format!("Could not write {}: {}", path.display(), e)
+        })?;
+    }
+    Ok(())
+}
@@ -21,7 +21,7 @@
 #![doc = include_str!("../../README.md")]
 
 use daemonize::Daemonize;
-use slog::{debug, error, info, trace, Logger};
+use slog::{debug, error, info, trace, warn, Logger};
 use sloggers::{
     file::FileLoggerBuilder,
     terminal::{Destination, TerminalLoggerBuilder},
@@ -29,16 +29,35 @@ use sloggers::{
     Build,
 };
 
-use std::{net, process, sync::Arc};
-use tokio::{net::TcpListener, runtime::Runtime, signal};
+use std::{
+    collections::BTreeMap, net, path::Path, process, sync::Arc, time::Duration,
+};
+use tokio::{
+    net::TcpListener,
+    runtime::Runtime,
+    signal::{
+        self,
+        unix::{signal, SignalKind},
+    },
+    sync::{mpsc, watch},
+    task::JoinHandle,
+};
 
+use diesel_async::{pooled_connection::deadpool::Pool, AsyncPgConnection};
 use libempowerd::{
-    graphql,
-    processors::{self, ProcessorInfo},
-    session_manager::SessionManager,
+    config_editor::ConfigEditor,
+    graph::NodeGraph,
+    graphql::{self, tls::TlsServer},
+    login_guard::LoginGuard,
+    models::{database_pool, ApiToken, Model, SeriesType},
+    notifications::Notifier,
+    processors::{self, ProcessorCommands, Selection},
+    reload::{self, Changes},
+    session_manager::{Identity, SessionManager},
     settings::Settings,
-    sinks, sources,
-    task_group::TaskGroup,
+    sinks::{self, Sinks},
+    sources,
+    task_group::{TaskGroup, TaskGroupBuilder},
     uiconfig::UiConfig,
     Globals,
 };
@@ -52,6 +71,14 @@ fn main() {
         }
     };
 
+    if let Some(format) = settings.export_graph {
+        let graph = NodeGraph::new(&settings);
+        print!("{}", graph.export(format, &BTreeMap::new()));
+        process::exit(0);
+    } else if settings.test_cfg {
+        print!("{}", NodeGraph::new(&settings));
+    }
+
     if settings.daemonize {
         let daemon = Daemonize::new()
             .pid_file(&settings.pid_file)
@@ -93,19 +120,6 @@ fn main() {
     process::exit(retval);
 }
 
-fn check_task_result(result: Result<(), ()>, logger: &Logger) -> i32 {
-    match result {
-        Ok(_) => {
-            info!(logger, "Some task finished, exit.");
-            0
-        }
-        Err(_) => {
-            info!(logger, "Some task failed, exit.");
-            1
-        }
-    }
-}
-
 async fn shutdown_group(
     mut group: TaskGroup,
     logger: &Logger,
@@ -114,81 +128,323 @@ async fn shutdown_group(
         error!(logger, "Canceling {} failed: {}", group.name(), e);
         return Err(());
     }
-    if group.run().await.is_err() {
-        error!(logger, "Error occured during {} shutdown", group.name());
-        return Err(());
-    }
+    group.run().await;
     Ok(())
 }
 
-async fn tokio_main(settings: Settings, logger: Logger) -> i32 {
-    let (mut sources, outputs) =
-        match sources::polling_tasks(logger.clone(), &settings) {
-            Ok(x) => x,
-            Err(e) => {
-                error!(logger, "Initializing sources failed: {}", e);
-                return 0;
+/// The running nodes which are updated by a reload.
+struct Nodes {
+    settings: Settings,
+    sources: TaskGroup,
+    processors: TaskGroup,
+    outputs: BTreeMap<String, watch::Sender<Model>>,
+    sinks: Sinks,
+    processor_cmds: ProcessorCommands,
+}
+
+impl Nodes {
+    fn new(
+        logger: &Logger,
+        settings: Settings,
+        database: &Pool<AsyncPgConnection>,
+    ) -> Result<Self, String> {
+        let mut outputs = BTreeMap::new();
+        let mut sources =
+            TaskGroupBuilder::new("sources".into(), logger.clone());
+        sources::polling_tasks(
+            logger.clone(),
+            &settings,
+            database.clone(),
+            &mut sources,
+            &mut outputs,
+            None,
+        )
+        .map_err(|e| format!("Initializing sources failed: {}", e))?;
+
+        let sinks = sinks::make_sinks(logger.clone(), &settings)
+            .map_err(|e| format!("Initializing sinks failed: {}", e))?;
+
+        let mut processors =
+            TaskGroupBuilder::new("processors".into(), logger.clone());
+        let processor_cmds = processors::processor_tasks(
+            logger.clone(),
+            &settings,
+            &mut processors,
+            &mut outputs,
+            &sinks,
+            Some(database.clone()),
+            Selection::All,
+        )
+        .map_err(|e| format!("Initializing processors failed: {}", e))?;
+
+        Ok(Self {
+            settings,
+            sources: sources.build(),
+            processors: processors.build(),
+            outputs,
+            sinks,
+            processor_cmds,
+        })
+    }
+
+    /// Restarts the changed nodes with the given settings. Failed nodes
+    /// stay stopped.
+    async fn apply(
+        &mut self,
+        logger: &Logger,
+        settings: &Settings,
+        changes: &Changes,
+        database: &Pool<AsyncPgConnection>,
+    ) -> Result<(), String> {
+        for name in &changes.processors {
+            if settings.processors.iter().any(|x| &x.name == name) {
+                self.processors.stop(name).await;
+            } else {
+                self.processors.remove(name).await;
+                self.outputs.remove(name);
             }
-        };
+        }
+        for name in &changes.sources {
+            if settings.sources.iter().any(|x| &x.name == name) {
+                self.sources.stop(name).await;
+            } else {
+                self.sources.remove(name).await;
+                self.outputs.remove(name);
+            }
+        }
 
-    let (sinks, switch_proc_info) =
-        match sinks::make_sinks(logger.clone(), &settings) {
-            Ok(x) => x,
-            Err(e) => {
-                error!(logger, "Initializing sinks failed: {}", e);
-                return 0;
+        self.sinks = sinks::update_sinks(
+            logger.clone(),
+            settings,
+            Some((&self.sinks, changes)),
+        )?;
+
+        let mut sources = self.sources.builder();
+        let result = sources::polling_tasks(
+            logger.clone(),
+            settings,
+            database.clone(),
+            &mut sources,
+            &mut self.outputs,
+            Some(&changes.sources),
+        );
+        self.sources.merge(sources.build());
+        result?;
+
+        let mut processors = self.processors.builder();
+        let result = processors::processor_tasks(
+            logger.clone(),
+            settings,
+            &mut processors,
+            &mut self.outputs,
+            &self.sinks,
+            Some(database.clone()),
+            Selection::Only(&changes.processors, &self.processor_cmds),
+        );
+        self.processors.merge(processors.build());
+        self.processor_cmds = result?;
+        Ok(())
+    }
+
+    /// Loads the config file again and restarts the changed nodes.
+    /// The previous nodes are restored if the new ones fail to start.
+    async fn reload(
+        &mut self,
+        logger: &Logger,
+        database: &Pool<AsyncPgConnection>,
+    ) -> Result<Changes, String> {
+        let settings = self.settings.reload()?;
+        let changes = reload::diff(&self.settings, &settings);
+        for x in &changes.unsupported {
+            warn!(logger, "Changed {} settings require a restart", x);
+        }
+        if changes.is_empty() {
+            info!(logger, "No nodes changed");
+        } else {
+            debug!(logger, "Changed nodes: {:?}", &changes);
+        }
+
+        if let Err(e) = self.apply(logger, &settings, &changes, database).await
+        {
+            let old = self.settings.clone();
+            if let Err(e) = self.apply(logger, &old, &changes, database).await {
+                error!(logger, "Restoring previous nodes failed: {}", e);
             }
+            return Err(e);
+        }
+        self.settings = settings;
+        Ok(changes)
+    }
+
+    fn globals(
+        &self,
+        logger: &Logger,
+        session_manager: Arc<SessionManager>,
+        login_guard: Arc<LoginGuard>,
+        config_editor: Arc<ConfigEditor>,
+        database: Pool<AsyncPgConnection>,
+    ) -> Result<Globals, String> {
+        Ok(Globals {
+            logger: logger.clone(),
+            username: self.settings.graphql.username.clone(),
+            hashed_pw: self.settings.graphql.hashed_password.clone(),
+            session_manager,
+            login_guard,
+            switch_mux: self.sinks.switch_mux()?,
+            processor_cmds: self.processor_cmds.clone(),
+            nodes: self
+                .outputs
+                .iter()
+                .map(|(name, tx)| (name.clone(), tx.subscribe()))
+                .collect(),
+            database,
+            series: SeriesType::from_settings(&self.settings),
+            task_errors: self
+                .sources
+                .errors()
+                .into_iter()
+                .chain(self.processors.errors())
+                .collect(),
+            task_health: self
+                .sources
+                .health()
+                .into_iter()
+                .chain(self.processors.health())
+                .collect(),
+            metrics_token: self.settings.graphql.metrics_token.clone(),
+            graph: NodeGraph::new(&self.settings),
+            config_editor,
+            uiconfig: UiConfig::from_settings(&self.settings),
+        })
+    }
+}
+
+fn spawn_notifier(
+    logger: &Logger,
+    settings: &Settings,
+    globals: &Globals,
+) -> Result<Option<JoinHandle<()>>, String> {
+    let notifier = Notifier::new(
+        logger.clone(),
+        settings,
+        globals.nodes.clone(),
+        &globals.task_errors,
+    )
+    .map_err(|e| format!("Initializing notifications failed: {e}"))?;
+
+    if notifier.is_empty() || settings.test_cfg {
+        return Ok(None);
+    }
+    Ok(Some(tokio::task::spawn(notifier.run())))
+}
+
+const API_TOKEN_RETRY: Duration = Duration::from_secs(30);
+
+async fn load_api_tokens(
+    logger: &Logger,
+    database: &Pool<AsyncPgConnection>,
+    session_manager: &SessionManager,
+) -> Result<(), String> {
+    let mut conn = database.get().await.map_err(|e| e.to_string())?;
+    let tokens = ApiToken::all(&mut conn).await.map_err(String::from)?;
+    for token in tokens {
+        let identity = Identity {
+            user: token.user,
+            role: token.role,
         };
+        session_manager
+            .add_api_token(token.token_hash, identity)
+            .map_err(|e| e.to_string(logger))?;
+    }
+    Ok(())
+}
 
-    let switch_mux = match sinks.get("_SwitchMux") {
-        Some(x) => match x {
-            sinks::ArcSink::SwitchMux(x) => x.clone(),
-            _ => {
-                error!(logger, "SwitchMux has invalid type");
-                return 2;
+async fn retry_api_tokens(
+    logger: Logger,
+    database: Pool<AsyncPgConnection>,
+    session_manager: Arc<SessionManager>,
+) {
+    loop {
+        tokio::time::sleep(API_TOKEN_RETRY).await;
+        match load_api_tokens(&logger, &database, &session_manager).await {
+            Ok(()) => {
+                info!(logger, "Loaded API tokens");
+                return;
             }
-        },
-        None => {
-            error!(logger, "Could not find SwitchMux sink");
+            Err(e) => debug!(logger, "Loading API tokens failed: {}", e),
+        }
+    }
+}
+
+async fn tokio_main(settings: Settings, logger: Logger) -> i32 {
+    let database = match database_pool(&settings.database) {
+        Ok(x) => x,
+        Err(e) => {
+            error!(logger, "Initializing database failed: {}", e);
+            return 0;
+        }
+    };
+
+    // Registered early, the default action would terminate the daemon.
+    let mut sighup = match signal(SignalKind::hangup()) {
+        Ok(x) => x,
+        Err(e) => {
+            error!(logger, "Registering SIGHUP handler failed: {}", e);
             return 2;
         }
     };
 
-    let ProcessorInfo {
-        tasks: mut processors,
-        commands: processor_cmds,
-    } = match processors::processor_tasks(
-        logger.clone(),
-        &settings,
-        outputs,
-        sinks,
-        switch_proc_info,
-    ) {
+    let mut nodes = match Nodes::new(&logger, settings, &database) {
         Ok(x) => x,
         Err(e) => {
-            error!(logger, "Initializing processors failed: {}", e);
+            error!(logger, "{}", e);
             return 0;
         }
     };
+    let settings = nodes.settings.clone();
 
-    let session_manager =
-        match SessionManager::new(settings.graphql.session_timeout) {
-            Ok(x) => x,
-            Err(e) => {
-                error!(logger, "Creating session manager failed: {}", e);
-                return 2;
-            }
-        };
+    let session_manager = match SessionManager::new(
+        settings.graphql.session_timeout,
+        settings.graphql.key_file.as_deref().map(Path::new),
+    ) {
+        Ok(x) => Arc::new(x),
+        Err(e) => {
+            error!(logger, "Creating session manager failed: {}", e);
+            return 2;
+        }
+    };
+    // The daemon also starts without database. API tokens are loaded as
+    // soon as it is available.
+    if let Err(e) = load_api_tokens(&logger, &database, &session_manager).await
+    {
+        warn!(logger, "Loading API tokens failed, retrying: {}", e);
+        tokio::task::spawn(retry_api_tokens(
+            logger.clone(),
+            database.clone(),
+            session_manager.clone(),
+        ));
+    }
 
-    let globals = Arc::new(Globals {
-        logger: logger.clone(),
-        username: settings.graphql.username.clone(),
-        hashed_pw: settings.graphql.hashed_password.clone(),
-        session_manager,
-        switch_mux,
-        processor_cmds,
-        uiconfig: UiConfig::from_settings(&settings),
-    });
+    let login_guard = Arc::new(LoginGuard::new(
+        std::thread::available_parallelism().map_or(1, |x| x.get() / 2),
+    ));
+    let (reload_tx, mut reload_rx) = mpsc::channel(1);
+    let config_editor =
+        Arc::new(ConfigEditor::new(settings.config_path.clone(), reload_tx));
+    let globals = match nodes.globals(
+        &logger,
+        session_manager.clone(),
+        login_guard.clone(),
+        config_editor.clone(),
+        database.clone(),
+    ) {
+        Ok(x) => Arc::new(x),
+        Err(e) => {
+            error!(logger, "{}", e);
+            return 2;
+        }
+    };
+    let (globals_tx, globals_rx) = watch::channel(globals.clone());
 
     let address =
         match settings.graphql.listen_address.parse::<net::SocketAddr>() {
@@ -209,44 +465,114 @@ async fn tokio_main(settings: Settings, logger: Logger) -> i32 {
             return 2;
         }
     };
-    info!(logger, "Listening on http://{}", address);
-    let server = tokio::task::spawn(graphql::server::run_graphql(
+    let tls = match settings.graphql.tls.clone().map(TlsServer::new) {
+        Some(Ok(x)) => Some(x),
+        Some(Err(e)) => {
+            error!(logger, "Initializing TLS failed: {e}");
+            return 2;
+        }
+        None => None,
+    };
+    let scheme = if tls.is_some() { "https" } else { "http" };
+    info!(logger, "Listening on {}://{}", scheme, address);
+    let mut server = tokio::task::spawn(graphql::server::run_graphql(
         listener,
-        globals.clone(),
+        globals_rx,
         logger.clone(),
+        tls,
     ));
 
+    let mut notifier = match spawn_notifier(&logger, &settings, &globals) {
+        Ok(x) => x,
+        Err(e) => {
+            error!(logger, "{e}");
+            return 2;
+        }
+    };
+
     if settings.test_cfg {
         info!(logger, "Config valid");
         return 0;
     }
 
-    let retval = tokio::select! {
-        x = sources.run() => {
-            check_task_result(x, &logger)
-        }
-        x = processors.run() => {
-            check_task_result(x, &logger)
-        }
-        _ = server => {
-            info!(logger, "server!!!");
-            1
+    let retval = loop {
+        let responder = tokio::select! {
+            // Task groups only finish after they were canceled. They are
+            // polled to mark failed tasks.
+            _ = nodes.sources.run() => break 1,
+            _ = nodes.processors.run() => break 1,
+            _ = &mut server => {
+                info!(logger, "server!!!");
+                break 1;
+            }
+            _ = signal::ctrl_c() => {
+                info!(logger, "Received SIGINT, exit.");
+                break 0;
+            }
+            _ = sighup.recv() => {
+                info!(logger, "Received SIGHUP, reloading config");
+                None
+            }
+            Some(x) = reload_rx.recv() => {
+                info!(logger, "Config was changed by the API, reloading");
+                Some(x)
+            }
+        };
+
+        let result = match nodes.reload(&logger, &database).await {
+            Ok(changes) => nodes
+                .globals(
+                    &logger,
+                    session_manager.clone(),
+                    login_guard.clone(),
+                    config_editor.clone(),
+                    database.clone(),
+                )
+                .map(|globals| (changes, globals)),
+            Err(e) => Err(format!("Reloading config failed: {}", e)),
+        };
+        let result = result.map(|(changes, globals)| {
+            if changes.notifications {
+                match spawn_notifier(&logger, &nodes.settings, &globals) {
+                    Ok(x) => {
+                        if let Some(old) = notifier.take() {
+                            old.abort();
+                        }
+                        notifier = x;
+                    }
+                    Err(e) => error!(logger, "{}", e),
+                }
+            }
+            globals_tx.send_replace(Arc::new(globals));
+            info!(logger, "Config reloaded");
+            changes
+        });
+        if let Err(e) = &result {
+            error!(logger, "{}", e);
         }
-        _ = signal::ctrl_c() => {
-            info!(logger, "Received SIGINT, exit.");
-            0
+        if let Some(x) = responder {
+            // The request may have been canceled by the client.
+            let _ = x.send(result);
         }
     };
 
+    let Nodes {
+        sources,
+        processors,
+        sinks,
+        ..
+    } = nodes;
     let (source_result, processor_result) = tokio::join! {
         shutdown_group(sources, &logger),
         shutdown_group(processors, &logger),
     };
 
     // Turn off all switches when shutting down.
-    for id in globals.switch_mux.ids() {
-        if let Err(e) = globals.switch_mux.write_val_raw(id, false).await {
-            error!(logger, "Failed to switch of channel {id}: {e}");
+    if let Ok(switch_mux) = sinks.switch_mux() {
+        for id in switch_mux.ids() {
+            if let Err(e) = switch_mux.write_val_raw(id, false).await {
+                error!(logger, "Failed to switch of channel {id}: {e}");
+            }
         }
     }
 
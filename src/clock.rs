@@ -0,0 +1,36 @@
+/******************************************************************************\
+    empowerd - empowers the offline smart home
+    Copyright (C) 2019 - 2026 Max Maisel
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU Affero General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU Affero General Public License for more details.
+
+    You should have received a copy of the GNU Affero General Public License
+    along with this program.  If not, see <https://www.gnu.org/licenses/>.
+\******************************************************************************/
+use chrono::{DateTime, Utc};
+use std::sync::atomic::{AtomicI64, Ordering};
+
+// Milliseconds since the epoch or i64::MIN if the system time is used.
+static VIRTUAL_TIME: AtomicI64 = AtomicI64::new(i64::MIN);
+
+/// Returns the current time of the processors. During a replay, this is the
+/// time of the latest replayed record instead of the system time.
+pub fn now() -> DateTime<Utc> {
+    match VIRTUAL_TIME.load(Ordering::Relaxed) {
+        i64::MIN => Utc::now(),
+        x => DateTime::from_timestamp_millis(x).unwrap_or_else(Utc::now),
+    }
+}
+
+/// Replaces the system time by the given time for the rest of the process.
+pub fn set_virtual_time(time: DateTime<Utc>) {
+    VIRTUAL_TIME.store(time.timestamp_millis(), Ordering::Relaxed);
+}
@@ -0,0 +1,165 @@
+/******************************************************************************\
+    empowerd - empowers the offline smart home
+    Copyright (C) 2019 - 2026 Max Maisel
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU Affero General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU Affero General Public License for more details.
+
+    You should have received a copy of the GNU Affero General Public License
+    along with this program.  If not, see <https://www.gnu.org/licenses/>.
+\******************************************************************************/
+use crate::{
+    config_file, models::ConfigVersion, reload::Changes, settings::Settings,
+    Error,
+};
+use diesel_async::AsyncPgConnection;
+use std::{
+    fs::{self, File},
+    io::Write,
+    path::PathBuf,
+};
+use tokio::sync::{mpsc, oneshot, Mutex};
+
+/// Requests a reload of the config file from the main loop.
+pub type ReloadRequest = oneshot::Sender<Result<Changes, String>>;
+
+/// Reads and writes the main config file and keeps a history of all
+/// changes in the database.
+pub struct ConfigEditor {
+    path: PathBuf,
+    reload: mpsc::Sender<ReloadRequest>,
+    /// Serializes updates of the config file.
+    lock: Mutex<()>,
+}
+
+impl ConfigEditor {
+    pub fn new(path: PathBuf, reload: mpsc::Sender<ReloadRequest>) -> Self {
+        Self {
+            path,
+            reload,
+            lock: Mutex::new(()),
+        }
+    }
+
+    fn read_raw(&self) -> Result<String, Error> {
+        fs::read_to_string(&self.path).map_err(|e| {
+            Error::System(format!(
+                "Could not read {}: {}",
+                self.path.display(),
+                e
+            ))
+        })
+    }
+
+    /// Reads the main config file with redacted secrets.
+    pub fn read(&self) -> Result<String, Error> {
+        config_file::redact(&self.read_raw()?).map_err(Error::InvalidInput)
+    }
+
+    /// Reads the main config file merged with its include files and
+    /// resolved secret references. Secrets are redacted.
+    pub fn read_resolved(&self) -> Result<String, Error> {
+        let config =
+            config_file::read(&self.path).map_err(Error::InvalidInput)?;
+        let config = toml::to_string(&config)
+            .map_err(|e| Error::Bug(format!("Serializing config: {}", e)))?;
+        config_file::redact(&config).map_err(Error::InvalidInput)
+    }
+
+    /// Replaces the config file by writing a temporary file next to it
+    /// and renaming it, so readers never see a partial file.
+    fn write(&self, config: &str) -> Result<(), Error> {
+        let error = |e: std::io::Error| {
+            Error::System(format!(
+                "Could not write {}: {}",
+                self.path.display(),
+                e
+            ))
+        };
+        let mut name = self.path.file_name().unwrap_or_default().to_owned();
+        name.push(".tmp");
+        let tmp = self.path.with_file_name(name);
+
+        let permissions =
+            fs::metadata(&self.path).map_err(error)?.permissions();
+        let mut file = File::create(&tmp).map_err(error)?;
+        file.set_permissions(permissions).map_err(error)?;
+        file.write_all(config.as_bytes()).map_err(error)?;
+        file.sync_all().map_err(error)?;
+        fs::rename(&tmp, &self.path).map_err(error)
+    }
+
+    async fn apply(&self) -> Result<Changes, String> {
+        let (tx, rx) = oneshot::channel();
+        self.reload
+            .send(tx)
+            .await
+            .map_err(|_| "Main loop is not running".to_string())?;
+        rx.await
+            .map_err(|_| "Main loop dropped the reload request".to_string())?
+    }
+
+    /// Validates the new content of the main config file, writes it and
+    /// reloads the config. Redacted secrets are replaced with the current
+    /// ones. The config file is restored if the reload fails. The history
+    /// only stores the redacted content.
+    pub async fn update(
+        &self,
+        conn: &mut AsyncPgConnection,
+        user: &str,
+        comment: &str,
+        config: &str,
+    ) -> Result<(ConfigVersion, Changes), Error> {
+        let _guard = self.lock.lock().await;
+        let current = self.read_raw()?;
+        let config = config_file::restore(config, &current)
+            .map_err(Error::InvalidInput)?;
+        Settings::parse(&config, &self.path).map_err(Error::InvalidInput)?;
+
+        // Manual changes of the file are stored first, so they can be
+        // restored as well.
+        let redacted =
+            config_file::redact(&current).map_err(Error::InvalidInput)?;
+        let latest = ConfigVersion::latest(conn, 1).await?;
+        if latest.first().map(|x| &x.config) != Some(&redacted) {
+            ConfigVersion::insert(conn, "", "Config file", &redacted).await?;
+        }
+
+        self.write(&config)?;
+        let changes = match self.apply().await {
+            Ok(x) => x,
+            Err(e) => {
+                self.write(&current)?;
+                return Err(Error::Temporary(format!(
+                    "Reloading config failed: {}",
+                    e
+                )));
+            }
+        };
+        let redacted =
+            config_file::redact(&config).map_err(Error::InvalidInput)?;
+        let version =
+            ConfigVersion::insert(conn, user, comment, &redacted).await?;
+        Ok((version, changes))
+    }
+
+    /// Restores a stored version of the config file with the current
+    /// secrets.
+    pub async fn rollback(
+        &self,
+        conn: &mut AsyncPgConnection,
+        user: &str,
+        id: i32,
+    ) -> Result<(ConfigVersion, Changes), Error> {
+        let version = ConfigVersion::get(conn, id).await?;
+        let comment = format!("Rollback to version {}", id);
+        self.update(conn, user, &comment, &version.config).await
+    }
+}
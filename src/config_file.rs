@@ -0,0 +1,482 @@
+/******************************************************************************\
+    empowerd - empowers the offline smart home
+    Copyright (C) 2019 - 2026 Max Maisel
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU Affero General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU Affero General Public License for more details.
+
+    You should have received a copy of the GNU Affero General Public License
+    along with this program.  If not, see <https://www.gnu.org/licenses/>.
+\******************************************************************************/
+use std::{
+    collections::BTreeMap,
+    path::{Path, PathBuf},
+};
+use toml::{Table, Value};
+use toml_edit::{DocumentMut, Item, TableLike};
+
+/// Replaces secret values in redacted config files.
+pub const REDACTED: &str = "**SECRET**";
+
+/// Reads a config file into a string.
+fn read_file(path: &Path) -> Result<String, String> {
+    std::fs::read_to_string(path).map_err(|e| {
+        format!("Could not read config file '{}': {}", path.display(), e)
+    })
+}
+
+/// Parses the TOML content of a file into a table.
+fn parse_table(toml: &str, path: &Path) -> Result<Table, String> {
+    toml.parse::<Table>().map_err(|e| {
+        format!("Could not parse config file '{}': {}", path.display(), e)
+    })
+}
+
+/// Matches a file name against a pattern with `*` and `?` wildcards.
+fn matches(pattern: &[char], name: &[char]) -> bool {
+    match (pattern.first(), name.first()) {
+        (None, None) => true,
+        (Some('*'), _) => {
+            matches(&pattern[1..], name)
+                || (!name.is_empty() && matches(pattern, &name[1..]))
+        }
+        (Some('?'), Some(_)) => matches(&pattern[1..], &name[1..]),
+        (Some(x), Some(y)) if x == y => matches(&pattern[1..], &name[1..]),
+        _ => false,
+    }
+}
+
+/// Returns the sorted paths of all files which match an include pattern.
+/// Patterns without wildcards must match an existing file.
+fn expand(base: &Path, pattern: &str) -> Result<Vec<PathBuf>, String> {
+    let pattern = base.join(pattern);
+    let name = match pattern.file_name().and_then(|x| x.to_str()) {
+        Some(x) => x,
+        None => return Err(format!("Invalid include '{}'", pattern.display())),
+    };
+    if !name.contains(['*', '?']) {
+        return Ok(vec![pattern]);
+    }
+
+    let dir = pattern.parent().unwrap_or(base);
+    let entries = std::fs::read_dir(dir).map_err(|e| {
+        format!(
+            "Could not read include directory '{}': {}",
+            dir.display(),
+            e
+        )
+    })?;
+    let name = name.chars().collect::<Vec<_>>();
+    let mut paths = Vec::new();
+    for entry in entries {
+        let entry = entry.map_err(|e| {
+            format!(
+                "Could not read include directory '{}': {}",
+                dir.display(),
+                e
+            )
+        })?;
+        let file_name = entry
+            .file_name()
+            .to_string_lossy()
+            .chars()
+            .collect::<Vec<_>>();
+        // Hidden files, e.g. editor backups, are only matched explicitly.
+        if file_name.first() == Some(&'.') && name.first() != Some(&'.') {
+            continue;
+        }
+        if matches(&name, &file_name) {
+            paths.push(entry.path());
+        }
+    }
+    paths.sort();
+    Ok(paths)
+}
+
+/// Merges an included table into the config.
+fn merge(
+    config: &mut Table,
+    include: Table,
+    prefix: &str,
+    path: &Path,
+) -> Result<(), String> {
+    for (key, value) in include {
+        let name = format!("{}{}", prefix, key);
+        match (config.get_mut(&key), value) {
+            (None, value) => {
+                config.insert(key, value);
+            }
+            (Some(Value::Array(x)), Value::Array(y)) => x.extend(y),
+            (Some(Value::Table(x)), Value::Table(y)) => {
+                merge(x, y, &format!("{}.", name), path)?
+            }
+            _ => {
+                return Err(format!(
+                    "'{}' in '{}' is already defined",
+                    name,
+                    path.display()
+                ))
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Reads the content of a secret reference, None if the string is not
+/// a reference.
+fn secret(value: &str, base: &Path) -> Option<Result<String, String>> {
+    let reference = value.strip_prefix("${")?.strip_suffix('}')?;
+    let (kind, name) = reference.split_once(':')?;
+    let path = match kind {
+        "env" => {
+            return Some(std::env::var(name).map_err(|_| {
+                format!("Environment variable '{}' is not set", name)
+            }))
+        }
+        "file" => base.join(name),
+        "credential" => match std::env::var_os("CREDENTIALS_DIRECTORY") {
+            Some(x) => Path::new(&x).join(name),
+            None => {
+                return Some(Err(format!(
+                    "Credential '{}': CREDENTIALS_DIRECTORY is not set",
+                    name
+                )))
+            }
+        },
+        _ => return None,
+    };
+
+    Some(
+        std::fs::read_to_string(&path)
+            .map(|x| x.trim_end_matches(['\r', '\n']).to_string())
+            .map_err(|e| format!("Could not read '{}': {}", path.display(), e)),
+    )
+}
+
+/// Replaces all secret references within a value.
+fn resolve(value: &mut Value, name: &str, base: &Path) -> Result<(), String> {
+    match value {
+        Value::String(x) => {
+            if let Some(secret) = secret(x, base) {
+                *x = secret.map_err(|e| {
+                    format!("Could not resolve secret '{}': {}", name, e)
+                })?;
+            }
+        }
+        Value::Array(x) => {
+            for (i, item) in x.iter_mut().enumerate() {
+                resolve(item, &format!("{}[{}]", name, i), base)?;
+            }
+        }
+        Value::Table(x) => {
+            for (key, item) in x.iter_mut() {
+                resolve(item, &format!("{}.{}", name, key), base)?;
+            }
+        }
+        _ => (),
+    }
+    Ok(())
+}
+
+/// Reads a config file with its include files and resolves secrets.
+pub fn read(path: &Path) -> Result<Table, String> {
+    parse(&read_file(path)?, path)
+}
+
+/// Parses the content of the config file at the given path. Includes are
+/// read and secrets resolved.
+///
+/// The top level `include` array contains paths of further TOML files,
+/// relative to the directory of the config file. Their file names may
+/// contain `*` and `?` wildcards. Arrays of included files like
+/// `[[source]]` are appended and tables are merged. Other values must not
+/// be defined twice.
+///
+/// String values of the form `${env:NAME}`, `${file:PATH}` or
+/// `${credential:NAME}` are replaced by the content of the environment
+/// variable, the file or the systemd credential.
+pub fn parse(toml: &str, path: &Path) -> Result<Table, String> {
+    let mut config = parse_table(toml, path)?;
+    let base = path.parent().unwrap_or(Path::new("."));
+
+    let includes = match config.remove("include") {
+        Some(Value::Array(x)) => x,
+        Some(_) => return Err("'include' must be an array of paths".into()),
+        None => Vec::new(),
+    };
+    for include in includes {
+        let pattern = match include.as_str() {
+            Some(x) => x,
+            None => return Err("'include' must be an array of paths".into()),
+        };
+        for path in expand(base, pattern)? {
+            let table = parse_table(&read_file(&path)?, &path)?;
+            if table.contains_key("include") {
+                return Err(format!(
+                    "Nested 'include' in '{}' is not supported",
+                    path.display()
+                ));
+            }
+            merge(&mut config, table, "", &path)?;
+        }
+    }
+
+    for (key, value) in config.iter_mut() {
+        resolve(value, key, base)?;
+    }
+    Ok(config)
+}
+
+/// Returns true if the config key holds a secret.
+fn is_secret(key: &str) -> bool {
+    key.ends_with("password") || key.ends_with("token")
+}
+
+/// Returns the path of a table within an array. Tables are identified by
+/// their name if they have one, so the path does not change if nodes
+/// are reordered.
+fn element_path(path: &str, index: usize, table: &dyn TableLike) -> String {
+    match table.get("name").and_then(|x| x.as_str()) {
+        Some(name) => format!("{}['{}']", path, name),
+        None => format!("{}[{}]", path, index),
+    }
+}
+
+fn visit_table(
+    table: &mut dyn TableLike,
+    path: &str,
+    f: &mut impl FnMut(&str, &mut toml_edit::Value) -> Result<(), String>,
+) -> Result<(), String> {
+    for (key, item) in table.iter_mut() {
+        let path = match path.is_empty() {
+            true => key.get().to_string(),
+            false => format!("{}.{}", path, key.get()),
+        };
+        visit_item(item, &path, is_secret(key.get()), f)?;
+    }
+    Ok(())
+}
+
+/// Calls f with the path and value of every secret string.
+fn visit_item(
+    item: &mut Item,
+    path: &str,
+    secret: bool,
+    f: &mut impl FnMut(&str, &mut toml_edit::Value) -> Result<(), String>,
+) -> Result<(), String> {
+    match item {
+        Item::Value(x) if secret && x.is_str() => f(path, x)?,
+        Item::Table(x) => visit_table(x, path, f)?,
+        Item::ArrayOfTables(x) => {
+            for (i, table) in x.iter_mut().enumerate() {
+                let path = element_path(path, i, table);
+                visit_table(table, &path, f)?;
+            }
+        }
+        Item::Value(toml_edit::Value::InlineTable(x)) => {
+            visit_table(x, path, f)?
+        }
+        Item::Value(toml_edit::Value::Array(x)) => {
+            for (i, value) in x.iter_mut().enumerate() {
+                if let toml_edit::Value::InlineTable(table) = value {
+                    let path = element_path(path, i, table);
+                    visit_table(table, &path, f)?;
+                }
+            }
+        }
+        _ => (),
+    }
+    Ok(())
+}
+
+fn parse_document(toml: &str) -> Result<DocumentMut, String> {
+    toml.parse::<DocumentMut>()
+        .map_err(|e| format!("Could not parse config: {}", e))
+}
+
+/// Replaces all secrets in a config file with a placeholder. References
+/// to secrets like `${env:NAME}` are kept. Comments and formatting are
+/// preserved.
+pub fn redact(toml: &str) -> Result<String, String> {
+    let mut document = parse_document(toml)?;
+    visit_item(document.as_item_mut(), "", false, &mut |_, value| {
+        let secret = value.as_str().unwrap_or_default();
+        if !(secret.starts_with("${") && secret.ends_with('}')) {
+            let decor = value.decor().clone();
+            *value = REDACTED.into();
+            *value.decor_mut() = decor;
+        }
+        Ok(())
+    })?;
+    Ok(document.to_string())
+}
+
+/// Replaces the placeholders of a redacted config file with the secrets
+/// at the same location in the current config file.
+pub fn restore(toml: &str, current: &str) -> Result<String, String> {
+    let mut secrets = BTreeMap::new();
+    let mut document = parse_document(current)?;
+    visit_item(document.as_item_mut(), "", false, &mut |path, value| {
+        secrets.insert(path.to_string(), value.as_str().map(String::from));
+        Ok(())
+    })?;
+
+    let mut document = parse_document(toml)?;
+    visit_item(document.as_item_mut(), "", false, &mut |path, value| {
+        if value.as_str() == Some(REDACTED) {
+            match secrets.get(path) {
+                Some(Some(secret)) => {
+                    let decor = value.decor().clone();
+                    *value = secret.into();
+                    *value.decor_mut() = decor;
+                }
+                _ => return Err(format!("Secret '{}' is not set", path)),
+            }
+        }
+        Ok(())
+    })?;
+    Ok(document.to_string())
+}
+
+#[test]
+fn test_read() {
+    let dir = std::env::temp_dir()
+        .join(format!("empowerd-config-{}", std::process::id()));
+    std::fs::create_dir_all(dir.join("conf.d")).unwrap();
+    std::fs::write(
+        dir.join("empowerd.conf"),
+        r#"
+        include = ["conf.d/*.toml"]
+
+        [database]
+        name = "empowerd"
+        password = "${env:EMPOWERD_TEST_PASSWORD}"
+
+        [[source]]
+        name = "Meter"
+        "#,
+    )
+    .unwrap();
+    std::fs::write(
+        dir.join("conf.d/dachs.toml"),
+        r#"
+        [[source]]
+        name = "Dachs"
+        password = "${file:dachs.secret}"
+        "#,
+    )
+    .unwrap();
+    std::fs::write(
+        dir.join("conf.d/db.toml"),
+        "[database]\nurl = \"127.0.0.1:5432\"\n",
+    )
+    .unwrap();
+    std::fs::write(dir.join("conf.d/.db.toml"), "[database]\nurl = 1").unwrap();
+    std::fs::write(dir.join("dachs.secret"), "s3cret\n").unwrap();
+    std::env::set_var("EMPOWERD_TEST_PASSWORD", "pw");
+
+    let config = read(&dir.join("empowerd.conf")).unwrap();
+    assert_eq!(None, config.get("include"));
+    assert_eq!("pw", config["database"]["password"].as_str().unwrap());
+    assert_eq!(
+        "127.0.0.1:5432",
+        config["database"]["url"].as_str().unwrap()
+    );
+    let sources = config["source"].as_array().unwrap();
+    assert_eq!(2, sources.len());
+    assert_eq!("s3cret", sources[1]["password"].as_str().unwrap());
+
+    std::fs::write(dir.join("conf.d/name.toml"), "[database]\nname = \"x\"")
+        .unwrap();
+    assert_eq!(
+        Err(format!(
+            "'database.name' in '{}' is already defined",
+            dir.join("conf.d/name.toml").display()
+        )),
+        read(&dir.join("empowerd.conf"))
+    );
+    std::fs::remove_file(dir.join("conf.d/name.toml")).unwrap();
+
+    std::env::remove_var("EMPOWERD_TEST_PASSWORD");
+    assert_eq!(
+        Err("Could not resolve secret 'database.password': Environment \
+            variable 'EMPOWERD_TEST_PASSWORD' is not set"
+            .into()),
+        read(&dir.join("empowerd.conf"))
+    );
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_redact() {
+    let config = r#"
+        # Database access
+        [database]
+        password = "pw" # inline comment
+
+        [graphql]
+        hashed_password = "$argon2i$v=19$..."
+        metrics_token = "${env:METRICS_TOKEN}"
+
+        [[source]]
+        name = "Dachs"
+        password = "dachs"
+
+        [[source]]
+        name = "Speedwire"
+        password = "speedwire"
+        "#;
+
+    let redacted = redact(config).unwrap();
+    assert_eq!(
+        r#"
+        # Database access
+        [database]
+        password = "**SECRET**" # inline comment
+
+        [graphql]
+        hashed_password = "**SECRET**"
+        metrics_token = "${env:METRICS_TOKEN}"
+
+        [[source]]
+        name = "Dachs"
+        password = "**SECRET**"
+
+        [[source]]
+        name = "Speedwire"
+        password = "**SECRET**"
+        "#,
+        redacted
+    );
+    assert_eq!(config, restore(&redacted, config).unwrap());
+
+    // Secrets follow their node if nodes are reordered.
+    let reordered = r#"
+        [[source]]
+        name = "Speedwire"
+        password = "**SECRET**"
+
+        [[source]]
+        name = "Dachs"
+        password = "**SECRET**"
+        "#;
+    let restored = restore(reordered, config).unwrap();
+    assert!(
+        restored.find("speedwire").unwrap() < restored.find("dachs").unwrap()
+    );
+
+    assert_eq!(
+        Err("Secret 'source['New'].password' is not set".into()),
+        restore(
+            "[[source]]\nname = \"New\"\npassword = \"**SECRET**\"",
+            config
+        )
+    );
+}
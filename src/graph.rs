@@ -0,0 +1,729 @@
+/******************************************************************************\
+    empowerd - empowers the offline smart home
+    Copyright (C) 2019 - 2026 Max Maisel
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU Affero General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU Affero General Public License for more details.
+
+    You should have received a copy of the GNU Affero General Public License
+    along with this program.  If not, see <https://www.gnu.org/licenses/>.
+\******************************************************************************/
+use crate::settings::{
+    config_location, NotificationType, PeakShavingLoad, ProcessorType,
+    Settings, SinkType, SourceType,
+};
+use serde_json::json;
+use std::{collections::BTreeMap, fmt};
+
+/// Output format of the exported node graph.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum)]
+pub enum GraphFormat {
+    /// Graphviz DOT
+    Dot,
+    Json,
+}
+
+/// Type of a node in the config file.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, juniper::GraphQLEnum)]
+pub enum NodeKind {
+    Source,
+    Processor,
+    Sink,
+}
+
+impl fmt::Display for NodeKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            NodeKind::Source => "source",
+            NodeKind::Processor => "processor",
+            NodeKind::Sink => "sink",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+/// A source, processor or sink.
+#[derive(Clone, Debug)]
+pub struct Node {
+    pub name: String,
+    pub kind: NodeKind,
+    /// Type of the node, e.g. "SmaMeter".
+    pub typ: &'static str,
+    /// Type of the published records, None if the node publishes nothing.
+    pub model: Option<&'static str>,
+    /// Location of the node in the config file.
+    pub location: String,
+}
+
+/// Relation between two nodes.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, juniper::GraphQLEnum)]
+pub enum EdgeKind {
+    /// A processor reads the records of a node.
+    Input,
+    /// A processor writes to a sink.
+    Output,
+    /// A processor sends commands to another processor or a switch.
+    Control,
+}
+
+/// A reference from one node to another, directed in data flow direction.
+#[derive(Clone, Debug)]
+pub struct Edge {
+    pub from: String,
+    pub to: String,
+    pub kind: EdgeKind,
+    /// Config key of the reference.
+    pub key: &'static str,
+}
+
+/// Expected type of a referenced node.
+enum Expect {
+    /// A source or processor which publishes one of the given records,
+    /// any record if empty.
+    Model(&'static [&'static str]),
+    /// A sink of one of the given types.
+    Sink(&'static [&'static str]),
+    /// A switch, which is a Gpio or ModbusCoil sink.
+    Switch,
+    /// A processor of the given type.
+    Processor(&'static str),
+}
+
+/// Graph of all sources, processors and sinks of a config file.
+/// Invalid references are not part of the graph but reported as errors.
+#[derive(Debug, Default)]
+pub struct NodeGraph {
+    pub nodes: Vec<Node>,
+    pub edges: Vec<Edge>,
+    errors: Vec<String>,
+}
+
+fn source_type(variant: &SourceType) -> (&'static str, &'static str) {
+    match variant {
+        SourceType::Debug(_) => ("Debug", "SimpleMeter"),
+        SourceType::SunnyBoyStorage(_) => ("SunnyBoyStorage", "Battery"),
+        SourceType::SunnyIsland(_) => ("SunnyIsland", "Battery"),
+        SourceType::SunspecSolar(_) => ("SunspecSolar", "SimpleMeter"),
+        SourceType::DachsMsrS(_) => ("DachsMsrS", "Generator"),
+        SourceType::KeContact(_) => ("KeContact", "SimpleMeter"),
+        SourceType::LambdaHeatPump(_) => ("LambdaHeatPump", "Heatpump"),
+        SourceType::SmaMeter(_) => ("SmaMeter", "BidirMeter"),
+        SourceType::SmlMeter(_) => ("SmlMeter", "BidirMeter"),
+        SourceType::SunnyBoySpeedwire(_) => {
+            ("SunnyBoySpeedwire", "SimpleMeter")
+        }
+        SourceType::Bresser6in1(_) => ("Bresser6in1", "Weather"),
+    }
+}
+
+fn processor_type(
+    variant: &ProcessorType,
+) -> (&'static str, Option<&'static str>) {
+    match variant {
+        ProcessorType::Debug(_) => ("Debug", None),
+        ProcessorType::AvailablePower(_) => {
+            ("AvailablePower", Some("AvailablePower"))
+        }
+        ProcessorType::Appliance(_) => ("Appliance", Some("AvailablePower")),
+        ProcessorType::LoadControl(_) => ("LoadControl", None),
+        ProcessorType::DeferrableLoad(_) => ("DeferrableLoad", None),
+        ProcessorType::Rules(_) => ("Rules", None),
+        ProcessorType::PeakShaving(_) => ("PeakShaving", None),
+        ProcessorType::ExportLimit(_) => ("ExportLimit", None),
+    }
+}
+
+fn sink_type(variant: &SinkType) -> &'static str {
+    match variant {
+        SinkType::Debug => "Debug",
+        SinkType::Gpio(_) => "Gpio",
+        SinkType::ModbusCoil(_) => "ModbusCoil",
+        SinkType::KeContact(_) => "KeContact",
+        SinkType::LambdaHeatPump(_) => "LambdaHeatPump",
+        SinkType::SunspecInverter(_) => "SunspecInverter",
+    }
+}
+
+/// Returns the config key, the name and the expected type of all nodes
+/// which are referenced by a processor.
+fn references(variant: &ProcessorType) -> Vec<(&'static str, &str, Expect)> {
+    match variant {
+        ProcessorType::Debug(x) => vec![
+            ("input", &x.input, Expect::Model(&[])),
+            ("output", &x.output, Expect::Sink(&["Debug"])),
+        ],
+        ProcessorType::AvailablePower(x) => vec![
+            (
+                "battery_input",
+                &x.battery_input,
+                Expect::Model(&["Battery"]),
+            ),
+            (
+                "meter_input",
+                &x.meter_input,
+                Expect::Model(&["BidirMeter", "SimpleMeter"]),
+            ),
+        ],
+        ProcessorType::Appliance(x) => vec![
+            (
+                "power_input",
+                &x.power_input,
+                Expect::Model(&["AvailablePower"]),
+            ),
+            (
+                "appliance_input",
+                &x.appliance_input,
+                Expect::Model(&["Heatpump", "SimpleMeter"]),
+            ),
+            (
+                "appliance_output",
+                &x.appliance_output,
+                Expect::Sink(&["KeContact", "LambdaHeatPump"]),
+            ),
+        ],
+        ProcessorType::LoadControl(x) => vec![(
+            "battery_input",
+            &x.battery_input,
+            Expect::Model(&["Battery"]),
+        )],
+        ProcessorType::DeferrableLoad(x) => vec![(
+            "power_input",
+            &x.power_input,
+            Expect::Model(&["AvailablePower"]),
+        )],
+        ProcessorType::Rules(_) => Vec::new(),
+        ProcessorType::PeakShaving(x) => {
+            let mut refs = vec![(
+                "meter_input",
+                x.meter_input.as_str(),
+                Expect::Model(&["BidirMeter"]),
+            )];
+            for load in &x.loads {
+                refs.push(match load {
+                    PeakShavingLoad::Switch { name } => {
+                        ("loads", name, Expect::Switch)
+                    }
+                    PeakShavingLoad::Appliance { name } => {
+                        ("loads", name, Expect::Processor("Appliance"))
+                    }
+                });
+            }
+            refs
+        }
+        ProcessorType::ExportLimit(x) => {
+            let mut refs = vec![(
+                "meter_input",
+                x.meter_input.as_str(),
+                Expect::Model(&["BidirMeter"]),
+            )];
+            if let Some(inverter) = &x.inverter_output {
+                refs.push((
+                    "inverter_output",
+                    inverter,
+                    Expect::Sink(&["SunspecInverter"]),
+                ));
+            }
+            refs
+        }
+    }
+}
+
+/// Returns the config key of a battery power request to the LoadControl
+/// processor, if any.
+fn load_control_request(variant: &ProcessorType) -> Option<&'static str> {
+    match variant {
+        ProcessorType::PeakShaving(x) if x.battery_discharge > 0.0 => {
+            Some("battery_discharge")
+        }
+        ProcessorType::ExportLimit(x) if x.battery_charge > 0.0 => {
+            Some("battery_charge")
+        }
+        _ => None,
+    }
+}
+
+impl NodeGraph {
+    /// Builds the graph and checks all references between the nodes.
+    pub fn new(settings: &Settings) -> Self {
+        let mut graph = Self::default();
+
+        for (i, source) in settings.sources.iter().enumerate() {
+            let (typ, model) = source_type(&source.variant);
+            graph.nodes.push(Node {
+                name: source.name.clone(),
+                kind: NodeKind::Source,
+                typ,
+                model: Some(model),
+                location: config_location("source", i, &source.name),
+            });
+        }
+        for (i, processor) in settings.processors.iter().enumerate() {
+            let (typ, model) = processor_type(&processor.variant);
+            graph.nodes.push(Node {
+                name: processor.name.clone(),
+                kind: NodeKind::Processor,
+                typ,
+                model,
+                location: config_location("processor", i, &processor.name),
+            });
+        }
+        for (i, sink) in settings.sinks.iter().enumerate() {
+            graph.nodes.push(Node {
+                name: sink.name.clone(),
+                kind: NodeKind::Sink,
+                typ: sink_type(&sink.variant),
+                model: None,
+                location: config_location("sink", i, &sink.name),
+            });
+        }
+
+        let load_control = graph
+            .nodes
+            .iter()
+            .find(|x| x.typ == "LoadControl")
+            .map(|x| x.name.clone());
+        for (i, processor) in settings.processors.iter().enumerate() {
+            let location = config_location("processor", i, &processor.name);
+            for (key, target, expect) in references(&processor.variant) {
+                graph.connect(&processor.name, &location, key, target, expect);
+            }
+            if let Some(key) = load_control_request(&processor.variant) {
+                match &load_control {
+                    Some(x) => graph.edges.push(Edge {
+                        from: processor.name.clone(),
+                        to: x.clone(),
+                        kind: EdgeKind::Control,
+                        key,
+                    }),
+                    None => graph.errors.push(format!(
+                        "{location}: '{key}' requires a LoadControl processor"
+                    )),
+                }
+            }
+        }
+
+        for (i, notification) in settings.notifications.iter().enumerate() {
+            let location =
+                config_location("notification", i, &notification.name);
+            let (node, expect) = match &notification.variant {
+                NotificationType::Threshold(x) => (&x.node, Expect::Model(&[])),
+                NotificationType::Increase(x) => (&x.node, Expect::Model(&[])),
+                NotificationType::Stale(x) => (&x.node, Expect::Model(&[])),
+                NotificationType::Errors(x) => {
+                    match graph.nodes.iter().find(|y| y.name == x.node) {
+                        Some(y) if y.kind == NodeKind::Sink => {
+                            graph.errors.push(format!(
+                                "{location}: 'node': '{}' is a sink, \
+                                expected a source or processor",
+                                x.node
+                            ))
+                        }
+                        Some(_) => (),
+                        None => graph.errors.push(format!(
+                            "{location}: 'node': Node '{}' does not exist",
+                            x.node
+                        )),
+                    }
+                    continue;
+                }
+            };
+            if let Err(e) = graph.check(node, expect) {
+                graph.errors.push(format!("{location}: 'node': {e}"));
+            }
+        }
+
+        graph.check_cycles();
+        graph
+    }
+
+    /// Errors of invalid references and cycles.
+    pub fn errors(&self) -> &[String] {
+        &self.errors
+    }
+
+    fn node(&self, name: &str) -> Option<&Node> {
+        self.nodes.iter().find(|x| x.name == name)
+    }
+
+    /// Checks if the named node exists and has the expected type.
+    fn check(&self, name: &str, expect: Expect) -> Result<EdgeKind, String> {
+        let node = match self.node(name) {
+            Some(x) => x,
+            None => return Err(format!("Node '{name}' does not exist")),
+        };
+        let (kind, valid, expected) = match expect {
+            Expect::Model(models) => (
+                EdgeKind::Input,
+                match node.model {
+                    Some(x) => models.is_empty() || models.contains(&x),
+                    None => false,
+                },
+                match models.is_empty() {
+                    true => "a node which publishes records".into(),
+                    false => {
+                        format!("a node with {} records", models.join(" or "))
+                    }
+                },
+            ),
+            Expect::Sink(types) => (
+                EdgeKind::Output,
+                node.kind == NodeKind::Sink && types.contains(&node.typ),
+                format!("a {} sink", types.join(" or ")),
+            ),
+            Expect::Switch => (
+                EdgeKind::Control,
+                node.kind == NodeKind::Sink
+                    && (node.typ == "Gpio" || node.typ == "ModbusCoil"),
+                "a Gpio or ModbusCoil sink".into(),
+            ),
+            Expect::Processor(typ) => (
+                EdgeKind::Control,
+                node.kind == NodeKind::Processor && node.typ == typ,
+                format!("a {typ} processor"),
+            ),
+        };
+
+        match valid {
+            true => Ok(kind),
+            false => Err(format!(
+                "'{name}' is a {} {}{}, expected {expected}",
+                node.typ,
+                node.kind,
+                match node.model {
+                    Some(x) => format!(" with {x} records"),
+                    None => String::new(),
+                },
+            )),
+        }
+    }
+
+    /// Adds an edge for a valid reference or records an error.
+    fn connect(
+        &mut self,
+        name: &str,
+        location: &str,
+        key: &'static str,
+        target: &str,
+        expect: Expect,
+    ) {
+        match self.check(target, expect) {
+            Ok(kind) => {
+                let (from, to) = match kind {
+                    EdgeKind::Input => (target, name),
+                    EdgeKind::Output | EdgeKind::Control => (name, target),
+                };
+                self.edges.push(Edge {
+                    from: from.into(),
+                    to: to.into(),
+                    kind,
+                    key,
+                });
+            }
+            Err(e) => self.errors.push(format!("{location}: '{key}': {e}")),
+        }
+    }
+
+    /// Records an error for every cycle of processor inputs.
+    fn check_cycles(&mut self) {
+        // false while the node is on the stack, true when it is done.
+        let mut visited = BTreeMap::new();
+        let mut cycles = Vec::new();
+        for node in &self.nodes {
+            self.visit(&node.name, &mut visited, &mut Vec::new(), &mut cycles);
+        }
+
+        let errors = cycles
+            .iter()
+            .map(|cycle| {
+                let location = match self.node(cycle[0]) {
+                    Some(x) => x.location.as_str(),
+                    None => "",
+                };
+                format!("{location}: Cycle of inputs: {}", cycle.join(" -> "))
+            })
+            .collect::<Vec<_>>();
+        self.errors.extend(errors);
+    }
+
+    fn visit<'a>(
+        &'a self,
+        name: &'a str,
+        visited: &mut BTreeMap<&'a str, bool>,
+        stack: &mut Vec<&'a str>,
+        cycles: &mut Vec<Vec<&'a str>>,
+    ) {
+        match visited.get(name) {
+            Some(true) => return,
+            Some(false) => {
+                let start = stack.iter().position(|x| *x == name).unwrap_or(0);
+                let mut cycle = stack[start..].to_vec();
+                cycle.push(name);
+                cycles.push(cycle);
+                return;
+            }
+            None => (),
+        }
+
+        visited.insert(name, false);
+        stack.push(name);
+        for edge in &self.edges {
+            if edge.kind == EdgeKind::Input && edge.from == name {
+                self.visit(&edge.to, visited, stack, cycles);
+            }
+        }
+        stack.pop();
+        visited.insert(name, true);
+    }
+}
+
+/// Quotes a DOT identifier or label.
+fn quote(text: &str) -> String {
+    let text = text
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n");
+    format!("\"{}\"", text)
+}
+
+impl NodeGraph {
+    /// Renders the graph in the given format. Values contains the live
+    /// values of the nodes of a running daemon.
+    pub fn export(
+        &self,
+        format: GraphFormat,
+        values: &BTreeMap<String, String>,
+    ) -> String {
+        match format {
+            GraphFormat::Dot => self.to_dot(values),
+            GraphFormat::Json => self.to_json(values),
+        }
+    }
+
+    fn to_dot(&self, values: &BTreeMap<String, String>) -> String {
+        let mut dot = String::from("digraph empowerd {\n    rankdir=LR;\n");
+        for node in &self.nodes {
+            let mut label = format!("{}\n{}", node.name, node.typ);
+            if let Some(value) = values.get(&node.name) {
+                label = format!("{}\n{}", label, value);
+            }
+            let shape = match node.kind {
+                NodeKind::Source => "box",
+                NodeKind::Processor => "ellipse",
+                NodeKind::Sink => "cds",
+            };
+            dot += &format!(
+                "    {} [shape={}, label={}];\n",
+                quote(&node.name),
+                shape,
+                quote(&label)
+            );
+        }
+        for edge in &self.edges {
+            let (label, style) = match edge.kind {
+                EdgeKind::Input => {
+                    let model = match self.node(&edge.from) {
+                        Some(x) => x.model.unwrap_or_default(),
+                        None => "",
+                    };
+                    (format!("{}\n{}", edge.key, model), "solid")
+                }
+                EdgeKind::Output => (edge.key.to_string(), "solid"),
+                EdgeKind::Control => (edge.key.to_string(), "dashed"),
+            };
+            dot += &format!(
+                "    {} -> {} [label={}, style={}];\n",
+                quote(&edge.from),
+                quote(&edge.to),
+                quote(&label),
+                style
+            );
+        }
+        dot + "}\n"
+    }
+
+    fn to_json(&self, values: &BTreeMap<String, String>) -> String {
+        let nodes = self
+            .nodes
+            .iter()
+            .map(|x| {
+                json!({
+                    "name": x.name,
+                    "kind": x.kind.to_string(),
+                    "type": x.typ,
+                    "model": x.model,
+                    "location": x.location,
+                    "value": values.get(&x.name),
+                })
+            })
+            .collect::<Vec<_>>();
+        let edges = self
+            .edges
+            .iter()
+            .map(|x| {
+                json!({
+                    "from": x.from,
+                    "to": x.to,
+                    "kind": format!("{:?}", x.kind).to_lowercase(),
+                    "key": x.key,
+                    "model": match x.kind {
+                        EdgeKind::Input => self.node(&x.from).and_then(|y| y.model),
+                        _ => None,
+                    },
+                })
+            })
+            .collect::<Vec<_>>();
+        let graph = json!({ "nodes": nodes, "edges": edges });
+        format!("{:#}\n", graph)
+    }
+}
+
+impl fmt::Display for NodeGraph {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for kind in [NodeKind::Source, NodeKind::Processor, NodeKind::Sink] {
+            for node in self.nodes.iter().filter(|x| x.kind == kind) {
+                write!(f, "{} '{}' ({})", node.kind, node.name, node.typ)?;
+                if let Some(model) = node.model {
+                    write!(f, " publishes {}", model)?;
+                }
+                writeln!(f)?;
+                for edge in &self.edges {
+                    if edge.to == node.name && edge.kind == EdgeKind::Input {
+                        writeln!(f, "    {} <- '{}'", edge.key, edge.from)?;
+                    } else if edge.from == node.name
+                        && edge.kind != EdgeKind::Input
+                    {
+                        writeln!(f, "    {} -> '{}'", edge.key, edge.to)?;
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+#[test]
+fn test_node_graph() {
+    let settings: Settings = toml::from_str(
+        r#"
+        [[source]]
+        name = "Meter"
+        series_id = 1
+        type = "Debug"
+        poll_interval = 10
+
+        [[processor]]
+        name = "Power"
+        type = "AvailablePower"
+        battery_input = "Meter"
+        meter_input = "Meter"
+        battery_threshold = 0
+        tau = 10
+
+        [[processor]]
+        name = "Heater"
+        type = "Appliance"
+        power_input = "Boiler"
+        appliance_input = "Meter"
+        appliance_output = "Pump"
+
+        [[processor]]
+        name = "Boiler"
+        type = "Appliance"
+        power_input = "Heater"
+        appliance_input = "Meter"
+        appliance_output = "Missing"
+
+        [[processor]]
+        name = "Peak"
+        type = "PeakShaving"
+        meter_input = "Meter"
+        min_peak = 4000
+        battery_discharge = 1000
+        loads = [{ type = "Switch", name = "Pump" }]
+
+        [[sink]]
+        name = "Pump"
+        type = "Gpio"
+        dev = "/dev/gpiochip0"
+        pin_num = 1
+        icon = "Valve"
+        "#,
+    )
+    .unwrap();
+
+    let graph = NodeGraph::new(&settings);
+    assert_eq!(
+        vec![
+            "[[processor]] #1 'Power': 'battery_input': 'Meter' is a Debug \
+            source with SimpleMeter records, expected a node with Battery \
+            records",
+            "[[processor]] #2 'Heater': 'appliance_output': 'Pump' is a Gpio \
+            sink, expected a KeContact or LambdaHeatPump sink",
+            "[[processor]] #3 'Boiler': 'appliance_output': Node 'Missing' \
+            does not exist",
+            "[[processor]] #4 'Peak': 'meter_input': 'Meter' is a Debug \
+            source with SimpleMeter records, expected a node with BidirMeter \
+            records",
+            "[[processor]] #4 'Peak': 'battery_discharge' requires a \
+            LoadControl processor",
+            "[[processor]] #2 'Heater': Cycle of inputs: Heater -> Boiler -> \
+            Heater",
+        ],
+        graph.errors()
+    );
+    assert_eq!(6, graph.edges.len());
+    assert!(graph.edges.iter().any(|x| x.from == "Peak"
+        && x.to == "Pump"
+        && x.kind == EdgeKind::Control));
+    assert!(graph.to_string().contains("    meter_input <- 'Meter'\n"));
+}
+
+#[test]
+fn test_export() {
+    let settings: Settings = toml::from_str(
+        r#"
+        [[source]]
+        name = "Meter \"A\""
+        series_id = 1
+        type = "Debug"
+        poll_interval = 10
+
+        [[processor]]
+        name = "Debug"
+        type = "Debug"
+        input = "Meter \"A\""
+        output = "Sink"
+
+        [[sink]]
+        name = "Sink"
+        type = "Debug"
+        "#,
+    )
+    .unwrap();
+
+    let graph = NodeGraph::new(&settings);
+    let values = BTreeMap::from([("Meter \"A\"".into(), "50 W".into())]);
+    let dot = graph.export(GraphFormat::Dot, &values);
+    assert!(dot.contains(
+        "    \"Meter \\\"A\\\"\" [shape=box, \
+        label=\"Meter \\\"A\\\"\\nDebug\\n50 W\"];\n"
+    ));
+    assert!(dot.contains(
+        "    \"Meter \\\"A\\\"\" -> \"Debug\" \
+        [label=\"input\\nSimpleMeter\", style=solid];\n"
+    ));
+
+    let json: serde_json::Value =
+        serde_json::from_str(&graph.export(GraphFormat::Json, &values))
+            .unwrap();
+    assert_eq!("50 W", json["nodes"][0]["value"]);
+    assert_eq!("output", json["edges"][1]["kind"]);
+    assert_eq!("Sink", json["edges"][1]["to"]);
+}
@@ -25,6 +25,9 @@ pub struct Appliance {
     pub id: i32,
     /// If the appliance is forced on/off or in automatic mode.
     pub force_on_off: TriState,
+    /// True if the mode was changed at runtime, false if it is the
+    /// default automatic mode.
+    pub force_on_off_overridden: bool,
     /// Name of the appliance.
     pub name: String,
 }
@@ -34,12 +37,13 @@ impl Appliance {
         Self {
             id,
             force_on_off: TriState::Auto,
+            force_on_off_overridden: false,
             name,
         }
     }
 }
 
-#[derive(juniper::GraphQLInputObject)]
+#[derive(Debug, juniper::GraphQLInputObject)]
 /// Controls an appliance.
 pub struct InputAppliance {
     /// References the appliance.
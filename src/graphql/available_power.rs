@@ -22,6 +22,9 @@ pub struct AvailablePower {
     pub id: i32,
     /// Current battery charge threshold for enable.
     pub threshold: f64,
+    /// True if the threshold was changed at runtime, false if it is the
+    /// config default.
+    pub threshold_overridden: bool,
     /// Currently available power.
     pub power: f64,
     /// Name of the channel.
@@ -33,13 +36,14 @@ impl AvailablePower {
         Self {
             id,
             threshold: 0.0,
+            threshold_overridden: false,
             power: 0.0,
             name,
         }
     }
 }
 
-#[derive(juniper::GraphQLInputObject)]
+#[derive(Debug, juniper::GraphQLInputObject)]
 /// Controls an available power controller.
 pub struct InputAvailablePower {
     /// References the channel.
@@ -0,0 +1,73 @@
+/******************************************************************************\
+    empowerd - empowers the offline smart home
+    Copyright (C) 2019 - 2026 Max Maisel
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU Affero General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU Affero General Public License for more details.
+
+    You should have received a copy of the GNU Affero General Public License
+    along with this program.  If not, see <https://www.gnu.org/licenses/>.
+\******************************************************************************/
+use crate::{
+    config_file, models::ConfigVersion as VersionModel, reload::Changes,
+};
+use chrono::{DateTime, Utc};
+
+#[derive(juniper::GraphQLObject)]
+/// Reads a stored version of the main config file.
+pub struct ConfigVersion {
+    /// References the version.
+    pub id: i32,
+    /// Time when the config was changed.
+    pub time: DateTime<Utc>,
+    /// User who changed the config. Empty for manual changes of the file.
+    pub user: String,
+    /// Describes the change.
+    pub comment: String,
+    /// Content of the config file with redacted secrets.
+    pub config: String,
+}
+
+impl ConfigVersion {
+    pub fn new(version: VersionModel) -> Result<Self, String> {
+        Ok(Self {
+            id: version.id,
+            time: version.time,
+            user: version.user,
+            comment: version.comment,
+            config: config_file::redact(&version.config)?,
+        })
+    }
+}
+
+#[derive(juniper::GraphQLObject)]
+/// Reads the result of a config change.
+pub struct ConfigUpdate {
+    /// The new version of the config file.
+    pub version: ConfigVersion,
+    /// Changed settings which are only applied by restarting the daemon.
+    pub restart_required: Vec<String>,
+}
+
+impl ConfigUpdate {
+    pub fn new(
+        version: VersionModel,
+        changes: Changes,
+    ) -> Result<Self, String> {
+        Ok(Self {
+            version: ConfigVersion::new(version)?,
+            restart_required: changes
+                .unsupported
+                .into_iter()
+                .map(String::from)
+                .collect(),
+        })
+    }
+}
@@ -0,0 +1,99 @@
+/******************************************************************************\
+    empowerd - empowers the offline smart home
+    Copyright (C) 2019 - 2026 Max Maisel
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU Affero General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU Affero General Public License for more details.
+
+    You should have received a copy of the GNU Affero General Public License
+    along with this program.  If not, see <https://www.gnu.org/licenses/>.
+\******************************************************************************/
+use crate::{
+    models::{units::watt, DeferrableJob as JobModel},
+    switch_mux::SwitchMux,
+};
+use chrono::{DateTime, Utc};
+
+#[derive(juniper::GraphQLObject)]
+/// Reads a deferrable load job.
+pub struct DeferrableJob {
+    /// References the job.
+    pub id: i32,
+    /// ID of the controlled switch.
+    pub switch_id: i32,
+    /// Nominal power of the load in watt.
+    pub power: f64,
+    /// Required runtime in seconds.
+    pub runtime: i32,
+    /// The job is not started before this time.
+    pub earliest_start: DateTime<Utc>,
+    /// The job is finished at this time.
+    pub latest_finish: DateTime<Utc>,
+    /// Start time of a running job.
+    pub started: Option<DateTime<Utc>>,
+}
+
+impl DeferrableJob {
+    pub fn from_model(
+        job: JobModel,
+        switch_mux: &SwitchMux,
+    ) -> Result<Self, String> {
+        Ok(Self {
+            id: job.id,
+            switch_id: switch_mux.id_by_name(&job.switch_name)? as i32,
+            power: job.power.get::<watt>(),
+            runtime: job.runtime.as_secs().try_into().unwrap_or(i32::MAX),
+            earliest_start: job.earliest_start,
+            latest_finish: job.latest_finish,
+            started: job.started,
+        })
+    }
+}
+
+#[derive(juniper::GraphQLObject)]
+/// Reads a deferrable load scheduler.
+pub struct DeferrableLoad {
+    /// References the scheduler.
+    pub id: i32,
+    /// Name of the scheduler.
+    pub name: String,
+    /// Unfinished jobs.
+    pub jobs: Vec<DeferrableJob>,
+}
+
+impl DeferrableLoad {
+    pub fn new(id: i32, name: String) -> Self {
+        Self {
+            id,
+            name,
+            jobs: Vec::new(),
+        }
+    }
+}
+
+#[derive(Debug, juniper::GraphQLInputObject)]
+/// Creates a deferrable load job.
+pub struct InputDeferrableJob {
+    /// References the scheduler.
+    pub id: i32,
+    /// ID of the controlled switch.
+    pub switch_id: i32,
+    /// Nominal power of the load in watt.
+    pub power: f64,
+    /// Required energy in watt hours.
+    /// Either energy or runtime must be given.
+    pub energy: Option<f64>,
+    /// Required runtime in seconds.
+    pub runtime: Option<i32>,
+    /// The job is not started before this time. Defaults to now.
+    pub earliest_start: Option<DateTime<Utc>>,
+    /// The job must be finished at this time.
+    pub latest_finish: DateTime<Utc>,
+}
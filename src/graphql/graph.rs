@@ -0,0 +1,115 @@
+/******************************************************************************\
+    empowerd - empowers the offline smart home
+    Copyright (C) 2019 - 2026 Max Maisel
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU Affero General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU Affero General Public License for more details.
+
+    You should have received a copy of the GNU Affero General Public License
+    along with this program.  If not, see <https://www.gnu.org/licenses/>.
+\******************************************************************************/
+use super::node::Node;
+use crate::{
+    graph::{self, EdgeKind, GraphFormat, NodeKind},
+    models::Model,
+};
+use std::collections::BTreeMap;
+use tokio::sync::watch;
+
+#[derive(juniper::GraphQLObject)]
+/// Reads a source, processor or sink of the node graph.
+pub struct GraphNode {
+    pub name: String,
+    pub kind: NodeKind,
+    /// Type of the node, e.g. "SmaMeter".
+    #[graphql(name = "type")]
+    pub typ: String,
+    /// Type of the published records.
+    pub model: Option<String>,
+    /// Location of the node in the config file.
+    pub location: String,
+    /// The latest record of the node.
+    pub record: Option<Node>,
+}
+
+#[derive(juniper::GraphQLObject)]
+/// Reads a reference between two nodes in data flow direction.
+pub struct GraphEdge {
+    pub from: String,
+    pub to: String,
+    pub kind: EdgeKind,
+    /// Config key of the reference.
+    pub key: String,
+}
+
+#[derive(juniper::GraphQLObject)]
+/// Reads the node graph of the running config.
+pub struct Graph {
+    pub nodes: Vec<GraphNode>,
+    pub edges: Vec<GraphEdge>,
+    /// The graph with live values in Graphviz DOT format.
+    pub dot: String,
+}
+
+/// Formats the latest record of a node for the DOT graph.
+fn live_value(record: &Node) -> Option<String> {
+    match (record.power, record.charge) {
+        (Some(power), Some(charge)) => {
+            Some(format!("{:.0} W, {:.0} Wh", power, charge))
+        }
+        (Some(power), None) => Some(format!("{:.0} W", power)),
+        (None, _) => None,
+    }
+}
+
+impl Graph {
+    pub fn new(
+        graph: &graph::NodeGraph,
+        records: &BTreeMap<String, watch::Receiver<Model>>,
+    ) -> Self {
+        let mut values = BTreeMap::new();
+        let nodes = graph
+            .nodes
+            .iter()
+            .map(|x| {
+                let record = records
+                    .get(&x.name)
+                    .and_then(|y| Node::from_model(&x.name, &y.borrow()));
+                if let Some(value) = record.as_ref().and_then(live_value) {
+                    values.insert(x.name.clone(), value);
+                }
+                GraphNode {
+                    name: x.name.clone(),
+                    kind: x.kind,
+                    typ: x.typ.into(),
+                    model: x.model.map(Into::into),
+                    location: x.location.clone(),
+                    record,
+                }
+            })
+            .collect();
+        let edges = graph
+            .edges
+            .iter()
+            .map(|x| GraphEdge {
+                from: x.from.clone(),
+                to: x.to.clone(),
+                kind: x.kind,
+                key: x.key.into(),
+            })
+            .collect();
+
+        Self {
+            nodes,
+            edges,
+            dot: graph.export(GraphFormat::Dot, &values),
+        }
+    }
+}
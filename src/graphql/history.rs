@@ -0,0 +1,344 @@
+/******************************************************************************\
+    empowerd - empowers the offline smart home
+    Copyright (C) 2019 - 2026 Max Maisel
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU Affero General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU Affero General Public License for more details.
+
+    You should have received a copy of the GNU Affero General Public License
+    along with this program.  If not, see <https://www.gnu.org/licenses/>.
+\******************************************************************************/
+use crate::{
+    models::{
+        units::{
+            celsius, degree, hectopascal, meter_per_second, millimeter,
+            percent, ratio, second, watt, watt_hour, Energy, Ratio,
+            Temperature, Time,
+        },
+        Battery, BidirMeter, EnergyDelta as EnergyDeltaModel, Generator,
+        Heatpump, SeriesType, SimpleMeter, Weather,
+    },
+    Context,
+};
+use chrono::{DateTime, Utc};
+use diesel_async::{pooled_connection::deadpool::Object, AsyncPgConnection};
+use std::time::Duration;
+
+/// Maximum number of buckets returned by a single history query.
+const MAX_BUCKETS: i64 = 10000;
+
+fn time(time: Time) -> DateTime<Utc> {
+    DateTime::from_timestamp(time.get::<second>() as i64, 0).unwrap_or_default()
+}
+
+fn energy(energy: Option<Energy>) -> Option<f64> {
+    energy.map(|x| x.get::<watt_hour>())
+}
+
+fn temperature(temperature: Option<Temperature>) -> Option<f64> {
+    temperature.map(|x| x.get::<celsius>())
+}
+
+fn humidity(humidity: Option<Ratio>) -> Option<f64> {
+    humidity.map(|x| x.get::<percent>())
+}
+
+/// Verifies the session and the query range and gets a database connection.
+pub async fn connect(
+    ctx: &Context,
+    series_id: i32,
+    series_type: SeriesType,
+    from: &DateTime<Utc>,
+    to: &DateTime<Utc>,
+    interval: Option<i32>,
+) -> juniper::FieldResult<Object<AsyncPgConnection>> {
+    if let Err(e) = ctx.verify() {
+        return Err(e.to_string(&ctx.globals.logger).into());
+    }
+
+    if ctx.globals.series.get(&series_id) != Some(&series_type) {
+        return Err(
+            format!("{series_type:?} series {series_id} not found").into()
+        );
+    }
+    if from >= to {
+        return Err("Start time must be before end time".into());
+    }
+    if let Some(interval) = interval {
+        if interval <= 0 {
+            return Err("Interval must be positive".into());
+        }
+        if (*to - *from).num_seconds() / interval as i64 > MAX_BUCKETS {
+            return Err(format!(
+                "Query would return more than {MAX_BUCKETS} records"
+            )
+            .into());
+        }
+    }
+
+    ctx.globals.database.get().await.map_err(|e| {
+        format!("Getting database connection from pool failed: {e}").into()
+    })
+}
+
+/// Converts the downsampling interval in seconds.
+pub fn interval(interval: i32) -> Duration {
+    Duration::from_secs(interval as u64)
+}
+
+#[derive(juniper::GraphQLObject)]
+/// Reads a downsampled battery record.
+pub struct BatteryRecord {
+    /// Start of the bucket.
+    pub time: DateTime<Utc>,
+    /// Battery charge in watt hours.
+    pub charge: f64,
+    /// Total charged energy in watt hours.
+    pub energy_in: f64,
+    /// Total discharged energy in watt hours.
+    pub energy_out: f64,
+    /// Battery power in watt.
+    pub power: f64,
+}
+
+impl From<Battery> for BatteryRecord {
+    fn from(record: Battery) -> Self {
+        Self {
+            time: time(record.time),
+            charge: record.charge.get::<watt_hour>(),
+            energy_in: record.energy_in.get::<watt_hour>(),
+            energy_out: record.energy_out.get::<watt_hour>(),
+            power: record.power.get::<watt>(),
+        }
+    }
+}
+
+#[derive(juniper::GraphQLObject)]
+/// Reads a downsampled bidirectional meter record.
+pub struct BidirMeterRecord {
+    /// Start of the bucket.
+    pub time: DateTime<Utc>,
+    /// Total imported energy in watt hours.
+    pub energy_in: f64,
+    /// Total exported energy in watt hours.
+    pub energy_out: f64,
+    /// Power in watt.
+    pub power: f64,
+}
+
+impl From<BidirMeter> for BidirMeterRecord {
+    fn from(record: BidirMeter) -> Self {
+        Self {
+            time: time(record.time),
+            energy_in: record.energy_in.get::<watt_hour>(),
+            energy_out: record.energy_out.get::<watt_hour>(),
+            power: record.power.get::<watt>(),
+        }
+    }
+}
+
+#[derive(juniper::GraphQLObject)]
+/// Reads a downsampled generator record.
+pub struct GeneratorRecord {
+    /// Start of the bucket.
+    pub time: DateTime<Utc>,
+    /// Total produced energy in watt hours.
+    pub energy: f64,
+    /// Power in watt.
+    pub power: f64,
+    /// Total runtime in seconds.
+    pub runtime: f64,
+}
+
+impl From<Generator> for GeneratorRecord {
+    fn from(record: Generator) -> Self {
+        Self {
+            time: time(record.time),
+            energy: record.energy.get::<watt_hour>(),
+            power: record.power.get::<watt>(),
+            runtime: record.runtime.get::<second>(),
+        }
+    }
+}
+
+#[derive(juniper::GraphQLObject)]
+/// Reads a downsampled heat pump record.
+pub struct HeatpumpRecord {
+    /// Start of the bucket.
+    pub time: DateTime<Utc>,
+    /// Total consumed energy in watt hours.
+    pub energy: f64,
+    /// Power in watt.
+    pub power: f64,
+    /// Total produced heat in watt hours.
+    pub heat: f64,
+    /// Total produced cold in watt hours.
+    pub cold: f64,
+    /// Total defrost energy in watt hours.
+    pub defrost: f64,
+    /// Coefficient of performance.
+    pub cop: f64,
+    /// Boiler top temperature in degree celsius.
+    pub boiler_top: Option<f64>,
+    /// Boiler middle temperature in degree celsius.
+    pub boiler_mid: Option<f64>,
+    /// Boiler bottom temperature in degree celsius.
+    pub boiler_bot: Option<f64>,
+}
+
+impl From<Heatpump> for HeatpumpRecord {
+    fn from(record: Heatpump) -> Self {
+        Self {
+            time: time(record.time),
+            energy: record.energy.get::<watt_hour>(),
+            power: record.power.get::<watt>(),
+            heat: record.heat.get::<watt_hour>(),
+            cold: record.cold.get::<watt_hour>(),
+            defrost: record.defrost.get::<watt_hour>(),
+            cop: record.cop.get::<ratio>(),
+            boiler_top: temperature(record.boiler_top),
+            boiler_mid: temperature(record.boiler_mid),
+            boiler_bot: temperature(record.boiler_bot),
+        }
+    }
+}
+
+#[derive(juniper::GraphQLObject)]
+/// Reads a downsampled meter record.
+pub struct SimpleMeterRecord {
+    /// Start of the bucket.
+    pub time: DateTime<Utc>,
+    /// Total energy in watt hours.
+    pub energy: f64,
+    /// Power in watt.
+    pub power: f64,
+}
+
+impl From<SimpleMeter> for SimpleMeterRecord {
+    fn from(record: SimpleMeter) -> Self {
+        Self {
+            time: time(record.time),
+            energy: record.energy.get::<watt_hour>(),
+            power: record.power.get::<watt>(),
+        }
+    }
+}
+
+#[derive(juniper::GraphQLObject)]
+/// Reads a downsampled weather record.
+pub struct WeatherRecord {
+    /// Start of the bucket.
+    pub time: DateTime<Utc>,
+    /// Indoor temperature in degree celsius.
+    pub temp_in: f64,
+    /// Indoor humidity in percent.
+    pub hum_in: f64,
+    /// Outdoor temperature in degree celsius.
+    pub temp_out: Option<f64>,
+    /// Outdoor humidity in percent.
+    pub hum_out: Option<f64>,
+    /// Rain of the current day in millimeter.
+    pub rain_day: Option<f64>,
+    /// Current rain in millimeter.
+    pub rain_act: Option<f64>,
+    /// Accumulated rain in millimeter.
+    pub rain_acc: f64,
+    /// Current wind speed in meter per second.
+    pub wind_act: Option<f64>,
+    /// Wind gust speed in meter per second.
+    pub wind_gust: Option<f64>,
+    /// Wind direction in degree.
+    pub wind_dir: Option<f64>,
+    /// Sea level pressure in hectopascal.
+    pub baro_sea: f64,
+    /// Absolute pressure in hectopascal.
+    pub baro_abs: f64,
+    /// UV index.
+    pub uv_index: Option<f64>,
+    /// Dew point in degree celsius.
+    pub dew_point: Option<f64>,
+    /// Temperatures of the extra sensors in degree celsius.
+    pub temp_x: Vec<Option<f64>>,
+    /// Humidities of the extra sensors in percent.
+    pub hum_x: Vec<Option<f64>>,
+}
+
+impl From<Weather> for WeatherRecord {
+    fn from(record: Weather) -> Self {
+        Self {
+            time: time(record.time),
+            temp_in: record.temp_in.get::<celsius>(),
+            hum_in: record.hum_in.get::<percent>(),
+            temp_out: temperature(record.temp_out),
+            hum_out: humidity(record.hum_out),
+            rain_day: record.rain_day.map(|x| x.get::<millimeter>()),
+            rain_act: record.rain_act.map(|x| x.get::<millimeter>()),
+            rain_acc: record.rain_acc.get::<millimeter>(),
+            wind_act: record.wind_act.map(|x| x.get::<meter_per_second>()),
+            wind_gust: record.wind_gust.map(|x| x.get::<meter_per_second>()),
+            wind_dir: record.wind_dir.map(|x| x.get::<degree>()),
+            baro_sea: record.baro_sea.get::<hectopascal>(),
+            baro_abs: record.baro_abs.get::<hectopascal>(),
+            uv_index: record.uv_index.map(|x| x.get::<ratio>()),
+            dew_point: temperature(record.dew_point),
+            temp_x: [
+                record.temp_x1,
+                record.temp_x2,
+                record.temp_x3,
+                record.temp_x4,
+                record.temp_x5,
+                record.temp_x6,
+                record.temp_x7,
+            ]
+            .into_iter()
+            .map(temperature)
+            .collect(),
+            hum_x: [
+                record.hum_x1,
+                record.hum_x2,
+                record.hum_x3,
+                record.hum_x4,
+                record.hum_x5,
+                record.hum_x6,
+                record.hum_x7,
+            ]
+            .into_iter()
+            .map(humidity)
+            .collect(),
+        }
+    }
+}
+
+#[derive(juniper::GraphQLObject)]
+/// Reads the energy counter increase during one calendar period.
+pub struct EnergyDelta {
+    /// Start of the period.
+    pub time: DateTime<Utc>,
+    /// Consumed or produced energy in watt hours.
+    pub energy: Option<f64>,
+    /// Imported or charged energy in watt hours.
+    pub energy_in: Option<f64>,
+    /// Exported or discharged energy in watt hours.
+    pub energy_out: Option<f64>,
+    /// Produced heat in watt hours.
+    pub heat: Option<f64>,
+}
+
+impl From<EnergyDeltaModel> for EnergyDelta {
+    fn from(delta: EnergyDeltaModel) -> Self {
+        Self {
+            time: delta.time,
+            energy: energy(delta.energy),
+            energy_in: energy(delta.energy_in),
+            energy_out: energy(delta.energy_out),
+            heat: energy(delta.heat),
+        }
+    }
+}
@@ -21,6 +21,9 @@
 pub struct LoadControl {
     /// If the grid charge mode is enabled.
     pub charge_mode: bool,
+    /// True if the mode was changed at runtime, false if it is the
+    /// default mode.
+    pub charge_mode_overridden: bool,
     /// Displayed name.
     pub name: String,
 }
@@ -29,12 +32,13 @@ impl LoadControl {
     pub fn new(name: String) -> Self {
         Self {
             charge_mode: false,
+            charge_mode_overridden: false,
             name,
         }
     }
 }
 
-#[derive(juniper::GraphQLInputObject)]
+#[derive(Debug, juniper::GraphQLInputObject)]
 /// Controls the grid mode.
 pub struct InputLoadControl {
     /// If the grid charge mode is enabled.
@@ -0,0 +1,388 @@
+/******************************************************************************\
+    empowerd - empowers the offline smart home
+    Copyright (C) 2019 - 2026 Max Maisel
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU Affero General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU Affero General Public License for more details.
+
+    You should have received a copy of the GNU Affero General Public License
+    along with this program.  If not, see <https://www.gnu.org/licenses/>.
+\******************************************************************************/
+//! Exports the latest records and internal health data in the Prometheus
+//! text format.
+use crate::{
+    models::{
+        units::{
+            celsius, meter_per_second, millimeter, pascal, ratio, second, watt,
+            watt_hour, Temperature,
+        },
+        Model,
+    },
+    Globals,
+};
+use hyper::{
+    body::Incoming, header::CONTENT_TYPE, Request, Response, StatusCode,
+};
+use sha2::{Digest, Sha256};
+use std::{collections::BTreeMap, fmt::Write, sync::atomic::Ordering};
+
+const CONTENT_TYPE_TEXT: &str = "text/plain; version=0.0.4; charset=utf-8";
+
+struct Family {
+    help: &'static str,
+    kind: &'static str,
+    samples: Vec<String>,
+}
+
+#[derive(Default)]
+struct Registry {
+    families: BTreeMap<&'static str, Family>,
+}
+
+impl Registry {
+    fn add(
+        &mut self,
+        name: &'static str,
+        kind: &'static str,
+        help: &'static str,
+        labels: &[(&str, &str)],
+        value: f64,
+    ) {
+        let labels = match labels.is_empty() {
+            true => String::new(),
+            false => format!(
+                "{{{}}}",
+                labels
+                    .iter()
+                    .map(|(k, v)| format!("{k}=\"{}\"", escape(v)))
+                    .collect::<Vec<_>>()
+                    .join(",")
+            ),
+        };
+        self.families
+            .entry(name)
+            .or_insert_with(|| Family {
+                help,
+                kind,
+                samples: Vec::new(),
+            })
+            .samples
+            .push(format!("{name}{labels} {value}"));
+    }
+
+    fn gauge(
+        &mut self,
+        name: &'static str,
+        help: &'static str,
+        labels: &[(&str, &str)],
+        value: f64,
+    ) {
+        self.add(name, "gauge", help, labels, value);
+    }
+
+    fn counter(
+        &mut self,
+        name: &'static str,
+        help: &'static str,
+        labels: &[(&str, &str)],
+        value: f64,
+    ) {
+        self.add(name, "counter", help, labels, value);
+    }
+
+    fn temperature(
+        &mut self,
+        node: &str,
+        sensor: &str,
+        x: Option<Temperature>,
+    ) {
+        if let Some(x) = x {
+            self.gauge(
+                "empowerd_temperature_celsius",
+                "Latest temperature.",
+                &[("node", node), ("sensor", sensor)],
+                x.get::<celsius>(),
+            );
+        }
+    }
+
+    fn energy(&mut self, node: &str, kind: &str, wh: f64) {
+        self.counter(
+            "empowerd_energy_watthours_total",
+            "Energy counter of a source.",
+            &[("node", node), ("type", kind)],
+            wh,
+        );
+    }
+
+    fn render(&self) -> String {
+        let mut output = String::new();
+        for (name, family) in &self.families {
+            let _ = writeln!(output, "# HELP {name} {}", family.help);
+            let _ = writeln!(output, "# TYPE {name} {}", family.kind);
+            for sample in &family.samples {
+                let _ = writeln!(output, "{sample}");
+            }
+        }
+        output
+    }
+}
+
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+fn add_model(registry: &mut Registry, node: &str, model: &Model) {
+    let (kind, time) = match model {
+        Model::None => return,
+        Model::AvailablePower(x) => ("AvailablePower", x.time),
+        Model::Battery(x) => ("Battery", x.time),
+        Model::BidirMeter(x) => ("BidirMeter", x.time),
+        Model::Generator(x) => ("Generator", x.time),
+        Model::Heatpump(x) => ("Heatpump", x.time),
+        Model::SimpleMeter(x) => ("SimpleMeter", x.time),
+        Model::Weather(x) => ("Weather", x.time),
+    };
+    registry.gauge(
+        "empowerd_node_timestamp_seconds",
+        "Time of the latest record of a source or processor.",
+        &[("node", node), ("kind", kind)],
+        time.get::<second>(),
+    );
+
+    let power = match model {
+        Model::None | Model::Weather(_) => None,
+        Model::AvailablePower(x) => Some(x.power),
+        Model::Battery(x) => Some(x.power),
+        Model::BidirMeter(x) => Some(x.power),
+        Model::Generator(x) => Some(x.power),
+        Model::Heatpump(x) => Some(x.power),
+        Model::SimpleMeter(x) => Some(x.power),
+    };
+    if let Some(power) = power {
+        registry.gauge(
+            "empowerd_power_watts",
+            "Latest power of a source or processor.",
+            &[("node", node)],
+            power.get::<watt>(),
+        );
+    }
+
+    match model {
+        Model::Battery(x) => {
+            registry.energy(node, "in", x.energy_in.get::<watt_hour>());
+            registry.energy(node, "out", x.energy_out.get::<watt_hour>());
+            registry.gauge(
+                "empowerd_battery_charge_watthours",
+                "Latest battery charge.",
+                &[("node", node)],
+                x.charge.get::<watt_hour>(),
+            );
+        }
+        Model::BidirMeter(x) => {
+            registry.energy(node, "in", x.energy_in.get::<watt_hour>());
+            registry.energy(node, "out", x.energy_out.get::<watt_hour>());
+        }
+        Model::Generator(x) => {
+            registry.energy(node, "total", x.energy.get::<watt_hour>());
+            registry.counter(
+                "empowerd_generator_runtime_seconds_total",
+                "Runtime counter of a generator.",
+                &[("node", node)],
+                x.runtime.get::<second>(),
+            );
+        }
+        Model::Heatpump(x) => {
+            registry.energy(node, "total", x.energy.get::<watt_hour>());
+            registry.energy(node, "heat", x.heat.get::<watt_hour>());
+            registry.energy(node, "cold", x.cold.get::<watt_hour>());
+            registry.energy(node, "defrost", x.defrost.get::<watt_hour>());
+            registry.gauge(
+                "empowerd_heatpump_cop",
+                "Latest coefficient of performance of a heatpump.",
+                &[("node", node)],
+                x.cop.get::<ratio>(),
+            );
+            registry.temperature(node, "boiler_top", x.boiler_top);
+            registry.temperature(node, "boiler_mid", x.boiler_mid);
+            registry.temperature(node, "boiler_bot", x.boiler_bot);
+        }
+        Model::SimpleMeter(x) => {
+            registry.energy(node, "total", x.energy.get::<watt_hour>());
+        }
+        Model::Weather(x) => {
+            registry.temperature(node, "dew_point", x.dew_point);
+            for (sensor, temp, hum) in [
+                ("in", Some(x.temp_in), Some(x.hum_in)),
+                ("out", x.temp_out, x.hum_out),
+                ("x1", x.temp_x1, x.hum_x1),
+                ("x2", x.temp_x2, x.hum_x2),
+                ("x3", x.temp_x3, x.hum_x3),
+                ("x4", x.temp_x4, x.hum_x4),
+                ("x5", x.temp_x5, x.hum_x5),
+                ("x6", x.temp_x6, x.hum_x6),
+                ("x7", x.temp_x7, x.hum_x7),
+            ] {
+                registry.temperature(node, sensor, temp);
+                if let Some(hum) = hum {
+                    registry.gauge(
+                        "empowerd_humidity_ratio",
+                        "Latest relative humidity.",
+                        &[("node", node), ("sensor", sensor)],
+                        hum.get::<ratio>(),
+                    );
+                }
+            }
+            registry.counter(
+                "empowerd_rain_millimeters_total",
+                "Accumulated rain.",
+                &[("node", node)],
+                x.rain_acc.get::<millimeter>(),
+            );
+            for (sensor, pressure) in [("sea", x.baro_sea), ("abs", x.baro_abs)]
+            {
+                registry.gauge(
+                    "empowerd_pressure_pascals",
+                    "Latest barometric pressure.",
+                    &[("node", node), ("sensor", sensor)],
+                    pressure.get::<pascal>(),
+                );
+            }
+            for (sensor, wind) in [("act", x.wind_act), ("gust", x.wind_gust)] {
+                if let Some(wind) = wind {
+                    registry.gauge(
+                        "empowerd_wind_speed_meters_per_second",
+                        "Latest wind speed.",
+                        &[("node", node), ("sensor", sensor)],
+                        wind.get::<meter_per_second>(),
+                    );
+                }
+            }
+        }
+        Model::None | Model::AvailablePower(_) => (),
+    }
+}
+
+fn render(globals: &Globals) -> String {
+    let mut registry = Registry::default();
+
+    for (name, node) in &globals.nodes {
+        add_model(&mut registry, name, &node.borrow());
+    }
+
+    let states = globals.switch_mux.subscribe().borrow().clone();
+    for (id, state) in states.into_iter().enumerate() {
+        let (open, name) = match (state, globals.switch_mux.name(id)) {
+            (Some(open), Ok(name)) => (open, name),
+            _ => continue,
+        };
+        registry.gauge(
+            "empowerd_switch_open",
+            "Latest state of a switch.",
+            &[("switch", &name), ("id", &id.to_string())],
+            if open { 1.0 } else { 0.0 },
+        );
+    }
+
+    for (task, errors) in &globals.task_errors {
+        registry.counter(
+            "empowerd_task_errors_total",
+            "Temporary errors of a source or processor task.",
+            &[("task", task)],
+            errors.load(Ordering::Relaxed) as f64,
+        );
+    }
+
+    registry.counter(
+        "empowerd_login_failures_total",
+        "Failed login attempts.",
+        &[],
+        globals.login_guard.failed_total() as f64,
+    );
+    registry.counter(
+        "empowerd_login_lockouts_total",
+        "Failed login attempts which caused a lockout.",
+        &[],
+        globals.login_guard.lockouts_total() as f64,
+    );
+
+    let status = globals.database.status();
+    registry.gauge(
+        "empowerd_database_connections_max",
+        "Maximum size of the database connection pool.",
+        &[],
+        status.max_size as f64,
+    );
+    for (state, value) in [
+        ("open", status.size),
+        ("available", status.available),
+        ("waiting", status.waiting),
+    ] {
+        registry.gauge(
+            "empowerd_database_connections",
+            "Connections of the database pool.",
+            &[("state", state)],
+            value as f64,
+        );
+    }
+
+    registry.render()
+}
+
+fn authorized(req: &Request<Incoming>, token: &Option<String>) -> bool {
+    let token = match token {
+        Some(x) => x,
+        None => return true,
+    };
+    let requested = match req.headers().get("Authorization") {
+        Some(x) => x.to_str().unwrap_or_default().replace("Bearer ", ""),
+        None => return false,
+    };
+    // Compare digests to avoid leaking the token by timing.
+    Sha256::digest(requested) == Sha256::digest(token)
+}
+
+/// Serves the /metrics route. It requires a bearer token if one is
+/// configured.
+pub fn handle(req: Request<Incoming>, globals: &Globals) -> Response<String> {
+    if !authorized(&req, &globals.metrics_token) {
+        let mut response = Response::new(String::new());
+        *response.status_mut() = StatusCode::UNAUTHORIZED;
+        return response;
+    }
+
+    let mut response = Response::new(render(globals));
+    response
+        .headers_mut()
+        .insert(CONTENT_TYPE, CONTENT_TYPE_TEXT.parse().unwrap());
+    response
+}
+
+#[test]
+fn test_registry() {
+    let mut registry = Registry::default();
+    registry.gauge("b_gauge", "Gauge.", &[("node", "a\"b")], 1.5);
+    registry.counter("a_total", "Counter.", &[], 2.0);
+    registry.gauge("b_gauge", "Gauge.", &[("node", "c")], -1.0);
+
+    assert_eq!(
+        "# HELP a_total Counter.\n\
+        # TYPE a_total counter\n\
+        a_total 2\n\
+        # HELP b_gauge Gauge.\n\
+        # TYPE b_gauge gauge\n\
+        b_gauge{node=\"a\\\"b\"} 1.5\n\
+        b_gauge{node=\"c\"} -1\n",
+        registry.render()
+    );
+}
@@ -16,12 +16,25 @@
     along with this program.  If not, see <https://www.gnu.org/licenses/>.
 \******************************************************************************/
 
+pub mod metrics;
 pub mod mutation;
 pub mod query;
 pub mod server;
+pub mod subscription;
+pub mod tls;
+pub mod websocket;
 
 pub mod appliance;
 pub mod available_power;
+pub mod config;
+pub mod deferrable_load;
+pub mod graph;
+pub mod history;
 pub mod load_control;
+pub mod node;
+pub mod peak_shaving;
 pub mod poweroff_timer;
+pub mod rules;
 pub mod switch;
+pub mod task;
+pub mod user;
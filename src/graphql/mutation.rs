@@ -15,20 +15,45 @@
     You should have received a copy of the GNU Affero General Public License
     along with this program.  If not, see <https://www.gnu.org/licenses/>.
 \******************************************************************************/
-use slog::warn;
+use chrono::Utc;
+use slog::{error, warn};
 use std::convert::TryInto;
+use std::fmt::Debug;
 use std::time::Duration;
 use tokio::sync::oneshot;
 
 use super::appliance::{Appliance, InputAppliance};
 use super::available_power::{AvailablePower, InputAvailablePower};
+use super::config::ConfigUpdate;
+use super::deferrable_load::{DeferrableJob, InputDeferrableJob};
 use super::load_control::{InputLoadControl, LoadControl};
 use super::poweroff_timer::{InputPoweroffTimer, PoweroffTimer};
+use super::rules::{InputSwitchRule, SwitchRule};
 use super::switch::{InputSwitch, Switch};
+use super::user::NewApiToken;
+use crate::models::{
+    units::{watt, Power},
+    ApiToken as TokenModel, DeferrableJob as JobModel, SwitchRule as RuleModel,
+    User,
+};
 use crate::processors::{
-    ApplianceCmd, AvailablePowerCmd, LoadControlCmd, PoweroffTimerCmd,
+    ApplianceCmd, AvailablePowerCmd, DeferrableLoadCmd, LoadControlCmd,
+    PoweroffTimerCmd, RulesCmd,
 };
-use crate::Context;
+use crate::schedule::{RuleCalendar, RuleTarget, Schedule};
+use crate::session_manager::{Identity, Role, SessionManager};
+use crate::{Context, Error};
+
+/// Checks the role of the session owner and records the mutation in the
+/// audit log.
+async fn authorize(
+    ctx: &Context,
+    role: Role,
+    mutation: &str,
+    input: &impl Debug,
+) -> juniper::FieldResult<Identity> {
+    Ok(ctx.authorize_mutation(role, mutation, input).await?)
+}
 
 pub struct Mutation;
 
@@ -42,22 +67,81 @@ impl Mutation {
         username: String,
         password: String,
     ) -> juniper::FieldResult<String> {
-        if username == ctx.globals.username {
-            match argon2::verify_encoded(
-                &ctx.globals.hashed_pw,
-                password.as_bytes(),
-            ) {
-                Ok(valid) => {
-                    if valid {
-                        return ctx.globals.session_manager.register().map_err(
-                            |e| e.to_string(&ctx.globals.logger).into(),
-                        );
-                    }
-                }
-                Err(e) => {
-                    warn!(ctx.globals.logger, "Verify password failed: {}", e)
-                }
+        let guard = &ctx.globals.login_guard;
+        let peer = match ctx.peer {
+            Some(x) => x.to_string(),
+            None => "unknown address".into(),
+        };
+        if let Err(remaining) = guard.check(ctx.peer, &username) {
+            warn!(
+                ctx.globals.logger,
+                "Refused login of '{username}' from {peer}, locked for {:?}",
+                remaining
+            );
+            return Err(format!(
+                "Too many failed logins, retry in {}s!",
+                remaining.as_secs() + 1
+            )
+            .into());
+        }
+
+        let user = match ctx.globals.database.get().await {
+            Ok(mut conn) => User::by_name(&mut conn, &username).await,
+            Err(e) => Err(Error::Temporary(e.to_string())),
+        };
+        // The user from the config file is kept as fallback administrator.
+        // Unknown users are verified against a dummy hash, so they cannot
+        // be told apart from wrong passwords by the response time.
+        let (user, exists) = match user {
+            Ok(x) => (x, true),
+            Err(Error::NotFound) if username == ctx.globals.username => (
+                User {
+                    id: 0,
+                    name: username.clone(),
+                    hashed_password: ctx.globals.hashed_pw.clone(),
+                    role: Role::Admin,
+                },
+                true,
+            ),
+            Err(Error::NotFound) => (User::dummy(username.clone()), false),
+            Err(e) => {
+                error!(ctx.globals.logger, "Loading user failed: {e}");
+                return Err("Login is temporarily unavailable!".into());
             }
+        };
+
+        let (name, role) = (user.name.clone(), user.role);
+        let verified = guard
+            .verify(move || user.verify_password(&password))
+            .await
+            .map_err(|e| {
+                warn!(ctx.globals.logger, "Login of '{name}' failed: {e}");
+                e
+            })?;
+        match verified {
+            Ok(true) if exists => {
+                guard.succeeded(ctx.peer, &username);
+                return ctx
+                    .globals
+                    .session_manager
+                    .register(&name, role)
+                    .map_err(|e| e.to_string(&ctx.globals.logger).into());
+            }
+            Ok(_) => (),
+            Err(e) => warn!(ctx.globals.logger, "{}", e),
+        }
+
+        let lockout = guard.failed(ctx.peer, &username);
+        match lockout.is_zero() {
+            true => warn!(
+                ctx.globals.logger,
+                "Failed login of '{username}' from {peer}"
+            ),
+            false => warn!(
+                ctx.globals.logger,
+                "Failed login of '{username}' from {peer}, locked for {:?}",
+                lockout
+            ),
         }
         return Err("Incorrect user or password!".into());
     }
@@ -75,9 +159,7 @@ impl Mutation {
         ctx: &Context,
         input: InputAvailablePower,
     ) -> juniper::FieldResult<AvailablePower> {
-        if let Err(e) = ctx.globals.session_manager.verify(&ctx.token) {
-            return Err(e.to_string(&ctx.globals.logger).into());
-        }
+        authorize(ctx, Role::Admin, "setAvailablePower", &input).await?;
 
         let id_u: usize = input
             .id
@@ -109,6 +191,44 @@ impl Mutation {
         Ok(AvailablePower {
             id: input.id,
             threshold: input.threshold,
+            threshold_overridden: true,
+            name: processor.name.clone(),
+            power: 0.0,
+        })
+    }
+
+    /// Resets the available power threshold to the config default.
+    async fn reset_available_power(
+        ctx: &Context,
+        id: i32,
+    ) -> juniper::FieldResult<AvailablePower> {
+        authorize(ctx, Role::Admin, "resetAvailablePower", &id).await?;
+
+        let id_u: usize =
+            id.try_into().map_err(|_| "'id' is invalid".to_string())?;
+
+        let processor =
+            match ctx.globals.processor_cmds.available_power.get(id_u) {
+                Some(x) => x,
+                None => {
+                    return Err(format!(
+                        "AvailablePowerProcessor with id {} does not exist",
+                        id
+                    )
+                    .into())
+                }
+            };
+
+        let (tx, rx) = oneshot::channel();
+        let cmd = AvailablePowerCmd::ResetThreshold { resp: tx };
+        let threshold = processor
+            .issue_command(&ctx.globals.logger, cmd, rx)
+            .await?;
+
+        Ok(AvailablePower {
+            id,
+            threshold,
+            threshold_overridden: false,
             name: processor.name.clone(),
             power: 0.0,
         })
@@ -119,9 +239,7 @@ impl Mutation {
         ctx: &Context,
         input: InputAppliance,
     ) -> juniper::FieldResult<Appliance> {
-        if let Err(e) = ctx.globals.session_manager.verify(&ctx.token) {
-            return Err(e.to_string(&ctx.globals.logger).into());
-        }
+        authorize(ctx, Role::Operator, "setAppliance", &input).await?;
 
         let id_u: usize = input
             .id
@@ -142,6 +260,7 @@ impl Mutation {
         let (tx, rx) = oneshot::channel();
         let cmd = ApplianceCmd::SetForceOnOff {
             force_on_off: input.force_on_off,
+            persist: true,
             resp: tx,
         };
 
@@ -152,18 +271,154 @@ impl Mutation {
         Ok(Appliance {
             id: input.id,
             force_on_off: input.force_on_off,
+            force_on_off_overridden: true,
             name: processor.name.clone(),
         })
     }
 
+    /// Returns an appliance to automatic mode.
+    async fn reset_appliance(
+        ctx: &Context,
+        id: i32,
+    ) -> juniper::FieldResult<Appliance> {
+        authorize(ctx, Role::Operator, "resetAppliance", &id).await?;
+
+        let id_u: usize =
+            id.try_into().map_err(|_| "'id' is invalid".to_string())?;
+
+        let processor = match ctx.globals.processor_cmds.appliance.get(id_u) {
+            Some(x) => x,
+            None => {
+                return Err(format!(
+                    "ApplianceProcessor with id {} does not exist",
+                    id
+                )
+                .into())
+            }
+        };
+
+        let (tx, rx) = oneshot::channel();
+        let cmd = ApplianceCmd::ResetForceOnOff { resp: tx };
+        let force_on_off = processor
+            .issue_command(&ctx.globals.logger, cmd, rx)
+            .await?;
+
+        Ok(Appliance {
+            id,
+            force_on_off,
+            force_on_off_overridden: false,
+            name: processor.name.clone(),
+        })
+    }
+
+    /// Registers a new deferrable load job.
+    async fn add_deferrable_job(
+        ctx: &Context,
+        input: InputDeferrableJob,
+    ) -> juniper::FieldResult<DeferrableJob> {
+        authorize(ctx, Role::Operator, "addDeferrableJob", &input).await?;
+
+        let id_u: usize = input
+            .id
+            .try_into()
+            .map_err(|_| "'id' is invalid".to_string())?;
+        let switch_id: usize = input
+            .switch_id
+            .try_into()
+            .map_err(|_| "'switch_id' is invalid".to_string())?;
+
+        if input.power <= 0.0 {
+            return Err("'power' must be greater than zero!".into());
+        }
+        let runtime_s: u64 = match (input.runtime, input.energy) {
+            (Some(runtime), _) => runtime
+                .try_into()
+                .map_err(|_| "'runtime' is invalid".to_string())?,
+            (None, Some(energy)) if energy > 0.0 => {
+                (energy / input.power * 3600.0).ceil() as u64
+            }
+            _ => return Err("Either 'energy' or 'runtime' is required".into()),
+        };
+        if runtime_s == 0 {
+            return Err("'runtime' must be greater than zero!".into());
+        }
+
+        let processor =
+            match ctx.globals.processor_cmds.deferrable_load.get(id_u) {
+                Some(x) => x,
+                None => {
+                    return Err(format!(
+                        "DeferrableLoadProcessor with id {} does not exist",
+                        input.id
+                    )
+                    .into())
+                }
+            };
+
+        let job = JobModel {
+            id: 0,
+            switch_name: ctx.globals.switch_mux.name(switch_id)?,
+            power: Power::new::<watt>(input.power),
+            runtime: Duration::from_secs(runtime_s),
+            earliest_start: input.earliest_start.unwrap_or_else(Utc::now),
+            latest_finish: input.latest_finish,
+            started: None,
+            finished: None,
+        };
+
+        let (tx, rx) = oneshot::channel();
+        let cmd = DeferrableLoadCmd::AddJob { job, resp: tx };
+
+        let job = processor
+            .issue_command(&ctx.globals.logger, cmd, rx)
+            .await??;
+
+        Ok(DeferrableJob::from_model(job, &ctx.globals.switch_mux)?)
+    }
+
+    /// Cancels a deferrable load job. Running jobs are switched off.
+    async fn cancel_deferrable_job(
+        ctx: &Context,
+        id: i32,
+        job_id: i32,
+    ) -> juniper::FieldResult<i32> {
+        authorize(ctx, Role::Operator, "cancelDeferrableJob", &(id, job_id))
+            .await?;
+
+        let id_u: usize =
+            id.try_into().map_err(|_| "'id' is invalid".to_string())?;
+
+        let processor =
+            match ctx.globals.processor_cmds.deferrable_load.get(id_u) {
+                Some(x) => x,
+                None => {
+                    return Err(format!(
+                        "DeferrableLoadProcessor with id {} does not exist",
+                        id
+                    )
+                    .into())
+                }
+            };
+
+        let (tx, rx) = oneshot::channel();
+        let cmd = DeferrableLoadCmd::CancelJob {
+            id: job_id,
+            resp: tx,
+        };
+
+        processor
+            .issue_command(&ctx.globals.logger, cmd, rx)
+            .await??;
+
+        Ok(job_id)
+    }
+
     /// Controls grid load control mode.
     async fn set_load_control(
         ctx: &Context,
         input: InputLoadControl,
     ) -> juniper::FieldResult<LoadControl> {
-        if let Err(e) = ctx.globals.session_manager.verify(&ctx.token) {
-            return Err(e.to_string(&ctx.globals.logger).into());
-        }
+        authorize(ctx, Role::Admin, "setLoadControl", &input).await?;
 
         let load_ctrl = match &ctx.globals.processor_cmds.load_control {
             Some(x) => x,
@@ -182,6 +437,31 @@ impl Mutation {
 
         Ok(LoadControl {
             charge_mode: input.charge_mode,
+            charge_mode_overridden: true,
+            name: load_ctrl.name.clone(),
+        })
+    }
+
+    /// Resets the grid load control mode to the default.
+    async fn reset_load_control(
+        ctx: &Context,
+    ) -> juniper::FieldResult<LoadControl> {
+        authorize(ctx, Role::Admin, "resetLoadControl", &()).await?;
+
+        let load_ctrl = match &ctx.globals.processor_cmds.load_control {
+            Some(x) => x,
+            None => return Err("LoadControl is not available".into()),
+        };
+
+        let (tx, rx) = oneshot::channel();
+        let cmd = LoadControlCmd::ResetChargeMode { resp: tx };
+        let charge_mode = load_ctrl
+            .issue_command(&ctx.globals.logger, cmd, rx)
+            .await?;
+
+        Ok(LoadControl {
+            charge_mode,
+            charge_mode_overridden: false,
             name: load_ctrl.name.clone(),
         })
     }
@@ -191,9 +471,7 @@ impl Mutation {
         ctx: &Context,
         input: InputPoweroffTimer,
     ) -> juniper::FieldResult<PoweroffTimer> {
-        if let Err(e) = ctx.globals.session_manager.verify(&ctx.token) {
-            return Err(e.to_string(&ctx.globals.logger).into());
-        }
+        authorize(ctx, Role::Admin, "setPoweroffTimer", &input).await?;
 
         let id_u: usize = input
             .id
@@ -228,7 +506,11 @@ impl Mutation {
         };
 
         let (tx, rx) = oneshot::channel();
-        let cmd = PoweroffTimerCmd::SetOnTime { on_time, resp: tx };
+        let cmd = PoweroffTimerCmd::SetOnTime {
+            on_time,
+            persist: true,
+            resp: tx,
+        };
 
         processor
             .issue_command(&ctx.globals.logger, cmd, rx)
@@ -237,18 +519,166 @@ impl Mutation {
         Ok(PoweroffTimer {
             id: input.id,
             on_time: input.on_time,
+            on_time_overridden: true,
+            switch_id,
+        })
+    }
+
+    /// Resets the on time of a poweroff timer to the config default.
+    async fn reset_poweroff_timer(
+        ctx: &Context,
+        id: i32,
+    ) -> juniper::FieldResult<PoweroffTimer> {
+        authorize(ctx, Role::Admin, "resetPoweroffTimer", &id).await?;
+
+        let id_u: usize =
+            id.try_into().map_err(|_| "'id' is invalid".to_string())?;
+
+        let processor =
+            match ctx.globals.processor_cmds.poweroff_timer.get(id_u) {
+                Some(x) => x,
+                None => {
+                    return Err(format!(
+                        "PoweroffTimerProcessor with id {} does not exist",
+                        id
+                    )
+                    .into())
+                }
+            };
+
+        let switch_id = match processor.switch_id {
+            Some(x) => x as i32,
+            None => return Err("Missing switch ID".into()),
+        };
+
+        let (tx, rx) = oneshot::channel();
+        let cmd = PoweroffTimerCmd::ResetOnTime { resp: tx };
+        let on_time = processor
+            .issue_command(&ctx.globals.logger, cmd, rx)
+            .await?
+            .as_secs()
+            .try_into()?;
+
+        Ok(PoweroffTimer {
+            id,
+            on_time,
+            on_time_overridden: false,
             switch_id,
         })
     }
 
+    /// Creates or updates a switch rule.
+    async fn set_rule(
+        ctx: &Context,
+        input: InputSwitchRule,
+    ) -> juniper::FieldResult<SwitchRule> {
+        authorize(ctx, Role::Admin, "setRule", &input).await?;
+
+        let id_u: usize = input
+            .id
+            .try_into()
+            .map_err(|_| "'id' is invalid".to_string())?;
+        let target_id: usize = input
+            .target_id
+            .try_into()
+            .map_err(|_| "'target_id' is invalid".to_string())?;
+        input
+            .schedule
+            .parse::<Schedule>()
+            .map_err(|e| format!("'schedule' is invalid: {e}"))?;
+
+        let processor = match ctx.globals.processor_cmds.rules.get(id_u) {
+            Some(x) => x,
+            None => {
+                return Err(format!(
+                    "RulesProcessor with id {} does not exist",
+                    input.id
+                )
+                .into())
+            }
+        };
+
+        let appliances = &ctx.globals.processor_cmds.appliance;
+        let target_name = match input.target {
+            RuleTarget::Switch => ctx.globals.switch_mux.name(target_id)?,
+            RuleTarget::Appliance => match appliances.get(target_id) {
+                Some(x) => x.name.clone(),
+                None => {
+                    return Err(format!(
+                        "Appliance with id {} does not exist",
+                        input.target_id
+                    )
+                    .into())
+                }
+            },
+        };
+
+        let rule = RuleModel {
+            id: input.rule_id.unwrap_or(0),
+            name: input.name,
+            enabled: input.enabled.unwrap_or(true),
+            schedule: input.schedule,
+            calendar: input.calendar.unwrap_or(RuleCalendar::Always),
+            target: input.target,
+            target_name,
+            action: input.action,
+        };
+
+        let (tx, rx) = oneshot::channel();
+        let cmd = RulesCmd::SetRule { rule, resp: tx };
+
+        let rule = processor
+            .issue_command(&ctx.globals.logger, cmd, rx)
+            .await??;
+
+        Ok(SwitchRule::from_model(
+            rule,
+            &ctx.globals.switch_mux,
+            appliances,
+        )?)
+    }
+
+    /// Deletes a switch rule.
+    async fn delete_rule(
+        ctx: &Context,
+        id: i32,
+        rule_id: i32,
+    ) -> juniper::FieldResult<i32> {
+        authorize(ctx, Role::Admin, "deleteRule", &(id, rule_id)).await?;
+
+        let id_u: usize =
+            id.try_into().map_err(|_| "'id' is invalid".to_string())?;
+
+        let processor = match ctx.globals.processor_cmds.rules.get(id_u) {
+            Some(x) => x,
+            None => {
+                return Err(format!(
+                    "RulesProcessor with id {} does not exist",
+                    id
+                )
+                .into())
+            }
+        };
+
+        let (tx, rx) = oneshot::channel();
+        let cmd = RulesCmd::DeleteRule {
+            id: rule_id,
+            resp: tx,
+        };
+
+        processor
+            .issue_command(&ctx.globals.logger, cmd, rx)
+            .await??;
+
+        Ok(rule_id)
+    }
+
     /// Open or close a switch.
     async fn set_switch(
         ctx: &Context,
         switch: InputSwitch,
     ) -> juniper::FieldResult<Switch> {
-        if let Err(e) = ctx.globals.session_manager.verify(&ctx.token) {
-            return Err(e.to_string(&ctx.globals.logger).into());
-        }
+        authorize(ctx, Role::Operator, "setSwitch", &switch).await?;
 
         let channel: usize = match switch.id.try_into() {
             Ok(x) => x,
@@ -278,4 +708,119 @@ impl Mutation {
             icon,
         });
     }
+
+    /// Creates a long-lived API token for the current user. The role of
+    /// the token must not exceed the role of its owner. The token itself
+    /// is only returned once.
+    async fn create_api_token(
+        ctx: &Context,
+        name: String,
+        role: Role,
+    ) -> juniper::FieldResult<NewApiToken> {
+        let identity =
+            authorize(ctx, Role::Viewer, "createApiToken", &(&name, role))
+                .await?;
+        if role > identity.role {
+            return Err("Token role exceeds user role".into());
+        }
+
+        let session_manager = &ctx.globals.session_manager;
+        let token = session_manager
+            .generate_api_token()
+            .map_err(|e| e.to_string(&ctx.globals.logger))?;
+        let mut model = TokenModel {
+            id: 0,
+            name,
+            user: identity.user.clone(),
+            role,
+            token_hash: SessionManager::hash_api_token(&token),
+            created: Utc::now(),
+        };
+
+        let mut conn = ctx.globals.database.get().await.map_err(|e| {
+            format!("Getting database connection from pool failed: {e}")
+        })?;
+        model.insert(&mut conn).await?;
+        session_manager
+            .add_api_token(
+                model.token_hash.clone(),
+                Identity {
+                    user: model.user.clone(),
+                    role: model.role,
+                },
+            )
+            .map_err(|e| e.to_string(&ctx.globals.logger))?;
+
+        Ok(NewApiToken {
+            token,
+            info: model.into(),
+        })
+    }
+
+    /// Revokes an API token. Admins may revoke tokens of all users.
+    async fn revoke_api_token(
+        ctx: &Context,
+        id: i32,
+    ) -> juniper::FieldResult<i32> {
+        let identity =
+            authorize(ctx, Role::Viewer, "revokeApiToken", &id).await?;
+
+        let mut conn = ctx.globals.database.get().await.map_err(|e| {
+            format!("Getting database connection from pool failed: {e}")
+        })?;
+        let token = TokenModel::by_id(&mut conn, id).await?;
+        if token.user != identity.user && identity.role < Role::Admin {
+            return Err(Error::NotFound.into());
+        }
+
+        TokenModel::delete(&mut conn, id).await?;
+        ctx.globals
+            .session_manager
+            .remove_api_token(&token.token_hash)
+            .map_err(|e| e.to_string(&ctx.globals.logger))?;
+
+        Ok(id)
+    }
+
+    /// Replaces the main config file and reloads it. Secrets which are
+    /// still redacted keep their current value. Requires the admin role.
+    async fn update_config(
+        ctx: &Context,
+        config: String,
+        comment: String,
+    ) -> juniper::FieldResult<ConfigUpdate> {
+        // The config may contain secrets, only the comment is logged.
+        let identity =
+            authorize(ctx, Role::Admin, "updateConfig", &comment).await?;
+
+        let mut conn = ctx.globals.database.get().await.map_err(|e| {
+            format!("Getting database connection from pool failed: {e}")
+        })?;
+        let (version, changes) = ctx
+            .globals
+            .config_editor
+            .update(&mut conn, &identity.user, &comment, &config)
+            .await?;
+        Ok(ConfigUpdate::new(version, changes)?)
+    }
+
+    /// Restores a stored version of the main config file and reloads it.
+    /// Requires the admin role.
+    async fn rollback_config(
+        ctx: &Context,
+        version: i32,
+    ) -> juniper::FieldResult<ConfigUpdate> {
+        let identity =
+            authorize(ctx, Role::Admin, "rollbackConfig", &version).await?;
+
+        let mut conn = ctx.globals.database.get().await.map_err(|e| {
+            format!("Getting database connection from pool failed: {e}")
+        })?;
+        let (version, changes) = ctx
+            .globals
+            .config_editor
+            .rollback(&mut conn, &identity.user, version)
+            .await?;
+        Ok(ConfigUpdate::new(version, changes)?)
+    }
 }
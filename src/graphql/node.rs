@@ -0,0 +1,147 @@
+/******************************************************************************\
+    empowerd - empowers the offline smart home
+    Copyright (C) 2019 - 2026 Max Maisel
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU Affero General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU Affero General Public License for more details.
+
+    You should have received a copy of the GNU Affero General Public License
+    along with this program.  If not, see <https://www.gnu.org/licenses/>.
+\******************************************************************************/
+use crate::{
+    models::{
+        units::{second, watt, watt_hour},
+        Model,
+    },
+    task_group::{TaskHealth, TaskStatus},
+};
+use chrono::{DateTime, Utc};
+
+#[derive(juniper::GraphQLObject)]
+/// Reads the latest record of a source or processor.
+pub struct Node {
+    /// Name of the source or processor.
+    pub name: String,
+    /// Type of the record.
+    pub kind: String,
+    /// Time of the record.
+    pub time: DateTime<Utc>,
+    /// Power in watt.
+    pub power: Option<f64>,
+    /// Total energy in watt hours.
+    pub energy: Option<f64>,
+    /// Total imported or charged energy in watt hours.
+    pub energy_in: Option<f64>,
+    /// Total exported or discharged energy in watt hours.
+    pub energy_out: Option<f64>,
+    /// Battery charge in watt hours.
+    pub charge: Option<f64>,
+}
+
+impl Node {
+    fn new(name: &str, kind: &str, time: f64) -> Self {
+        Self {
+            name: name.into(),
+            kind: kind.into(),
+            time: DateTime::from_timestamp(time as i64, 0).unwrap_or_default(),
+            power: None,
+            energy: None,
+            energy_in: None,
+            energy_out: None,
+            charge: None,
+        }
+    }
+
+    /// Converts a record, returns None if the node did not publish any
+    /// record yet.
+    pub fn from_model(name: &str, model: &Model) -> Option<Self> {
+        Some(match model {
+            Model::None => return None,
+            Model::AvailablePower(x) => Self {
+                power: Some(x.power.get::<watt>()),
+                ..Self::new(name, "AvailablePower", x.time.get::<second>())
+            },
+            Model::Battery(x) => Self {
+                power: Some(x.power.get::<watt>()),
+                energy_in: Some(x.energy_in.get::<watt_hour>()),
+                energy_out: Some(x.energy_out.get::<watt_hour>()),
+                charge: Some(x.charge.get::<watt_hour>()),
+                ..Self::new(name, "Battery", x.time.get::<second>())
+            },
+            Model::BidirMeter(x) => Self {
+                power: Some(x.power.get::<watt>()),
+                energy_in: Some(x.energy_in.get::<watt_hour>()),
+                energy_out: Some(x.energy_out.get::<watt_hour>()),
+                ..Self::new(name, "BidirMeter", x.time.get::<second>())
+            },
+            Model::Generator(x) => Self {
+                power: Some(x.power.get::<watt>()),
+                energy: Some(x.energy.get::<watt_hour>()),
+                ..Self::new(name, "Generator", x.time.get::<second>())
+            },
+            Model::Heatpump(x) => Self {
+                power: Some(x.power.get::<watt>()),
+                energy: Some(x.energy.get::<watt_hour>()),
+                ..Self::new(name, "Heatpump", x.time.get::<second>())
+            },
+            Model::SimpleMeter(x) => Self {
+                power: Some(x.power.get::<watt>()),
+                energy: Some(x.energy.get::<watt_hour>()),
+                ..Self::new(name, "SimpleMeter", x.time.get::<second>())
+            },
+            Model::Weather(x) => {
+                Self::new(name, "Weather", x.time.get::<second>())
+            }
+        })
+    }
+}
+
+#[derive(juniper::GraphQLObject)]
+/// Reads the health of a source or processor.
+pub struct NodeHealth {
+    /// Name of the source or processor.
+    pub name: String,
+    /// State of the task, None if the node has no own task.
+    pub status: Option<TaskStatus>,
+    /// Time of the latest published record.
+    pub last_record: Option<DateTime<Utc>>,
+    /// Time of the latest successful run.
+    pub last_success: Option<DateTime<Utc>>,
+    /// Number of errors since the latest successful run.
+    pub consecutive_failures: i32,
+    /// The latest error message.
+    pub last_error: Option<String>,
+}
+
+impl NodeHealth {
+    pub fn new(name: &str, model: &Model, health: Option<TaskHealth>) -> Self {
+        let last_record = model.time().and_then(|x| {
+            DateTime::from_timestamp(x.get::<second>() as i64, 0)
+        });
+        match health {
+            Some(health) => Self {
+                name: name.into(),
+                status: Some(health.status),
+                last_record,
+                last_success: health.last_success,
+                consecutive_failures: health.consecutive_failures as i32,
+                last_error: health.last_error,
+            },
+            None => Self {
+                name: name.into(),
+                status: None,
+                last_record,
+                last_success: None,
+                consecutive_failures: 0,
+                last_error: None,
+            },
+        }
+    }
+}
@@ -0,0 +1,54 @@
+/******************************************************************************\
+    empowerd - empowers the offline smart home
+    Copyright (C) 2019 - 2026 Max Maisel
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU Affero General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU Affero General Public License for more details.
+
+    You should have received a copy of the GNU Affero General Public License
+    along with this program.  If not, see <https://www.gnu.org/licenses/>.
+\******************************************************************************/
+use crate::{models::units::watt, processors::PeakStatus};
+
+#[derive(juniper::GraphQLObject)]
+/// Reads the demand of a peak shaving processor.
+pub struct PeakShaving {
+    /// References the peak shaving processor.
+    pub id: i32,
+    /// Name of the peak shaving processor.
+    pub name: String,
+    /// Highest interval average of the current month in watt.
+    pub month_peak: f64,
+    /// Average import of the current interval in watt.
+    pub average: f64,
+    /// Predicted average import at the end of the interval in watt.
+    pub predicted: f64,
+    /// Interval averages above this value in watt are shaved.
+    pub threshold: f64,
+    /// Number of currently shed loads.
+    pub shed_loads: i32,
+    /// Requested battery discharge power in watt.
+    pub discharge: f64,
+}
+
+impl PeakShaving {
+    pub fn new(id: i32, name: String, status: PeakStatus) -> Self {
+        Self {
+            id,
+            name,
+            month_peak: status.month_peak.get::<watt>(),
+            average: status.average.get::<watt>(),
+            predicted: status.predicted.get::<watt>(),
+            threshold: status.threshold.get::<watt>(),
+            shed_loads: status.shed_loads as i32,
+            discharge: status.discharge.get::<watt>(),
+        }
+    }
+}
@@ -23,6 +23,9 @@ pub struct PoweroffTimer {
     pub id: i32,
     /// Current on time in seconds.
     pub on_time: i32,
+    /// True if the on time was changed at runtime, false if it is the
+    /// config default.
+    pub on_time_overridden: bool,
     /// ID of the controlled switch.
     pub switch_id: i32,
 }
@@ -32,12 +35,13 @@ impl PoweroffTimer {
         Self {
             id,
             on_time: 0,
+            on_time_overridden: false,
             switch_id,
         }
     }
 }
 
-#[derive(juniper::GraphQLInputObject)]
+#[derive(Debug, juniper::GraphQLInputObject)]
 /// Controls a poweroff timer.
 pub struct InputPoweroffTimer {
     /// References the channel.
@@ -15,17 +15,41 @@
     You should have received a copy of the GNU Affero General Public License
     along with this program.  If not, see <https://www.gnu.org/licenses/>.
 \******************************************************************************/
+use chrono::{DateTime, Utc};
 use slog::{error, trace};
+use std::sync::atomic::Ordering;
 use tokio::sync::oneshot;
 
 use super::{
-    appliance::Appliance, available_power::AvailablePower,
-    load_control::LoadControl, poweroff_timer::PoweroffTimer, switch::Switch,
+    appliance::Appliance,
+    available_power::AvailablePower,
+    config::ConfigVersion,
+    deferrable_load::{DeferrableJob, DeferrableLoad},
+    graph::Graph,
+    history::{
+        self, BatteryRecord, BidirMeterRecord, EnergyDelta, GeneratorRecord,
+        HeatpumpRecord, SimpleMeterRecord, WeatherRecord,
+    },
+    load_control::LoadControl,
+    node::NodeHealth,
+    peak_shaving::PeakShaving,
+    poweroff_timer::PoweroffTimer,
+    rules::{Rules, SwitchRule},
+    switch::Switch,
+    task::Task,
+    user::{ApiToken, AuditEntry, CurrentUser},
 };
 use crate::{
+    models::{
+        Aggregate, ApiToken as TokenModel, AuditEntry as AuditModel, Battery,
+        BidirMeter, ConfigVersion as VersionModel, Generator, Heatpump, Period,
+        SeriesType, SimpleMeter, Weather,
+    },
     processors::{
-        ApplianceCmd, AvailablePowerCmd, LoadControlCmd, PoweroffTimerCmd,
+        ApplianceCmd, AvailablePowerCmd, DeferrableLoadCmd, LoadControlCmd,
+        PeakShavingCmd, PoweroffTimerCmd, RulesCmd,
     },
+    session_manager::Role,
     Context,
 };
 
@@ -40,13 +64,14 @@ impl Query {
         ctx: &Context,
         executor: &juniper::Executor<'_, '_, Context, S>,
     ) -> juniper::FieldResult<Vec<AvailablePower>> {
-        if let Err(e) = ctx.globals.session_manager.verify(&ctx.token) {
+        if let Err(e) = ctx.verify() {
             return Err(e.to_string(&ctx.globals.logger).into());
         }
 
         let lookahead = executor.look_ahead().children();
         let get_power = lookahead.has_child("power");
-        let get_threshold = lookahead.has_child("threshold");
+        let get_threshold = lookahead.has_child("threshold")
+            || lookahead.has_child("thresholdOverridden");
 
         let mut result_vec = Vec::<AvailablePower>::new();
         for (i, processor) in ctx
@@ -61,9 +86,11 @@ impl Query {
             if get_threshold {
                 let (tx, rx) = oneshot::channel();
                 let cmd = AvailablePowerCmd::GetThreshold { resp: tx };
-                result.threshold = processor
+                let threshold = processor
                     .issue_command(&ctx.globals.logger, cmd, rx)
                     .await?;
+                result.threshold = threshold.get();
+                result.threshold_overridden = threshold.is_overridden();
             }
             if get_power {
                 let (tx, rx) = oneshot::channel();
@@ -84,12 +111,13 @@ impl Query {
         ctx: &Context,
         executor: &juniper::Executor<'_, '_, Context, S>,
     ) -> juniper::FieldResult<Vec<Appliance>> {
-        if let Err(e) = ctx.globals.session_manager.verify(&ctx.token) {
+        if let Err(e) = ctx.verify() {
             return Err(e.to_string(&ctx.globals.logger).into());
         }
 
         let lookahead = executor.look_ahead().children();
-        let get_force_on_off = lookahead.has_child("forceOnOff");
+        let get_force_on_off = lookahead.has_child("forceOnOff")
+            || lookahead.has_child("forceOnOffOverridden");
 
         let mut result_vec = Vec::<Appliance>::new();
         for (i, processor) in
@@ -99,9 +127,52 @@ impl Query {
             if get_force_on_off {
                 let (tx, rx) = oneshot::channel();
                 let cmd = ApplianceCmd::GetForceOnOff { resp: tx };
-                result.force_on_off = processor
+                let force_on_off = processor
                     .issue_command(&ctx.globals.logger, cmd, rx)
                     .await?;
+                result.force_on_off = force_on_off.get();
+                result.force_on_off_overridden = force_on_off.is_overridden();
+            }
+
+            result_vec.push(result);
+        }
+
+        Ok(result_vec)
+    }
+
+    /// Get all deferrable load schedulers and their unfinished jobs.
+    async fn deferrable_loads<S: juniper::ScalarValue>(
+        ctx: &Context,
+        executor: &juniper::Executor<'_, '_, Context, S>,
+    ) -> juniper::FieldResult<Vec<DeferrableLoad>> {
+        if let Err(e) = ctx.verify() {
+            return Err(e.to_string(&ctx.globals.logger).into());
+        }
+
+        let lookahead = executor.look_ahead().children();
+        let get_jobs = lookahead.has_child("jobs");
+
+        let mut result_vec = Vec::<DeferrableLoad>::new();
+        for (i, processor) in ctx
+            .globals
+            .processor_cmds
+            .deferrable_load
+            .iter()
+            .enumerate()
+        {
+            let mut result =
+                DeferrableLoad::new(i as i32, processor.name.clone());
+            if get_jobs {
+                let (tx, rx) = oneshot::channel();
+                let cmd = DeferrableLoadCmd::GetJobs { resp: tx };
+                result.jobs = processor
+                    .issue_command(&ctx.globals.logger, cmd, rx)
+                    .await?
+                    .into_iter()
+                    .map(|x| {
+                        DeferrableJob::from_model(x, &ctx.globals.switch_mux)
+                    })
+                    .collect::<Result<Vec<_>, String>>()?;
             }
 
             result_vec.push(result);
@@ -115,12 +186,13 @@ impl Query {
         ctx: &Context,
         executor: &juniper::Executor<'_, '_, Context, S>,
     ) -> juniper::FieldResult<Option<LoadControl>> {
-        if let Err(e) = ctx.globals.session_manager.verify(&ctx.token) {
+        if let Err(e) = ctx.verify() {
             return Err(e.to_string(&ctx.globals.logger).into());
         }
 
         let lookahead = executor.look_ahead().children();
-        let get_charge_mode = lookahead.has_child("chargeMode");
+        let get_charge_mode = lookahead.has_child("chargeMode")
+            || lookahead.has_child("chargeModeOverridden");
 
         let mut result_opt = None;
         if let Some(load_ctrl) = &ctx.globals.processor_cmds.load_control {
@@ -128,9 +200,11 @@ impl Query {
             if get_charge_mode {
                 let (tx, rx) = oneshot::channel();
                 let cmd = LoadControlCmd::GetChargeMode { resp: tx };
-                result.charge_mode = load_ctrl
+                let charge_mode = load_ctrl
                     .issue_command(&ctx.globals.logger, cmd, rx)
                     .await?;
+                result.charge_mode = charge_mode.get();
+                result.charge_mode_overridden = charge_mode.is_overridden();
             }
 
             result_opt = Some(result);
@@ -139,17 +213,45 @@ impl Query {
         Ok(result_opt)
     }
 
+    /// Get the current demand of all peak shaving processors.
+    async fn peak_shavings(
+        ctx: &Context,
+    ) -> juniper::FieldResult<Vec<PeakShaving>> {
+        if let Err(e) = ctx.verify() {
+            return Err(e.to_string(&ctx.globals.logger).into());
+        }
+
+        let mut result_vec = Vec::<PeakShaving>::new();
+        for (i, processor) in
+            ctx.globals.processor_cmds.peak_shaving.iter().enumerate()
+        {
+            let (tx, rx) = oneshot::channel();
+            let cmd = PeakShavingCmd::GetStatus { resp: tx };
+            let status = processor
+                .issue_command(&ctx.globals.logger, cmd, rx)
+                .await?;
+            result_vec.push(PeakShaving::new(
+                i as i32,
+                processor.name.clone(),
+                status,
+            ));
+        }
+
+        Ok(result_vec)
+    }
+
     /// Get all poweroff timers.
     async fn poweroff_timers<S: juniper::ScalarValue>(
         ctx: &Context,
         executor: &juniper::Executor<'_, '_, Context, S>,
     ) -> juniper::FieldResult<Vec<PoweroffTimer>> {
-        if let Err(e) = ctx.globals.session_manager.verify(&ctx.token) {
+        if let Err(e) = ctx.verify() {
             return Err(e.to_string(&ctx.globals.logger).into());
         }
 
         let lookahead = executor.look_ahead().children();
-        let get_on_time = lookahead.has_child("onTime");
+        let get_on_time = lookahead.has_child("onTime")
+            || lookahead.has_child("onTimeOverridden");
 
         let mut result_vec = Vec::<PoweroffTimer>::new();
         for (i, processor) in
@@ -164,12 +266,11 @@ impl Query {
             if get_on_time {
                 let (tx, rx) = oneshot::channel();
                 let cmd = PoweroffTimerCmd::GetOnTime { resp: tx };
-                result.on_time = match processor
+                let on_time = processor
                     .issue_command(&ctx.globals.logger, cmd, rx)
-                    .await?
-                    .as_secs()
-                    .try_into()
-                {
+                    .await?;
+                result.on_time_overridden = on_time.is_overridden();
+                result.on_time = match on_time.get().as_secs().try_into() {
                     Ok(x) => x,
                     Err(e) => return Err(e.into()),
                 };
@@ -181,12 +282,52 @@ impl Query {
         Ok(result_vec)
     }
 
+    /// Get all rules processors and their rules.
+    async fn rules<S: juniper::ScalarValue>(
+        ctx: &Context,
+        executor: &juniper::Executor<'_, '_, Context, S>,
+    ) -> juniper::FieldResult<Vec<Rules>> {
+        if let Err(e) = ctx.verify() {
+            return Err(e.to_string(&ctx.globals.logger).into());
+        }
+
+        let lookahead = executor.look_ahead().children();
+        let get_rules = lookahead.has_child("rules");
+
+        let mut result_vec = Vec::<Rules>::new();
+        for (i, processor) in
+            ctx.globals.processor_cmds.rules.iter().enumerate()
+        {
+            let mut result = Rules::new(i as i32, processor.name.clone());
+            if get_rules {
+                let (tx, rx) = oneshot::channel();
+                let cmd = RulesCmd::GetRules { resp: tx };
+                result.rules = processor
+                    .issue_command(&ctx.globals.logger, cmd, rx)
+                    .await?
+                    .into_iter()
+                    .map(|x| {
+                        SwitchRule::from_model(
+                            x,
+                            &ctx.globals.switch_mux,
+                            &ctx.globals.processor_cmds.appliance,
+                        )
+                    })
+                    .collect::<Result<Vec<_>, String>>()?;
+            }
+
+            result_vec.push(result);
+        }
+
+        Ok(result_vec)
+    }
+
     /// Get the current state of the switches.
     async fn switches<S: juniper::ScalarValue>(
         ctx: &Context,
         executor: &juniper::Executor<'_, '_, Context, S>,
     ) -> juniper::FieldResult<Vec<Switch>> {
-        if let Err(e) = ctx.globals.session_manager.verify(&ctx.token) {
+        if let Err(e) = ctx.verify() {
             return Err(e.to_string(&ctx.globals.logger).into());
         }
 
@@ -237,15 +378,379 @@ impl Query {
         };
     }
 
+    /// Get the supervision state of all source and processor tasks.
+    async fn tasks(ctx: &Context) -> juniper::FieldResult<Vec<Task>> {
+        if let Err(e) = ctx.verify() {
+            return Err(e.to_string(&ctx.globals.logger).into());
+        }
+
+        let mut tasks = Vec::with_capacity(ctx.globals.task_health.len());
+        for (name, health) in &ctx.globals.task_health {
+            let health = health.lock().map_err(|e| e.to_string())?.clone();
+            let errors = ctx
+                .globals
+                .task_errors
+                .get(name)
+                .map_or(0, |x| x.load(Ordering::Relaxed));
+            tasks.push(Task::new(name, health, errors));
+        }
+        Ok(tasks)
+    }
+
+    /// Get the health of all sources and processors.
+    async fn node_health(
+        ctx: &Context,
+    ) -> juniper::FieldResult<Vec<NodeHealth>> {
+        if let Err(e) = ctx.verify() {
+            return Err(e.to_string(&ctx.globals.logger).into());
+        }
+
+        let mut nodes = Vec::with_capacity(ctx.globals.nodes.len());
+        for (name, model) in &ctx.globals.nodes {
+            let health = match ctx.globals.task_health.get(name) {
+                Some(x) => Some(x.lock().map_err(|e| e.to_string())?.clone()),
+                None => None,
+            };
+            nodes.push(NodeHealth::new(name, &model.borrow(), health));
+        }
+        Ok(nodes)
+    }
+
+    /// Get the node graph of sources, processors and sinks with the
+    /// latest records.
+    async fn node_graph(ctx: &Context) -> juniper::FieldResult<Graph> {
+        if let Err(e) = ctx.verify() {
+            return Err(e.to_string(&ctx.globals.logger).into());
+        }
+
+        Ok(Graph::new(&ctx.globals.graph, &ctx.globals.nodes))
+    }
+
     /// Get backend config string for UI.
     pub async fn backend_config<S: juniper::ScalarValue>(
         ctx: &Context,
         _executor: &juniper::Executor<'_, '_, Context, S>,
     ) -> juniper::FieldResult<String> {
-        if let Err(e) = ctx.globals.session_manager.verify(&ctx.token) {
+        if let Err(e) = ctx.verify() {
             return Err(e.to_string(&ctx.globals.logger).into());
         }
 
         Ok(serde_json::to_string(&ctx.globals.uiconfig)?)
     }
+
+    /// Get the downsampled history of a battery series.
+    /// Interval is given in seconds, aggregate defaults to average.
+    async fn battery_history(
+        ctx: &Context,
+        series_id: i32,
+        from: DateTime<Utc>,
+        to: DateTime<Utc>,
+        interval: i32,
+        aggregate: Option<Aggregate>,
+    ) -> juniper::FieldResult<Vec<BatteryRecord>> {
+        let mut conn = history::connect(
+            ctx,
+            series_id,
+            SeriesType::Battery,
+            &from,
+            &to,
+            Some(interval),
+        )
+        .await?;
+
+        let records = Battery::history(
+            &mut conn,
+            series_id,
+            from,
+            to,
+            history::interval(interval),
+            aggregate.unwrap_or(Aggregate::Avg),
+        )
+        .await?;
+        Ok(records.into_iter().map(|x| x.into()).collect())
+    }
+
+    /// Get the downsampled history of a bidirectional meter series.
+    /// Interval is given in seconds, aggregate defaults to average.
+    async fn bidir_meter_history(
+        ctx: &Context,
+        series_id: i32,
+        from: DateTime<Utc>,
+        to: DateTime<Utc>,
+        interval: i32,
+        aggregate: Option<Aggregate>,
+    ) -> juniper::FieldResult<Vec<BidirMeterRecord>> {
+        let mut conn = history::connect(
+            ctx,
+            series_id,
+            SeriesType::BidirMeter,
+            &from,
+            &to,
+            Some(interval),
+        )
+        .await?;
+
+        let records = BidirMeter::history(
+            &mut conn,
+            series_id,
+            from,
+            to,
+            history::interval(interval),
+            aggregate.unwrap_or(Aggregate::Avg),
+        )
+        .await?;
+        Ok(records.into_iter().map(|x| x.into()).collect())
+    }
+
+    /// Get the downsampled history of a generator series.
+    /// Interval is given in seconds, aggregate defaults to average.
+    async fn generator_history(
+        ctx: &Context,
+        series_id: i32,
+        from: DateTime<Utc>,
+        to: DateTime<Utc>,
+        interval: i32,
+        aggregate: Option<Aggregate>,
+    ) -> juniper::FieldResult<Vec<GeneratorRecord>> {
+        let mut conn = history::connect(
+            ctx,
+            series_id,
+            SeriesType::Generator,
+            &from,
+            &to,
+            Some(interval),
+        )
+        .await?;
+
+        let records = Generator::history(
+            &mut conn,
+            series_id,
+            from,
+            to,
+            history::interval(interval),
+            aggregate.unwrap_or(Aggregate::Avg),
+        )
+        .await?;
+        Ok(records.into_iter().map(|x| x.into()).collect())
+    }
+
+    /// Get the downsampled history of a heat pump series.
+    /// Interval is given in seconds, aggregate defaults to average.
+    async fn heatpump_history(
+        ctx: &Context,
+        series_id: i32,
+        from: DateTime<Utc>,
+        to: DateTime<Utc>,
+        interval: i32,
+        aggregate: Option<Aggregate>,
+    ) -> juniper::FieldResult<Vec<HeatpumpRecord>> {
+        let mut conn = history::connect(
+            ctx,
+            series_id,
+            SeriesType::Heatpump,
+            &from,
+            &to,
+            Some(interval),
+        )
+        .await?;
+
+        let records = Heatpump::history(
+            &mut conn,
+            series_id,
+            from,
+            to,
+            history::interval(interval),
+            aggregate.unwrap_or(Aggregate::Avg),
+        )
+        .await?;
+        Ok(records.into_iter().map(|x| x.into()).collect())
+    }
+
+    /// Get the downsampled history of a meter series.
+    /// Interval is given in seconds, aggregate defaults to average.
+    async fn simple_meter_history(
+        ctx: &Context,
+        series_id: i32,
+        from: DateTime<Utc>,
+        to: DateTime<Utc>,
+        interval: i32,
+        aggregate: Option<Aggregate>,
+    ) -> juniper::FieldResult<Vec<SimpleMeterRecord>> {
+        let mut conn = history::connect(
+            ctx,
+            series_id,
+            SeriesType::SimpleMeter,
+            &from,
+            &to,
+            Some(interval),
+        )
+        .await?;
+
+        let records = SimpleMeter::history(
+            &mut conn,
+            series_id,
+            from,
+            to,
+            history::interval(interval),
+            aggregate.unwrap_or(Aggregate::Avg),
+        )
+        .await?;
+        Ok(records.into_iter().map(|x| x.into()).collect())
+    }
+
+    /// Get the downsampled history of a weather station series.
+    /// Interval is given in seconds, aggregate defaults to average.
+    async fn weather_history(
+        ctx: &Context,
+        series_id: i32,
+        from: DateTime<Utc>,
+        to: DateTime<Utc>,
+        interval: i32,
+        aggregate: Option<Aggregate>,
+    ) -> juniper::FieldResult<Vec<WeatherRecord>> {
+        let mut conn = history::connect(
+            ctx,
+            series_id,
+            SeriesType::Weather,
+            &from,
+            &to,
+            Some(interval),
+        )
+        .await?;
+
+        let records = Weather::history(
+            &mut conn,
+            series_id,
+            from,
+            to,
+            history::interval(interval),
+            aggregate.unwrap_or(Aggregate::Avg),
+        )
+        .await?;
+        Ok(records.into_iter().map(|x| x.into()).collect())
+    }
+
+    /// Get the energy counter increase of a series per calendar period.
+    /// Periods are aligned to the given time zone which defaults to UTC.
+    async fn energy_history(
+        ctx: &Context,
+        series_id: i32,
+        from: DateTime<Utc>,
+        to: DateTime<Utc>,
+        period: Period,
+        timezone: Option<String>,
+    ) -> juniper::FieldResult<Vec<EnergyDelta>> {
+        let series_type = match ctx.globals.series.get(&series_id) {
+            Some(x) => *x,
+            None => return Err(format!("Series {series_id} not found").into()),
+        };
+        let mut conn =
+            history::connect(ctx, series_id, series_type, &from, &to, None)
+                .await?;
+
+        let deltas = series_type
+            .energy_deltas(
+                &mut conn,
+                series_id,
+                from,
+                to,
+                period,
+                timezone.as_deref().unwrap_or("UTC"),
+            )
+            .await?;
+        Ok(deltas.into_iter().map(|x| x.into()).collect())
+    }
+
+    /// Get the owner of the current session.
+    async fn current_user(ctx: &Context) -> juniper::FieldResult<CurrentUser> {
+        match ctx.verify() {
+            Ok(x) => Ok(CurrentUser {
+                name: x.user,
+                role: x.role,
+            }),
+            Err(e) => Err(e.to_string(&ctx.globals.logger).into()),
+        }
+    }
+
+    /// Get the newest audit log entries. Requires the admin role.
+    async fn audit_log(
+        ctx: &Context,
+        limit: Option<i32>,
+    ) -> juniper::FieldResult<Vec<AuditEntry>> {
+        if let Err(e) = ctx.authorize(Role::Admin) {
+            return Err(e.to_string(&ctx.globals.logger).into());
+        }
+
+        let mut conn = ctx.globals.database.get().await.map_err(|e| {
+            format!("Getting database connection from pool failed: {e}")
+        })?;
+        let entries = AuditModel::latest(
+            &mut conn,
+            limit.unwrap_or(100).clamp(1, 10000).into(),
+        )
+        .await?;
+        Ok(entries.into_iter().map(|x| x.into()).collect())
+    }
+
+    /// Get the API tokens of the current user. Admins get all tokens.
+    async fn api_tokens(ctx: &Context) -> juniper::FieldResult<Vec<ApiToken>> {
+        let identity = match ctx.verify() {
+            Ok(x) => x,
+            Err(e) => return Err(e.to_string(&ctx.globals.logger).into()),
+        };
+
+        let mut conn = ctx.globals.database.get().await.map_err(|e| {
+            format!("Getting database connection from pool failed: {e}")
+        })?;
+        let tokens = TokenModel::all(&mut conn).await?;
+        Ok(tokens
+            .into_iter()
+            .filter(|x| identity.role == Role::Admin || x.user == identity.user)
+            .map(|x| x.into())
+            .collect())
+    }
+
+    /// Get the main config file with redacted secrets. Requires the admin
+    /// role.
+    async fn config(ctx: &Context) -> juniper::FieldResult<String> {
+        if let Err(e) = ctx.authorize(Role::Admin) {
+            return Err(e.to_string(&ctx.globals.logger).into());
+        }
+
+        Ok(ctx.globals.config_editor.read()?)
+    }
+
+    /// Get the config merged with all include files and resolved secret
+    /// references, with redacted secrets. Requires the admin role.
+    async fn resolved_config(ctx: &Context) -> juniper::FieldResult<String> {
+        if let Err(e) = ctx.authorize(Role::Admin) {
+            return Err(e.to_string(&ctx.globals.logger).into());
+        }
+
+        Ok(ctx.globals.config_editor.read_resolved()?)
+    }
+
+    /// Get the newest stored versions of the main config file.
+    /// Requires the admin role.
+    async fn config_history(
+        ctx: &Context,
+        limit: Option<i32>,
+    ) -> juniper::FieldResult<Vec<ConfigVersion>> {
+        if let Err(e) = ctx.authorize(Role::Admin) {
+            return Err(e.to_string(&ctx.globals.logger).into());
+        }
+
+        let mut conn = ctx.globals.database.get().await.map_err(|e| {
+            format!("Getting database connection from pool failed: {e}")
+        })?;
+        let versions = VersionModel::latest(
+            &mut conn,
+            limit.unwrap_or(20).clamp(1, 1000).into(),
+        )
+        .await?;
+        Ok(versions
+            .into_iter()
+            .map(ConfigVersion::new)
+            .collect::<Result<_, _>>()?)
+    }
 }
@@ -0,0 +1,121 @@
+/******************************************************************************\
+    empowerd - empowers the offline smart home
+    Copyright (C) 2019 - 2026 Max Maisel
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU Affero General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU Affero General Public License for more details.
+
+    You should have received a copy of the GNU Affero General Public License
+    along with this program.  If not, see <https://www.gnu.org/licenses/>.
+\******************************************************************************/
+use crate::{
+    models::SwitchRule as RuleModel,
+    processors::{ApplianceCmd, CommandSender},
+    schedule::{RuleCalendar, RuleTarget},
+    switch_mux::SwitchMux,
+    tri_state::TriState,
+};
+
+#[derive(juniper::GraphQLObject)]
+/// Reads a switch rule.
+pub struct SwitchRule {
+    /// References the rule.
+    pub id: i32,
+    /// Name of the rule.
+    pub name: String,
+    /// Disabled rules are never triggered.
+    pub enabled: bool,
+    /// Cron expression like "30 6 * * 1-5" or sun relative time
+    /// like "sunset-15" in minutes.
+    pub schedule: String,
+    /// Restricts the rule to holidays or non-holidays.
+    pub calendar: RuleCalendar,
+    /// Type of the controlled device.
+    pub target: RuleTarget,
+    /// ID of the controlled switch or appliance.
+    pub target_id: i32,
+    /// New switch state or appliance force mode.
+    pub action: TriState,
+}
+
+impl SwitchRule {
+    pub fn from_model(
+        rule: RuleModel,
+        switch_mux: &SwitchMux,
+        appliances: &[CommandSender<ApplianceCmd>],
+    ) -> Result<Self, String> {
+        let target_id = match rule.target {
+            RuleTarget::Switch => switch_mux.id_by_name(&rule.target_name)?,
+            RuleTarget::Appliance => appliances
+                .iter()
+                .position(|x| x.name == rule.target_name)
+                .ok_or_else(|| {
+                    format!("Appliance '{}' does not exist", rule.target_name)
+                })?,
+        };
+
+        Ok(Self {
+            id: rule.id,
+            name: rule.name,
+            enabled: rule.enabled,
+            schedule: rule.schedule,
+            calendar: rule.calendar,
+            target: rule.target,
+            target_id: target_id as i32,
+            action: rule.action,
+        })
+    }
+}
+
+#[derive(juniper::GraphQLObject)]
+/// Reads a rules processor.
+pub struct Rules {
+    /// References the rules processor.
+    pub id: i32,
+    /// Name of the rules processor.
+    pub name: String,
+    /// Configured rules.
+    pub rules: Vec<SwitchRule>,
+}
+
+impl Rules {
+    pub fn new(id: i32, name: String) -> Self {
+        Self {
+            id,
+            name,
+            rules: Vec::new(),
+        }
+    }
+}
+
+#[derive(Debug, juniper::GraphQLInputObject)]
+/// Creates or updates a switch rule.
+pub struct InputSwitchRule {
+    /// References the rules processor.
+    pub id: i32,
+    /// References the rule. A new rule is created if omitted.
+    pub rule_id: Option<i32>,
+    /// Name of the rule.
+    pub name: String,
+    /// Disabled rules are never triggered. Defaults to enabled.
+    pub enabled: Option<bool>,
+    /// Cron expression like "30 6 * * 1-5" or sun relative time
+    /// like "sunset-15" in minutes.
+    pub schedule: String,
+    /// Restricts the rule to holidays or non-holidays.
+    /// Defaults to always.
+    pub calendar: Option<RuleCalendar>,
+    /// Type of the controlled device.
+    pub target: RuleTarget,
+    /// ID of the controlled switch or appliance.
+    pub target_id: i32,
+    /// New switch state or appliance force mode.
+    pub action: TriState,
+}
@@ -16,38 +16,104 @@
     along with this program.  If not, see <https://www.gnu.org/licenses/>.
 \******************************************************************************/
 
-use super::{mutation::Mutation, query::Query};
-use crate::{Context, Globals};
+use super::{
+    metrics,
+    mutation::Mutation,
+    query::Query,
+    subscription::Subscription,
+    tls::TlsServer,
+    websocket::{self, Schema},
+};
+use crate::{
+    rest::{api, homeassistant, openapi},
+    session_manager::Identity,
+    Context, Globals,
+};
 use hyper::{
-    body::Incoming, server::conn::http1, service::service_fn, Method, Request,
-    Response, StatusCode,
+    body::Incoming, service::service_fn, Method, Request, Response, StatusCode,
+};
+use hyper_util::{
+    rt::{TokioExecutor, TokioIo},
+    server::conn::auto,
+};
+use juniper::RootNode;
+use slog::{debug, error, info, Logger};
+use std::{
+    convert::Infallible,
+    net::{IpAddr, SocketAddr},
+    sync::Arc,
 };
-use hyper_util::rt::TokioIo;
-use juniper::{EmptySubscription, RootNode};
-use slog::{error, Logger};
-use std::{convert::Infallible, sync::Arc};
-use tokio::net::TcpListener;
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    net::TcpListener,
+    signal::unix::{signal, Signal, SignalKind},
+    sync::watch,
+};
+
+/// Returns the address of the client. The "X-Real-IP" header is only
+/// trusted from a reverse proxy on the same host.
+fn peer_address(req: &Request<Incoming>, peer: IpAddr) -> IpAddr {
+    if !peer.is_loopback() {
+        return peer;
+    }
+    req.headers()
+        .get("X-Real-IP")
+        .and_then(|x| x.to_str().ok())
+        .and_then(|x| x.trim().parse().ok())
+        .unwrap_or(peer)
+}
+
+/// Creates the request context from the "Authorization" header.
+fn context(
+    req: &Request<Incoming>,
+    globals: Arc<Globals>,
+    client: Option<Identity>,
+    peer: IpAddr,
+) -> Context {
+    let token = match req.headers().get("Authorization") {
+        Some(x) => match x.to_str() {
+            Ok(y) => y.replace("Bearer ", ""),
+            Err(_) => "".into(),
+        },
+        None => "".into(),
+    };
+    Context {
+        globals,
+        token,
+        client,
+        peer: Some(peer_address(req, peer)),
+    }
+}
 
 async fn handle_connection(
     req: Request<Incoming>,
-    root_node: Arc<
-        RootNode<'static, Query, Mutation, EmptySubscription<Context>>,
-    >,
+    root_node: Arc<Schema>,
     globals: Arc<Globals>,
+    client: Option<Identity>,
+    peer: IpAddr,
 ) -> Result<Response<String>, Infallible> {
     Ok::<_, Infallible>(match (req.method(), req.uri().path()) {
         (&Method::GET, "/") => juniper_hyper::graphiql("/graphql", None).await,
+        (&Method::GET, "/metrics") => metrics::handle(req, &globals),
+        (&Method::GET, "/openapi.json") => {
+            openapi::handle(&root_node, &context(&req, globals, client, peer))
+        }
+        (&Method::GET, "/graphql") if websocket::is_upgrade(&req) => {
+            let peer = peer_address(&req, peer);
+            websocket::upgrade(req, root_node, globals, client, peer)
+        }
         (&Method::GET, "/graphql") | (&Method::POST, "/graphql") => {
-            let token = match req.headers().get("Authorization") {
-                Some(x) => match x.to_str() {
-                    Ok(y) => y.replace("Bearer ", ""),
-                    Err(_) => "".into(),
-                },
-                None => "".into(),
-            };
-            let context = Arc::new(Context { globals, token });
+            let context = Arc::new(context(&req, globals, client, peer));
             juniper_hyper::graphql(root_node, context, req).await
         }
+        (_, path) if path.starts_with("/api/ha/") => {
+            let context = context(&req, globals, client, peer);
+            homeassistant::handle(req, context).await
+        }
+        (_, path) if path.starts_with("/api/v1/") => {
+            let context = context(&req, globals, client, peer);
+            api::handle(req, &root_node, context).await
+        }
         _ => {
             let mut response = Response::new(String::new());
             *response.status_mut() = StatusCode::NOT_FOUND;
@@ -56,41 +122,101 @@ async fn handle_connection(
     })
 }
 
+async fn serve<I>(
+    io: I,
+    root_node: Arc<Schema>,
+    globals: watch::Receiver<Arc<Globals>>,
+    client: Option<Identity>,
+    peer: SocketAddr,
+    logger: Logger,
+) where
+    I: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let conn_result = auto::Builder::new(TokioExecutor::new())
+        .serve_connection_with_upgrades(
+            TokioIo::new(io),
+            service_fn(move |req| {
+                handle_connection(
+                    req,
+                    root_node.clone(),
+                    globals.borrow().clone(),
+                    client.clone(),
+                    peer.ip(),
+                )
+            }),
+        )
+        .await;
+
+    if let Err(e) = conn_result {
+        error!(logger, "Handling connection failed: {e}");
+    };
+}
+
+async fn hangup(signal: &mut Option<Signal>) {
+    match signal {
+        Some(x) => {
+            x.recv().await;
+        }
+        None => std::future::pending().await,
+    }
+}
+
+/// Serves HTTP/1.1 and HTTP/2 connections. The TLS certificates are
+/// reloaded on SIGHUP if TLS is enabled. Each request uses the latest
+/// published globals.
 pub async fn run_graphql(
     listener: TcpListener,
-    globals: Arc<Globals>,
+    globals: watch::Receiver<Arc<Globals>>,
     logger: Logger,
+    mut tls: Option<TlsServer>,
 ) -> Result<(), std::io::Error> {
-    let root_node = Arc::new(RootNode::new(
-        Query {},
-        Mutation {},
-        EmptySubscription::<Context>::new(),
-    ));
+    let root_node =
+        Arc::new(RootNode::new(Query {}, Mutation {}, Subscription {}));
+    let mut sighup = match tls {
+        Some(_) => Some(signal(SignalKind::hangup())?),
+        None => None,
+    };
 
     loop {
-        let (stream, _) = listener.accept().await?;
-        let io = TokioIo::new(stream);
+        let (stream, peer) = tokio::select! {
+            x = listener.accept() => x?,
+            _ = hangup(&mut sighup) => {
+                if let Some(tls) = &mut tls {
+                    match tls.reload() {
+                        Ok(()) => info!(logger, "Reloaded TLS certificates"),
+                        Err(e) => error!(logger, "{e}"),
+                    }
+                }
+                continue;
+            }
+        };
         let root_node = root_node.clone();
         let globals = globals.clone();
         let logger = logger.clone();
 
-        tokio::spawn(async move {
-            let conn_result = http1::Builder::new()
-                .serve_connection(
-                    io,
-                    service_fn(move |req| {
-                        handle_connection(
-                            req,
-                            root_node.clone(),
-                            globals.clone(),
-                        )
-                    }),
-                )
-                .await;
-
-            if let Err(e) = conn_result {
-                error!(logger, "Handling connection failed: {e}");
-            };
-        });
+        match &tls {
+            Some(tls) => {
+                let tls = tls.clone();
+                tokio::spawn(async move {
+                    match tls.acceptor().accept(stream).await {
+                        Ok(stream) => {
+                            let client =
+                                tls.client_identity(stream.get_ref().1);
+                            serve(
+                                stream, root_node, globals, client, peer,
+                                logger,
+                            )
+                            .await
+                        }
+                        Err(e) => debug!(logger, "TLS handshake failed: {e}"),
+                    }
+                });
+            }
+            None => {
+                tokio::spawn(serve(
+                    stream, root_node, globals, None, peer, logger,
+                ));
+            }
+        }
     }
 }
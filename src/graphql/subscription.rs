@@ -0,0 +1,118 @@
+/******************************************************************************\
+    empowerd - empowers the offline smart home
+    Copyright (C) 2019 - 2026 Max Maisel
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU Affero General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU Affero General Public License for more details.
+
+    You should have received a copy of the GNU Affero General Public License
+    along with this program.  If not, see <https://www.gnu.org/licenses/>.
+\******************************************************************************/
+use super::{node::Node, switch::Switch};
+use crate::Context;
+use futures::{
+    future,
+    stream::{self, Stream, StreamExt},
+};
+use juniper::FieldResult;
+use slog::error;
+use std::pin::Pin;
+
+type BoxStream<T> = Pin<Box<dyn Stream<Item = FieldResult<T>> + Send>>;
+
+pub struct Subscription;
+
+#[juniper::graphql_subscription]
+#[graphql(Context = Context)]
+impl Subscription {
+    /// Streams every new record of the given sources and processors.
+    /// All nodes are streamed if no names are given.
+    async fn nodes(
+        ctx: &Context,
+        names: Option<Vec<String>>,
+    ) -> FieldResult<BoxStream<Node>> {
+        if let Err(e) = ctx.verify() {
+            return Err(e.to_string(&ctx.globals.logger).into());
+        }
+
+        if let Some(names) = &names {
+            for name in names {
+                if !ctx.globals.nodes.contains_key(name) {
+                    return Err(format!("Node '{name}' does not exist").into());
+                }
+            }
+        }
+
+        let streams = ctx
+            .globals
+            .nodes
+            .iter()
+            .filter(|(name, _)| match &names {
+                Some(names) => names.contains(name),
+                None => true,
+            })
+            .map(|(name, rx)| {
+                let mut rx = rx.clone();
+                rx.mark_changed();
+                Box::pin(stream::unfold(
+                    (name.clone(), rx),
+                    |(name, mut rx)| async {
+                        rx.changed().await.ok()?;
+                        let node =
+                            Node::from_model(&name, &rx.borrow_and_update());
+                        Some((node, (name, rx)))
+                    },
+                ))
+            });
+
+        Ok(Box::pin(
+            stream::select_all(streams)
+                .filter_map(|x| future::ready(x.map(Ok))),
+        ))
+    }
+
+    /// Streams the state of all switches whenever one of them changes.
+    async fn switches(ctx: &Context) -> FieldResult<BoxStream<Vec<Switch>>> {
+        if let Err(e) = ctx.verify() {
+            return Err(e.to_string(&ctx.globals.logger).into());
+        }
+
+        let switch_mux = ctx.globals.switch_mux.clone();
+        let mut rx = switch_mux.subscribe();
+        // Reading all switches once publishes the initial state.
+        for idx in switch_mux.ids() {
+            if let Err(e) = switch_mux.read_val(idx).await {
+                error!(ctx.globals.logger, "{}", e);
+            }
+        }
+        rx.mark_changed();
+
+        Ok(Box::pin(stream::unfold(
+            (switch_mux, rx),
+            |(switch_mux, mut rx)| async move {
+                rx.changed().await.ok()?;
+                let state = rx.borrow_and_update().clone();
+                let switches = state
+                    .into_iter()
+                    .enumerate()
+                    .filter_map(|(idx, open)| {
+                        Some(Switch {
+                            id: idx as i32,
+                            open: open?,
+                            name: switch_mux.name(idx).ok()?,
+                            icon: switch_mux.icon(idx).ok()?,
+                        })
+                    })
+                    .collect();
+                Some((Ok(switches), (switch_mux, rx)))
+            },
+        )))
+    }
+}
@@ -28,7 +28,7 @@ pub struct Switch {
     pub icon: String,
 }
 
-#[derive(juniper::GraphQLInputObject)]
+#[derive(Debug, juniper::GraphQLInputObject)]
 /// Controls a physical IO channel.
 pub struct InputSwitch {
     /// References the channel.
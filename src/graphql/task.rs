@@ -0,0 +1,49 @@
+/******************************************************************************\
+    empowerd - empowers the offline smart home
+    Copyright (C) 2019 - 2026 Max Maisel
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU Affero General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU Affero General Public License for more details.
+
+    You should have received a copy of the GNU Affero General Public License
+    along with this program.  If not, see <https://www.gnu.org/licenses/>.
+\******************************************************************************/
+use crate::task_group::{TaskHealth, TaskStatus};
+use chrono::{DateTime, Utc};
+
+#[derive(juniper::GraphQLObject)]
+/// Reads the supervision state of a source or processor task.
+pub struct Task {
+    /// Name of the source or processor.
+    pub name: String,
+    /// Current state of the task.
+    pub status: TaskStatus,
+    /// Number of restarts after the task failed.
+    pub restarts: i32,
+    /// Number of temporary errors.
+    pub errors: i32,
+    /// The latest error which stopped the task.
+    pub last_error: Option<String>,
+    /// Time of the latest status change.
+    pub since: DateTime<Utc>,
+}
+
+impl Task {
+    pub fn new(name: &str, health: TaskHealth, errors: u64) -> Self {
+        Self {
+            name: name.into(),
+            status: health.status,
+            restarts: health.restarts as i32,
+            errors: errors as i32,
+            last_error: health.last_error,
+            since: health.since,
+        }
+    }
+}
@@ -0,0 +1,115 @@
+/******************************************************************************\
+    empowerd - empowers the offline smart home
+    Copyright (C) 2019 - 2026 Max Maisel
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU Affero General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU Affero General Public License for more details.
+
+    You should have received a copy of the GNU Affero General Public License
+    along with this program.  If not, see <https://www.gnu.org/licenses/>.
+\******************************************************************************/
+//! Terminates TLS for the GraphQL server.
+use crate::{session_manager::Identity, settings::Tls};
+use std::sync::Arc;
+use tokio_rustls::{
+    rustls::{
+        crypto::ring,
+        pki_types::{pem::PemObject, CertificateDer, PrivateKeyDer},
+        server::{ServerConnection, WebPkiClientVerifier},
+        RootCertStore, ServerConfig,
+    },
+    TlsAcceptor,
+};
+use x509_parser::prelude::{FromDer, X509Certificate};
+
+#[derive(Clone)]
+pub struct TlsServer {
+    settings: Tls,
+    acceptor: TlsAcceptor,
+}
+
+impl TlsServer {
+    pub fn new(settings: Tls) -> Result<Self, String> {
+        let acceptor = TlsAcceptor::from(load_config(&settings)?);
+        Ok(Self { settings, acceptor })
+    }
+
+    /// Reads the certificates and the key again.
+    /// Established connections are not affected.
+    pub fn reload(&mut self) -> Result<(), String> {
+        self.acceptor = TlsAcceptor::from(load_config(&self.settings)?);
+        Ok(())
+    }
+
+    pub fn acceptor(&self) -> TlsAcceptor {
+        self.acceptor.clone()
+    }
+
+    /// Gets the owner of a verified client certificate from its common name.
+    pub fn client_identity(&self, conn: &ServerConnection) -> Option<Identity> {
+        let cert = conn.peer_certificates()?.first()?;
+        let (_, cert) = X509Certificate::from_der(cert).ok()?;
+        let name = cert.subject().iter_common_name().next()?.as_str().ok()?;
+        Some(Identity {
+            user: name.into(),
+            role: self.settings.client_role,
+        })
+    }
+}
+
+fn load_certs(path: &str) -> Result<Vec<CertificateDer<'static>>, String> {
+    let certs = CertificateDer::pem_file_iter(path)
+        .and_then(|x| x.collect::<Result<Vec<_>, _>>())
+        .map_err(|e| format!("Reading certificates from {path} failed: {e}"))?;
+    if certs.is_empty() {
+        return Err(format!("{path} does not contain any certificates"));
+    }
+    Ok(certs)
+}
+
+fn load_config(settings: &Tls) -> Result<Arc<ServerConfig>, String> {
+    let certs = load_certs(&settings.cert_file)?;
+    let key =
+        PrivateKeyDer::from_pem_file(&settings.key_file).map_err(|e| {
+            format!("Reading key from {} failed: {e}", settings.key_file)
+        })?;
+
+    let provider = Arc::new(ring::default_provider());
+    let builder = ServerConfig::builder_with_provider(provider.clone())
+        .with_safe_default_protocol_versions()
+        .map_err(|e| format!("Creating TLS config failed: {e}"))?;
+
+    let builder = match &settings.client_ca_file {
+        Some(path) => {
+            let mut roots = RootCertStore::empty();
+            for cert in load_certs(path)? {
+                roots.add(cert).map_err(|e| {
+                    format!("Adding client CA from {path} failed: {e}")
+                })?;
+            }
+            // Browsers without certificate still log in with a password.
+            let verifier = WebPkiClientVerifier::builder_with_provider(
+                Arc::new(roots),
+                provider,
+            )
+            .allow_unauthenticated()
+            .build()
+            .map_err(|e| format!("Creating client verifier failed: {e}"))?;
+            builder.with_client_cert_verifier(verifier)
+        }
+        None => builder.with_no_client_auth(),
+    };
+
+    let mut config = builder
+        .with_single_cert(certs, key)
+        .map_err(|e| format!("Loading certificate failed: {e}"))?;
+    config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+    Ok(Arc::new(config))
+}
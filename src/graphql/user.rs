@@ -0,0 +1,94 @@
+/******************************************************************************\
+    empowerd - empowers the offline smart home
+    Copyright (C) 2019 - 2026 Max Maisel
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU Affero General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU Affero General Public License for more details.
+
+    You should have received a copy of the GNU Affero General Public License
+    along with this program.  If not, see <https://www.gnu.org/licenses/>.
+\******************************************************************************/
+use crate::{
+    models::{ApiToken as TokenModel, AuditEntry as AuditModel},
+    session_manager::Role,
+};
+use chrono::{DateTime, Utc};
+
+#[derive(juniper::GraphQLObject)]
+/// Reads the owner of the current session.
+pub struct CurrentUser {
+    /// Name of the user.
+    pub name: String,
+    /// Permission level of the user.
+    pub role: Role,
+}
+
+#[derive(juniper::GraphQLObject)]
+/// Reads an audit log entry.
+pub struct AuditEntry {
+    /// References the entry.
+    pub id: i32,
+    /// Time when the mutation was issued.
+    pub time: DateTime<Utc>,
+    /// User who issued the mutation.
+    pub user: String,
+    /// Name of the mutation.
+    pub mutation: String,
+    /// Input of the mutation.
+    pub input: String,
+}
+
+impl From<AuditModel> for AuditEntry {
+    fn from(entry: AuditModel) -> Self {
+        Self {
+            id: entry.id,
+            time: entry.time,
+            user: entry.user,
+            mutation: entry.mutation,
+            input: entry.input,
+        }
+    }
+}
+
+#[derive(juniper::GraphQLObject)]
+/// Reads an API token. The token itself is not stored.
+pub struct ApiToken {
+    /// References the token.
+    pub id: i32,
+    /// Descriptive name of the token.
+    pub name: String,
+    /// Owner of the token.
+    pub user: String,
+    /// Permission level of the token.
+    pub role: Role,
+    /// Time when the token was created.
+    pub created: DateTime<Utc>,
+}
+
+impl From<TokenModel> for ApiToken {
+    fn from(token: TokenModel) -> Self {
+        Self {
+            id: token.id,
+            name: token.name,
+            user: token.user,
+            role: token.role,
+            created: token.created,
+        }
+    }
+}
+
+#[derive(juniper::GraphQLObject)]
+/// Reads a newly created API token.
+pub struct NewApiToken {
+    /// Secret token for the Authorization header. It is shown only once.
+    pub token: String,
+    /// Information about the token.
+    pub info: ApiToken,
+}
@@ -0,0 +1,451 @@
+/******************************************************************************\
+    empowerd - empowers the offline smart home
+    Copyright (C) 2019 - 2026 Max Maisel
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU Affero General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU Affero General Public License for more details.
+
+    You should have received a copy of the GNU Affero General Public License
+    along with this program.  If not, see <https://www.gnu.org/licenses/>.
+\******************************************************************************/
+//! Serves GraphQL operations over WebSocket with the graphql-transport-ws
+//! protocol.
+use super::{mutation::Mutation, query::Query, subscription::Subscription};
+use crate::{session_manager::Identity, Context, Globals};
+use futures::{SinkExt, StreamExt};
+use hyper::{
+    body::Incoming,
+    header::{
+        CONNECTION, SEC_WEBSOCKET_ACCEPT, SEC_WEBSOCKET_KEY,
+        SEC_WEBSOCKET_PROTOCOL, UPGRADE,
+    },
+    Request, Response, StatusCode,
+};
+use hyper_util::rt::TokioIo;
+use juniper::{
+    http::GraphQLRequest, DefaultScalarValue, GraphQLError, RootNode, Value,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use slog::{debug, error, Logger};
+use std::{collections::BTreeMap, net::IpAddr, sync::Arc, time::Duration};
+use tokio::{sync::mpsc, task::JoinHandle};
+use tokio_tungstenite::{
+    tungstenite::{
+        handshake::derive_accept_key,
+        protocol::{frame::coding::CloseCode, CloseFrame, Role},
+        Message,
+    },
+    WebSocketStream,
+};
+
+pub type Schema = RootNode<'static, Query, Mutation, Subscription>;
+
+const PROTOCOL: &str = "graphql-transport-ws";
+const INIT_TIMEOUT: Duration = Duration::from_secs(10);
+const SESSION_CHECK_INTERVAL: Duration = Duration::from_secs(10);
+
+#[derive(Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum ClientMessage {
+    ConnectionInit {
+        payload: Option<InitPayload>,
+    },
+    Ping,
+    Pong,
+    Subscribe {
+        id: String,
+        payload: GraphQLRequest<DefaultScalarValue>,
+    },
+    Complete {
+        id: String,
+    },
+}
+
+#[derive(Deserialize)]
+struct InitPayload {
+    token: Option<String>,
+    #[serde(rename = "Authorization")]
+    authorization: Option<String>,
+}
+
+impl InitPayload {
+    fn token(self) -> Option<String> {
+        self.token
+            .or(self.authorization.map(|x| x.replace("Bearer ", "")))
+    }
+}
+
+#[derive(Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum ServerMessage {
+    ConnectionAck,
+    Pong,
+    Next {
+        id: String,
+        payload: serde_json::Value,
+    },
+    Error {
+        id: String,
+        payload: serde_json::Value,
+    },
+    Complete {
+        id: String,
+    },
+}
+
+fn close_frame(code: u16, reason: &str) -> CloseFrame {
+    CloseFrame {
+        code: CloseCode::from(code),
+        reason: reason.into(),
+    }
+}
+
+/// Checks if the client requests a WebSocket connection.
+pub fn is_upgrade(req: &Request<Incoming>) -> bool {
+    match req.headers().get(UPGRADE) {
+        Some(x) => x.as_bytes().eq_ignore_ascii_case(b"websocket"),
+        None => false,
+    }
+}
+
+/// Accepts the WebSocket handshake and serves the connection after the
+/// upgrade was completed.
+pub fn upgrade(
+    mut req: Request<Incoming>,
+    root_node: Arc<Schema>,
+    globals: Arc<Globals>,
+    client: Option<Identity>,
+    peer: IpAddr,
+) -> Response<String> {
+    let key = req.headers().get(SEC_WEBSOCKET_KEY).cloned();
+    let protocol = req
+        .headers()
+        .get(SEC_WEBSOCKET_PROTOCOL)
+        .and_then(|x| x.to_str().ok())
+        .map(|x| x.split(',').any(|y| y.trim() == PROTOCOL))
+        .unwrap_or(false);
+
+    let key = match key {
+        Some(x) if protocol => x,
+        _ => {
+            let mut response = Response::new(String::new());
+            *response.status_mut() = StatusCode::BAD_REQUEST;
+            return response;
+        }
+    };
+
+    let on_upgrade = hyper::upgrade::on(&mut req);
+    tokio::spawn(async move {
+        match on_upgrade.await {
+            Ok(upgraded) => {
+                let ws = WebSocketStream::from_raw_socket(
+                    TokioIo::new(upgraded),
+                    Role::Server,
+                    None,
+                )
+                .await;
+                Connection::new(root_node, globals, client, peer)
+                    .run(ws)
+                    .await;
+            }
+            Err(e) => {
+                error!(globals.logger, "Upgrading connection failed: {e}")
+            }
+        }
+    });
+
+    let mut response = Response::new(String::new());
+    *response.status_mut() = StatusCode::SWITCHING_PROTOCOLS;
+    let headers = response.headers_mut();
+    headers.insert(CONNECTION, "Upgrade".parse().unwrap());
+    headers.insert(UPGRADE, "websocket".parse().unwrap());
+    headers.insert(
+        SEC_WEBSOCKET_ACCEPT,
+        derive_accept_key(key.as_bytes()).parse().unwrap(),
+    );
+    headers.insert(SEC_WEBSOCKET_PROTOCOL, PROTOCOL.parse().unwrap());
+    response
+}
+
+struct Connection {
+    root_node: Arc<Schema>,
+    globals: Arc<Globals>,
+    client: Option<Identity>,
+    peer: IpAddr,
+    context: Option<Arc<Context>>,
+    operations: BTreeMap<String, JoinHandle<()>>,
+    tx: mpsc::Sender<ServerMessage>,
+    rx: mpsc::Receiver<ServerMessage>,
+    logger: Logger,
+}
+
+impl Connection {
+    fn new(
+        root_node: Arc<Schema>,
+        globals: Arc<Globals>,
+        client: Option<Identity>,
+        peer: IpAddr,
+    ) -> Self {
+        let (tx, rx) = mpsc::channel(16);
+        let logger = globals.logger.clone();
+        Self {
+            root_node,
+            globals,
+            client,
+            peer,
+            context: None,
+            operations: BTreeMap::new(),
+            tx,
+            rx,
+            logger,
+        }
+    }
+
+    async fn run<T>(mut self, ws: WebSocketStream<T>)
+    where
+        T: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin,
+    {
+        let (mut sink, mut stream) = ws.split();
+        let init_timeout = tokio::time::sleep(INIT_TIMEOUT);
+        tokio::pin!(init_timeout);
+        let mut session_check = tokio::time::interval(SESSION_CHECK_INTERVAL);
+
+        let close = loop {
+            tokio::select! {
+                msg = stream.next() => match msg {
+                    Some(Ok(Message::Text(text))) => {
+                        if let Err(e) = self.handle_message(text.as_str()) {
+                            break Some(e);
+                        }
+                    }
+                    Some(Ok(Message::Binary(_))) => {
+                        break Some(close_frame(4400, "Binary message"));
+                    }
+                    Some(Ok(Message::Close(_))) | None => break None,
+                    Some(Ok(_)) => (),
+                    Some(Err(e)) => {
+                        debug!(self.logger, "Reading websocket failed: {e}");
+                        break None;
+                    }
+                },
+                Some(msg) = self.rx.recv() => {
+                    if let Err(e) = self.check_session() {
+                        break Some(e);
+                    }
+                    let msg = match serde_json::to_string(&msg) {
+                        Ok(x) => x,
+                        Err(e) => {
+                            error!(self.logger, "Serializing failed: {e}");
+                            continue;
+                        }
+                    };
+                    if let Err(e) = sink.send(Message::text(msg)).await {
+                        debug!(self.logger, "Writing websocket failed: {e}");
+                        break None;
+                    }
+                }
+                _ = session_check.tick() => {
+                    if let Err(e) = self.check_session() {
+                        break Some(e);
+                    }
+                }
+                _ = &mut init_timeout, if self.context.is_none() => {
+                    break Some(close_frame(
+                        4408,
+                        "Connection initialisation timeout",
+                    ));
+                }
+            }
+        };
+
+        for operation in self.operations.values() {
+            operation.abort();
+        }
+        if let Some(frame) = close {
+            if let Err(e) = sink.send(Message::Close(Some(frame))).await {
+                debug!(self.logger, "Closing websocket failed: {e}");
+            }
+        }
+    }
+
+    fn handle_message(&mut self, text: &str) -> Result<(), CloseFrame> {
+        let msg = serde_json::from_str::<ClientMessage>(text)
+            .map_err(|e| close_frame(4400, &e.to_string()))?;
+
+        match msg {
+            ClientMessage::ConnectionInit { payload } => {
+                if self.context.is_some() {
+                    return Err(close_frame(
+                        4429,
+                        "Too many initialisation requests",
+                    ));
+                }
+                let context = Context {
+                    globals: self.globals.clone(),
+                    token: payload.and_then(|x| x.token()).unwrap_or_default(),
+                    client: self.client.clone(),
+                    peer: Some(self.peer),
+                };
+                if let Err(e) = context.verify() {
+                    e.to_string(&self.logger);
+                    return Err(close_frame(4403, "Forbidden"));
+                }
+                self.context = Some(Arc::new(context));
+                self.reply(ServerMessage::ConnectionAck);
+            }
+            ClientMessage::Ping => self.reply(ServerMessage::Pong),
+            ClientMessage::Pong => (),
+            ClientMessage::Subscribe { id, payload } => {
+                let context = match &self.context {
+                    Some(x) => x.clone(),
+                    None => return Err(close_frame(4401, "Unauthorized")),
+                };
+                self.operations.retain(|_, x| !x.is_finished());
+                if self.operations.contains_key(&id) {
+                    return Err(close_frame(
+                        4409,
+                        &format!("Subscriber for {id} already exists"),
+                    ));
+                }
+                let operation = tokio::spawn(execute(
+                    id.clone(),
+                    payload,
+                    self.root_node.clone(),
+                    context,
+                    self.tx.clone(),
+                ));
+                self.operations.insert(id, operation);
+            }
+            ClientMessage::Complete { id } => {
+                if let Some(operation) = self.operations.remove(&id) {
+                    operation.abort();
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Ends the connection once the session has expired or was revoked.
+    fn check_session(&self) -> Result<(), CloseFrame> {
+        match &self.context {
+            Some(context) => match context.check() {
+                Ok(_) => Ok(()),
+                Err(e) => {
+                    e.to_string(&self.logger);
+                    Err(close_frame(4401, "Unauthorized"))
+                }
+            },
+            None => Ok(()),
+        }
+    }
+
+    fn reply(&self, msg: ServerMessage) {
+        if self.tx.try_send(msg).is_err() {
+            error!(self.logger, "Websocket reply queue is full");
+        }
+    }
+}
+
+async fn execute(
+    id: String,
+    request: GraphQLRequest<DefaultScalarValue>,
+    root_node: Arc<Schema>,
+    context: Arc<Context>,
+    tx: mpsc::Sender<ServerMessage>,
+) {
+    let result =
+        juniper::http::resolve_into_stream(&request, &root_node, &context)
+            .await;
+
+    let msg = match result {
+        Ok((Value::Object(obj), errors)) if errors.is_empty() => {
+            for (name, value) in obj.into_iter() {
+                let mut stream = match value {
+                    Value::Scalar(x) => x,
+                    _ => continue,
+                };
+                while let Some(item) = stream.next().await {
+                    let payload = match item {
+                        Ok(x) => json!({ "data": { &name: x } }),
+                        Err(e) => json!({ "data": null, "errors": [e] }),
+                    };
+                    let msg = ServerMessage::Next {
+                        id: id.clone(),
+                        payload,
+                    };
+                    if tx.send(msg).await.is_err() {
+                        return;
+                    }
+                }
+            }
+            ServerMessage::Complete { id }
+        }
+        Ok((_, errors)) => ServerMessage::Error {
+            id,
+            payload: json!(errors),
+        },
+        Err(GraphQLError::NotSubscription) => {
+            // Queries and mutations are answered with a single result.
+            let response = request.execute(&root_node, &context).await;
+            let msg = ServerMessage::Next {
+                id: id.clone(),
+                payload: json!(response),
+            };
+            if tx.send(msg).await.is_err() {
+                return;
+            }
+            ServerMessage::Complete { id }
+        }
+        Err(e) => ServerMessage::Error {
+            id,
+            payload: json!([{ "message": e.to_string() }]),
+        },
+    };
+
+    let _ = tx.send(msg).await;
+}
+
+#[test]
+fn test_client_message() {
+    let msg = r#"{"type":"connection_init","payload":{"token":"abc"}}"#;
+    match serde_json::from_str::<ClientMessage>(msg).unwrap() {
+        ClientMessage::ConnectionInit { payload } => {
+            assert_eq!(Some("abc".into()), payload.unwrap().token())
+        }
+        _ => panic!("Unexpected message type"),
+    }
+
+    let msg = r#"{"type":"connection_init",
+        "payload":{"Authorization":"Bearer xyz"}}"#;
+    match serde_json::from_str::<ClientMessage>(msg).unwrap() {
+        ClientMessage::ConnectionInit { payload } => {
+            assert_eq!(Some("xyz".into()), payload.unwrap().token())
+        }
+        _ => panic!("Unexpected message type"),
+    }
+
+    let msg = r#"{"id":"1","type":"subscribe",
+        "payload":{"query":"subscription { switches { id } }"}}"#;
+    match serde_json::from_str::<ClientMessage>(msg).unwrap() {
+        ClientMessage::Subscribe { id, payload } => {
+            assert_eq!("1", id);
+            assert_eq!("subscription { switches { id } }", payload.query);
+        }
+        _ => panic!("Unexpected message type"),
+    }
+
+    assert_eq!(
+        r#"{"type":"complete","id":"1"}"#,
+        serde_json::to_string(&ServerMessage::Complete { id: "1".into() })
+            .unwrap()
+    );
+}
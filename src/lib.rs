@@ -19,13 +19,24 @@
 #![allow(clippy::needless_return)]
 #![allow(clippy::redundant_field_names)]
 
+pub mod clock;
+pub mod config_editor;
+pub mod config_file;
 pub mod error;
+pub mod graph;
 pub mod graphql;
+pub mod login_guard;
 pub mod misc;
 pub mod models;
 pub mod multi_setpoint_hysteresis;
+pub mod notifications;
+pub mod pid;
 pub mod processors;
 pub mod pt1;
+pub mod reload;
+pub mod replay;
+pub mod rest;
+pub mod schedule;
 pub mod seasonal;
 pub mod session_manager;
 pub mod settings;
@@ -36,29 +47,101 @@ pub mod task_group;
 pub mod tri_state;
 pub mod uiconfig;
 
+use config_editor::ConfigEditor;
+use diesel_async::{pooled_connection::deadpool::Pool, AsyncPgConnection};
 use error::Error;
+use graph::NodeGraph;
+use login_guard::LoginGuard;
+use models::{AuditEntry, Model, SeriesType};
 use processors::ProcessorCommands;
-use session_manager::SessionManager;
-use slog::Logger;
-use std::sync::Arc;
+use session_manager::{AuthError, Identity, Role, SessionManager};
+use slog::{error, Logger};
+use std::{collections::BTreeMap, fmt::Debug, net::IpAddr, sync::Arc};
 use switch_mux::{SwitchGroup, SwitchMux};
+use task_group::{ErrorCounter, HealthRef};
+use tokio::sync::watch;
 use uiconfig::UiConfig;
 
-#[derive(Debug)]
 pub struct Globals {
     pub logger: Logger,
     pub username: String,
     pub hashed_pw: String,
-    pub session_manager: SessionManager,
+    pub session_manager: Arc<SessionManager>,
+    pub login_guard: Arc<LoginGuard>,
     pub switch_mux: Arc<SwitchMux>,
     pub processor_cmds: ProcessorCommands,
+    pub nodes: BTreeMap<String, watch::Receiver<Model>>,
+    pub database: Pool<AsyncPgConnection>,
+    pub series: BTreeMap<i32, SeriesType>,
+    pub task_errors: BTreeMap<String, ErrorCounter>,
+    pub task_health: BTreeMap<String, HealthRef>,
+    pub metrics_token: Option<String>,
+    pub graph: NodeGraph,
+    pub config_editor: Arc<ConfigEditor>,
     pub uiconfig: UiConfig,
 }
 
-#[derive(Debug)]
 pub struct Context {
     pub globals: Arc<Globals>,
     pub token: String,
+    /// Owner of a verified TLS client certificate.
+    pub client: Option<Identity>,
+    /// Address of the client, used to throttle failed logins.
+    pub peer: Option<IpAddr>,
+}
+
+impl Context {
+    /// Verifies the session token. Requests without a token are accepted
+    /// if the client presented a valid certificate.
+    pub fn verify(&self) -> Result<Identity, AuthError> {
+        match &self.client {
+            Some(x) if self.token.is_empty() => Ok(x.clone()),
+            _ => self.globals.session_manager.verify(&self.token),
+        }
+    }
+
+    /// Checks that the session is still valid without extending it.
+    pub fn check(&self) -> Result<Identity, AuthError> {
+        match &self.client {
+            Some(x) if self.token.is_empty() => Ok(x.clone()),
+            _ => self.globals.session_manager.check(&self.token),
+        }
+    }
+
+    /// Verifies the session and checks that its owner has at least the
+    /// given role.
+    pub fn authorize(&self, role: Role) -> Result<Identity, AuthError> {
+        let identity = self.verify()?;
+        identity.require(role)?;
+        Ok(identity)
+    }
+
+    /// Checks the role of the session owner and records the mutation in
+    /// the audit log.
+    pub async fn authorize_mutation(
+        &self,
+        role: Role,
+        mutation: &str,
+        input: &impl Debug,
+    ) -> Result<Identity, String> {
+        let identity = self
+            .authorize(role)
+            .map_err(|e| e.to_string(&self.globals.logger))?;
+
+        let input = format!("{input:?}");
+        let result = match self.globals.database.get().await {
+            Ok(mut conn) => {
+                AuditEntry::insert(&mut conn, &identity.user, mutation, &input)
+                    .await
+            }
+            Err(e) => Err(Error::Temporary(e.to_string())),
+        };
+        if let Err(e) = result {
+            error!(self.globals.logger, "Writing audit log failed: {e}");
+        }
+
+        Ok(identity)
+    }
 }
 
 impl juniper::Context for Context {}
@@ -0,0 +1,277 @@
+/******************************************************************************\
+    empowerd - empowers the offline smart home
+    Copyright (C) 2019 - 2026 Max Maisel
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU Affero General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU Affero General Public License for more details.
+
+    You should have received a copy of the GNU Affero General Public License
+    along with this program.  If not, see <https://www.gnu.org/licenses/>.
+\******************************************************************************/
+//! Protects the login against brute-force attacks. Failed attempts are
+//! counted per client address and per username. After a few free attempts,
+//! further logins are refused for an exponentially growing lockout time.
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+    time::{Duration, Instant},
+};
+use tokio::sync::Semaphore;
+
+/// Failed attempts before the first lockout.
+const FREE_ATTEMPTS: u32 = 3;
+const BASE_LOCKOUT: Duration = Duration::from_secs(1);
+const MAX_LOCKOUT: Duration = Duration::from_secs(900);
+/// Failures are forgotten if there was no further attempt for this time.
+const FORGET_AFTER: Duration = Duration::from_secs(3600);
+/// Maximum number of tracked users and addresses. The oldest entry is
+/// evicted when a new one would exceed it.
+const MAX_ENTRIES: usize = 10000;
+/// Maximum time a login waits for a free password verification slot.
+const VERIFY_TIMEOUT: Duration = Duration::from_secs(5);
+
+struct Failures {
+    count: u32,
+    last: Instant,
+    locked_until: Instant,
+}
+
+pub struct LoginGuard {
+    failures: Mutex<HashMap<String, Failures>>,
+    max_entries: usize,
+    verifications: Semaphore,
+    failed_total: AtomicU64,
+    lockouts_total: AtomicU64,
+}
+
+fn keys(peer: Option<IpAddr>, user: &str) -> Vec<String> {
+    let mut keys = vec![format!("user:{user}")];
+    if let Some(peer) = peer {
+        keys.push(format!("ip:{peer}"));
+    }
+    keys
+}
+
+fn lockout(count: u32) -> Duration {
+    match count.checked_sub(FREE_ATTEMPTS + 1) {
+        Some(x) => BASE_LOCKOUT
+            .checked_mul(2u32.saturating_pow(x))
+            .unwrap_or(MAX_LOCKOUT)
+            .min(MAX_LOCKOUT),
+        None => Duration::ZERO,
+    }
+}
+
+impl LoginGuard {
+    /// Creates a guard which allows at most `max_verifications` concurrent
+    /// password hash verifications.
+    pub fn new(max_verifications: usize) -> Self {
+        Self {
+            failures: Mutex::new(HashMap::new()),
+            max_entries: MAX_ENTRIES,
+            verifications: Semaphore::new(max_verifications.max(1)),
+            failed_total: AtomicU64::new(0),
+            lockouts_total: AtomicU64::new(0),
+        }
+    }
+
+    fn check_at(
+        &self,
+        peer: Option<IpAddr>,
+        user: &str,
+        now: Instant,
+    ) -> Result<(), Duration> {
+        // Logins are refused if the failures are not accessible.
+        let failures = match self.failures.lock() {
+            Ok(x) => x,
+            Err(_) => return Err(MAX_LOCKOUT),
+        };
+        let remaining = keys(peer, user)
+            .iter()
+            .filter_map(|x| failures.get(x))
+            .map(|x| x.locked_until.saturating_duration_since(now))
+            .max()
+            .unwrap_or_default();
+        match remaining.is_zero() {
+            true => Ok(()),
+            false => Err(remaining),
+        }
+    }
+
+    /// Checks if logins for this user or from this address are locked.
+    /// Returns the remaining lockout time if they are.
+    pub fn check(
+        &self,
+        peer: Option<IpAddr>,
+        user: &str,
+    ) -> Result<(), Duration> {
+        self.check_at(peer, user, Instant::now())
+    }
+
+    fn failed_at(
+        &self,
+        peer: Option<IpAddr>,
+        user: &str,
+        now: Instant,
+    ) -> Duration {
+        self.failed_total.fetch_add(1, Ordering::Relaxed);
+        let mut failures = match self.failures.lock() {
+            Ok(x) => x,
+            Err(_) => return MAX_LOCKOUT,
+        };
+        failures.retain(|_, x| now.duration_since(x.last) < FORGET_AFTER);
+
+        let mut max_lockout = Duration::ZERO;
+        for key in keys(peer, user) {
+            if !failures.contains_key(&key)
+                && failures.len() >= self.max_entries
+            {
+                let oldest = failures
+                    .iter()
+                    .min_by_key(|(_, x)| x.last)
+                    .map(|(key, _)| key.clone());
+                if let Some(oldest) = oldest {
+                    failures.remove(&oldest);
+                }
+            }
+            let entry = failures.entry(key).or_insert(Failures {
+                count: 0,
+                last: now,
+                locked_until: now,
+            });
+            entry.count = entry.count.saturating_add(1);
+            entry.last = now;
+            let lockout = lockout(entry.count);
+            if !lockout.is_zero() {
+                entry.locked_until = now + lockout;
+            }
+            max_lockout = max_lockout.max(lockout);
+        }
+        if !max_lockout.is_zero() {
+            self.lockouts_total.fetch_add(1, Ordering::Relaxed);
+        }
+        max_lockout
+    }
+
+    /// Records a failed login and returns the resulting lockout time.
+    pub fn failed(&self, peer: Option<IpAddr>, user: &str) -> Duration {
+        self.failed_at(peer, user, Instant::now())
+    }
+
+    /// Forgets the failed attempts after a successful login.
+    pub fn succeeded(&self, peer: Option<IpAddr>, user: &str) {
+        if let Ok(mut failures) = self.failures.lock() {
+            for key in keys(peer, user) {
+                failures.remove(&key);
+            }
+        }
+    }
+
+    /// Runs a password verification on the blocking thread pool. At most
+    /// `max_verifications` are running at the same time, so the login can
+    /// not starve the processors of CPU time.
+    pub async fn verify<F, R>(&self, verify: F) -> Result<R, String>
+    where
+        F: FnOnce() -> R + Send + 'static,
+        R: Send + 'static,
+    {
+        let _permit = match tokio::time::timeout(
+            VERIFY_TIMEOUT,
+            self.verifications.acquire(),
+        )
+        .await
+        {
+            Ok(Ok(x)) => x,
+            Ok(Err(e)) => return Err(e.to_string()),
+            Err(_) => return Err("Too many concurrent logins".into()),
+        };
+        tokio::task::spawn_blocking(verify)
+            .await
+            .map_err(|e| format!("Verifying password failed: {e}"))
+    }
+
+    pub fn failed_total(&self) -> u64 {
+        self.failed_total.load(Ordering::Relaxed)
+    }
+
+    pub fn lockouts_total(&self) -> u64 {
+        self.lockouts_total.load(Ordering::Relaxed)
+    }
+}
+
+#[test]
+fn test_login_backoff() {
+    let guard = LoginGuard::new(1);
+    let peer = Some("192.168.1.2".parse().unwrap());
+    let other = Some("192.168.1.3".parse().unwrap());
+    let now = Instant::now();
+
+    for _ in 0..FREE_ATTEMPTS {
+        assert_eq!(Duration::ZERO, guard.failed_at(peer, "alice", now));
+    }
+    assert_eq!(Ok(()), guard.check_at(peer, "alice", now));
+    assert_eq!(BASE_LOCKOUT, guard.failed_at(peer, "alice", now));
+    assert_eq!(Err(BASE_LOCKOUT), guard.check_at(peer, "alice", now));
+    // Both the user and the address are locked.
+    assert!(guard.check_at(other, "alice", now).is_err());
+    assert!(guard.check_at(peer, "bob", now).is_err());
+    assert_eq!(Ok(()), guard.check_at(other, "bob", now));
+
+    let now = now + BASE_LOCKOUT;
+    assert_eq!(Ok(()), guard.check_at(peer, "alice", now));
+    assert_eq!(2 * BASE_LOCKOUT, guard.failed_at(peer, "alice", now));
+    assert_eq!(5, guard.failed_total());
+    assert_eq!(2, guard.lockouts_total());
+
+    guard.succeeded(peer, "alice");
+    assert_eq!(Ok(()), guard.check_at(peer, "alice", now));
+    assert_eq!(MAX_LOCKOUT, lockout(100));
+}
+
+#[test]
+fn test_login_max_entries() {
+    let mut guard = LoginGuard::new(1);
+    guard.max_entries = 3;
+    let peer = Some("192.168.1.2".parse().unwrap());
+    let now = Instant::now();
+    let later = now + Duration::from_millis(500);
+
+    for _ in 0..=FREE_ATTEMPTS {
+        guard.failed_at(None, "alice", now);
+        guard.failed_at(peer, "bob", later);
+    }
+    assert!(guard.check_at(None, "alice", now).is_err());
+    guard.failed_at(None, "carol", later);
+    assert_eq!(3, guard.failures.lock().unwrap().len());
+    // The oldest entry is evicted first.
+    assert_eq!(Ok(()), guard.check_at(None, "alice", now));
+    assert!(guard.check_at(peer, "dave", later).is_err());
+}
+
+#[test]
+fn test_login_poisoned() {
+    let guard = LoginGuard::new(1);
+    let result = std::thread::scope(|s| {
+        s.spawn(|| {
+            let _failures = guard.failures.lock().unwrap();
+            panic!("Poisoning the failures");
+        })
+        .join()
+    });
+    assert!(result.is_err());
+
+    assert_eq!(Err(MAX_LOCKOUT), guard.check(None, "alice"));
+    assert_eq!(MAX_LOCKOUT, guard.failed(None, "alice"));
+    guard.succeeded(None, "alice");
+}
@@ -40,8 +40,11 @@ pub mod units {
 
 pub use available_power::AvailablePower;
 pub use postgres::{
-    run_migrations, Battery, BidirMeter, Generator, Heatpump, SimpleMeter,
-    Weather,
+    database_pool, database_url,
+    history::{Aggregate, EnergyDelta, Period, SeriesType},
+    run_migrations, ApiToken, AuditEntry, Battery, BidirMeter, ConfigVersion,
+    ControlState, DeferrableJob, Generator, Heatpump, SimpleMeter, SwitchRule,
+    User, Weather,
 };
 
 #[derive(Clone, Debug)]
@@ -56,6 +59,22 @@ pub enum Model {
     Weather(Weather),
 }
 
+impl Model {
+    /// Returns the timestamp of the record.
+    pub fn time(&self) -> Option<units::Time> {
+        match self {
+            Model::None => None,
+            Model::AvailablePower(x) => Some(x.time),
+            Model::Battery(x) => Some(x.time),
+            Model::BidirMeter(x) => Some(x.time),
+            Model::Generator(x) => Some(x.time),
+            Model::Heatpump(x) => Some(x.time),
+            Model::SimpleMeter(x) => Some(x.time),
+            Model::Weather(x) => Some(x.time),
+        }
+    }
+}
+
 // Conversions to Model
 
 impl From<AvailablePower> for Model {
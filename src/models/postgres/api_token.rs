@@ -0,0 +1,141 @@
+/******************************************************************************\
+    empowerd - empowers the offline smart home
+    Copyright (C) 2019 - 2026 Max Maisel
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU Affero General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU Affero General Public License for more details.
+
+    You should have received a copy of the GNU Affero General Public License
+    along with this program.  If not, see <https://www.gnu.org/licenses/>.
+\******************************************************************************/
+use super::schema;
+use crate::{session_manager::Role, Error};
+use chrono::{DateTime, NaiveDateTime, Utc};
+use diesel::prelude::{ExpressionMethods, Insertable, QueryDsl, Queryable};
+use diesel_async::{AsyncPgConnection, RunQueryDsl};
+
+#[derive(Queryable)]
+#[diesel(table_name = schema::api_tokens)]
+#[diesel(check_for_backend(diesel::pg::Pg))]
+struct RawApiToken {
+    pub id: i32,
+    pub name: String,
+    pub username: String,
+    pub role: String,
+    pub token_hash: String,
+    pub created: NaiveDateTime,
+}
+
+#[derive(Insertable)]
+#[diesel(table_name = schema::api_tokens)]
+struct NewRawApiToken<'a> {
+    pub name: &'a str,
+    pub username: &'a str,
+    pub role: String,
+    pub token_hash: &'a str,
+    pub created: NaiveDateTime,
+}
+
+/// A long-lived API token. Only the hash of the token is stored.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ApiToken {
+    pub id: i32,
+    pub name: String,
+    pub user: String,
+    pub role: Role,
+    pub token_hash: String,
+    pub created: DateTime<Utc>,
+}
+
+impl ApiToken {
+    pub async fn all(conn: &mut AsyncPgConnection) -> Result<Vec<Self>, Error> {
+        use schema::api_tokens::dsl;
+        dsl::api_tokens
+            .order(dsl::id.asc())
+            .load::<RawApiToken>(conn)
+            .await
+            .map_err(Error::from)?
+            .into_iter()
+            .map(|x| x.try_into().map_err(Error::Bug))
+            .collect()
+    }
+
+    pub async fn by_id(
+        conn: &mut AsyncPgConnection,
+        id: i32,
+    ) -> Result<Self, Error> {
+        use schema::api_tokens::dsl;
+        dsl::api_tokens
+            .filter(dsl::id.eq(id))
+            .first::<RawApiToken>(conn)
+            .await
+            .map_err(Error::from)?
+            .try_into()
+            .map_err(Error::Bug)
+    }
+
+    /// Inserts a new token and updates its ID.
+    pub async fn insert(
+        &mut self,
+        conn: &mut AsyncPgConnection,
+    ) -> Result<(), Error> {
+        let raw = NewRawApiToken {
+            name: &self.name,
+            username: &self.user,
+            role: self.role.to_string(),
+            token_hash: &self.token_hash,
+            created: self.created.naive_utc(),
+        };
+
+        self.id = diesel::insert_into(schema::api_tokens::table)
+            .values(&raw)
+            .returning(schema::api_tokens::id)
+            .get_result::<i32>(conn)
+            .await
+            .map_err(|e| {
+                Error::Temporary(format!("Inserting API token failed: {e}"))
+            })?;
+
+        Ok(())
+    }
+
+    pub async fn delete(
+        conn: &mut AsyncPgConnection,
+        id: i32,
+    ) -> Result<(), Error> {
+        use schema::api_tokens::dsl;
+        let count = diesel::delete(dsl::api_tokens.filter(dsl::id.eq(id)))
+            .execute(conn)
+            .await
+            .map_err(|e| {
+                Error::Temporary(format!("Deleting API token {id} failed: {e}"))
+            })?;
+
+        if count == 0 {
+            return Err(Error::NotFound);
+        }
+        Ok(())
+    }
+}
+
+impl TryFrom<RawApiToken> for ApiToken {
+    type Error = String;
+
+    fn try_from(input: RawApiToken) -> Result<Self, Self::Error> {
+        Ok(Self {
+            id: input.id,
+            name: input.name,
+            user: input.username,
+            role: input.role.parse()?,
+            token_hash: input.token_hash,
+            created: input.created.and_utc(),
+        })
+    }
+}
@@ -0,0 +1,105 @@
+/******************************************************************************\
+    empowerd - empowers the offline smart home
+    Copyright (C) 2019 - 2026 Max Maisel
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU Affero General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU Affero General Public License for more details.
+
+    You should have received a copy of the GNU Affero General Public License
+    along with this program.  If not, see <https://www.gnu.org/licenses/>.
+\******************************************************************************/
+use super::schema;
+use crate::Error;
+use chrono::{DateTime, NaiveDateTime, Utc};
+use diesel::prelude::{ExpressionMethods, Insertable, QueryDsl, Queryable};
+use diesel_async::{AsyncPgConnection, RunQueryDsl};
+
+#[derive(Queryable)]
+#[diesel(table_name = schema::audit_log)]
+#[diesel(check_for_backend(diesel::pg::Pg))]
+struct RawAuditEntry {
+    pub id: i32,
+    pub time: NaiveDateTime,
+    pub username: String,
+    pub mutation: String,
+    pub input: String,
+}
+
+#[derive(Insertable)]
+#[diesel(table_name = schema::audit_log)]
+struct NewRawAuditEntry<'a> {
+    pub time: NaiveDateTime,
+    pub username: &'a str,
+    pub mutation: &'a str,
+    pub input: &'a str,
+}
+
+/// Records which user issued which mutation.
+#[derive(Clone, Debug, PartialEq)]
+pub struct AuditEntry {
+    pub id: i32,
+    pub time: DateTime<Utc>,
+    pub user: String,
+    pub mutation: String,
+    pub input: String,
+}
+
+impl AuditEntry {
+    pub async fn insert(
+        conn: &mut AsyncPgConnection,
+        user: &str,
+        mutation: &str,
+        input: &str,
+    ) -> Result<(), Error> {
+        let raw = NewRawAuditEntry {
+            time: Utc::now().naive_utc(),
+            username: user,
+            mutation,
+            input,
+        };
+
+        diesel::insert_into(schema::audit_log::table)
+            .values(&raw)
+            .execute(conn)
+            .await
+            .map_err(|e| {
+                Error::Temporary(format!("Inserting audit entry failed: {e}"))
+            })?;
+
+        Ok(())
+    }
+
+    /// Loads the newest entries, newest first.
+    pub async fn latest(
+        conn: &mut AsyncPgConnection,
+        limit: i64,
+    ) -> Result<Vec<Self>, Error> {
+        use schema::audit_log::dsl;
+        dsl::audit_log
+            .order(dsl::id.desc())
+            .limit(limit)
+            .load::<RawAuditEntry>(conn)
+            .await
+            .map(|x| x.into_iter().map(|y| y.into()).collect())
+            .map_err(|e| e.into())
+    }
+}
+
+impl From<RawAuditEntry> for AuditEntry {
+    fn from(input: RawAuditEntry) -> Self {
+        Self {
+            id: input.id,
+            time: input.time.and_utc(),
+            user: input.username,
+            mutation: input.mutation,
+            input: input.input,
+        }
+    }
+}
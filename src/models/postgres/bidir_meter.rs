@@ -16,6 +16,7 @@
     along with this program.  If not, see <https://www.gnu.org/licenses/>.
 \******************************************************************************/
 use super::{
+    history::impl_history,
     impl_timeseries, schema,
     units::{second, watt, watt_hour, Abbreviation, Energy, Power, Time},
 };
@@ -23,10 +24,17 @@ use crate::Error;
 use chrono::{DateTime, NaiveDateTime};
 use diesel::prelude::{
     AsChangeset, ExpressionMethods, Identifiable, Insertable, Queryable,
-    Selectable,
+    QueryableByName, Selectable,
 };
 
-#[derive(AsChangeset, Identifiable, Insertable, Queryable, Selectable)]
+#[derive(
+    AsChangeset,
+    Identifiable,
+    Insertable,
+    Queryable,
+    QueryableByName,
+    Selectable,
+)]
 #[diesel(table_name = schema::bidir_meters)]
 #[diesel(check_for_backend(diesel::pg::Pg))]
 #[diesel(primary_key(series_id, time))]
@@ -47,6 +55,16 @@ pub struct BidirMeter {
 }
 
 impl_timeseries!(RawBidirMeter, BidirMeter, bidir_meters);
+impl_history!(
+    RawBidirMeter,
+    BidirMeter,
+    bidir_meters,
+    [
+        energy_in_wh: INT8,
+        energy_out_wh: INT8,
+        power_w: INT4,
+    ]
+);
 
 impl BidirMeter {
     // TODO: dedup this
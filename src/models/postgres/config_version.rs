@@ -0,0 +1,120 @@
+/******************************************************************************\
+    empowerd - empowers the offline smart home
+    Copyright (C) 2019 - 2026 Max Maisel
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU Affero General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU Affero General Public License for more details.
+
+    You should have received a copy of the GNU Affero General Public License
+    along with this program.  If not, see <https://www.gnu.org/licenses/>.
+\******************************************************************************/
+use super::schema;
+use crate::Error;
+use chrono::{DateTime, NaiveDateTime, Utc};
+use diesel::prelude::{ExpressionMethods, Insertable, QueryDsl, Queryable};
+use diesel_async::{AsyncPgConnection, RunQueryDsl};
+
+#[derive(Queryable)]
+#[diesel(table_name = schema::config_versions)]
+#[diesel(check_for_backend(diesel::pg::Pg))]
+struct RawConfigVersion {
+    pub id: i32,
+    pub time: NaiveDateTime,
+    pub username: String,
+    pub comment: String,
+    pub config: String,
+}
+
+#[derive(Insertable)]
+#[diesel(table_name = schema::config_versions)]
+struct NewRawConfigVersion<'a> {
+    pub time: NaiveDateTime,
+    pub username: &'a str,
+    pub comment: &'a str,
+    pub config: &'a str,
+}
+
+/// A stored version of the main config file.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ConfigVersion {
+    pub id: i32,
+    pub time: DateTime<Utc>,
+    pub user: String,
+    pub comment: String,
+    /// Content of the config file including secrets.
+    pub config: String,
+}
+
+impl ConfigVersion {
+    pub async fn insert(
+        conn: &mut AsyncPgConnection,
+        user: &str,
+        comment: &str,
+        config: &str,
+    ) -> Result<Self, Error> {
+        let raw = NewRawConfigVersion {
+            time: Utc::now().naive_utc(),
+            username: user,
+            comment,
+            config,
+        };
+
+        diesel::insert_into(schema::config_versions::table)
+            .values(&raw)
+            .get_result::<RawConfigVersion>(conn)
+            .await
+            .map(|x| x.into())
+            .map_err(|e| {
+                Error::Temporary(format!(
+                    "Inserting config version failed: {e}"
+                ))
+            })
+    }
+
+    pub async fn get(
+        conn: &mut AsyncPgConnection,
+        id: i32,
+    ) -> Result<Self, Error> {
+        use schema::config_versions::dsl;
+        dsl::config_versions
+            .filter(dsl::id.eq(id))
+            .first::<RawConfigVersion>(conn)
+            .await
+            .map(|x| x.into())
+            .map_err(|e| e.into())
+    }
+
+    /// Loads the newest versions, newest first.
+    pub async fn latest(
+        conn: &mut AsyncPgConnection,
+        limit: i64,
+    ) -> Result<Vec<Self>, Error> {
+        use schema::config_versions::dsl;
+        dsl::config_versions
+            .order(dsl::id.desc())
+            .limit(limit)
+            .load::<RawConfigVersion>(conn)
+            .await
+            .map(|x| x.into_iter().map(|y| y.into()).collect())
+            .map_err(|e| e.into())
+    }
+}
+
+impl From<RawConfigVersion> for ConfigVersion {
+    fn from(input: RawConfigVersion) -> Self {
+        Self {
+            id: input.id,
+            time: input.time.and_utc(),
+            user: input.username,
+            comment: input.comment,
+            config: input.config,
+        }
+    }
+}
@@ -0,0 +1,100 @@
+/******************************************************************************\
+    empowerd - empowers the offline smart home
+    Copyright (C) 2019 - 2026 Max Maisel
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU Affero General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU Affero General Public License for more details.
+
+    You should have received a copy of the GNU Affero General Public License
+    along with this program.  If not, see <https://www.gnu.org/licenses/>.
+\******************************************************************************/
+use super::schema;
+use crate::Error;
+use chrono::{NaiveDateTime, Utc};
+use diesel::{
+    prelude::{ExpressionMethods, Insertable, QueryDsl},
+    upsert::excluded,
+};
+use diesel_async::{AsyncPgConnection, RunQueryDsl};
+use std::collections::BTreeMap;
+
+#[derive(Insertable)]
+#[diesel(table_name = schema::control_states)]
+struct NewRawControlState<'a> {
+    pub processor: &'a str,
+    pub key: &'a str,
+    pub value: &'a str,
+    pub time: NaiveDateTime,
+}
+
+/// Values of processors which were changed at runtime.
+pub struct ControlState;
+
+impl ControlState {
+    /// Loads all values of a processor by their key.
+    pub async fn all(
+        conn: &mut AsyncPgConnection,
+        processor: &str,
+    ) -> Result<BTreeMap<String, String>, Error> {
+        use schema::control_states::dsl;
+        dsl::control_states
+            .filter(dsl::processor.eq(processor))
+            .select((dsl::key, dsl::value))
+            .load::<(String, String)>(conn)
+            .await
+            .map(|x| x.into_iter().collect())
+            .map_err(|e| e.into())
+    }
+
+    /// Inserts or replaces a value.
+    pub async fn set(
+        conn: &mut AsyncPgConnection,
+        processor: &str,
+        key: &str,
+        value: &str,
+    ) -> Result<(), Error> {
+        use schema::control_states::dsl;
+        let raw = NewRawControlState {
+            processor,
+            key,
+            value,
+            time: Utc::now().naive_utc(),
+        };
+
+        diesel::insert_into(dsl::control_states)
+            .values(&raw)
+            .on_conflict((dsl::processor, dsl::key))
+            .do_update()
+            .set((dsl::value.eq(excluded(dsl::value)), dsl::time.eq(raw.time)))
+            .execute(conn)
+            .await
+            .map(|_| ())
+            .map_err(|e| {
+                Error::Temporary(format!("Storing control state failed: {e}"))
+            })
+    }
+
+    pub async fn delete(
+        conn: &mut AsyncPgConnection,
+        processor: &str,
+        key: &str,
+    ) -> Result<(), Error> {
+        use schema::control_states::dsl;
+        diesel::delete(
+            dsl::control_states
+                .filter(dsl::processor.eq(processor))
+                .filter(dsl::key.eq(key)),
+        )
+        .execute(conn)
+        .await
+        .map(|_| ())
+        .map_err(|e| e.into())
+    }
+}
@@ -0,0 +1,185 @@
+/******************************************************************************\
+    empowerd - empowers the offline smart home
+    Copyright (C) 2019 - 2026 Max Maisel
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU Affero General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU Affero General Public License for more details.
+
+    You should have received a copy of the GNU Affero General Public License
+    along with this program.  If not, see <https://www.gnu.org/licenses/>.
+\******************************************************************************/
+use super::{
+    schema,
+    units::{watt, Power},
+};
+use crate::Error;
+use chrono::{DateTime, NaiveDateTime, Utc};
+use diesel::prelude::{
+    AsChangeset, ExpressionMethods, Identifiable, Insertable, QueryDsl,
+    Queryable, Selectable,
+};
+use diesel_async::{AsyncPgConnection, RunQueryDsl};
+use std::time::Duration;
+
+#[derive(AsChangeset, Identifiable, Queryable, Selectable)]
+#[diesel(table_name = schema::deferrable_jobs)]
+#[diesel(check_for_backend(diesel::pg::Pg))]
+#[diesel(treat_none_as_null = true)]
+pub struct RawDeferrableJob {
+    pub id: i32,
+    pub processor: String,
+    pub switch_name: String,
+    pub power_w: i32,
+    pub runtime_s: i64,
+    pub earliest_start: NaiveDateTime,
+    pub latest_finish: NaiveDateTime,
+    pub started: Option<NaiveDateTime>,
+    pub finished: Option<NaiveDateTime>,
+}
+
+#[derive(Insertable)]
+#[diesel(table_name = schema::deferrable_jobs)]
+struct NewRawDeferrableJob<'a> {
+    pub processor: &'a str,
+    pub switch_name: &'a str,
+    pub power_w: i32,
+    pub runtime_s: i64,
+    pub earliest_start: NaiveDateTime,
+    pub latest_finish: NaiveDateTime,
+}
+
+/// A one-shot job which must run for a given time on a switch channel
+/// between its earliest start and latest finish time.
+#[derive(Clone, Debug, PartialEq)]
+pub struct DeferrableJob {
+    pub id: i32,
+    pub switch_name: String,
+    pub power: Power,
+    pub runtime: Duration,
+    pub earliest_start: DateTime<Utc>,
+    pub latest_finish: DateTime<Utc>,
+    pub started: Option<DateTime<Utc>>,
+    pub finished: Option<DateTime<Utc>>,
+}
+
+impl DeferrableJob {
+    /// Loads all unfinished jobs of a processor ordered by their deadline.
+    pub async fn pending(
+        conn: &mut AsyncPgConnection,
+        processor: &str,
+    ) -> Result<Vec<Self>, Error> {
+        use schema::deferrable_jobs::dsl;
+        dsl::deferrable_jobs
+            .filter(dsl::processor.eq(processor))
+            .filter(dsl::finished.is_null())
+            .order(dsl::latest_finish.asc())
+            .load::<RawDeferrableJob>(conn)
+            .await
+            .map(|x| x.into_iter().map(|y| y.into()).collect())
+            .map_err(|e| e.into())
+    }
+
+    /// Inserts a new job and updates its ID.
+    pub async fn insert(
+        &mut self,
+        conn: &mut AsyncPgConnection,
+        processor: &str,
+    ) -> Result<(), Error> {
+        let raw = NewRawDeferrableJob {
+            processor,
+            switch_name: &self.switch_name,
+            power_w: self.power.get::<watt>().round() as i32,
+            runtime_s: self.runtime.as_secs() as i64,
+            earliest_start: self.earliest_start.naive_utc(),
+            latest_finish: self.latest_finish.naive_utc(),
+        };
+
+        self.id = diesel::insert_into(schema::deferrable_jobs::table)
+            .values(&raw)
+            .returning(schema::deferrable_jobs::id)
+            .get_result::<i32>(conn)
+            .await
+            .map_err(|e| {
+                Error::Temporary(format!("Inserting job failed: {e}"))
+            })?;
+
+        Ok(())
+    }
+
+    pub async fn save_changes(
+        &self,
+        conn: &mut AsyncPgConnection,
+        processor: &str,
+    ) -> Result<(), Error> {
+        use diesel_async::SaveChangesDsl;
+        let raw = self.to_raw(processor);
+
+        raw.save_changes::<RawDeferrableJob>(conn)
+            .await
+            .map_err(|e| {
+                Error::Temporary(format!(
+                    "Updating job {} failed: {}",
+                    self.id, e
+                ))
+            })?;
+
+        Ok(())
+    }
+
+    pub async fn delete(
+        conn: &mut AsyncPgConnection,
+        id: i32,
+    ) -> Result<(), Error> {
+        use schema::deferrable_jobs::dsl;
+        diesel::delete(dsl::deferrable_jobs.filter(dsl::id.eq(id)))
+            .execute(conn)
+            .await
+            .map_err(|e| {
+                Error::Temporary(format!("Deleting job {id} failed: {e}"))
+            })?;
+
+        Ok(())
+    }
+
+    /// Time when a running job will be finished.
+    pub fn end_time(&self) -> Option<DateTime<Utc>> {
+        let runtime = chrono::Duration::from_std(self.runtime).ok()?;
+        self.started.map(|x| x + runtime)
+    }
+
+    fn to_raw(&self, processor: &str) -> RawDeferrableJob {
+        RawDeferrableJob {
+            id: self.id,
+            processor: processor.into(),
+            switch_name: self.switch_name.clone(),
+            power_w: self.power.get::<watt>().round() as i32,
+            runtime_s: self.runtime.as_secs() as i64,
+            earliest_start: self.earliest_start.naive_utc(),
+            latest_finish: self.latest_finish.naive_utc(),
+            started: self.started.map(|x| x.naive_utc()),
+            finished: self.finished.map(|x| x.naive_utc()),
+        }
+    }
+}
+
+impl From<RawDeferrableJob> for DeferrableJob {
+    fn from(input: RawDeferrableJob) -> Self {
+        Self {
+            id: input.id,
+            switch_name: input.switch_name,
+            power: Power::new::<watt>(input.power_w as f64),
+            runtime: Duration::from_secs(input.runtime_s.max(0) as u64),
+            earliest_start: input.earliest_start.and_utc(),
+            latest_finish: input.latest_finish.and_utc(),
+            started: input.started.map(|x| x.and_utc()),
+            finished: input.finished.map(|x| x.and_utc()),
+        }
+    }
+}
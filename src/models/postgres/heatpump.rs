@@ -16,6 +16,7 @@
     along with this program.  If not, see <https://www.gnu.org/licenses/>.
 \******************************************************************************/
 use super::{
+    history::impl_history,
     impl_timeseries, schema,
     units::{
         celsius, percent, second, watt, watt_hour, Abbreviation, Energy, Power,
@@ -26,10 +27,17 @@ use crate::Error;
 use chrono::{DateTime, NaiveDateTime};
 use diesel::prelude::{
     AsChangeset, ExpressionMethods, Identifiable, Insertable, Queryable,
-    Selectable,
+    QueryableByName, Selectable,
 };
 
-#[derive(AsChangeset, Identifiable, Insertable, Queryable, Selectable)]
+#[derive(
+    AsChangeset,
+    Identifiable,
+    Insertable,
+    Queryable,
+    QueryableByName,
+    Selectable,
+)]
 #[diesel(table_name = schema::heatpumps)]
 #[diesel(check_for_backend(diesel::pg::Pg))]
 #[diesel(primary_key(series_id, time))]
@@ -62,6 +70,22 @@ pub struct Heatpump {
 }
 
 impl_timeseries!(RawHeatpump, Heatpump, heatpumps);
+impl_history!(
+    RawHeatpump,
+    Heatpump,
+    heatpumps,
+    [
+        energy_wh: INT8,
+        power_w: INT4,
+        heat_wh: INT8,
+        cold_wh: INT8,
+        defrost_wh: INT8,
+        cop_pct: INT2,
+        boiler_top_degc_e1: INT2,
+        boiler_mid_degc_e1: INT2,
+        boiler_bot_degc_e1: INT2,
+    ]
+);
 
 impl Heatpump {
     // TODO: dedup
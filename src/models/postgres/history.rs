@@ -0,0 +1,328 @@
+/******************************************************************************\
+    empowerd - empowers the offline smart home
+    Copyright (C) 2019 - 2026 Max Maisel
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU Affero General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU Affero General Public License for more details.
+
+    You should have received a copy of the GNU Affero General Public License
+    along with this program.  If not, see <https://www.gnu.org/licenses/>.
+\******************************************************************************/
+use super::units::{watt_hour, Energy};
+use crate::{
+    settings::{Settings, SourceType},
+    Error,
+};
+use chrono::{DateTime, NaiveDateTime, Utc};
+use diesel::{
+    sql_types::{BigInt, Integer, Text, Timestamp},
+    QueryableByName,
+};
+use diesel_async::{AsyncPgConnection, RunQueryDsl};
+use std::collections::BTreeMap;
+
+/// Function which combines all records of a downsampling bucket.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, juniper::GraphQLEnum)]
+pub enum Aggregate {
+    Avg,
+    Min,
+    Max,
+    Last,
+}
+
+impl Aggregate {
+    fn sql(&self, column: &str) -> String {
+        match self {
+            Aggregate::Avg => format!("AVG({column})"),
+            Aggregate::Min => format!("MIN({column})"),
+            Aggregate::Max => format!("MAX({column})"),
+            Aggregate::Last => {
+                format!("(ARRAY_AGG({column} ORDER BY time DESC))[1]")
+            }
+        }
+    }
+}
+
+/// Calendar period of an energy delta.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, juniper::GraphQLEnum)]
+pub enum Period {
+    Day,
+    Month,
+    Year,
+}
+
+impl Period {
+    fn sql(&self) -> &'static str {
+        match self {
+            Period::Day => "day",
+            Period::Month => "month",
+            Period::Year => "year",
+        }
+    }
+}
+
+/// Builds a query which downsamples a timeseries into buckets of equal size.
+/// The aggregated values are cast back to the column types so that the
+/// result can be loaded as the raw record type.
+///
+/// Binds: $1 interval in seconds, $2 start time, $3 series ID, $4 end time.
+pub fn downsample_query(
+    table: &str,
+    columns: &[(&str, &str)],
+    aggregate: Aggregate,
+) -> String {
+    let columns = columns
+        .iter()
+        .map(|(name, ty)| {
+            format!("CAST({} AS {ty}) AS {name}", aggregate.sql(name))
+        })
+        .collect::<Vec<_>>()
+        .join(", ");
+
+    format!(
+        "SELECT series_id, \
+            date_bin(make_interval(secs => $1), time, $2) AS time, \
+            {columns} \
+        FROM {table} \
+        WHERE series_id = $3 AND time >= $2 AND time < $4 \
+        GROUP BY 1, 2 ORDER BY 2"
+    )
+}
+
+/// Builds a query which calculates the increase of an energy counter per
+/// calendar period. Periods are aligned to the given time zone. The first
+/// period without a predecessor only counts the increase within itself.
+///
+/// Binds: $1 period, $2 time zone, $3 series ID, $4 start time, $5 end time.
+fn energy_delta_query(table: &str, column: &str) -> String {
+    format!(
+        "SELECT time, delta FROM ( \
+            SELECT (bucket AT TIME ZONE $2) AT TIME ZONE 'UTC' AS time, \
+                CAST(COALESCE( \
+                    value - LAG(value) OVER (ORDER BY bucket), \
+                    value - first \
+                ) AS INT8) AS delta \
+            FROM ( \
+                SELECT date_trunc($1, time AT TIME ZONE 'UTC' \
+                        AT TIME ZONE $2) AS bucket, \
+                    MIN({column}) AS first, MAX({column}) AS value \
+                FROM {table} \
+                WHERE series_id = $3 \
+                    AND time >= $4 - CAST('2 ' || $1 AS INTERVAL) \
+                    AND time < $5 \
+                GROUP BY 1 \
+            ) AS buckets \
+        ) AS deltas \
+        WHERE delta IS NOT NULL \
+            AND time > $4 - CAST('1 ' || $1 AS INTERVAL) \
+        ORDER BY time"
+    )
+}
+
+/// Downsamples a timeseries model, see `downsample_query`.
+macro_rules! impl_history {
+    ($raw_ty: ident, $ty: ident, $schema: ident,
+        [$($column: ident: $sql_ty: ident),* $(,)?]) => {
+        impl $ty {
+            pub async fn history(
+                conn: &mut diesel_async::AsyncPgConnection,
+                series_id: i32,
+                from: chrono::DateTime<chrono::Utc>,
+                to: chrono::DateTime<chrono::Utc>,
+                interval: std::time::Duration,
+                aggregate: super::history::Aggregate,
+            ) -> Result<Vec<$ty>, crate::Error> {
+                use diesel::sql_types::{Double, Integer, Timestamp};
+                use diesel_async::RunQueryDsl;
+                let query = super::history::downsample_query(
+                    stringify!($schema),
+                    &[$((stringify!($column), stringify!($sql_ty))),*],
+                    aggregate,
+                );
+
+                diesel::sql_query(query)
+                    .bind::<Double, _>(interval.as_secs_f64())
+                    .bind::<Timestamp, _>(from.naive_utc())
+                    .bind::<Integer, _>(series_id)
+                    .bind::<Timestamp, _>(to.naive_utc())
+                    .load::<$raw_ty>(conn)
+                    .await
+                    .map(|x| x.into_iter().map(|y| y.into()).collect())
+                    .map_err(|e| e.into())
+            }
+        }
+    };
+}
+
+pub(crate) use impl_history;
+
+#[derive(QueryableByName)]
+struct RawEnergyDelta {
+    #[diesel(sql_type = Timestamp)]
+    time: NaiveDateTime,
+    #[diesel(sql_type = BigInt)]
+    delta: i64,
+}
+
+/// Energy counter increase of a series during one calendar period.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct EnergyDelta {
+    pub time: DateTime<Utc>,
+    pub energy: Option<Energy>,
+    pub energy_in: Option<Energy>,
+    pub energy_out: Option<Energy>,
+    pub heat: Option<Energy>,
+}
+
+impl EnergyDelta {
+    fn field(&mut self, column: &str) -> &mut Option<Energy> {
+        match column {
+            "energy_in_wh" => &mut self.energy_in,
+            "energy_out_wh" => &mut self.energy_out,
+            "heat_wh" => &mut self.heat,
+            _ => &mut self.energy,
+        }
+    }
+}
+
+/// Database table which stores a series.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum SeriesType {
+    Battery,
+    BidirMeter,
+    Generator,
+    Heatpump,
+    SimpleMeter,
+    Weather,
+}
+
+impl SeriesType {
+    pub fn from_source(variant: &SourceType) -> Option<Self> {
+        match variant {
+            SourceType::Debug(_) => None,
+            SourceType::SunnyBoyStorage(_) => Some(Self::Battery),
+            SourceType::SunnyIsland(_) => Some(Self::Battery),
+            SourceType::SunspecSolar(_) => Some(Self::SimpleMeter),
+            SourceType::DachsMsrS(_) => Some(Self::Generator),
+            SourceType::KeContact(_) => Some(Self::SimpleMeter),
+            SourceType::LambdaHeatPump(_) => Some(Self::Heatpump),
+            SourceType::SmaMeter(_) => Some(Self::BidirMeter),
+            SourceType::SmlMeter(_) => Some(Self::BidirMeter),
+            SourceType::SunnyBoySpeedwire(_) => Some(Self::SimpleMeter),
+            SourceType::Bresser6in1(_) => Some(Self::Weather),
+        }
+    }
+
+    /// Maps the series IDs of all configured sources to their type.
+    pub fn from_settings(settings: &Settings) -> BTreeMap<i32, Self> {
+        settings
+            .sources
+            .iter()
+            .filter_map(|x| {
+                Self::from_source(&x.variant).map(|y| (x.series_id, y))
+            })
+            .collect()
+    }
+
+    fn table(&self) -> &'static str {
+        match self {
+            SeriesType::Battery => "batteries",
+            SeriesType::BidirMeter => "bidir_meters",
+            SeriesType::Generator => "generators",
+            SeriesType::Heatpump => "heatpumps",
+            SeriesType::SimpleMeter => "simple_meters",
+            SeriesType::Weather => "weathers",
+        }
+    }
+
+    fn energy_columns(&self) -> &'static [&'static str] {
+        match self {
+            SeriesType::Battery | SeriesType::BidirMeter => {
+                &["energy_in_wh", "energy_out_wh"]
+            }
+            SeriesType::Generator | SeriesType::SimpleMeter => &["energy_wh"],
+            SeriesType::Heatpump => &["energy_wh", "heat_wh"],
+            SeriesType::Weather => &[],
+        }
+    }
+
+    /// Calculates the energy counter increase of a series per calendar
+    /// period.
+    pub async fn energy_deltas(
+        &self,
+        conn: &mut AsyncPgConnection,
+        series_id: i32,
+        from: DateTime<Utc>,
+        to: DateTime<Utc>,
+        period: Period,
+        timezone: &str,
+    ) -> Result<Vec<EnergyDelta>, Error> {
+        if self.energy_columns().is_empty() {
+            return Err(Error::InvalidInput(format!(
+                "Series {series_id} has no energy counter"
+            )));
+        }
+
+        let mut deltas = BTreeMap::<NaiveDateTime, EnergyDelta>::new();
+        for column in self.energy_columns() {
+            let query = energy_delta_query(self.table(), column);
+            let rows = diesel::sql_query(query)
+                .bind::<Text, _>(period.sql())
+                .bind::<Text, _>(timezone)
+                .bind::<Integer, _>(series_id)
+                .bind::<Timestamp, _>(from.naive_utc())
+                .bind::<Timestamp, _>(to.naive_utc())
+                .load::<RawEnergyDelta>(conn)
+                .await?;
+
+            for row in rows {
+                let delta = deltas.entry(row.time).or_insert(EnergyDelta {
+                    time: row.time.and_utc(),
+                    ..Default::default()
+                });
+                *delta.field(column) =
+                    Some(Energy::new::<watt_hour>(row.delta as f64));
+            }
+        }
+
+        Ok(deltas.into_values().collect())
+    }
+}
+
+#[test]
+fn test_downsample_query() {
+    let query = downsample_query(
+        "simple_meters",
+        &[("energy_wh", "INT8"), ("power_w", "INT4")],
+        Aggregate::Last,
+    );
+    assert_eq!(
+        "SELECT series_id, \
+            date_bin(make_interval(secs => $1), time, $2) AS time, \
+            CAST((ARRAY_AGG(energy_wh ORDER BY time DESC))[1] AS INT8) \
+            AS energy_wh, \
+            CAST((ARRAY_AGG(power_w ORDER BY time DESC))[1] AS INT4) \
+            AS power_w \
+        FROM simple_meters \
+        WHERE series_id = $3 AND time >= $2 AND time < $4 \
+        GROUP BY 1, 2 ORDER BY 2",
+        query
+    );
+}
+
+#[test]
+fn test_energy_delta_field() {
+    let mut delta = EnergyDelta::default();
+    *delta.field("energy_in_wh") = Some(Energy::new::<watt_hour>(1.0));
+    *delta.field("energy_wh") = Some(Energy::new::<watt_hour>(2.0));
+    assert_eq!(Some(Energy::new::<watt_hour>(1.0)), delta.energy_in);
+    assert_eq!(Some(Energy::new::<watt_hour>(2.0)), delta.energy);
+    assert_eq!(None, delta.energy_out);
+}
@@ -24,8 +24,21 @@ pub const MIGRATIONS: EmbeddedMigrations = embed_migrations!("db");
 pub fn run_migrations(db_url: &str) -> Result<(), String> {
     let mut conn =
         PgConnection::establish(db_url).map_err(|e| e.to_string())?;
-    conn.run_pending_migrations(MIGRATIONS)
+    // Diesel sorts the versions as strings, which would run 11000 before
+    // 9000 on an empty database.
+    let mut migrations = conn
+        .pending_migrations(MIGRATIONS)
         .map_err(|e| e.to_string())?;
+    migrations.sort_by_key(|x| {
+        x.name()
+            .version()
+            .to_string()
+            .parse::<u64>()
+            .unwrap_or(u64::MAX)
+    });
+    for migration in migrations {
+        conn.run_migration(&migration).map_err(|e| e.to_string())?;
+    }
 
     Ok(())
 }
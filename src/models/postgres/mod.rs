@@ -16,25 +16,66 @@
     along with this program.  If not, see <https://www.gnu.org/licenses/>.
 \******************************************************************************/
 use super::units;
-
+use crate::settings::Database;
+use diesel_async::{
+    pooled_connection::{deadpool::Pool, AsyncDieselConnectionManager},
+    AsyncPgConnection,
+};
+
+pub mod api_token;
+pub mod audit_entry;
 pub mod battery;
 pub mod bidir_meter;
+pub mod config_version;
+pub mod control_state;
+pub mod deferrable_job;
 pub mod generator;
 pub mod heatpump;
+pub mod history;
 pub mod simple_meter;
+pub mod switch_rule;
+pub mod user;
 pub mod weather;
 
 mod migrations;
 mod schema;
 
+pub use api_token::ApiToken;
+pub use audit_entry::AuditEntry;
 pub use battery::Battery;
 pub use bidir_meter::BidirMeter;
+pub use config_version::ConfigVersion;
+pub use control_state::ControlState;
+pub use deferrable_job::DeferrableJob;
 pub use generator::Generator;
 pub use heatpump::Heatpump;
 pub use migrations::run_migrations;
 pub use simple_meter::SimpleMeter;
+pub use switch_rule::SwitchRule;
+pub use user::User;
 pub use weather::Weather;
 
+/// Returns the connection URL of the database.
+pub fn database_url(settings: &Database) -> String {
+    format!(
+        "postgres://{}:{}@{}/{}",
+        settings.user, settings.password, settings.url, settings.name,
+    )
+}
+
+/// Runs pending database migrations and creates a connection pool.
+pub fn database_pool(
+    settings: &Database,
+) -> Result<Pool<AsyncPgConnection>, String> {
+    let pg_url = database_url(settings);
+    tokio::task::block_in_place(|| run_migrations(&pg_url))?;
+    let pool_cfg =
+        AsyncDieselConnectionManager::<AsyncPgConnection>::new(pg_url);
+    Pool::builder(pool_cfg)
+        .build()
+        .map_err(|e| format!("Building database connection pool failed: {e}"))
+}
+
 macro_rules! impl_timeseries {
     ($raw_ty: ident, $ty: ident, $schema: ident) => {
         impl $raw_ty {
@@ -1,5 +1,26 @@
 // @generated automatically by Diesel CLI.
 
+diesel::table! {
+    api_tokens (id) {
+        id -> Int4,
+        name -> Text,
+        username -> Text,
+        role -> Text,
+        token_hash -> Text,
+        created -> Timestamp,
+    }
+}
+
+diesel::table! {
+    audit_log (id) {
+        id -> Int4,
+        time -> Timestamp,
+        username -> Text,
+        mutation -> Text,
+        input -> Text,
+    }
+}
+
 diesel::table! {
     batteries (series_id, time) {
         series_id -> Int4,
@@ -21,6 +42,39 @@ diesel::table! {
     }
 }
 
+diesel::table! {
+    config_versions (id) {
+        id -> Int4,
+        time -> Timestamp,
+        username -> Text,
+        comment -> Text,
+        config -> Text,
+    }
+}
+
+diesel::table! {
+    control_states (processor, key) {
+        processor -> Text,
+        key -> Text,
+        value -> Text,
+        time -> Timestamp,
+    }
+}
+
+diesel::table! {
+    deferrable_jobs (id) {
+        id -> Int4,
+        processor -> Text,
+        switch_name -> Text,
+        power_w -> Int4,
+        runtime_s -> Int8,
+        earliest_start -> Timestamp,
+        latest_finish -> Timestamp,
+        started -> Nullable<Timestamp>,
+        finished -> Nullable<Timestamp>,
+    }
+}
+
 diesel::table! {
     generators (series_id, time) {
         series_id -> Int4,
@@ -56,6 +110,29 @@ diesel::table! {
     }
 }
 
+diesel::table! {
+    switch_rules (id) {
+        id -> Int4,
+        processor -> Text,
+        name -> Text,
+        enabled -> Bool,
+        schedule -> Text,
+        calendar -> Text,
+        target_type -> Text,
+        target_name -> Text,
+        action -> Text,
+    }
+}
+
+diesel::table! {
+    users (id) {
+        id -> Int4,
+        name -> Text,
+        hashed_password -> Text,
+        role -> Text,
+    }
+}
+
 diesel::table! {
     weathers (series_id, time) {
         series_id -> Int4,
@@ -92,10 +169,17 @@ diesel::table! {
 }
 
 diesel::allow_tables_to_appear_in_same_query!(
+    api_tokens,
+    audit_log,
     batteries,
     bidir_meters,
+    config_versions,
+    control_states,
+    deferrable_jobs,
     generators,
     heatpumps,
     simple_meters,
+    switch_rules,
+    users,
     weathers,
 );
@@ -16,6 +16,7 @@
     along with this program.  If not, see <https://www.gnu.org/licenses/>.
 \******************************************************************************/
 use super::{
+    history::impl_history,
     impl_timeseries, schema,
     units::{second, watt, watt_hour, Abbreviation, Energy, Power, Time},
 };
@@ -23,10 +24,17 @@ use crate::Error;
 use chrono::{DateTime, NaiveDateTime};
 use diesel::prelude::{
     AsChangeset, ExpressionMethods, Identifiable, Insertable, Queryable,
-    Selectable,
+    QueryableByName, Selectable,
 };
 
-#[derive(AsChangeset, Identifiable, Insertable, Queryable, Selectable)]
+#[derive(
+    AsChangeset,
+    Identifiable,
+    Insertable,
+    Queryable,
+    QueryableByName,
+    Selectable,
+)]
 #[diesel(table_name = schema::simple_meters)]
 #[diesel(check_for_backend(diesel::pg::Pg))]
 #[diesel(primary_key(series_id, time))]
@@ -45,6 +53,15 @@ pub struct SimpleMeter {
 }
 
 impl_timeseries!(RawSimpleMeter, SimpleMeter, simple_meters);
+impl_history!(
+    RawSimpleMeter,
+    SimpleMeter,
+    simple_meters,
+    [
+        energy_wh: INT8,
+        power_w: INT4,
+    ]
+);
 
 impl SimpleMeter {
     pub fn calc_power(&self, other: &Self) -> Power {
@@ -0,0 +1,179 @@
+/******************************************************************************\
+    empowerd - empowers the offline smart home
+    Copyright (C) 2019 - 2026 Max Maisel
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU Affero General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU Affero General Public License for more details.
+
+    You should have received a copy of the GNU Affero General Public License
+    along with this program.  If not, see <https://www.gnu.org/licenses/>.
+\******************************************************************************/
+use super::schema;
+use crate::{
+    schedule::{RuleCalendar, RuleTarget, Schedule},
+    tri_state::TriState,
+    Error,
+};
+use diesel::prelude::{
+    AsChangeset, ExpressionMethods, Identifiable, Insertable, QueryDsl,
+    Queryable, Selectable,
+};
+use diesel_async::{AsyncPgConnection, RunQueryDsl};
+
+#[derive(AsChangeset, Identifiable, Queryable, Selectable)]
+#[diesel(table_name = schema::switch_rules)]
+#[diesel(check_for_backend(diesel::pg::Pg))]
+pub struct RawSwitchRule {
+    pub id: i32,
+    pub processor: String,
+    pub name: String,
+    pub enabled: bool,
+    pub schedule: String,
+    pub calendar: String,
+    pub target_type: String,
+    pub target_name: String,
+    pub action: String,
+}
+
+#[derive(Insertable)]
+#[diesel(table_name = schema::switch_rules)]
+struct NewRawSwitchRule<'a> {
+    pub processor: &'a str,
+    pub name: &'a str,
+    pub enabled: bool,
+    pub schedule: &'a str,
+    pub calendar: String,
+    pub target_type: String,
+    pub target_name: &'a str,
+    pub action: String,
+}
+
+/// Sets a switch channel or an appliance force mode whenever
+/// its schedule triggers on an allowed calendar day.
+#[derive(Clone, Debug, PartialEq)]
+pub struct SwitchRule {
+    pub id: i32,
+    pub name: String,
+    pub enabled: bool,
+    pub schedule: String,
+    pub calendar: RuleCalendar,
+    pub target: RuleTarget,
+    pub target_name: String,
+    pub action: TriState,
+}
+
+impl SwitchRule {
+    /// Loads all rules of a processor.
+    pub async fn all(
+        conn: &mut AsyncPgConnection,
+        processor: &str,
+    ) -> Result<Vec<Self>, Error> {
+        use schema::switch_rules::dsl;
+        dsl::switch_rules
+            .filter(dsl::processor.eq(processor))
+            .order(dsl::id.asc())
+            .load::<RawSwitchRule>(conn)
+            .await
+            .map_err(Error::from)?
+            .into_iter()
+            .map(|x| x.try_into().map_err(Error::Bug))
+            .collect()
+    }
+
+    /// Inserts a new rule and updates its ID.
+    pub async fn insert(
+        &mut self,
+        conn: &mut AsyncPgConnection,
+        processor: &str,
+    ) -> Result<(), Error> {
+        let raw = NewRawSwitchRule {
+            processor,
+            name: &self.name,
+            enabled: self.enabled,
+            schedule: &self.schedule,
+            calendar: self.calendar.to_string(),
+            target_type: self.target.to_string(),
+            target_name: &self.target_name,
+            action: self.action.to_string(),
+        };
+
+        self.id = diesel::insert_into(schema::switch_rules::table)
+            .values(&raw)
+            .returning(schema::switch_rules::id)
+            .get_result::<i32>(conn)
+            .await
+            .map_err(|e| {
+                Error::Temporary(format!("Inserting rule failed: {e}"))
+            })?;
+
+        Ok(())
+    }
+
+    pub async fn save_changes(
+        &self,
+        conn: &mut AsyncPgConnection,
+        processor: &str,
+    ) -> Result<(), Error> {
+        use diesel_async::SaveChangesDsl;
+        let raw = RawSwitchRule {
+            id: self.id,
+            processor: processor.into(),
+            name: self.name.clone(),
+            enabled: self.enabled,
+            schedule: self.schedule.clone(),
+            calendar: self.calendar.to_string(),
+            target_type: self.target.to_string(),
+            target_name: self.target_name.clone(),
+            action: self.action.to_string(),
+        };
+
+        raw.save_changes::<RawSwitchRule>(conn).await.map_err(|e| {
+            Error::Temporary(format!("Updating rule {} failed: {}", self.id, e))
+        })?;
+
+        Ok(())
+    }
+
+    pub async fn delete(
+        conn: &mut AsyncPgConnection,
+        id: i32,
+    ) -> Result<(), Error> {
+        use schema::switch_rules::dsl;
+        diesel::delete(dsl::switch_rules.filter(dsl::id.eq(id)))
+            .execute(conn)
+            .await
+            .map_err(|e| {
+                Error::Temporary(format!("Deleting rule {id} failed: {e}"))
+            })?;
+
+        Ok(())
+    }
+
+    pub fn parsed_schedule(&self) -> Result<Schedule, String> {
+        self.schedule.parse()
+    }
+}
+
+impl TryFrom<RawSwitchRule> for SwitchRule {
+    type Error = String;
+
+    fn try_from(input: RawSwitchRule) -> Result<Self, Self::Error> {
+        Ok(Self {
+            id: input.id,
+            name: input.name,
+            enabled: input.enabled,
+            schedule: input.schedule,
+            calendar: input.calendar.parse()?,
+            target: input.target_type.parse()?,
+            target_name: input.target_name,
+            action: input.action.parse()?,
+        })
+    }
+}
@@ -0,0 +1,222 @@
+/******************************************************************************\
+    empowerd - empowers the offline smart home
+    Copyright (C) 2019 - 2026 Max Maisel
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU Affero General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU Affero General Public License for more details.
+
+    You should have received a copy of the GNU Affero General Public License
+    along with this program.  If not, see <https://www.gnu.org/licenses/>.
+\******************************************************************************/
+use super::schema;
+use crate::{session_manager::Role, Error};
+use diesel::prelude::{
+    AsChangeset, ExpressionMethods, Identifiable, Insertable, QueryDsl,
+    Queryable, Selectable,
+};
+use diesel_async::{AsyncPgConnection, RunQueryDsl};
+use ring::rand::{SecureRandom, SystemRandom};
+
+/// Hash of a random password with the default parameters. It is verified
+/// for unknown users, so a failed login takes the same time for all names.
+const DUMMY_HASH: &str = "$argon2id$v=19$m=19456,t=2,p=1$\
+    cQgQUJQbDaPbvbCXw0Nbbw$e1o6p+BbYos7kqDJULhNTHUm0F7o4adZY9cOS7zgr58";
+
+#[derive(AsChangeset, Identifiable, Queryable, Selectable)]
+#[diesel(table_name = schema::users)]
+#[diesel(check_for_backend(diesel::pg::Pg))]
+pub struct RawUser {
+    pub id: i32,
+    pub name: String,
+    pub hashed_password: String,
+    pub role: String,
+}
+
+#[derive(Insertable)]
+#[diesel(table_name = schema::users)]
+struct NewRawUser<'a> {
+    pub name: &'a str,
+    pub hashed_password: &'a str,
+    pub role: String,
+}
+
+/// An API user with an argon2 password hash.
+#[derive(Clone, Debug, PartialEq)]
+pub struct User {
+    pub id: i32,
+    pub name: String,
+    pub hashed_password: String,
+    pub role: Role,
+}
+
+impl User {
+    /// Creates a new user and hashes the password with a random salt.
+    pub fn new(
+        name: String,
+        password: &str,
+        role: Role,
+    ) -> Result<Self, Error> {
+        Ok(Self {
+            id: 0,
+            name,
+            hashed_password: Self::hash_password(password)?,
+            role,
+        })
+    }
+
+    /// Creates a user which does not exist and never matches a password.
+    pub fn dummy(name: String) -> Self {
+        Self {
+            id: 0,
+            name,
+            hashed_password: DUMMY_HASH.into(),
+            role: Role::Viewer,
+        }
+    }
+
+    pub fn hash_password(password: &str) -> Result<String, Error> {
+        let mut salt = [0; 16];
+        SystemRandom::new().fill(&mut salt).map_err(|e| {
+            Error::System(format!("Could not generate salt: {e}"))
+        })?;
+        argon2::hash_encoded(
+            password.as_bytes(),
+            &salt,
+            &argon2::Config::default(),
+        )
+        .map_err(|e| Error::System(format!("Hashing password failed: {e}")))
+    }
+
+    pub fn verify_password(&self, password: &str) -> Result<bool, Error> {
+        argon2::verify_encoded(&self.hashed_password, password.as_bytes())
+            .map_err(|e| {
+                Error::System(format!("Verifying password failed: {e}"))
+            })
+    }
+
+    pub async fn all(conn: &mut AsyncPgConnection) -> Result<Vec<Self>, Error> {
+        use schema::users::dsl;
+        dsl::users
+            .order(dsl::name.asc())
+            .load::<RawUser>(conn)
+            .await
+            .map_err(Error::from)?
+            .into_iter()
+            .map(|x| x.try_into().map_err(Error::Bug))
+            .collect()
+    }
+
+    pub async fn by_name(
+        conn: &mut AsyncPgConnection,
+        name: &str,
+    ) -> Result<Self, Error> {
+        use schema::users::dsl;
+        dsl::users
+            .filter(dsl::name.eq(name))
+            .first::<RawUser>(conn)
+            .await
+            .map_err(Error::from)?
+            .try_into()
+            .map_err(Error::Bug)
+    }
+
+    /// Inserts a new user and updates its ID.
+    pub async fn insert(
+        &mut self,
+        conn: &mut AsyncPgConnection,
+    ) -> Result<(), Error> {
+        let raw = NewRawUser {
+            name: &self.name,
+            hashed_password: &self.hashed_password,
+            role: self.role.to_string(),
+        };
+
+        self.id = diesel::insert_into(schema::users::table)
+            .values(&raw)
+            .returning(schema::users::id)
+            .get_result::<i32>(conn)
+            .await
+            .map_err(|e| {
+                Error::Temporary(format!(
+                    "Inserting user {} failed: {e}",
+                    self.name
+                ))
+            })?;
+
+        Ok(())
+    }
+
+    pub async fn save_changes(
+        &self,
+        conn: &mut AsyncPgConnection,
+    ) -> Result<(), Error> {
+        use diesel_async::SaveChangesDsl;
+        let raw = RawUser {
+            id: self.id,
+            name: self.name.clone(),
+            hashed_password: self.hashed_password.clone(),
+            role: self.role.to_string(),
+        };
+
+        raw.save_changes::<RawUser>(conn).await.map_err(|e| {
+            Error::Temporary(format!(
+                "Updating user {} failed: {}",
+                self.name, e
+            ))
+        })?;
+
+        Ok(())
+    }
+
+    pub async fn delete(
+        conn: &mut AsyncPgConnection,
+        name: &str,
+    ) -> Result<(), Error> {
+        use schema::users::dsl;
+        let count = diesel::delete(dsl::users.filter(dsl::name.eq(name)))
+            .execute(conn)
+            .await
+            .map_err(|e| {
+                Error::Temporary(format!("Deleting user {name} failed: {e}"))
+            })?;
+
+        match count {
+            0 => Err(Error::NotFound),
+            _ => Ok(()),
+        }
+    }
+}
+
+impl TryFrom<RawUser> for User {
+    type Error = String;
+
+    fn try_from(input: RawUser) -> Result<Self, Self::Error> {
+        Ok(Self {
+            id: input.id,
+            name: input.name,
+            hashed_password: input.hashed_password,
+            role: input.role.parse()?,
+        })
+    }
+}
+
+#[test]
+fn test_password() {
+    let user = User::new("user".into(), "secret", Role::Operator).unwrap();
+    assert!(user.hashed_password.starts_with("$argon2"));
+    assert!(user.verify_password("secret").unwrap());
+    assert!(!user.verify_password("wrong").unwrap());
+
+    let other = User::new("other".into(), "secret", Role::Viewer).unwrap();
+    assert_ne!(user.hashed_password, other.hashed_password);
+
+    let dummy = User::dummy("dummy".into());
+    assert!(!dummy.verify_password("secret").unwrap());
+}
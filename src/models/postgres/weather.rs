@@ -16,6 +16,7 @@
     along with this program.  If not, see <https://www.gnu.org/licenses/>.
 \******************************************************************************/
 use super::{
+    history::impl_history,
     impl_timeseries, schema,
     units::{
         celsius, degree, hectopascal, meter_per_second, micrometer, millimeter,
@@ -27,11 +28,18 @@ use crate::Error;
 use chrono::{DateTime, NaiveDateTime};
 use diesel::prelude::{
     AsChangeset, ExpressionMethods, Identifiable, Insertable, Queryable,
-    Selectable,
+    QueryableByName, Selectable,
 };
 use ws6in1_proto::parser::Ws6in1Data;
 
-#[derive(AsChangeset, Identifiable, Insertable, Queryable, Selectable)]
+#[derive(
+    AsChangeset,
+    Identifiable,
+    Insertable,
+    Queryable,
+    QueryableByName,
+    Selectable,
+)]
 #[diesel(table_name = schema::weathers)]
 #[diesel(check_for_backend(diesel::pg::Pg))]
 #[diesel(primary_key(series_id, time))]
@@ -102,6 +110,41 @@ pub struct Weather {
 }
 
 impl_timeseries!(RawWeather, Weather, weathers);
+impl_history!(
+    RawWeather,
+    Weather,
+    weathers,
+    [
+        temp_in_degc_e1: INT2,
+        hum_in_e3: INT2,
+        temp_out_degc_e1: INT2,
+        hum_out_e3: INT2,
+        rain_day_um: INT4,
+        rain_act_um: INT4,
+        rain_acc_um: INT8,
+        wind_act_mms: INT4,
+        wind_gust_mms: INT4,
+        wind_dir_deg_e1: INT2,
+        baro_sea_pa: INT4,
+        baro_abs_pa: INT4,
+        uv_index_e1: INT2,
+        dew_point_degc_e1: INT2,
+        temp_x1_degc_e1: INT2,
+        hum_x1_e3: INT2,
+        temp_x2_degc_e1: INT2,
+        hum_x2_e3: INT2,
+        temp_x3_degc_e1: INT2,
+        hum_x3_e3: INT2,
+        temp_x4_degc_e1: INT2,
+        hum_x4_e3: INT2,
+        temp_x5_degc_e1: INT2,
+        hum_x5_e3: INT2,
+        temp_x6_degc_e1: INT2,
+        hum_x6_e3: INT2,
+        temp_x7_degc_e1: INT2,
+        hum_x7_e3: INT2,
+    ]
+);
 
 impl Weather {
     pub fn new(data: Ws6in1Data, rain_acc: Length) -> Self {
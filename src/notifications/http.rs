@@ -0,0 +1,180 @@
+/******************************************************************************\
+    empowerd - empowers the offline smart home
+    Copyright (C) 2019 - 2026 Max Maisel
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU Affero General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU Affero General Public License for more details.
+
+    You should have received a copy of the GNU Affero General Public License
+    along with this program.  If not, see <https://www.gnu.org/licenses/>.
+\******************************************************************************/
+//! Delivers notifications to ntfy or Gotify push servers and to webhooks.
+use super::{encode_header, Message};
+use crate::settings::HttpChannel;
+use reqwest::{header::CONTENT_TYPE, Client};
+use serde_json::json;
+use std::time::Duration;
+
+const TIMEOUT: Duration = Duration::from_secs(30);
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum HttpKind {
+    Ntfy,
+    Gotify,
+    Webhook,
+}
+
+pub struct HttpSender {
+    kind: HttpKind,
+    settings: HttpChannel,
+    client: Client,
+}
+
+impl HttpSender {
+    pub fn new(kind: HttpKind, settings: HttpChannel) -> Result<Self, String> {
+        let client = Client::builder()
+            .timeout(TIMEOUT)
+            .build()
+            .map_err(|e| format!("Creating HTTP client failed: {e}"))?;
+        Ok(Self {
+            kind,
+            settings,
+            client,
+        })
+    }
+
+    pub async fn send(&self, message: &Message) -> Result<(), String> {
+        let url = self.settings.url.trim_end_matches('/');
+        let token = self.settings.token.as_deref();
+        let request = match self.kind {
+            HttpKind::Ntfy => self
+                .client
+                .post(url)
+                .header("Title", encode_header(&message.title()))
+                .header(
+                    "Priority",
+                    match message.urgent {
+                        true => "urgent",
+                        false => "default",
+                    },
+                )
+                .header(
+                    "Tags",
+                    match message.resolved {
+                        true => "white_check_mark",
+                        false => "warning",
+                    },
+                )
+                .body(message.text.clone()),
+            HttpKind::Gotify => self
+                .client
+                .post(format!("{url}/message"))
+                .header("X-Gotify-Key", token.unwrap_or_default())
+                .header(CONTENT_TYPE, "application/json")
+                .body(
+                    json!({
+                        "title": message.title(),
+                        "message": message.text,
+                        "priority": if message.urgent { 8 } else { 5 },
+                    })
+                    .to_string(),
+                ),
+            HttpKind::Webhook => self
+                .client
+                .post(url)
+                .header(CONTENT_TYPE, "application/json")
+                .body(serde_json::to_string(message).map_err(|e| {
+                    format!("Serializing notification failed: {e}")
+                })?),
+        };
+        let request = match (self.kind, token) {
+            (HttpKind::Ntfy | HttpKind::Webhook, Some(x)) => {
+                request.bearer_auth(x)
+            }
+            _ => request,
+        };
+
+        request
+            .send()
+            .await
+            .and_then(|x| x.error_for_status())
+            .map(|_| ())
+            .map_err(|e| format!("Sending notification to {url} failed: {e}"))
+    }
+}
+
+#[tokio::test]
+async fn test_http() {
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+    };
+
+    // Local stand-in for the push server which returns the raw requests.
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let url = format!("http://{}/alarms", listener.local_addr().unwrap());
+    let server = tokio::spawn(async move {
+        let mut requests = Vec::new();
+        for _ in 0..2 {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut request = Vec::new();
+            let mut buffer = [0; 4096];
+            loop {
+                let len = stream.read(&mut buffer).await.unwrap();
+                request.extend_from_slice(&buffer[..len]);
+                let text = String::from_utf8_lossy(&request).to_lowercase();
+                let end = match text.find("\r\n\r\n") {
+                    Some(x) => x,
+                    None => continue,
+                };
+                let length = text
+                    .lines()
+                    .find_map(|x| x.strip_prefix("content-length: "))
+                    .map_or(0, |x| x.trim().parse().unwrap());
+                if request.len() >= end + 4 + length {
+                    break;
+                }
+            }
+            stream
+                .write_all(b"HTTP/1.1 200 OK\r\ncontent-length: 0\r\n\r\n")
+                .await
+                .unwrap();
+            requests.push(String::from_utf8(request).unwrap());
+        }
+        requests
+    });
+
+    let message = Message {
+        rule: "Wärmepumpe".into(),
+        text: "Defrosts too often".into(),
+        resolved: false,
+        urgent: false,
+        time: Default::default(),
+    };
+    let settings = HttpChannel {
+        url,
+        token: Some("secret".into()),
+    };
+    for kind in [HttpKind::Ntfy, HttpKind::Webhook] {
+        HttpSender::new(kind, settings.clone())
+            .unwrap()
+            .send(&message)
+            .await
+            .unwrap();
+    }
+
+    let requests = server.await.unwrap();
+    assert!(requests[0].starts_with("POST /alarms HTTP/1.1"));
+    assert!(requests[0].contains("title: =?UTF-8?B?V8Okcm1lcHVtcGU=?="));
+    assert!(requests[0].contains("authorization: Bearer secret"));
+    assert!(requests[0].ends_with("\r\n\r\nDefrosts too often"));
+    assert!(requests[1].contains(r#""rule":"Wärmepumpe""#));
+    assert!(requests[1].contains(r#""resolved":false"#));
+}
@@ -0,0 +1,516 @@
+/******************************************************************************\
+    empowerd - empowers the offline smart home
+    Copyright (C) 2019 - 2026 Max Maisel
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU Affero General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU Affero General Public License for more details.
+
+    You should have received a copy of the GNU Affero General Public License
+    along with this program.  If not, see <https://www.gnu.org/licenses/>.
+\******************************************************************************/
+//! Evaluates the notification rules and sends a message when an alarm
+//! becomes active, repeats or is resolved.
+use crate::{
+    models::{units::second, Model},
+    rest::homeassistant::reading,
+    settings::{ChannelType, NotificationType, Settings},
+    task_group::ErrorCounter,
+};
+use base64::prelude::{Engine, BASE64_STANDARD};
+use chrono::{DateTime, Local, NaiveTime, Utc};
+use serde::Serialize;
+use slog::{error, info, Logger};
+use std::{
+    collections::{BTreeMap, VecDeque},
+    sync::atomic::Ordering,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use tokio::sync::watch;
+
+pub mod http;
+pub mod smtp;
+
+pub use http::{HttpKind, HttpSender};
+pub use smtp::SmtpSender;
+
+const CHECK_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Notification about an alarm.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct Message {
+    pub rule: String,
+    pub text: String,
+    pub resolved: bool,
+    pub urgent: bool,
+    pub time: DateTime<Utc>,
+}
+
+impl Message {
+    pub fn title(&self) -> String {
+        match self.resolved {
+            true => format!("Resolved: {}", self.rule),
+            false => self.rule.clone(),
+        }
+    }
+}
+
+/// Encodes non-ASCII header values as RFC 2047 encoded word.
+fn encode_header(value: &str) -> String {
+    match value.is_ascii() {
+        true => value.into(),
+        false => format!("=?UTF-8?B?{}?=", BASE64_STANDARD.encode(value)),
+    }
+}
+
+fn parse_time(time: &str) -> Result<NaiveTime, String> {
+    NaiveTime::parse_from_str(time, "%H:%M")
+        .map_err(|e| format!("Invalid time '{time}': {e}"))
+}
+
+pub enum Sender {
+    Smtp(SmtpSender),
+    Http(HttpSender),
+}
+
+struct Channel {
+    name: String,
+    quiet_hours: Option<(NaiveTime, NaiveTime)>,
+    sender: Sender,
+    /// Latest message per rule held back during quiet hours.
+    pending: BTreeMap<String, Message>,
+}
+
+impl Channel {
+    fn is_quiet(&self, now: NaiveTime) -> bool {
+        match self.quiet_hours {
+            Some((start, end)) if start <= end => now >= start && now < end,
+            Some((start, end)) => now >= start || now < end,
+            None => false,
+        }
+    }
+
+    async fn send(&self, message: &Message) -> Result<(), String> {
+        match &self.sender {
+            Sender::Smtp(x) => x.send(message).await,
+            Sender::Http(x) => x.send(message).await,
+        }
+    }
+}
+
+/// Keeps the samples of a growing value within a time window. The oldest
+/// sample is kept as baseline if it is older than the window.
+#[derive(Default)]
+struct Window {
+    samples: VecDeque<(f64, f64)>,
+}
+
+impl Window {
+    /// Adds a sample and returns the increase within the window.
+    fn increase(&mut self, now: f64, value: f64, window: f64) -> f64 {
+        self.samples.push_back((now, value));
+        while self.samples.len() > 1 && self.samples[1].0 <= now - window {
+            self.samples.pop_front();
+        }
+        value - self.samples.front().map_or(value, |x| x.1)
+    }
+}
+
+enum Condition {
+    Threshold {
+        node: String,
+        field: String,
+        above: Option<f64>,
+        below: Option<f64>,
+    },
+    Increase {
+        node: String,
+        field: String,
+        above: f64,
+        window: f64,
+        samples: Window,
+    },
+    Stale {
+        node: String,
+        max_age: f64,
+        started: f64,
+    },
+    Errors {
+        node: String,
+        counter: ErrorCounter,
+        count: u64,
+        window: f64,
+        samples: Window,
+    },
+}
+
+impl Condition {
+    /// Returns the description of the alarm if it is active.
+    fn check(
+        &mut self,
+        now: f64,
+        nodes: &BTreeMap<String, Model>,
+    ) -> Option<String> {
+        match self {
+            Condition::Threshold {
+                node,
+                field,
+                above,
+                below,
+            } => {
+                let value = reading(nodes.get(node)?, field)?;
+                if matches!(above, Some(x) if value > *x) {
+                    Some(format!(
+                        "{field} of {node} is {value:.1}, above {}",
+                        above.unwrap_or_default()
+                    ))
+                } else if matches!(below, Some(x) if value < *x) {
+                    Some(format!(
+                        "{field} of {node} is {value:.1}, below {}",
+                        below.unwrap_or_default()
+                    ))
+                } else {
+                    None
+                }
+            }
+            Condition::Increase {
+                node,
+                field,
+                above,
+                window,
+                samples,
+            } => {
+                let value = reading(nodes.get(node)?, field)?;
+                let increase = samples.increase(now, value, *window);
+                (increase > *above).then(|| {
+                    format!(
+                        "{field} of {node} increased by {increase:.1} \
+                        within {window}s, above {above}"
+                    )
+                })
+            }
+            Condition::Stale {
+                node,
+                max_age,
+                started,
+            } => {
+                let time = nodes
+                    .get(node)
+                    .and_then(|x| x.time())
+                    .map_or(*started, |x| x.get::<second>());
+                let age = now - time;
+                (age > *max_age)
+                    .then(|| format!("{node} has no new data for {:.0}s", age))
+            }
+            Condition::Errors {
+                node,
+                counter,
+                count,
+                window,
+                samples,
+            } => {
+                let value = counter.load(Ordering::Relaxed) as f64;
+                let errors = samples.increase(now, value, *window);
+                (errors >= *count as f64).then(|| {
+                    format!("{node} reported {errors} errors within {window}s")
+                })
+            }
+        }
+    }
+}
+
+struct Rule {
+    name: String,
+    channels: Vec<usize>,
+    repeat: Option<f64>,
+    urgent: bool,
+    condition: Condition,
+    active_since: Option<f64>,
+    last_sent: f64,
+}
+
+impl Rule {
+    /// Updates the alarm state. Returns a message if the alarm became
+    /// active, is due for repetition or was resolved.
+    fn update(
+        &mut self,
+        now: f64,
+        nodes: &BTreeMap<String, Model>,
+    ) -> Option<Message> {
+        let text = match (self.condition.check(now, nodes), self.active_since) {
+            (Some(text), None) => {
+                self.active_since = Some(now);
+                text
+            }
+            (Some(text), Some(_)) => match self.repeat {
+                Some(x) if now - self.last_sent >= x => text,
+                _ => return None,
+            },
+            (None, Some(since)) => {
+                self.active_since = None;
+                format!("Alarm was active for {:.0}s", now - since)
+            }
+            (None, None) => return None,
+        };
+        self.last_sent = now;
+
+        let time = UNIX_EPOCH + Duration::from_secs_f64(now.max(0.0));
+        Some(Message {
+            rule: self.name.clone(),
+            text,
+            resolved: self.active_since.is_none(),
+            urgent: self.urgent,
+            time: time.into(),
+        })
+    }
+}
+
+pub struct Notifier {
+    logger: Logger,
+    channels: Vec<Channel>,
+    rules: Vec<Rule>,
+    nodes: BTreeMap<String, watch::Receiver<Model>>,
+}
+
+fn now() -> f64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs_f64()
+}
+
+impl Notifier {
+    pub fn new(
+        logger: Logger,
+        settings: &Settings,
+        nodes: BTreeMap<String, watch::Receiver<Model>>,
+        errors: &BTreeMap<String, ErrorCounter>,
+    ) -> Result<Self, String> {
+        let mut channels = Vec::new();
+        for channel in &settings.notification_channels {
+            let quiet_hours = match &channel.quiet_hours {
+                Some(x) => Some((parse_time(&x.start)?, parse_time(&x.end)?)),
+                None => None,
+            };
+            let sender = match &channel.variant {
+                ChannelType::Smtp(x) => {
+                    Sender::Smtp(SmtpSender::new(x.clone()))
+                }
+                ChannelType::Ntfy(x) => {
+                    Sender::Http(HttpSender::new(HttpKind::Ntfy, x.clone())?)
+                }
+                ChannelType::Gotify(x) => {
+                    Sender::Http(HttpSender::new(HttpKind::Gotify, x.clone())?)
+                }
+                ChannelType::Webhook(x) => {
+                    Sender::Http(HttpSender::new(HttpKind::Webhook, x.clone())?)
+                }
+            };
+            channels.push(Channel {
+                name: channel.name.clone(),
+                quiet_hours,
+                sender,
+                pending: BTreeMap::new(),
+            });
+        }
+
+        let node = |name: &String| match nodes.contains_key(name) {
+            true => Ok(name.clone()),
+            false => Err(format!("Notification uses unknown node '{name}'")),
+        };
+        let started = now();
+        let mut rules = Vec::new();
+        for notification in &settings.notifications {
+            let condition = match &notification.variant {
+                NotificationType::Threshold(x) => Condition::Threshold {
+                    node: node(&x.node)?,
+                    field: x.field.clone(),
+                    above: x.above,
+                    below: x.below,
+                },
+                NotificationType::Increase(x) => Condition::Increase {
+                    node: node(&x.node)?,
+                    field: x.field.clone(),
+                    above: x.above,
+                    window: x.window as f64,
+                    samples: Window::default(),
+                },
+                NotificationType::Stale(x) => Condition::Stale {
+                    node: node(&x.node)?,
+                    max_age: x.max_age as f64,
+                    started,
+                },
+                NotificationType::Errors(x) => Condition::Errors {
+                    node: x.node.clone(),
+                    counter: errors.get(&x.node).cloned().ok_or_else(|| {
+                        format!("Notification uses unknown node '{}'", x.node)
+                    })?,
+                    count: x.count,
+                    window: x.window as f64,
+                    samples: Window::default(),
+                },
+            };
+            let channels = notification
+                .channels
+                .iter()
+                .filter_map(|x| channels.iter().position(|y| &y.name == x))
+                .collect();
+            rules.push(Rule {
+                name: notification.name.clone(),
+                channels,
+                repeat: notification.repeat.map(|x| x as f64),
+                urgent: notification.urgent,
+                condition,
+                active_since: None,
+                last_sent: 0.0,
+            });
+        }
+
+        Ok(Self {
+            logger,
+            channels,
+            rules,
+            nodes,
+        })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rules.is_empty()
+    }
+
+    async fn deliver(&self, channel: &Channel, message: &Message) {
+        match channel.send(message).await {
+            Ok(()) => info!(
+                self.logger,
+                "Sent notification '{}' to {}",
+                message.title(),
+                channel.name
+            ),
+            Err(e) => error!(
+                self.logger,
+                "Sending notification to {} failed: {e}", channel.name
+            ),
+        }
+    }
+
+    async fn check(&mut self, now: f64, local: NaiveTime) {
+        let nodes = self
+            .nodes
+            .iter()
+            .map(|(name, rx)| (name.clone(), rx.borrow().clone()))
+            .collect();
+
+        let mut outgoing = Vec::new();
+        for rule in &mut self.rules {
+            if let Some(message) = rule.update(now, &nodes) {
+                for channel in &rule.channels {
+                    outgoing.push((*channel, message.clone()));
+                }
+            }
+        }
+
+        for (idx, message) in outgoing {
+            let channel = &mut self.channels[idx];
+            if channel.is_quiet(local) && !message.urgent {
+                channel.pending.insert(message.rule.clone(), message);
+                continue;
+            }
+            self.deliver(&self.channels[idx], &message).await;
+        }
+
+        for idx in 0..self.channels.len() {
+            let channel = &mut self.channels[idx];
+            if channel.pending.is_empty() || channel.is_quiet(local) {
+                continue;
+            }
+            let pending = std::mem::take(&mut channel.pending);
+            for message in pending.values() {
+                self.deliver(&self.channels[idx], message).await;
+            }
+        }
+    }
+
+    pub async fn run(mut self) {
+        let mut interval = tokio::time::interval(CHECK_INTERVAL);
+        loop {
+            interval.tick().await;
+            self.check(now(), Local::now().time()).await;
+        }
+    }
+}
+
+#[test]
+fn test_rules() {
+    use crate::models::{
+        units::{watt, watt_hour, Energy, Power, Time},
+        Battery,
+    };
+    use std::sync::{atomic::AtomicU64, Arc};
+
+    let battery = |time: f64, charge: f64| {
+        Model::Battery(Battery {
+            time: Time::new::<second>(time),
+            charge: Energy::new::<watt_hour>(charge),
+            energy_in: Energy::new::<watt_hour>(0.0),
+            energy_out: Energy::new::<watt_hour>(0.0),
+            power: Power::new::<watt>(0.0),
+        })
+    };
+    let rule = |condition| Rule {
+        name: "Battery low".into(),
+        channels: Vec::new(),
+        repeat: Some(60.0),
+        urgent: false,
+        condition,
+        active_since: None,
+        last_sent: 0.0,
+    };
+
+    let mut threshold = rule(Condition::Threshold {
+        node: "Battery".into(),
+        field: "charge".into(),
+        above: None,
+        below: Some(500.0),
+    });
+    let mut nodes = BTreeMap::from([("Battery".into(), battery(0.0, 1000.0))]);
+    assert_eq!(None, threshold.update(0.0, &nodes));
+    nodes.insert("Battery".into(), battery(10.0, 400.0));
+    let message = threshold.update(10.0, &nodes).unwrap();
+    assert_eq!("charge of Battery is 400.0, below 500", message.text);
+    assert!(!message.resolved);
+    // Active alarms are only repeated after the repeat interval.
+    assert_eq!(None, threshold.update(20.0, &nodes));
+    assert!(threshold.update(70.0, &nodes).is_some());
+    nodes.insert("Battery".into(), battery(80.0, 600.0));
+    let message = threshold.update(80.0, &nodes).unwrap();
+    assert!(message.resolved);
+    assert_eq!("Resolved: Battery low", message.title());
+
+    let mut stale = rule(Condition::Stale {
+        node: "Battery".into(),
+        max_age: 60.0,
+        started: 0.0,
+    });
+    assert_eq!(None, stale.update(100.0, &nodes));
+    assert!(stale.update(150.0, &nodes).is_some());
+
+    let counter = Arc::new(AtomicU64::new(5));
+    let mut errors = rule(Condition::Errors {
+        node: "Battery".into(),
+        counter: counter.clone(),
+        count: 2,
+        window: 100.0,
+        samples: Window::default(),
+    });
+    assert_eq!(None, errors.update(0.0, &nodes));
+    counter.fetch_add(2, Ordering::Relaxed);
+    assert!(errors.update(50.0, &nodes).is_some());
+    // The errors left the window.
+    assert!(errors.update(160.0, &nodes).unwrap().resolved);
+}
@@ -0,0 +1,282 @@
+/******************************************************************************\
+    empowerd - empowers the offline smart home
+    Copyright (C) 2019 - 2026 Max Maisel
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU Affero General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU Affero General Public License for more details.
+
+    You should have received a copy of the GNU Affero General Public License
+    along with this program.  If not, see <https://www.gnu.org/licenses/>.
+\******************************************************************************/
+//! Minimal SMTP client which delivers notifications as plain text e-mail.
+use super::{encode_header, Message};
+use crate::settings::{SmtpChannel, SmtpTls};
+use base64::prelude::{Engine, BASE64_STANDARD};
+use std::{sync::Arc, time::Duration};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt, BufReader},
+    net::TcpStream,
+};
+use tokio_rustls::{
+    client::TlsStream,
+    rustls::{
+        crypto::ring, pki_types::ServerName, ClientConfig, RootCertStore,
+    },
+    TlsConnector,
+};
+
+const TIMEOUT: Duration = Duration::from_secs(30);
+
+struct Session<S> {
+    stream: BufReader<S>,
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> Session<S> {
+    fn new(stream: S) -> Self {
+        Self {
+            stream: BufReader::new(stream),
+        }
+    }
+
+    /// Reads a possibly multi-line reply and checks its status code.
+    async fn expect(&mut self, code: u16) -> Result<(), String> {
+        loop {
+            let mut line = String::new();
+            self.stream
+                .read_line(&mut line)
+                .await
+                .map_err(|e| format!("Reading SMTP reply failed: {e}"))?;
+            let status = line.get(0..3).and_then(|x| x.parse::<u16>().ok());
+            if status != Some(code) {
+                return Err(format!("Unexpected SMTP reply: {}", line.trim()));
+            }
+            // Continuation lines have a '-' after the status code.
+            if line.as_bytes().get(3) != Some(&b'-') {
+                return Ok(());
+            }
+        }
+    }
+
+    async fn write(&mut self, data: &str) -> Result<(), String> {
+        self.stream
+            .get_mut()
+            .write_all(data.as_bytes())
+            .await
+            .map_err(|e| format!("Writing SMTP command failed: {e}"))
+    }
+
+    async fn command(
+        &mut self,
+        command: &str,
+        code: u16,
+    ) -> Result<(), String> {
+        self.write(&format!("{command}\r\n")).await?;
+        self.expect(code).await
+    }
+
+    async fn deliver(
+        &mut self,
+        settings: &SmtpChannel,
+        message: &Message,
+    ) -> Result<(), String> {
+        self.command("EHLO empowerd", 250).await?;
+        if let Some(username) = &settings.username {
+            let password = settings.password.as_deref().unwrap_or_default();
+            let credentials =
+                BASE64_STANDARD.encode(format!("\0{username}\0{password}"));
+            self.command(&format!("AUTH PLAIN {credentials}"), 235)
+                .await?;
+        }
+        self.command(&format!("MAIL FROM:<{}>", settings.from), 250)
+            .await?;
+        for to in &settings.to {
+            self.command(&format!("RCPT TO:<{to}>"), 250).await?;
+        }
+        self.command("DATA", 354).await?;
+        self.write(&format_mail(settings, message)).await?;
+        self.command(".", 250).await?;
+        self.command("QUIT", 221).await
+    }
+}
+
+/// Formats the mail headers and body. Lines starting with a dot are
+/// escaped, so they do not end the DATA command.
+fn format_mail(settings: &SmtpChannel, message: &Message) -> String {
+    let mut mail = format!(
+        "From: {}\r\nTo: {}\r\nSubject: {}\r\nDate: {}\r\n\
+        MIME-Version: 1.0\r\nContent-Type: text/plain; charset=utf-8\r\n\
+        Content-Transfer-Encoding: 8bit\r\n\r\n",
+        settings.from,
+        settings.to.join(", "),
+        encode_header(&format!("[empowerd] {}", message.title())),
+        message.time.to_rfc2822(),
+    );
+    for line in message.text.lines() {
+        if line.starts_with('.') {
+            mail.push('.');
+        }
+        mail.push_str(line);
+        mail.push_str("\r\n");
+    }
+    mail
+}
+
+/// Creates a TLS config which verifies servers with the root
+/// certificates of the system.
+fn tls_config() -> Result<Arc<ClientConfig>, String> {
+    let mut roots = RootCertStore::empty();
+    let native = rustls_native_certs::load_native_certs();
+    let (added, _) = roots.add_parsable_certificates(native.certs);
+    if added == 0 {
+        return Err(format!(
+            "Loading root certificates failed: {:?}",
+            native.errors
+        ));
+    }
+
+    let config =
+        ClientConfig::builder_with_provider(Arc::new(ring::default_provider()))
+            .with_safe_default_protocol_versions()
+            .map_err(|e| format!("Creating TLS config failed: {e}"))?
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+    Ok(Arc::new(config))
+}
+
+pub struct SmtpSender {
+    settings: SmtpChannel,
+}
+
+impl SmtpSender {
+    pub fn new(settings: SmtpChannel) -> Self {
+        Self { settings }
+    }
+
+    async fn tls(
+        &self,
+        stream: TcpStream,
+    ) -> Result<TlsStream<TcpStream>, String> {
+        let name = ServerName::try_from(self.settings.host.clone())
+            .map_err(|e| format!("Invalid server name: {e}"))?;
+        TlsConnector::from(tls_config()?)
+            .connect(name, stream)
+            .await
+            .map_err(|e| format!("TLS handshake failed: {e}"))
+    }
+
+    async fn deliver(&self, message: &Message) -> Result<(), String> {
+        let stream = TcpStream::connect((
+            self.settings.host.as_str(),
+            self.settings.port,
+        ))
+        .await
+        .map_err(|e| {
+            format!(
+                "Connecting to {}:{} failed: {e}",
+                self.settings.host, self.settings.port
+            )
+        })?;
+
+        match self.settings.tls {
+            SmtpTls::None => {
+                let mut session = Session::new(stream);
+                session.expect(220).await?;
+                session.deliver(&self.settings, message).await
+            }
+            SmtpTls::Tls => {
+                let mut session = Session::new(self.tls(stream).await?);
+                session.expect(220).await?;
+                session.deliver(&self.settings, message).await
+            }
+            SmtpTls::StartTls => {
+                let mut session = Session::new(stream);
+                session.expect(220).await?;
+                session.command("EHLO empowerd", 250).await?;
+                session.command("STARTTLS", 220).await?;
+                let stream = session.stream.into_inner();
+                let mut session = Session::new(self.tls(stream).await?);
+                session.deliver(&self.settings, message).await
+            }
+        }
+    }
+
+    pub async fn send(&self, message: &Message) -> Result<(), String> {
+        match tokio::time::timeout(TIMEOUT, self.deliver(message)).await {
+            Ok(x) => x,
+            Err(_) => Err("SMTP server timed out".into()),
+        }
+    }
+}
+
+#[tokio::test]
+async fn test_smtp() {
+    use tokio::net::TcpListener;
+
+    // Local stand-in for an SMTP server which records the commands.
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let port = listener.local_addr().unwrap().port();
+    let server = tokio::spawn(async move {
+        let (stream, _) = listener.accept().await.unwrap();
+        let mut stream = BufReader::new(stream);
+        let mut received = Vec::new();
+        let mut data = false;
+        stream.get_mut().write_all(b"220 test\r\n").await.unwrap();
+        loop {
+            let mut line = String::new();
+            if stream.read_line(&mut line).await.unwrap() == 0 {
+                return received;
+            }
+            let line = line.trim_end().to_string();
+            let reply: &[u8] = match line.as_str() {
+                "." if data => {
+                    data = false;
+                    b"250 queued\r\n"
+                }
+                _ if data => b"",
+                "EHLO empowerd" => b"250-test\r\n250 AUTH PLAIN\r\n",
+                "DATA" => {
+                    data = true;
+                    b"354 go ahead\r\n"
+                }
+                "QUIT" => b"221 bye\r\n",
+                x if x.starts_with("AUTH PLAIN") => b"235 ok\r\n",
+                _ => b"250 ok\r\n",
+            };
+            received.push(line);
+            stream.get_mut().write_all(reply).await.unwrap();
+        }
+    });
+
+    let sender = SmtpSender::new(SmtpChannel {
+        host: "127.0.0.1".into(),
+        port,
+        tls: SmtpTls::None,
+        username: Some("user".into()),
+        password: Some("secret".into()),
+        from: "empowerd@example.com".into(),
+        to: vec!["a@example.com".into(), "b@example.com".into()],
+    });
+    let message = Message {
+        rule: "Battery low".into(),
+        text: "Charge is low\n.hidden".into(),
+        resolved: false,
+        urgent: true,
+        time: Default::default(),
+    };
+    sender.send(&message).await.unwrap();
+
+    let received = server.await.unwrap();
+    assert_eq!("AUTH PLAIN AHVzZXIAc2VjcmV0", received[1]);
+    assert_eq!("MAIL FROM:<empowerd@example.com>", received[2]);
+    assert_eq!("RCPT TO:<b@example.com>", received[4]);
+    assert!(received.contains(&"Subject: [empowerd] Battery low".into()));
+    assert!(received.contains(&"..hidden".into()));
+    assert_eq!("QUIT", received[received.len() - 1]);
+}
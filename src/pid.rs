@@ -0,0 +1,256 @@
+/******************************************************************************\
+    empowerd - empowers the offline smart home
+    Copyright (C) 2019 - 2026 Max Maisel
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU Affero General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU Affero General Public License for more details.
+
+    You should have received a copy of the GNU Affero General Public License
+    along with this program.  If not, see <https://www.gnu.org/licenses/>.
+\******************************************************************************/
+use crate::models::units::{ratio, second, Time};
+
+/// PID controller with output clamping, integrator anti-windup,
+/// filtered derivative on measurement and bumpless transfer.
+#[derive(Clone, Debug)]
+pub struct Pid<T> {
+    kp: f64,
+    // Integral gain per second.
+    ki: Option<f64>,
+    td: Option<Time>,
+    tf: Time,
+    min: T,
+    max: T,
+    integral: T,
+    derivative: T,
+    last_measurement: Option<T>,
+    last_time: Option<Time>,
+}
+
+impl<T> Pid<T>
+where
+    T: Copy
+        + std::cmp::PartialOrd
+        + std::ops::Add<Output = T>
+        + std::ops::Sub<Output = T>
+        + std::ops::Mul<f64, Output = T>,
+{
+    /// Creates a proportional controller with gain "kp" whose output
+    /// is limited between "min" and "max".
+    pub fn new(kp: f64, min: T, max: T) -> Self {
+        Self {
+            kp,
+            ki: None,
+            td: None,
+            tf: Time::new::<second>(0.0),
+            min,
+            max,
+            integral: min * 0.0,
+            derivative: min * 0.0,
+            last_measurement: None,
+            last_time: None,
+        }
+    }
+
+    /// Adds an integral part with integral time "ti".
+    pub fn integral(mut self, ti: Time) -> Self {
+        self.ki = Some(self.kp / ti.get::<second>());
+        self
+    }
+
+    /// Adds an integral part with gain "ki" per second. Unlike
+    /// "integral", this also works without a proportional part.
+    pub fn integral_gain(mut self, ki: f64) -> Self {
+        self.ki = Some(ki);
+        self
+    }
+
+    /// Adds a derivative part with derivative time "td" which is
+    /// filtered with a PT1 element with time constant "tf".
+    pub fn derivative(mut self, td: Time, tf: Time) -> Self {
+        self.td = Some(td);
+        self.tf = tf;
+        self
+    }
+
+    fn clamp(&self, value: T) -> T {
+        if value > self.max {
+            self.max
+        } else if value < self.min {
+            self.min
+        } else {
+            value
+        }
+    }
+
+    pub fn process(&mut self, setpoint: T, measurement: T, time: Time) -> T {
+        let delta_t = match self.last_time {
+            Some(x) if time > x => time - x,
+            _ => time * 0.0,
+        };
+        self.last_time = Some(time);
+
+        let error = setpoint - measurement;
+        let proportional = error * self.kp;
+
+        // The derivative is calculated on the measurement to avoid
+        // output spikes on setpoint changes.
+        if let (Some(td), Some(last)) = (self.td, self.last_measurement) {
+            if delta_t.value > 0.0 {
+                let raw = (last - measurement)
+                    * (self.kp * (td / delta_t).get::<ratio>());
+                let alpha = (delta_t / (self.tf + delta_t)).get::<ratio>();
+                self.derivative =
+                    self.derivative + (raw - self.derivative) * alpha;
+            }
+        }
+        self.last_measurement = Some(measurement);
+
+        if let Some(ki) = self.ki {
+            let integral =
+                self.integral + error * (ki * delta_t.get::<second>());
+            // Limit the integrator so that the total output stays within
+            // bounds. This stops windup while the output is saturated.
+            let min = self.min - proportional - self.derivative;
+            let max = self.max - proportional - self.derivative;
+            self.integral = if integral > max {
+                max
+            } else if integral < min {
+                min
+            } else {
+                integral
+            };
+        }
+
+        self.clamp(proportional + self.integral + self.derivative)
+    }
+
+    /// Follows an externally controlled output, e.g. during manual
+    /// control, so that the next call to "process" continues bumpless
+    /// from this output. Without integral part, the output only depends
+    /// on the current error.
+    pub fn track(
+        &mut self,
+        output: T,
+        setpoint: T,
+        measurement: T,
+        time: Time,
+    ) {
+        let proportional = (setpoint - measurement) * self.kp;
+        self.derivative = output * 0.0;
+        if self.ki.is_some() {
+            self.integral = self.clamp(output) - proportional;
+        }
+        self.last_measurement = Some(measurement);
+        self.last_time = Some(time);
+    }
+
+    /// Restarts the controller from the given output without knowing
+    /// the current measurement.
+    pub fn reset(&mut self, output: T) {
+        self.derivative = output * 0.0;
+        if self.ki.is_some() {
+            self.integral = self.clamp(output);
+        }
+        self.last_measurement = None;
+        self.last_time = None;
+    }
+}
+
+#[cfg(test)]
+use crate::models::units::{watt, Power};
+
+#[cfg(test)]
+fn seconds(x: f64) -> Time {
+    Time::new::<second>(x)
+}
+
+#[cfg(test)]
+fn watts(x: f64) -> Power {
+    Power::new::<watt>(x)
+}
+
+#[test]
+fn test_proportional() {
+    let mut pid = Pid::new(0.5, -10.0, 10.0);
+
+    assert_eq!(2.0, pid.process(4.0, 0.0, seconds(0.0)));
+    assert_eq!(-1.0, pid.process(0.0, 2.0, seconds(1.0)));
+    assert_eq!(10.0, pid.process(100.0, 0.0, seconds(2.0)), "Not clamped");
+    assert_eq!(-10.0, pid.process(-100.0, 0.0, seconds(3.0)), "Not clamped");
+}
+
+#[test]
+fn test_proportional_reset() {
+    let mut pid = Pid::new(0.5, -10.0, 10.0);
+
+    pid.reset(8.0);
+    assert_eq!(2.0, pid.process(4.0, 0.0, seconds(0.0)), "Reset was kept");
+    pid.track(-6.0, 0.0, 2.0, seconds(1.0));
+    assert_eq!(1.0, pid.process(2.0, 0.0, seconds(2.0)), "Track was kept");
+}
+
+#[test]
+fn test_integral_anti_windup() {
+    let mut pid =
+        Pid::new(1.0, watts(0.0), watts(1000.0)).integral(seconds(2.0));
+
+    let output = pid.process(watts(100.0), watts(0.0), seconds(0.0));
+    assert_eq!(watts(100.0), output, "Integrated without time step");
+    let output = pid.process(watts(100.0), watts(0.0), seconds(1.0));
+    assert_eq!(watts(150.0), output, "Wrong integral part");
+
+    // Saturate the output for a long time.
+    for i in 2..1000 {
+        pid.process(watts(5000.0), watts(0.0), seconds(i as f64));
+    }
+    let output = pid.process(watts(0.0), watts(100.0), seconds(1000.0));
+    assert!(
+        output < watts(1000.0),
+        "Integrator wound up, output is {:?}",
+        output
+    );
+}
+
+#[test]
+fn test_derivative_filter() {
+    let mut pid =
+        Pid::new(1.0, -100.0, 100.0).derivative(seconds(1.0), seconds(1.0));
+
+    assert_eq!(0.0, pid.process(0.0, 0.0, seconds(0.0)));
+    // Setpoint changes do not cause a derivative kick.
+    assert_eq!(10.0, pid.process(10.0, 0.0, seconds(1.0)));
+
+    // Measurement step: raw derivative is -10, filtered by half.
+    let output = pid.process(10.0, 10.0, seconds(2.0));
+    assert!(
+        (output - -5.0).abs() < 1e-9,
+        "Wrong filtered output {output}"
+    );
+    let output = pid.process(10.0, 10.0, seconds(3.0));
+    assert!(
+        (output - -2.5).abs() < 1e-9,
+        "Derivative did not decay {output}"
+    );
+}
+
+#[test]
+fn test_bumpless_transfer() {
+    let mut pid =
+        Pid::new(0.5, watts(0.0), watts(1000.0)).integral(seconds(10.0));
+
+    pid.track(watts(600.0), watts(0.0), watts(-200.0), seconds(0.0));
+    let output = pid.process(watts(0.0), watts(-200.0), seconds(0.0));
+    assert_eq!(watts(600.0), output, "Transfer was not bumpless");
+
+    pid.reset(watts(300.0));
+    let output = pid.process(watts(0.0), watts(0.0), seconds(5.0));
+    assert_eq!(watts(300.0), output, "Reset was ignored");
+}
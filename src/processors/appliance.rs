@@ -15,20 +15,22 @@
     You should have received a copy of the GNU Affero General Public License
     along with this program.  If not, see <https://www.gnu.org/licenses/>.
 \******************************************************************************/
-use super::ProcessorBase;
+use super::{Overridable, ProcessorBase, StateStore};
 use crate::{
+    clock,
     models::{
-        units::{second, watt, Abbreviation, Power},
+        units::{second, watt, Abbreviation, Power, Time},
         Model,
     },
+    pid::Pid,
     seasonal::Seasonal,
     sinks::ArcSink,
     task_group::TaskResult,
     tri_state::TriState,
     Error,
 };
-use slog::{debug, warn, Logger};
-use std::time::Duration;
+use slog::{debug, info, warn, Logger};
+use std::{collections::BTreeMap, time::Duration};
 use tokio::sync::{mpsc, oneshot, watch};
 use tokio::time;
 
@@ -42,11 +44,16 @@ enum State {
 pub enum Command {
     SetForceOnOff {
         force_on_off: TriState,
+        /// False for temporary changes like load shedding, which must not
+        /// survive a restart.
+        persist: bool,
         resp: oneshot::Sender<()>,
     },
     GetForceOnOff {
-        resp: oneshot::Sender<TriState>,
+        resp: oneshot::Sender<Overridable<TriState>>,
     },
+    /// Returns to automatic mode.
+    ResetForceOnOff { resp: oneshot::Sender<TriState> },
 }
 
 pub struct ApplianceProcessor {
@@ -61,8 +68,12 @@ pub struct ApplianceProcessor {
     last_target_power: Power,
     last_appliance_power: Power,
     state: State,
-    force_on_off: TriState,
+    force_on_off: Overridable<TriState>,
+    control_state: StateStore,
     seasonal: Option<Seasonal>,
+    controller: Option<Pid<Power>>,
+    input_timeout: Duration,
+    failed: bool,
 }
 
 impl ApplianceProcessor {
@@ -75,6 +86,9 @@ impl ApplianceProcessor {
         appliance_output: ArcSink,
         retransmit_interval: Duration,
         seasonal: Option<Seasonal>,
+        controller: Option<Pid<Power>>,
+        input_timeout: Duration,
+        control_state: StateStore,
     ) -> Self {
         Self {
             base,
@@ -88,8 +102,12 @@ impl ApplianceProcessor {
             last_target_power: Power::new::<watt>(0.0),
             last_appliance_power: Power::new::<watt>(0.0),
             state: State::Off,
-            force_on_off: TriState::Auto,
+            force_on_off: Overridable::new(TriState::Auto),
+            control_state,
             seasonal,
+            controller,
+            input_timeout,
+            failed: false,
         }
     }
 
@@ -100,18 +118,24 @@ impl ApplianceProcessor {
     pub fn validate_appliance(appliance: &ArcSink) -> bool {
         matches!(
             appliance,
-            ArcSink::KeContact(_) | ArcSink::LambdaHeatPump(_)
+            ArcSink::KeContact(_)
+                | ArcSink::LambdaHeatPump(_)
+                | ArcSink::Simulated(_)
         )
     }
 
     pub async fn run(&mut self) -> TaskResult {
+        if let Some(values) = self.control_state.restore().await {
+            self.restore(&values);
+        }
+
         tokio::select! {
             _ = self.base.canceled.changed() => {
                 return Err(Error::Canceled(self.base.name.clone()));
             }
             x = self.command_input.recv() => {
                 if let Some(command) = x {
-                    if let Err(e) = self.handle_command(command) {
+                    if let Err(e) = self.handle_command(command).await {
                         return Err(Error::Bug(e));
                     }
                 }
@@ -137,8 +161,15 @@ impl ApplianceProcessor {
                     self.last_appliance_power
                 ).await.map(|_| ());
             }
+            _ = time::sleep(self.input_timeout) => {
+                return self.check_power_input().await.map(|_| ());
+            }
         };
 
+        if self.check_power_input().await? {
+            return Ok(());
+        }
+
         let mut available_power = match *self.power_input.borrow() {
             Model::AvailablePower(ref x) => x.clone(),
             Model::None => return Ok(()),
@@ -191,13 +222,24 @@ impl ApplianceProcessor {
             None => Power::new::<watt>(0.0),
         };
 
-        let (new_state, output_power, target_power) = Self::calc_power(
-            self.force_on_off,
+        let (new_state, output_power, mut target_power) = Self::calc_power(
+            self.force_on_off.get(),
             self.state,
             available_power.power,
             appliance.power,
             seasonal_correction,
         );
+        if let Some(controller) = &mut self.controller {
+            target_power = Self::calc_closed_loop_power(
+                controller,
+                self.force_on_off.get() == TriState::Auto
+                    && self.state == State::On
+                    && new_state == State::On,
+                available_power.power + seasonal_correction,
+                target_power,
+                available_power.time,
+            );
+        }
 
         Self::set_output(&self.appliance_output, target_power, appliance.power)
             .await?;
@@ -229,6 +271,39 @@ impl ApplianceProcessor {
         Ok(())
     }
 
+    /// Switches the appliance off while the power input is stale, unless
+    /// it is forced on. Returns true in fail-safe.
+    async fn check_power_input(&mut self) -> Result<bool, Error> {
+        let stale = super::is_stale(
+            &self.power_input.borrow(),
+            clock::now().timestamp() as f64,
+            self.input_timeout,
+        );
+
+        if stale && !self.failed {
+            warn!(
+                self.base.logger,
+                "Power input is silent, entering fail-safe"
+            );
+            if self.force_on_off.get() != TriState::On {
+                let zero = Power::new::<watt>(0.0);
+                Self::set_output(
+                    &self.appliance_output,
+                    zero,
+                    self.last_appliance_power,
+                )
+                .await?;
+                self.last_target_power = zero;
+                self.state = State::Off;
+            }
+            self.failed = true;
+        } else if !stale && self.failed {
+            warn!(self.base.logger, "Power input is back, leaving fail-safe");
+            self.failed = false;
+        }
+        Ok(stale)
+    }
+
     fn calc_power(
         force_on_off: TriState,
         state: State,
@@ -286,6 +361,27 @@ impl ApplianceProcessor {
         }
     }
 
+    /// Replaces the open loop appliance power by the controller output
+    /// while the appliance is running automatically. Otherwise the
+    /// controller follows the open loop power to allow bumpless transfer.
+    fn calc_closed_loop_power(
+        controller: &mut Pid<Power>,
+        closed_loop: bool,
+        corrected_power: Power,
+        open_loop_power: Power,
+        time: Time,
+    ) -> Power {
+        // Control the available power to zero, so that all excess power
+        // is consumed by the appliance.
+        let setpoint = Power::new::<watt>(0.0);
+        if closed_loop {
+            controller.process(setpoint, -corrected_power, time)
+        } else {
+            controller.track(open_loop_power, setpoint, -corrected_power, time);
+            open_loop_power
+        }
+    }
+
     async fn set_output(
         output: &ArcSink,
         target_power: Power,
@@ -300,14 +396,34 @@ impl ApplianceProcessor {
                 .set_available_power(target_power)
                 .await
                 .map_err(Error::Temporary),
+            ArcSink::Simulated(appliance) => appliance
+                .set_available_power(target_power)
+                .map_err(Error::Bug),
             _ => Err(Error::Bug("Unsupported appliance type".into())),
         }
     }
 
-    fn handle_command(&mut self, command: Command) -> Result<(), String> {
+    /// Applies the mode which was set before the last restart.
+    fn restore(&mut self, values: &BTreeMap<String, String>) {
+        if let Some(x) = self.control_state.parse(values, "force_on_off") {
+            info!(self.base.logger, "Restored mode {}", x);
+            self.force_on_off.set(x);
+        }
+    }
+
+    async fn handle_command(&mut self, command: Command) -> Result<(), String> {
         match command {
-            Command::SetForceOnOff { force_on_off, resp } => {
-                self.force_on_off = force_on_off;
+            Command::SetForceOnOff {
+                force_on_off,
+                persist,
+                resp,
+            } => {
+                self.force_on_off.set(force_on_off);
+                if persist {
+                    self.control_state
+                        .save("force_on_off", &force_on_off)
+                        .await;
+                }
                 if resp.send(()).is_err() {
                     return Err("Sending SetForceOnOff response failed!".into());
                 }
@@ -317,6 +433,15 @@ impl ApplianceProcessor {
                     return Err("Sending GetForceOnOff response failed!".into());
                 }
             }
+            Command::ResetForceOnOff { resp } => {
+                self.force_on_off.reset();
+                self.control_state.reset("force_on_off").await;
+                if resp.send(self.force_on_off.get()).is_err() {
+                    return Err(
+                        "Sending ResetForceOnOff response failed!".into()
+                    );
+                }
+            }
         }
 
         Ok(())
@@ -567,6 +692,58 @@ fn test_seasonal_correction() {
     );
 }
 
+#[test]
+fn test_closed_loop() {
+    let mut controller =
+        Pid::new(0.5, Power::new::<watt>(0.0), Power::new::<watt>(3680.0))
+            .integral(Time::new::<second>(10.0));
+
+    assert_eq!(
+        Power::new::<watt>(100.0),
+        ApplianceProcessor::calc_closed_loop_power(
+            &mut controller,
+            false,
+            Power::new::<watt>(100.0),
+            Power::new::<watt>(100.0),
+            Time::new::<second>(0.0),
+        ),
+        "Open loop power was not used when switching on",
+    );
+    assert_eq!(
+        Power::new::<watt>(100.0),
+        ApplianceProcessor::calc_closed_loop_power(
+            &mut controller,
+            true,
+            Power::new::<watt>(100.0),
+            Power::new::<watt>(200.0),
+            Time::new::<second>(0.0),
+        ),
+        "Transfer to closed loop was not bumpless",
+    );
+    assert_eq!(
+        Power::new::<watt>(50.0),
+        ApplianceProcessor::calc_closed_loop_power(
+            &mut controller,
+            true,
+            Power::new::<watt>(0.0),
+            Power::new::<watt>(200.0),
+            Time::new::<second>(10.0),
+        ),
+        "Wrong power without excess power",
+    );
+    assert_eq!(
+        Power::new::<watt>(70.0),
+        ApplianceProcessor::calc_closed_loop_power(
+            &mut controller,
+            true,
+            Power::new::<watt>(20.0),
+            Power::new::<watt>(200.0),
+            Time::new::<second>(20.0),
+        ),
+        "Controller did not increase power",
+    );
+}
+
 #[test]
 fn test_force_on() {
     assert_eq!(
@@ -15,8 +15,9 @@
     You should have received a copy of the GNU Affero General Public License
     along with this program.  If not, see <https://www.gnu.org/licenses/>.
 \******************************************************************************/
-use super::ProcessorBase;
+use super::{Overridable, ProcessorBase, StateStore};
 use crate::{
+    clock,
     models::{
         units::{
             millisecond, second, watt, watt_hour, Abbreviation, Energy, Power,
@@ -28,8 +29,8 @@ use crate::{
     task_group::TaskResult,
     Error,
 };
-use chrono::Utc;
-use slog::{debug, warn, Logger};
+use slog::{debug, info, warn, Logger};
+use std::{collections::BTreeMap, time::Duration};
 use tokio::sync::{mpsc, oneshot, watch};
 
 #[derive(Debug)]
@@ -39,6 +40,10 @@ pub enum Command {
         resp: oneshot::Sender<()>,
     },
     GetThreshold {
+        resp: oneshot::Sender<Overridable<f64>>,
+    },
+    /// Returns to the configured threshold.
+    ResetThreshold {
         resp: oneshot::Sender<f64>,
     },
     GetPower {
@@ -46,15 +51,30 @@ pub enum Command {
     },
 }
 
+/// Options of the available power processor.
+pub struct AvailablePowerOptions {
+    /// Battery energy in watt hours above which power is available.
+    pub battery_threshold: f64,
+    /// Time constant of the output filter in seconds.
+    pub tau: f64,
+    /// Fail-safe is entered when an input is silent this long.
+    pub input_timeout: Duration,
+    /// Persists the threshold override across restarts.
+    pub control_state: StateStore,
+}
+
 pub struct AvailablePowerProcessor {
     base: ProcessorBase,
     command_input: mpsc::Receiver<Command>,
     battery_input: watch::Receiver<Model>,
     meter_input: watch::Receiver<Model>,
     power_output: watch::Sender<Model>,
-    battery_threshold: Energy,
+    battery_threshold: Overridable<Energy>,
+    control_state: StateStore,
     skipped_events: u8,
     filter: PT1<Power>,
+    input_timeout: Duration,
+    failed: bool,
 }
 
 impl AvailablePowerProcessor {
@@ -64,8 +84,7 @@ impl AvailablePowerProcessor {
         battery_input: watch::Receiver<Model>,
         meter_input: watch::Receiver<Model>,
         power_output: watch::Sender<Model>,
-        battery_threshold: f64,
-        tau: f64,
+        options: AvailablePowerOptions,
     ) -> Self {
         Self {
             base,
@@ -73,15 +92,20 @@ impl AvailablePowerProcessor {
             battery_input,
             meter_input,
             power_output,
-            battery_threshold: Energy::new::<watt_hour>(battery_threshold),
+            battery_threshold: Overridable::new(Energy::new::<watt_hour>(
+                options.battery_threshold,
+            )),
+            control_state: options.control_state,
             skipped_events: 0,
             filter: PT1::new(
-                Time::new::<second>(tau),
+                Time::new::<second>(options.tau),
                 Power::new::<watt>(0.0),
                 Power::new::<watt>(-super::MAX_POWER_W),
                 Power::new::<watt>(super::MAX_POWER_W),
-                Time::new::<millisecond>(Utc::now().timestamp_millis() as f64),
+                Time::new::<millisecond>(clock::now().timestamp_millis() as f64),
             ),
+            input_timeout: options.input_timeout,
+            failed: false,
         }
     }
 
@@ -90,13 +114,17 @@ impl AvailablePowerProcessor {
     }
 
     pub async fn run(&mut self) -> TaskResult {
+        if let Some(values) = self.control_state.restore().await {
+            self.restore(&values);
+        }
+
         tokio::select! {
             _ = self.base.canceled.changed() => {
                 return Err(Error::Canceled(self.base.name.clone()));
             }
             x = self.command_input.recv() => {
                 if let Some(command) = x {
-                    if let Err(e) = self.handle_command(command) {
+                    if let Err(e) = self.handle_command(command).await {
                         return Err(Error::Bug(e));
                     }
                 }
@@ -115,8 +143,16 @@ impl AvailablePowerProcessor {
                     ));
                 }
             }
+            _ = tokio::time::sleep(self.input_timeout) => {
+                self.check_inputs();
+                return Ok(());
+            }
         };
 
+        if self.check_inputs() {
+            return Ok(());
+        }
+
         let (meter_time, meter_power) = match *self.meter_input.borrow() {
             Model::BidirMeter(ref x) => (x.time, x.power),
             Model::SimpleMeter(ref x) => (x.time, x.power),
@@ -159,7 +195,7 @@ impl AvailablePowerProcessor {
             "Available power: {}",
             filtered_power.into_format_args(watt, Abbreviation)
         );
-        let available_power = if battery.charge < self.battery_threshold {
+        let available_power = if battery.charge < self.battery_threshold.get() {
             debug!(self.base.logger, "Battery is below threshold!");
             AvailablePower::new(
                 meter_time,
@@ -173,23 +209,73 @@ impl AvailablePowerProcessor {
         Ok(())
     }
 
-    fn handle_command(&mut self, command: Command) -> Result<(), String> {
+    /// Publishes that no power is available while an input is stale, so
+    /// that appliances are switched off. Returns true in fail-safe.
+    fn check_inputs(&mut self) -> bool {
+        let now = clock::now().timestamp() as f64;
+        let stale = super::is_stale(
+            &self.meter_input.borrow(),
+            now,
+            self.input_timeout,
+        ) || super::is_stale(
+            &self.battery_input.borrow(),
+            now,
+            self.input_timeout,
+        );
+
+        if stale && !self.failed {
+            warn!(self.base.logger, "Inputs are silent, entering fail-safe");
+            self.failed = true;
+            self.power_output.send_replace(
+                AvailablePower::new(
+                    Time::new::<second>(now),
+                    Power::new::<watt>(-super::MAX_POWER_W),
+                )
+                .into(),
+            );
+        } else if !stale && self.failed {
+            warn!(self.base.logger, "Inputs are back, leaving fail-safe");
+            self.failed = false;
+        }
+        stale
+    }
+
+    /// Applies the values which were set before the last restart.
+    fn restore(&mut self, values: &BTreeMap<String, String>) {
+        if let Some(x) = self.control_state.parse::<f64>(values, "threshold") {
+            info!(self.base.logger, "Restored threshold of {} Wh", x);
+            self.battery_threshold.set(Energy::new::<watt_hour>(x));
+        }
+    }
+
+    async fn handle_command(&mut self, command: Command) -> Result<(), String> {
         match command {
             Command::SetThreshold { threshold, resp } => {
-                self.battery_threshold =
-                    Energy::new::<watt_hour>(threshold.abs());
+                let threshold = threshold.abs();
+                self.battery_threshold
+                    .set(Energy::new::<watt_hour>(threshold));
+                self.control_state.save("threshold", &threshold).await;
                 if resp.send(()).is_err() {
                     return Err("Sending SetThreshold response failed!".into());
                 }
             }
             Command::GetThreshold { resp } => {
-                if resp
-                    .send(self.battery_threshold.get::<watt_hour>())
-                    .is_err()
-                {
+                let threshold =
+                    self.battery_threshold.map(|x| x.get::<watt_hour>());
+                if resp.send(threshold).is_err() {
                     return Err("Sending GetThreshold response failed!".into());
                 }
             }
+            Command::ResetThreshold { resp } => {
+                self.battery_threshold.reset();
+                self.control_state.reset("threshold").await;
+                let threshold = self.battery_threshold.get().get::<watt_hour>();
+                if resp.send(threshold).is_err() {
+                    return Err(
+                        "Sending ResetThreshold response failed!".into()
+                    );
+                }
+            }
             Command::GetPower { resp } => {
                 let output = &*self.power_output.borrow();
                 let power = match output {
@@ -0,0 +1,607 @@
+/******************************************************************************\
+    empowerd - empowers the offline smart home
+    Copyright (C) 2019 - 2026 Max Maisel
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU Affero General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU Affero General Public License for more details.
+
+    You should have received a copy of the GNU Affero General Public License
+    along with this program.  If not, see <https://www.gnu.org/licenses/>.
+\******************************************************************************/
+use super::{PoweroffTimerCmd, ProcessorBase};
+use crate::{
+    models::{
+        units::{watt, Abbreviation, Power},
+        DeferrableJob, Model,
+    },
+    task_group::TaskResult,
+    Error, SwitchMux,
+};
+use chrono::{DateTime, Utc};
+use diesel_async::{
+    pooled_connection::deadpool::{Object, Pool},
+    AsyncPgConnection,
+};
+use slog::{debug, info, Logger};
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::{mpsc, oneshot, watch};
+
+#[derive(Debug)]
+pub enum Command {
+    AddJob {
+        job: DeferrableJob,
+        resp: oneshot::Sender<Result<DeferrableJob, String>>,
+    },
+    CancelJob {
+        id: i32,
+        resp: oneshot::Sender<Result<(), String>>,
+    },
+    GetJobs {
+        resp: oneshot::Sender<Vec<DeferrableJob>>,
+    },
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+enum Action {
+    Wait,
+    Start,
+    Stop,
+}
+
+/// Starts jobs through the poweroff timers of their switches, which switch
+/// them off after their runtime. A job is finished when its switch is off.
+pub struct DeferrableLoadProcessor {
+    base: ProcessorBase,
+    command_input: mpsc::Receiver<Command>,
+    power_input: watch::Receiver<Model>,
+    switch_output: Arc<SwitchMux>,
+    switch_state: watch::Receiver<Vec<Option<bool>>>,
+    // Poweroff timers by switch ID.
+    poweroff_timers: BTreeMap<usize, mpsc::Sender<PoweroffTimerCmd>>,
+    database: Pool<AsyncPgConnection>,
+    check_interval: Duration,
+    // Jobs are loaded from the database on first run.
+    jobs: Option<Vec<DeferrableJob>>,
+}
+
+impl DeferrableLoadProcessor {
+    const SWITCH_TIMEOUT: Duration = Duration::from_secs(5);
+
+    pub fn new(
+        base: ProcessorBase,
+        command_input: mpsc::Receiver<Command>,
+        power_input: watch::Receiver<Model>,
+        switch_output: Arc<SwitchMux>,
+        poweroff_timers: BTreeMap<usize, mpsc::Sender<PoweroffTimerCmd>>,
+        database: Pool<AsyncPgConnection>,
+        check_interval: Duration,
+    ) -> Self {
+        Self {
+            base,
+            command_input,
+            power_input,
+            switch_state: switch_output.subscribe(),
+            switch_output,
+            poweroff_timers,
+            database,
+            check_interval,
+            jobs: None,
+        }
+    }
+
+    pub fn logger(&self) -> &Logger {
+        &self.base.logger
+    }
+
+    pub async fn run(&mut self) -> TaskResult {
+        if self.jobs.is_none() {
+            self.load_jobs().await?;
+        }
+
+        tokio::select! {
+            _ = self.base.canceled.changed() => {
+                return Err(Error::Canceled(self.base.name.clone()));
+            }
+            x = self.command_input.recv() => {
+                if let Some(command) = x {
+                    self.handle_command(command).await?;
+                }
+            }
+            x = self.power_input.changed() => {
+                if let Err(e) = x {
+                    return Err(Error::Bug(
+                        format!("Reading available power failed: {e}")
+                    ));
+                }
+            }
+            x = self.switch_state.changed() => {
+                if let Err(e) = x {
+                    return Err(Error::Bug(
+                        format!("Reading switch state failed: {e}")
+                    ));
+                }
+            }
+            _ = tokio::time::sleep(self.check_interval) => (),
+        };
+
+        self.schedule(Utc::now()).await
+    }
+
+    async fn get_database(&self) -> Result<Object<AsyncPgConnection>, Error> {
+        self.database.get().await.map_err(|e| {
+            Error::Temporary(format!(
+                "Getting database connection from pool failed: {e}",
+            ))
+        })
+    }
+
+    async fn load_jobs(&mut self) -> Result<(), Error> {
+        let mut conn = self.get_database().await?;
+        let mut jobs =
+            DeferrableJob::pending(&mut conn, &self.base.name).await?;
+
+        // Switches are turned off during shutdown, resume running jobs
+        // for their remaining runtime.
+        let now = Utc::now();
+        for job in jobs.iter_mut() {
+            let remaining = match job.end_time() {
+                Some(x) => (x - now).to_std().unwrap_or_default(),
+                None => continue,
+            };
+            if remaining.is_zero() {
+                info!(
+                    self.base.logger,
+                    "Job {} on '{}' finished during shutdown",
+                    job.id,
+                    job.switch_name
+                );
+                job.finished = Some(now);
+                job.save_changes(&mut conn, &self.base.name).await?;
+            } else {
+                info!(
+                    self.base.logger,
+                    "Resuming job {} on '{}'", job.id, job.switch_name
+                );
+                self.start_switch(&job.switch_name, remaining).await?;
+            }
+        }
+
+        jobs.retain(|x| x.finished.is_none());
+        self.jobs = Some(jobs);
+        Ok(())
+    }
+
+    fn jobs_mut(&mut self) -> Result<&mut Vec<DeferrableJob>, Error> {
+        self.jobs
+            .as_mut()
+            .ok_or_else(|| Error::Bug("Jobs were not loaded".into()))
+    }
+
+    fn poweroff_timer(
+        &self,
+        name: &str,
+    ) -> Result<(usize, &mpsc::Sender<PoweroffTimerCmd>), String> {
+        let id = self.switch_output.id_by_name(name)?;
+        match self.poweroff_timers.get(&id) {
+            Some(x) => Ok((id, x)),
+            None => Err(format!(
+                "Switch '{name}' has no poweroff timer, an on_time is required"
+            )),
+        }
+    }
+
+    fn is_on(state: &[Option<bool>], id: usize) -> bool {
+        matches!(state.get(id), Some(Some(true)))
+    }
+
+    /// Sets the on time of the poweroff timer for a single run and turns
+    /// the switch on. Returns when the poweroff timer switched it on.
+    async fn start_switch(
+        &self,
+        name: &str,
+        on_time: Duration,
+    ) -> Result<(), Error> {
+        let (id, poweroff_timer) =
+            self.poweroff_timer(name).map_err(Error::Temporary)?;
+        let (tx, rx) = oneshot::channel();
+        let cmd = PoweroffTimerCmd::SetOnTime {
+            on_time,
+            persist: false,
+            resp: tx,
+        };
+        poweroff_timer.send(cmd).await.map_err(|e| {
+            Error::Temporary(format!("Sending SetOnTime command failed: {e}"))
+        })?;
+        rx.await.map_err(|e| {
+            Error::Temporary(format!(
+                "Receiving SetOnTime response failed: {e}"
+            ))
+        })?;
+
+        let mut state = self.switch_state.clone();
+        self.switch_output
+            .write_val(id, true)
+            .await
+            .map_err(Error::Temporary)?;
+        let switched_on = async {
+            while !Self::is_on(&state.borrow_and_update(), id) {
+                if state.changed().await.is_err() {
+                    return false;
+                }
+            }
+            true
+        };
+        match tokio::time::timeout(Self::SWITCH_TIMEOUT, switched_on).await {
+            Ok(true) => Ok(()),
+            _ => Err(Error::Temporary(format!(
+                "Switch '{name}' was not turned on"
+            ))),
+        }
+    }
+
+    /// Turns the switch off through its poweroff timer.
+    async fn stop_switch(&self, name: &str) -> Result<(), String> {
+        let (id, _) = self.poweroff_timer(name)?;
+        self.switch_output.write_val(id, false).await
+    }
+
+    fn is_off(&self, job: &DeferrableJob) -> bool {
+        match self.switch_output.id_by_name(&job.switch_name) {
+            Ok(id) => !Self::is_on(&self.switch_state.borrow(), id),
+            Err(_) => true,
+        }
+    }
+
+    async fn schedule(&mut self, now: DateTime<Utc>) -> TaskResult {
+        let available_power = match *self.power_input.borrow() {
+            Model::AvailablePower(ref x) => x.power,
+            Model::None => Power::new::<watt>(0.0),
+            _ => {
+                return Err(Error::Temporary(format!(
+                    "Received invalid model from power input: {:?}",
+                    *self.power_input.borrow()
+                )))
+            }
+        };
+
+        let check_interval = self.check_interval;
+        let switched_off = self
+            .jobs
+            .iter()
+            .flatten()
+            .map(|x| x.started.is_some() && self.is_off(x))
+            .collect::<Vec<_>>();
+        let actions = Self::plan(
+            now,
+            self.jobs_mut()?,
+            &switched_off,
+            available_power,
+            check_interval,
+        );
+        if actions.iter().all(|x| *x == Action::Wait) {
+            return Ok(());
+        }
+
+        let mut conn = self.get_database().await?;
+        let mut jobs = self.jobs.take().unwrap_or_default();
+        let mut result = Ok(());
+        for (job, action) in jobs.iter_mut().zip(actions) {
+            match action {
+                Action::Wait => continue,
+                Action::Start => {
+                    if let Err(e) =
+                        self.start_switch(&job.switch_name, job.runtime).await
+                    {
+                        result = Err(e);
+                        continue;
+                    }
+                    debug!(
+                        self.base.logger,
+                        "Starting job {} on '{}' with {} available",
+                        job.id,
+                        job.switch_name,
+                        available_power.into_format_args(watt, Abbreviation),
+                    );
+                    job.started = Some(now);
+                }
+                Action::Stop => {
+                    debug!(
+                        self.base.logger,
+                        "Finished job {} on '{}'", job.id, job.switch_name
+                    );
+                    job.finished = Some(now);
+                }
+            }
+            if let Err(e) = job.save_changes(&mut conn, &self.base.name).await {
+                result = Err(e);
+            }
+        }
+
+        jobs.retain(|x| x.finished.is_none());
+        self.jobs = Some(jobs);
+        result
+    }
+
+    /// Plans the actions of all jobs. A running job is stopped when its
+    /// poweroff timer switched it off.
+    fn plan(
+        now: DateTime<Utc>,
+        jobs: &[DeferrableJob],
+        switched_off: &[bool],
+        available_power: Power,
+        check_interval: Duration,
+    ) -> Vec<Action> {
+        let margin = chrono::Duration::from_std(check_interval)
+            .unwrap_or(chrono::Duration::zero());
+        let mut surplus = available_power;
+
+        // Jobs are ordered by deadline, so the most urgent job gets
+        // the surplus power first.
+        jobs.iter()
+            .zip(switched_off)
+            .map(|(job, switched_off)| {
+                if job.started.is_some() {
+                    return if *switched_off {
+                        Action::Stop
+                    } else {
+                        Action::Wait
+                    };
+                }
+                if now < job.earliest_start {
+                    return Action::Wait;
+                }
+
+                let runtime = chrono::Duration::from_std(job.runtime)
+                    .unwrap_or(chrono::Duration::zero());
+                if now + runtime + margin >= job.latest_finish {
+                    // Force the job on, otherwise it would miss
+                    // its deadline.
+                    surplus =
+                        (surplus - job.power).max(Power::new::<watt>(0.0));
+                    Action::Start
+                } else if surplus >= job.power {
+                    surplus -= job.power;
+                    Action::Start
+                } else {
+                    Action::Wait
+                }
+            })
+            .collect()
+    }
+
+    async fn handle_command(&mut self, command: Command) -> Result<(), Error> {
+        match command {
+            Command::AddJob { job, resp } => {
+                let result = self.add_job(job).await;
+                if resp.send(result).is_err() {
+                    return Err(Error::Bug(
+                        "Sending AddJob response failed!".into(),
+                    ));
+                }
+            }
+            Command::CancelJob { id, resp } => {
+                let result = self.cancel_job(id).await;
+                if resp.send(result).is_err() {
+                    return Err(Error::Bug(
+                        "Sending CancelJob response failed!".into(),
+                    ));
+                }
+            }
+            Command::GetJobs { resp } => {
+                let jobs = self.jobs_mut()?.clone();
+                if resp.send(jobs).is_err() {
+                    return Err(Error::Bug(
+                        "Sending GetJobs response failed!".into(),
+                    ));
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    async fn add_job(
+        &mut self,
+        mut job: DeferrableJob,
+    ) -> Result<DeferrableJob, String> {
+        self.poweroff_timer(&job.switch_name)?;
+        let runtime = chrono::Duration::from_std(job.runtime)
+            .map_err(|e| format!("Invalid runtime: {e}"))?;
+        if job.earliest_start + runtime > job.latest_finish {
+            return Err("Job does not fit between start and finish".into());
+        }
+
+        let mut conn = self.get_database().await.map_err(String::from)?;
+        job.insert(&mut conn, &self.base.name)
+            .await
+            .map_err(String::from)?;
+        info!(
+            self.base.logger,
+            "Added job {} on '{}' until {}",
+            job.id,
+            job.switch_name,
+            job.latest_finish
+        );
+
+        let jobs = self.jobs_mut().map_err(String::from)?;
+        jobs.push(job.clone());
+        jobs.sort_by_key(|x| x.latest_finish);
+        Ok(job)
+    }
+
+    async fn cancel_job(&mut self, id: i32) -> Result<(), String> {
+        let jobs = self.jobs_mut().map_err(String::from)?;
+        let job = match jobs.iter().position(|x| x.id == id) {
+            Some(idx) => jobs.remove(idx),
+            None => return Err(format!("Job {id} does not exist")),
+        };
+
+        if job.started.is_some() {
+            self.stop_switch(&job.switch_name).await?;
+        }
+
+        let mut conn = self.get_database().await.map_err(String::from)?;
+        DeferrableJob::delete(&mut conn, id)
+            .await
+            .map_err(String::from)
+    }
+}
+
+#[cfg(test)]
+fn make_job(start: i64, finish: i64, runtime: u64) -> DeferrableJob {
+    DeferrableJob {
+        id: 0,
+        switch_name: "dishwasher".into(),
+        power: Power::new::<watt>(1000.0),
+        runtime: Duration::from_secs(runtime),
+        earliest_start: DateTime::from_timestamp(start, 0).unwrap(),
+        latest_finish: DateTime::from_timestamp(finish, 0).unwrap(),
+        started: None,
+        finished: None,
+    }
+}
+
+#[test]
+fn test_plan_surplus() {
+    let now = DateTime::from_timestamp(1000, 0).unwrap();
+    let interval = Duration::from_secs(60);
+    let jobs = vec![make_job(0, 10000, 3600), make_job(0, 20000, 3600)];
+
+    assert_eq!(
+        vec![Action::Wait, Action::Wait],
+        DeferrableLoadProcessor::plan(
+            now,
+            &jobs,
+            &[false, false],
+            Power::new::<watt>(999.0),
+            interval
+        ),
+        "Job started without enough surplus",
+    );
+    assert_eq!(
+        vec![Action::Start, Action::Wait],
+        DeferrableLoadProcessor::plan(
+            now,
+            &jobs,
+            &[false, false],
+            Power::new::<watt>(1500.0),
+            interval
+        ),
+        "Surplus was not assigned to the most urgent job",
+    );
+    assert_eq!(
+        vec![Action::Start, Action::Start],
+        DeferrableLoadProcessor::plan(
+            now,
+            &jobs,
+            &[false, false],
+            Power::new::<watt>(2000.0),
+            interval
+        ),
+        "Surplus was not shared between jobs",
+    );
+
+    let jobs = vec![make_job(0, 4000, 3600), make_job(0, 10000, 3600)];
+    assert_eq!(
+        vec![Action::Start, Action::Wait],
+        DeferrableLoadProcessor::plan(
+            now,
+            &jobs,
+            &[false, false],
+            Power::new::<watt>(1500.0),
+            interval
+        ),
+        "Surplus of a forced job was assigned again",
+    );
+    assert_eq!(
+        vec![Action::Start, Action::Start],
+        DeferrableLoadProcessor::plan(
+            now,
+            &jobs,
+            &[false, false],
+            Power::new::<watt>(2000.0),
+            interval
+        ),
+        "Remaining surplus was not assigned after a forced job",
+    );
+}
+
+#[test]
+fn test_plan_deadline() {
+    let interval = Duration::from_secs(60);
+    let jobs = vec![make_job(2000, 10000, 3600)];
+
+    assert_eq!(
+        vec![Action::Wait],
+        DeferrableLoadProcessor::plan(
+            DateTime::from_timestamp(1000, 0).unwrap(),
+            &jobs,
+            &[false],
+            Power::new::<watt>(5000.0),
+            interval
+        ),
+        "Job started before earliest start",
+    );
+    assert_eq!(
+        vec![Action::Wait],
+        DeferrableLoadProcessor::plan(
+            DateTime::from_timestamp(6339, 0).unwrap(),
+            &jobs,
+            &[false],
+            Power::new::<watt>(-500.0),
+            interval
+        ),
+        "Job was forced on too early",
+    );
+    assert_eq!(
+        vec![Action::Start],
+        DeferrableLoadProcessor::plan(
+            DateTime::from_timestamp(6340, 0).unwrap(),
+            &jobs,
+            &[false],
+            Power::new::<watt>(-500.0),
+            interval
+        ),
+        "Job was not forced on before deadline",
+    );
+}
+
+#[test]
+fn test_plan_running() {
+    let interval = Duration::from_secs(60);
+    let mut job = make_job(0, 10000, 3600);
+    job.started = Some(DateTime::from_timestamp(1000, 0).unwrap());
+    let jobs = vec![job];
+
+    assert_eq!(
+        vec![Action::Wait],
+        DeferrableLoadProcessor::plan(
+            DateTime::from_timestamp(5000, 0).unwrap(),
+            &jobs,
+            &[false],
+            Power::new::<watt>(-5000.0),
+            interval
+        ),
+        "Job was stopped while its switch is on",
+    );
+    assert_eq!(
+        vec![Action::Stop],
+        DeferrableLoadProcessor::plan(
+            DateTime::from_timestamp(4600, 0).unwrap(),
+            &jobs,
+            &[true],
+            Power::new::<watt>(5000.0),
+            interval
+        ),
+        "Job was not finished after its switch was turned off",
+    );
+}
@@ -0,0 +1,372 @@
+/******************************************************************************\
+    empowerd - empowers the offline smart home
+    Copyright (C) 2019 - 2026 Max Maisel
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU Affero General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU Affero General Public License for more details.
+
+    You should have received a copy of the GNU Affero General Public License
+    along with this program.  If not, see <https://www.gnu.org/licenses/>.
+\******************************************************************************/
+use super::{LoadControlCmd, ProcessorBase};
+use crate::{
+    models::{
+        units::{watt, Abbreviation, Power, Time},
+        Model,
+    },
+    pid::Pid,
+    sinks::SunspecInverterSink,
+    task_group::TaskResult,
+    Error,
+};
+use slog::{debug, warn, Logger};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::{mpsc, oneshot, watch};
+
+/// Calculates the curtailment power which keeps grid export
+/// below the configured limit.
+#[derive(Clone, Debug)]
+pub struct ExportController {
+    limit: Power,
+    fail_safe: Power,
+    controller: Pid<Power>,
+}
+
+impl ExportController {
+    /// Creates a new controller for the given maximum export power.
+    /// The curtailment is set to the fail-safe value until the first
+    /// meter sample arrives.
+    pub fn new(
+        limit: Power,
+        kp: f64,
+        ki: f64,
+        max_curtailment: Power,
+        fail_safe: Power,
+    ) -> Self {
+        let mut controller =
+            Pid::new(kp, Power::new::<watt>(0.0), max_curtailment);
+        if ki > 0.0 {
+            controller = controller.integral_gain(ki);
+        }
+        controller.reset(fail_safe);
+
+        Self {
+            limit,
+            fail_safe,
+            controller,
+        }
+    }
+
+    /// Processes a grid power sample where import is positive.
+    pub fn process(&mut self, time: Time, grid_power: Power) -> Power {
+        // Export is negative grid power.
+        self.controller.process(-self.limit, grid_power, time)
+    }
+
+    /// Returns the fail-safe curtailment and restarts the controller
+    /// from there once the meter is back.
+    pub fn fail_safe(&mut self) -> Power {
+        self.controller.reset(self.fail_safe);
+        self.fail_safe
+    }
+}
+
+pub struct ExportLimitProcessor {
+    base: ProcessorBase,
+    meter_input: watch::Receiver<Model>,
+    controller: ExportController,
+    inverter_output: Option<Arc<SunspecInverterSink>>,
+    battery_output: Option<mpsc::Sender<LoadControlCmd>>,
+    battery_charge: Power,
+    meter_timeout: Duration,
+    inverter_limit: Option<(Power, Instant)>,
+    charge_power: Power,
+    failed: bool,
+}
+
+impl ExportLimitProcessor {
+    pub fn new(
+        base: ProcessorBase,
+        meter_input: watch::Receiver<Model>,
+        controller: ExportController,
+        inverter_output: Option<Arc<SunspecInverterSink>>,
+        battery_output: Option<mpsc::Sender<LoadControlCmd>>,
+        battery_charge: Power,
+        meter_timeout: Duration,
+    ) -> Self {
+        Self {
+            base,
+            meter_input,
+            controller,
+            inverter_output,
+            battery_output,
+            battery_charge,
+            meter_timeout,
+            inverter_limit: None,
+            charge_power: Power::new::<watt>(0.0),
+            failed: false,
+        }
+    }
+
+    pub fn logger(&self) -> &Logger {
+        &self.base.logger
+    }
+
+    pub async fn run(&mut self) -> TaskResult {
+        let curtailment = tokio::select! {
+            _ = self.base.canceled.changed() => {
+                return Err(Error::Canceled(self.base.name.clone()));
+            }
+            x = self.meter_input.changed() => {
+                if let Err(e) = x {
+                    return Err(Error::Bug(
+                        format!("Reading meter input failed: {e}")
+                    ));
+                }
+                let (time, power) = match *self.meter_input.borrow() {
+                    Model::BidirMeter(ref x) => (x.time, x.power),
+                    Model::None => return Ok(()),
+                    _ => {
+                        return Err(Error::Temporary(format!(
+                            "Received invalid model from meter input: {:?}",
+                            *self.meter_input.borrow()
+                        )))
+                    }
+                };
+                if self.failed {
+                    warn!(self.base.logger, "Meter is back, leaving fail-safe");
+                    self.failed = false;
+                }
+                self.controller.process(time, power)
+            }
+            _ = tokio::time::sleep(self.meter_timeout) => {
+                if !self.failed {
+                    warn!(
+                        self.base.logger,
+                        "Meter is silent, entering fail-safe"
+                    );
+                    self.failed = true;
+                }
+                self.controller.fail_safe()
+            }
+        };
+
+        self.apply(curtailment).await
+    }
+
+    /// Distributes the curtailment power to the outputs.
+    /// The battery is charged first to avoid wasting energy.
+    async fn apply(&mut self, curtailment: Power) -> TaskResult {
+        let zero = Power::new::<watt>(0.0);
+        let charge_power = if self.battery_output.is_some() {
+            curtailment.min(self.battery_charge).max(zero)
+        } else {
+            zero
+        };
+
+        // Write battery and inverter independently, so that one broken
+        // output does not disable the other.
+        let battery_result = self.set_charge_power(charge_power).await;
+        if let Some(inverter) = &self.inverter_output {
+            let limit = (inverter.nominal_power() - curtailment + charge_power)
+                .max(zero)
+                .min(inverter.nominal_power());
+            let changed = match self.inverter_limit {
+                Some((x, written)) => {
+                    let expired = match inverter.refresh_interval() {
+                        Some(y) => written.elapsed() >= y,
+                        None => false,
+                    };
+                    (x - limit).abs() >= inverter.nominal_power() * 0.01
+                        || expired
+                }
+                None => true,
+            };
+            if changed {
+                debug!(
+                    self.base.logger,
+                    "Setting inverter limit to {}",
+                    limit.into_format_args(watt, Abbreviation),
+                );
+                inverter
+                    .set_power_limit(limit)
+                    .await
+                    .map_err(Error::Temporary)?;
+                self.inverter_limit = Some((limit, Instant::now()));
+            }
+        }
+
+        battery_result
+    }
+
+    async fn set_charge_power(&mut self, power: Power) -> TaskResult {
+        let battery = match &self.battery_output {
+            Some(x) => x,
+            None => return Ok(()),
+        };
+        if (self.charge_power - power).abs() < Power::new::<watt>(1.0) {
+            return Ok(());
+        }
+
+        // Negative discharge power makes the battery charge.
+        let (tx, rx) = oneshot::channel();
+        let cmd = LoadControlCmd::SetDischargePower {
+            power: -power,
+            resp: tx,
+        };
+        if let Err(e) = battery.send(cmd).await {
+            return Err(Error::Temporary(format!(
+                "Sending charge request failed: {e}"
+            )));
+        }
+        rx.await.map_err(|e| {
+            Error::Temporary(format!("Receiving charge response failed: {e}"))
+        })?;
+
+        self.charge_power = power;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+use crate::models::units::second;
+
+#[cfg(test)]
+fn watts(x: f64) -> Power {
+    Power::new::<watt>(x)
+}
+
+/// Simulates a PV system with a curtailable inverter and a constant load.
+/// The meter sees the result of the previous inverter limit.
+#[cfg(test)]
+fn simulate(
+    controller: &mut ExportController,
+    pv_power: &[f64],
+    load: f64,
+    nominal: f64,
+) -> Vec<f64> {
+    let mut limit = nominal;
+    pv_power
+        .iter()
+        .enumerate()
+        .map(|(i, pv)| {
+            let grid = load - pv.min(limit);
+            let curtailment = controller
+                .process(Time::new::<second>(i as f64), watts(grid))
+                .get::<watt>();
+            limit = nominal - curtailment;
+            -grid
+        })
+        .collect()
+}
+
+#[test]
+fn test_zero_export() {
+    let mut controller =
+        ExportController::new(watts(0.0), 0.3, 0.5, watts(10000.0), watts(0.0));
+    controller.controller.reset(watts(0.0));
+
+    let pv = vec![8000.0; 60];
+    let export = simulate(&mut controller, &pv, 1000.0, 10000.0);
+    assert!(export[0] > 6000.0, "Initial export was not simulated");
+    for (i, x) in export.iter().enumerate().skip(20) {
+        assert!(x.abs() < 50.0, "Export at {i} s was {x} W");
+    }
+}
+
+#[test]
+fn test_integral_only() {
+    let mut controller =
+        ExportController::new(watts(0.0), 0.0, 0.5, watts(10000.0), watts(0.0));
+    controller.controller.reset(watts(0.0));
+
+    let export = simulate(&mut controller, &[8000.0; 60], 1000.0, 10000.0);
+    assert!(export.iter().all(|x| x.is_finite()), "Output is not finite");
+    assert!(export[59].abs() < 50.0, "Export was {} W", export[59]);
+}
+
+#[test]
+fn test_proportional_fail_safe() {
+    let mut controller = ExportController::new(
+        watts(1000.0),
+        0.5,
+        0.0,
+        watts(10000.0),
+        watts(4000.0),
+    );
+
+    assert_eq!(watts(4000.0), controller.fail_safe());
+    let output = controller.process(Time::new::<second>(1.0), watts(-3000.0));
+    assert_eq!(watts(1000.0), output, "Fail-safe curtailment was kept");
+}
+
+#[test]
+fn test_feed_in_limit_anti_windup() {
+    // 70 % of a 10 kW system.
+    let mut controller = ExportController::new(
+        watts(7000.0),
+        0.3,
+        0.5,
+        watts(10000.0),
+        watts(0.0),
+    );
+    controller.controller.reset(watts(0.0));
+
+    // Clouds reduce production below the limit for a long time.
+    let mut pv = vec![9500.0; 40];
+    pv.extend(vec![3000.0; 300]);
+    pv.extend(vec![9500.0; 40]);
+    let export = simulate(&mut controller, &pv, 500.0, 10000.0);
+
+    for (i, x) in export.iter().enumerate().take(40).skip(20) {
+        assert!(*x < 7050.0, "Export at {i} s was {x} W");
+    }
+    // Without windup, curtailment is released immediately.
+    assert!(
+        export[300] > 2400.0,
+        "Curtailment was not released, export is {} W",
+        export[300]
+    );
+    // The controller reacts quickly after a long time without curtailment.
+    assert!(
+        export[345..].iter().all(|x| *x < 7050.0),
+        "Limit was not restored fast enough: {:?}",
+        &export[340..]
+    );
+}
+
+#[test]
+fn test_fail_safe() {
+    let mut controller = ExportController::new(
+        watts(0.0),
+        0.3,
+        0.5,
+        watts(10000.0),
+        watts(8000.0),
+    );
+    assert_eq!(
+        watts(8000.0),
+        controller.process(Time::new::<second>(0.0), watts(0.0)),
+        "Controller did not start in fail-safe"
+    );
+
+    let export = simulate(&mut controller, &[5000.0; 60], 1000.0, 10000.0);
+    assert!(export[59].abs() < 50.0, "Controller did not settle");
+
+    assert_eq!(watts(8000.0), controller.fail_safe());
+    let curtailment =
+        controller.process(Time::new::<second>(100.0), watts(0.0));
+    assert_eq!(
+        watts(8000.0),
+        curtailment,
+        "Controller did not continue from fail-safe"
+    );
+}
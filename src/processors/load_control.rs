@@ -15,7 +15,7 @@
     You should have received a copy of the GNU Affero General Public License
     along with this program.  If not, see <https://www.gnu.org/licenses/>.
 \******************************************************************************/
-use super::ProcessorBase;
+use super::{Overridable, ProcessorBase, StateStore};
 use crate::{
     models::{
         units::{watt, watt_hour, Abbreviation, Energy, Power},
@@ -26,13 +26,14 @@ use crate::{
     task_group::TaskResult,
     Error,
 };
-use slog::{debug, Logger};
+use chrono::Utc;
+use slog::{debug, info, warn, Logger};
 use sma_proto::{
     client::{SmaClient, SmaSession},
     energymeter::ObisValue,
     SmaEndpoint,
 };
-use std::net::Ipv4Addr;
+use std::{collections::BTreeMap, net::Ipv4Addr};
 use tokio::{
     sync::{mpsc, oneshot, watch},
     time::{self, Duration},
@@ -63,7 +64,13 @@ pub enum Command {
         resp: oneshot::Sender<()>,
     },
     GetChargeMode {
-        resp: oneshot::Sender<bool>,
+        resp: oneshot::Sender<Overridable<bool>>,
+    },
+    /// Returns to the default mode without grid charging.
+    ResetChargeMode { resp: oneshot::Sender<bool> },
+    SetDischargePower {
+        power: Power,
+        resp: oneshot::Sender<()>,
     },
 }
 
@@ -76,8 +83,12 @@ pub struct LoadControlProcessor {
     grid_power: Power,
     controller: MultiSetpointHysteresis<Energy, Power>,
     seasonal: Option<Seasonal>,
-    charge_power: Power,
+    charge_mode: Overridable<bool>,
+    control_state: StateStore,
     charge_power_setpoint: Power,
+    discharge_power: Power,
+    input_timeout: Duration,
+    failed: bool,
 
     sma_client: SmaClient,
     session: SmaSession,
@@ -95,6 +106,8 @@ impl LoadControlProcessor {
         controller: MultiSetpointHysteresis<Energy, Power>,
         seasonal: Option<Seasonal>,
         charge_power_setpoint: Power,
+        input_timeout: Duration,
+        control_state: StateStore,
     ) -> Result<Self, String> {
         let ctrl_endpoint = SmaEndpoint {
             susy_id: meter_susy_id,
@@ -115,8 +128,12 @@ impl LoadControlProcessor {
             grid_power: Power::new::<watt>(0.0),
             controller,
             seasonal,
-            charge_power: Power::new::<watt>(0.0),
+            charge_mode: Overridable::new(false),
+            control_state,
             charge_power_setpoint,
+            discharge_power: Power::new::<watt>(0.0),
+            input_timeout,
+            failed: false,
             sma_client,
             session,
         })
@@ -143,6 +160,14 @@ impl LoadControlProcessor {
             }
         }
 
+        let restored = match self.control_state.restore().await {
+            Some(values) => {
+                self.restore(&values);
+                true
+            }
+            None => false,
+        };
+
         let (timestamp_ms, em_data) =
             time::timeout(Duration::from_millis(500), async {
                 self.sma_client
@@ -163,7 +188,7 @@ impl LoadControlProcessor {
 
         let command_received = match self.command_input.try_recv() {
             Ok(command) => {
-                self.handle_command(command)?;
+                self.handle_command(command).await?;
                 true
             }
             Err(mpsc::error::TryRecvError::Empty) => false,
@@ -177,7 +202,8 @@ impl LoadControlProcessor {
                 Error::Bug(format!("Reading battery input failed: {e}"))
             })?;
 
-        if command_received || battery_changed {
+        if restored || command_received || battery_changed {
+            let charge_power = self.charge_power();
             match *self.battery_input.borrow() {
                 Model::None => (),
                 Model::Battery(ref x) => {
@@ -185,7 +211,7 @@ impl LoadControlProcessor {
                         Self::calc_grid_power(
                             &mut self.controller,
                             &self.seasonal,
-                            self.charge_power,
+                            charge_power,
                             x.charge.to_owned(),
                         );
                     // Print a debug message when grid power has changed.
@@ -211,7 +237,30 @@ impl LoadControlProcessor {
             }
         }
 
-        payload.apply_power_offset(self.grid_power.get::<watt>());
+        // Stop charging from the grid while the battery state is unknown.
+        let stale = super::is_stale(
+            &self.battery_input.borrow(),
+            Utc::now().timestamp() as f64,
+            self.input_timeout,
+        );
+        if stale {
+            if !self.failed {
+                warn!(
+                    self.base.logger,
+                    "Battery input is silent, entering fail-safe"
+                );
+                self.failed = true;
+            }
+            self.grid_power = Power::new::<watt>(0.0);
+        } else if self.failed {
+            warn!(self.base.logger, "Battery input is back, leaving fail-safe");
+            self.failed = false;
+        }
+
+        // Additional load on the virtual meter makes the battery discharge.
+        payload.apply_power_offset(
+            (self.grid_power + self.discharge_power).get::<watt>(),
+        );
         if let Err(e) = self
             .sma_client
             .write_em_message(
@@ -251,14 +300,26 @@ impl LoadControlProcessor {
         (new_grid_power + charge_power, seasonal_correction)
     }
 
-    fn handle_command(&mut self, command: Command) -> Result<(), Error> {
+    fn charge_power(&self) -> Power {
+        match self.charge_mode.get() {
+            true => self.charge_power_setpoint,
+            false => Power::new::<watt>(0.0),
+        }
+    }
+
+    /// Applies the mode which was set before the last restart.
+    fn restore(&mut self, values: &BTreeMap<String, String>) {
+        if let Some(x) = self.control_state.parse(values, "charge_mode") {
+            info!(self.base.logger, "Restored charge mode {}", x);
+            self.charge_mode.set(x);
+        }
+    }
+
+    async fn handle_command(&mut self, command: Command) -> Result<(), Error> {
         match command {
             Command::SetChargeMode { enabled, resp } => {
-                if enabled {
-                    self.charge_power = self.charge_power_setpoint;
-                } else {
-                    self.charge_power = Power::new::<watt>(0.0);
-                }
+                self.charge_mode.set(enabled);
+                self.control_state.save("charge_mode", &enabled).await;
 
                 if resp.send(()).is_err() {
                     return Err(Error::Bug(
@@ -267,13 +328,38 @@ impl LoadControlProcessor {
                 }
             }
             Command::GetChargeMode { resp } => {
-                let enabled = self.charge_power > Power::new::<watt>(0.1);
-                if resp.send(enabled).is_err() {
+                if resp.send(self.charge_mode).is_err() {
                     return Err(Error::Bug(
                         "Sending GetChargeMode response failed!".into(),
                     ));
                 }
             }
+            Command::ResetChargeMode { resp } => {
+                self.charge_mode.reset();
+                self.control_state.reset("charge_mode").await;
+                if resp.send(self.charge_mode.get()).is_err() {
+                    return Err(Error::Bug(
+                        "Sending ResetChargeMode response failed!".into(),
+                    ));
+                }
+            }
+            Command::SetDischargePower { power, resp } => {
+                if (self.discharge_power - power).abs()
+                    > Power::new::<watt>(0.1)
+                {
+                    debug!(
+                        self.base.logger,
+                        "Requested battery discharge of {}",
+                        power.into_format_args(watt, Abbreviation),
+                    );
+                }
+                self.discharge_power = power;
+                if resp.send(()).is_err() {
+                    return Err(Error::Bug(
+                        "Sending SetDischargePower response failed!".into(),
+                    ));
+                }
+            }
         }
 
         Ok(())
@@ -17,38 +17,66 @@
 \******************************************************************************/
 use crate::{
     models::{
-        units::{joule, watt, watt_hour, Energy, Power},
+        units::{joule, second, watt, watt_hour, Energy, Power, Time},
         Model,
     },
     multi_setpoint_hysteresis::LinspaceBuilder,
+    pid::Pid,
+    schedule::Holidays,
     seasonal::SeasonalBuilder,
-    settings::{ProcessorType, Settings},
-    sinks::{ArcSink, SwitchProcCreateInfo},
-    task_group::{task_loop, TaskGroup, TaskGroupBuilder, TaskState},
+    settings::{PeakShavingLoad, ProcessorType, RestartPolicy, Settings},
+    sinks::{ArcSink, Sinks},
+    task_group::{task_loop, TaskGroupBuilder, TaskState},
 };
+use diesel_async::{pooled_connection::deadpool::Pool, AsyncPgConnection};
 use slog::{debug, error, Logger};
-use std::collections::BTreeMap;
+use std::collections::{BTreeMap, BTreeSet};
 use std::time::Duration;
 use tokio::sync::{mpsc, oneshot, watch};
 
 mod appliance;
 mod available_power;
 mod debug;
+mod deferrable_load;
 mod dummy;
+mod export_limit;
 mod load_control;
+mod peak_shaving;
 mod poweroff_timer;
+mod rules;
+mod state_store;
 
 pub use appliance::{ApplianceProcessor, Command as ApplianceCmd};
 pub use available_power::{
-    AvailablePowerProcessor, Command as AvailablePowerCmd,
+    AvailablePowerOptions, AvailablePowerProcessor,
+    Command as AvailablePowerCmd,
 };
 pub use debug::DebugProcessor;
+pub use deferrable_load::{
+    Command as DeferrableLoadCmd, DeferrableLoadProcessor,
+};
 pub use dummy::DummyProcessor;
+pub use export_limit::{ExportController, ExportLimitProcessor};
 pub use load_control::{Command as LoadControlCmd, LoadControlProcessor};
+pub use peak_shaving::{
+    Command as PeakShavingCmd, DemandLimits, PeakShavingOptions,
+    PeakShavingProcessor, PeakStatus, SheddableLoad,
+};
 pub use poweroff_timer::{Command as PoweroffTimerCmd, PoweroffTimerProcessor};
+pub use rules::{Command as RulesCmd, RulesProcessor};
+pub use state_store::{Overridable, StateStore};
 
 pub const MAX_POWER_W: f64 = 12800.0;
 
+/// Returns true if the latest record of an input is older than the timeout.
+/// Inputs without any record are not stale, nothing was controlled yet.
+pub fn is_stale(input: &Model, now: f64, timeout: Duration) -> bool {
+    match input.time() {
+        Some(time) => now - time.get::<second>() > timeout.as_secs_f64(),
+        None => false,
+    }
+}
+
 #[derive(Debug)]
 pub struct CommandSender<T> {
     pub name: String,
@@ -56,6 +84,16 @@ pub struct CommandSender<T> {
     pub tx: mpsc::Sender<T>,
 }
 
+impl<T> Clone for CommandSender<T> {
+    fn clone(&self) -> Self {
+        Self {
+            name: self.name.clone(),
+            switch_id: self.switch_id,
+            tx: self.tx.clone(),
+        }
+    }
+}
+
 impl<T> CommandSender<T> {
     pub async fn issue_command<U>(
         &self,
@@ -81,17 +119,69 @@ impl<T> CommandSender<T> {
     }
 }
 
-#[derive(Debug, Default)]
+#[derive(Clone, Debug, Default)]
 pub struct ProcessorCommands {
     pub available_power: Vec<CommandSender<AvailablePowerCmd>>,
     pub appliance: Vec<CommandSender<ApplianceCmd>>,
     pub load_control: Option<CommandSender<LoadControlCmd>>,
     pub poweroff_timer: Vec<CommandSender<PoweroffTimerCmd>>,
+    pub deferrable_load: Vec<CommandSender<DeferrableLoadCmd>>,
+    pub rules: Vec<CommandSender<RulesCmd>>,
+    pub peak_shaving: Vec<CommandSender<PeakShavingCmd>>,
 }
 
-pub struct ProcessorInfo {
-    pub tasks: TaskGroup,
-    pub commands: ProcessorCommands,
+impl ProcessorCommands {
+    /// Copies the command senders of a running processor.
+    fn keep(&mut self, running: &ProcessorCommands, name: &str) {
+        fn copy<T>(
+            to: &mut Vec<CommandSender<T>>,
+            from: &[CommandSender<T>],
+            name: &str,
+        ) {
+            to.extend(from.iter().filter(|x| x.name == name).cloned());
+        }
+
+        copy(&mut self.available_power, &running.available_power, name);
+        copy(&mut self.appliance, &running.appliance, name);
+        copy(&mut self.poweroff_timer, &running.poweroff_timer, name);
+        copy(&mut self.deferrable_load, &running.deferrable_load, name);
+        copy(&mut self.rules, &running.rules, name);
+        copy(&mut self.peak_shaving, &running.peak_shaving, name);
+        if let Some(x) = &running.load_control {
+            if x.name == name {
+                self.load_control = Some(x.clone());
+            }
+        }
+    }
+}
+
+/// Selects the processors which are created by `processor_tasks`.
+pub enum Selection<'a> {
+    All,
+    /// Creates only the named processors. The command senders of the other
+    /// processors are taken from the running ones.
+    Only(&'a BTreeSet<String>, &'a ProcessorCommands),
+}
+
+impl Selection<'_> {
+    fn contains(&self, name: &str) -> bool {
+        match self {
+            Selection::All => true,
+            Selection::Only(names, _) => names.contains(name),
+        }
+    }
+
+    /// Returns true if the processor is created. Otherwise the commands of
+    /// the running processor are kept.
+    fn select(&self, name: &str, commands: &mut ProcessorCommands) -> bool {
+        if self.contains(name) {
+            return true;
+        }
+        if let Selection::Only(_, running) = self {
+            commands.keep(running, name);
+        }
+        false
+    }
 }
 
 pub struct ProcessorBase {
@@ -114,24 +204,46 @@ impl ProcessorBase {
     }
 }
 
+pub fn poweroff_timer_name(switch: &str) -> String {
+    format!("_PoweroffTimerProcessor_{}", switch)
+}
+
+/// Creates the tasks of all processors or only of the selected ones.
+/// Returns the command senders of all processors. Without a database,
+/// runtime changes are not stored and rules and deferrable loads are not
+/// available.
 pub fn processor_tasks(
     logger: Logger,
     settings: &Settings,
-    mut inputs: BTreeMap<String, watch::Receiver<Model>>,
-    sinks: BTreeMap<String, ArcSink>,
-    switch_info: Vec<SwitchProcCreateInfo>,
-) -> Result<ProcessorInfo, String> {
-    let tasks = TaskGroupBuilder::new("processors".into(), logger.clone());
+    tasks: &mut TaskGroupBuilder,
+    nodes: &mut BTreeMap<String, watch::Sender<Model>>,
+    sinks: &Sinks,
+    database: Option<Pool<AsyncPgConnection>>,
+    selection: Selection,
+) -> Result<ProcessorCommands, String> {
+    let switch_info = &sinks.switches;
+    let sinks = &sinks.sinks;
     let mut outputs = BTreeMap::<String, watch::Sender<Model>>::new();
     let mut commands = ProcessorCommands::default();
 
+    // Existing channels are reused, so restarted processors keep their
+    // subscribers.
     for p in &settings.processors {
-        let (tx, rx) = watch::channel(Model::None);
-        inputs.insert(p.name.clone(), rx);
-        outputs.insert(p.name.clone(), tx);
+        let tx = nodes
+            .entry(p.name.clone())
+            .or_insert_with(|| watch::channel(Model::None).0);
+        outputs.insert(p.name.clone(), tx.clone());
     }
+    let inputs = nodes
+        .iter()
+        .map(|(name, tx)| (name.clone(), tx.subscribe()))
+        .collect::<BTreeMap<_, _>>();
 
     for p in &settings.processors {
+        if !selection.select(&p.name, &mut commands) {
+            continue;
+        }
+        let supervisor = tasks.supervisor(&p.name, p.restart);
         match &p.variant {
             ProcessorType::AvailablePower(setting) => {
                 let battery_source = match inputs.get(&setting.battery_input) {
@@ -165,17 +277,27 @@ pub fn processor_tasks(
                 let mut processor = AvailablePowerProcessor::new(
                     ProcessorBase::new(
                         p.name.clone(),
-                        tasks.cancel_rx(),
+                        tasks.cancel_rx(&p.name),
                         logger.clone(),
                     ),
                     command_rx,
                     battery_source,
                     meter_source,
                     power_output,
-                    setting.battery_threshold,
-                    setting.tau,
+                    AvailablePowerOptions {
+                        battery_threshold: setting.battery_threshold,
+                        tau: setting.tau,
+                        input_timeout: Duration::from_secs(
+                            setting.input_timeout,
+                        ),
+                        control_state: StateStore::new(
+                            p.name.clone(),
+                            database.clone(),
+                            logger.clone(),
+                        ),
+                    },
                 );
-                tasks.add_task(task_loop!(processor));
+                tasks.add_task(&p.name, task_loop!(processor, supervisor));
                 commands.available_power.push(CommandSender {
                     name: p.name.clone(),
                     switch_id: None,
@@ -205,13 +327,13 @@ pub fn processor_tasks(
                     let mut processor = DebugProcessor::new(
                         ProcessorBase::new(
                             p.name.clone(),
-                            tasks.cancel_rx(),
+                            tasks.cancel_rx(&p.name),
                             logger.clone(),
                         ),
                         source,
                         sink,
                     );
-                    tasks.add_task(task_loop!(processor));
+                    tasks.add_task(&p.name, task_loop!(processor, supervisor));
                 } else {
                     return Err(
                         "Unsupported sink type for DebugProcessor".into()
@@ -288,11 +410,29 @@ pub fn processor_tasks(
                     None => None,
                 };
 
+                let controller = setting.controller.as_ref().map(|x| {
+                    let mut pid = Pid::new(
+                        x.kp,
+                        Power::new::<watt>(0.0),
+                        Power::new::<watt>(x.max_power),
+                    );
+                    if let Some(ti) = x.ti {
+                        pid = pid.integral(Time::new::<second>(ti));
+                    }
+                    if let Some(td) = x.td {
+                        pid = pid.derivative(
+                            Time::new::<second>(td),
+                            Time::new::<second>(x.tf),
+                        );
+                    }
+                    pid
+                });
+
                 let (command_tx, command_rx) = mpsc::channel(1);
                 let mut processor = ApplianceProcessor::new(
                     ProcessorBase::new(
                         p.name.clone(),
-                        tasks.cancel_rx(),
+                        tasks.cancel_rx(&p.name),
                         logger.clone(),
                     ),
                     command_rx,
@@ -302,8 +442,15 @@ pub fn processor_tasks(
                     appliance_sink,
                     Duration::from_secs(setting.retransmit_interval),
                     seasonal,
+                    controller,
+                    Duration::from_secs(setting.input_timeout),
+                    StateStore::new(
+                        p.name.clone(),
+                        database.clone(),
+                        logger.clone(),
+                    ),
                 );
-                tasks.add_task(task_loop!(processor));
+                tasks.add_task(&p.name, task_loop!(processor, supervisor));
                 commands.appliance.push(CommandSender {
                     name: p.name.clone(),
                     switch_id: None,
@@ -368,7 +515,7 @@ pub fn processor_tasks(
                 let mut processor = match LoadControlProcessor::new(
                     ProcessorBase::new(
                         p.name.clone(),
-                        tasks.cancel_rx(),
+                        tasks.cancel_rx(&p.name),
                         logger.clone(),
                     ),
                     command_rx,
@@ -380,6 +527,12 @@ pub fn processor_tasks(
                     controller,
                     seasonal,
                     Power::new::<watt>(-setting.charge_power),
+                    Duration::from_secs(setting.input_timeout),
+                    StateStore::new(
+                        p.name.clone(),
+                        database.clone(),
+                        logger.clone(),
+                    ),
                 ) {
                     Ok(x) => x,
                     Err(e) => {
@@ -389,38 +542,52 @@ pub fn processor_tasks(
                         ))
                     }
                 };
-                tasks.add_task(task_loop!(processor));
+                tasks.add_task(&p.name, task_loop!(processor, supervisor));
                 commands.load_control = Some(CommandSender {
                     name: p.name.clone(),
                     switch_id: None,
                     tx: command_tx,
                 });
             }
+            // These control other processors and are created afterwards.
+            ProcessorType::DeferrableLoad(_)
+            | ProcessorType::Rules(_)
+            | ProcessorType::PeakShaving(_)
+            | ProcessorType::ExportLimit(_) => (),
         }
     }
 
     if let Some(x) = sinks.get("_SwitchMux") {
         match x {
             ArcSink::SwitchMux(switch_mux) => {
-                for switch in switch_info.into_iter() {
+                for switch in switch_info {
                     let id = switch_mux.id_by_name(&switch.name)?;
-                    let name =
-                        format!("_PoweroffTimerProcessor_{}", switch.name);
+                    let name = poweroff_timer_name(&switch.name);
+                    if !selection.select(&name, &mut commands) {
+                        continue;
+                    }
                     let (command_tx, command_rx) = mpsc::channel(1);
 
                     let mut processor = PoweroffTimerProcessor::new(
                         ProcessorBase::new(
                             name.clone(),
-                            tasks.cancel_rx(),
+                            tasks.cancel_rx(&name),
                             logger.clone(),
                         ),
                         command_rx,
-                        switch.channel,
+                        switch.channel.clone(),
                         switch_mux.clone(),
                         id,
                         Duration::from_secs(switch.on_time),
+                        StateStore::new(
+                            name.clone(),
+                            database.clone(),
+                            logger.clone(),
+                        ),
                     );
-                    tasks.add_task(task_loop!(processor));
+                    let supervisor =
+                        tasks.supervisor(&name, RestartPolicy::default());
+                    tasks.add_task(&name, task_loop!(processor, supervisor));
                     commands.poweroff_timer.push(CommandSender {
                         name,
                         switch_id: Some(id),
@@ -432,18 +599,329 @@ pub fn processor_tasks(
         }
     }
 
-    if !tasks.has_tasks() {
+    for p in &settings.processors {
+        if !selection.contains(&p.name) {
+            continue;
+        }
+        let supervisor = tasks.supervisor(&p.name, p.restart);
+        match &p.variant {
+            ProcessorType::DeferrableLoad(setting) => {
+                let power_source = match inputs.get(&setting.power_input) {
+                    Some(x) => x.clone(),
+                    None => {
+                        return Err(format!(
+                            "Missing power input for Processor {}",
+                            &p.name
+                        ))
+                    }
+                };
+                let switch_mux = match sinks.get("_SwitchMux") {
+                    Some(ArcSink::SwitchMux(x)) => x.clone(),
+                    _ => {
+                        return Err(format!(
+                            "Missing SwitchMux for Processor {}",
+                            &p.name
+                        ))
+                    }
+                };
+
+                let poweroff_timers = commands
+                    .poweroff_timer
+                    .iter()
+                    .filter_map(|x| Some((x.switch_id?, x.tx.clone())))
+                    .collect();
+                let database = match &database {
+                    Some(x) => x.clone(),
+                    None => {
+                        return Err(format!(
+                            "Processor {} requires a database",
+                            &p.name
+                        ))
+                    }
+                };
+
+                let (command_tx, command_rx) = mpsc::channel(1);
+                let mut processor = DeferrableLoadProcessor::new(
+                    ProcessorBase::new(
+                        p.name.clone(),
+                        tasks.cancel_rx(&p.name),
+                        logger.clone(),
+                    ),
+                    command_rx,
+                    power_source,
+                    switch_mux,
+                    poweroff_timers,
+                    database,
+                    Duration::from_secs(setting.check_interval),
+                );
+                tasks.add_task(&p.name, task_loop!(processor, supervisor));
+                commands.deferrable_load.push(CommandSender {
+                    name: p.name.clone(),
+                    switch_id: None,
+                    tx: command_tx,
+                });
+            }
+            ProcessorType::Rules(setting) => {
+                let switch_mux = match sinks.get("_SwitchMux") {
+                    Some(ArcSink::SwitchMux(x)) => x.clone(),
+                    _ => {
+                        return Err(format!(
+                            "Missing SwitchMux for Processor {}",
+                            &p.name
+                        ))
+                    }
+                };
+                let appliances = commands
+                    .appliance
+                    .iter()
+                    .map(|x| (x.name.clone(), x.tx.clone()))
+                    .collect();
+                let holidays = Holidays::new(&setting.holidays)?;
+                let database = match &database {
+                    Some(x) => x.clone(),
+                    None => {
+                        return Err(format!(
+                            "Processor {} requires a database",
+                            &p.name
+                        ))
+                    }
+                };
+
+                let (command_tx, command_rx) = mpsc::channel(1);
+                let mut processor = RulesProcessor::new(
+                    ProcessorBase::new(
+                        p.name.clone(),
+                        tasks.cancel_rx(&p.name),
+                        logger.clone(),
+                    ),
+                    command_rx,
+                    switch_mux,
+                    appliances,
+                    database,
+                    settings.location.clone(),
+                    holidays,
+                );
+                tasks.add_task(&p.name, task_loop!(processor, supervisor));
+                commands.rules.push(CommandSender {
+                    name: p.name.clone(),
+                    switch_id: None,
+                    tx: command_tx,
+                });
+            }
+            ProcessorType::PeakShaving(setting) => {
+                let meter_source = match inputs.get(&setting.meter_input) {
+                    Some(x) => x.clone(),
+                    None => {
+                        return Err(format!(
+                            "Missing meter input for Processor {}",
+                            &p.name
+                        ))
+                    }
+                };
+                let switch_mux = match sinks.get("_SwitchMux") {
+                    Some(ArcSink::SwitchMux(x)) => x.clone(),
+                    _ => {
+                        return Err(format!(
+                            "Missing SwitchMux for Processor {}",
+                            &p.name
+                        ))
+                    }
+                };
+
+                let mut loads = Vec::new();
+                for load in &setting.loads {
+                    loads.push(match load {
+                        PeakShavingLoad::Switch { name } => {
+                            SheddableLoad::Switch(switch_mux.id_by_name(name)?)
+                        }
+                        PeakShavingLoad::Appliance { name } => {
+                            match commands
+                                .appliance
+                                .iter()
+                                .find(|x| &x.name == name)
+                            {
+                                Some(x) => SheddableLoad::Appliance(
+                                    x.name.clone(),
+                                    x.tx.clone(),
+                                ),
+                                None => {
+                                    return Err(format!(
+                                        "Appliance '{}' for Processor {} \
+                                        does not exist",
+                                        name, &p.name
+                                    ))
+                                }
+                            }
+                        }
+                    });
+                }
+
+                let load_control = if setting.battery_discharge > 0.0 {
+                    match &commands.load_control {
+                        Some(x) => Some(x.tx.clone()),
+                        None => {
+                            return Err(format!(
+                                "Battery discharge for Processor {} \
+                                requires LoadControl",
+                                &p.name
+                            ))
+                        }
+                    }
+                } else {
+                    None
+                };
+
+                let limits = DemandLimits {
+                    interval: Time::new::<second>(setting.interval as f64),
+                    limit: setting.limit.map(Power::new::<watt>),
+                    min_peak: Power::new::<watt>(setting.min_peak),
+                    max_discharge: Power::new::<watt>(
+                        setting.battery_discharge,
+                    ),
+                    shed_delay: Time::new::<second>(setting.shed_delay as f64),
+                };
+
+                let (command_tx, command_rx) = mpsc::channel(1);
+                let mut processor = PeakShavingProcessor::new(
+                    ProcessorBase::new(
+                        p.name.clone(),
+                        tasks.cancel_rx(&p.name),
+                        logger.clone(),
+                    ),
+                    command_rx,
+                    meter_source,
+                    switch_mux,
+                    loads,
+                    load_control,
+                    PeakShavingOptions {
+                        limits,
+                        control_state: StateStore::new(
+                            p.name.clone(),
+                            database.clone(),
+                            logger.clone(),
+                        ),
+                    },
+                );
+                tasks.add_task(&p.name, task_loop!(processor, supervisor));
+                commands.peak_shaving.push(CommandSender {
+                    name: p.name.clone(),
+                    switch_id: None,
+                    tx: command_tx,
+                });
+            }
+            ProcessorType::ExportLimit(setting) => {
+                let meter_source = match inputs.get(&setting.meter_input) {
+                    Some(x) => x.clone(),
+                    None => {
+                        return Err(format!(
+                            "Missing meter input for Processor {}",
+                            &p.name
+                        ))
+                    }
+                };
+                let inverter = match &setting.inverter_output {
+                    Some(name) => match sinks.get(name) {
+                        Some(ArcSink::SunspecInverter(x)) => Some(x.clone()),
+                        Some(x) => {
+                            return Err(format!(
+                                "Unsupported sink type '{}' for \
+                                ExportLimitProcessor",
+                                x
+                            ))
+                        }
+                        None => {
+                            return Err(format!(
+                                "Missing sink 'inverter_output' for \
+                                Processor {}",
+                                &p.name
+                            ))
+                        }
+                    },
+                    None => None,
+                };
+                let battery = if setting.battery_charge > 0.0 {
+                    match &commands.load_control {
+                        Some(x) => Some(x.tx.clone()),
+                        None => {
+                            return Err(format!(
+                                "Battery charge for Processor {} \
+                                requires LoadControl",
+                                &p.name
+                            ))
+                        }
+                    }
+                } else {
+                    None
+                };
+                if inverter.is_none() && battery.is_none() {
+                    return Err(format!(
+                        "Processor {} requires an inverter or battery output",
+                        &p.name
+                    ));
+                }
+
+                let battery_charge = Power::new::<watt>(setting.battery_charge);
+                let nominal_power = match &inverter {
+                    Some(x) => x.nominal_power(),
+                    None => Power::new::<watt>(0.0),
+                };
+                let max_curtailment = nominal_power + battery_charge;
+                let fail_safe = (max_curtailment
+                    - Power::new::<watt>(setting.fail_safe_power))
+                .max(Power::new::<watt>(0.0));
+                let controller = ExportController::new(
+                    Power::new::<watt>(setting.limit),
+                    setting.kp,
+                    setting.ki,
+                    max_curtailment,
+                    fail_safe,
+                );
+
+                let mut processor = ExportLimitProcessor::new(
+                    ProcessorBase::new(
+                        p.name.clone(),
+                        tasks.cancel_rx(&p.name),
+                        logger.clone(),
+                    ),
+                    meter_source,
+                    controller,
+                    inverter,
+                    battery,
+                    battery_charge,
+                    Duration::from_secs(setting.meter_timeout),
+                );
+                tasks.add_task(&p.name, task_loop!(processor, supervisor));
+            }
+            _ => (),
+        }
+    }
+
+    if matches!(selection, Selection::All) && !tasks.has_tasks() {
         debug!(logger, "No processors enabled, using dummy");
         let mut dummy = DummyProcessor::new(ProcessorBase::new(
             "dummy".into(),
-            tasks.cancel_rx(),
+            tasks.cancel_rx("dummy"),
             logger,
         ));
-        tasks.add_task(task_loop!(dummy));
+        let supervisor = tasks.supervisor("dummy", RestartPolicy::default());
+        tasks.add_task("dummy", task_loop!(dummy, supervisor));
     }
 
-    Ok(ProcessorInfo {
-        tasks: tasks.build(),
-        commands,
-    })
+    Ok(commands)
+}
+
+#[test]
+fn test_is_stale() {
+    use crate::models::AvailablePower;
+
+    let timeout = Duration::from_secs(60);
+    assert!(!is_stale(&Model::None, 1000.0, timeout));
+
+    let record: Model = AvailablePower::new(
+        Time::new::<second>(900.0),
+        Power::new::<watt>(100.0),
+    )
+    .into();
+    assert!(!is_stale(&record, 960.0, timeout));
+    assert!(is_stale(&record, 961.0, timeout));
 }
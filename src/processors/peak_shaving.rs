@@ -0,0 +1,639 @@
+/******************************************************************************\
+    empowerd - empowers the offline smart home
+    Copyright (C) 2019 - 2026 Max Maisel
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU Affero General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU Affero General Public License for more details.
+
+    You should have received a copy of the GNU Affero General Public License
+    along with this program.  If not, see <https://www.gnu.org/licenses/>.
+\******************************************************************************/
+use super::{ApplianceCmd, LoadControlCmd, ProcessorBase, StateStore};
+use crate::{
+    models::{
+        units::{joule, second, watt, Abbreviation, Energy, Power, Time},
+        Model,
+    },
+    task_group::TaskResult,
+    tri_state::TriState,
+    Error, SwitchMux,
+};
+use chrono::{DateTime, Datelike, Local};
+use slog::{debug, info, warn, Logger};
+use std::{collections::BTreeMap, sync::Arc};
+use tokio::sync::{mpsc, oneshot, watch};
+
+#[derive(Clone, Debug)]
+pub struct PeakStatus {
+    pub month_peak: Power,
+    pub average: Power,
+    pub predicted: Power,
+    pub threshold: Power,
+    pub shed_loads: usize,
+    pub discharge: Power,
+}
+
+#[derive(Debug)]
+pub enum Command {
+    GetStatus { resp: oneshot::Sender<PeakStatus> },
+}
+
+/// A load which can be switched off to reduce the demand peak.
+pub enum SheddableLoad {
+    Switch(usize),
+    Appliance(String, mpsc::Sender<ApplianceCmd>),
+}
+
+/// State of a shed load before it was switched off.
+enum ShedState {
+    Switch(bool),
+    Appliance(TriState),
+}
+
+/// Limits of the peak shaving controller.
+#[derive(Clone, Debug)]
+pub struct DemandLimits {
+    /// Length of a demand interval.
+    pub interval: Time,
+    /// Hard limit for the interval average import.
+    pub limit: Option<Power>,
+    /// Interval averages below this value are never shaved.
+    pub min_peak: Power,
+    /// Maximum requested battery discharge power.
+    pub max_discharge: Power,
+    /// Minimum time between shedding two loads.
+    pub shed_delay: Time,
+}
+
+/// Options of the peak shaving processor.
+pub struct PeakShavingOptions {
+    pub limits: DemandLimits,
+    /// Persists the month peak across restarts.
+    pub control_state: StateStore,
+}
+
+/// Tracks the average grid import of fixed demand intervals
+/// and the highest interval average of the current month.
+#[derive(Clone, Debug)]
+pub struct DemandTracker {
+    interval: Time,
+    interval_start: Time,
+    last_time: Option<Time>,
+    last_power: Power,
+    energy: Energy,
+    month: Option<(i32, u32)>,
+    month_peak: Power,
+}
+
+impl DemandTracker {
+    pub fn new(interval: Time) -> Self {
+        Self {
+            interval,
+            interval_start: Time::new::<second>(0.0),
+            last_time: None,
+            last_power: Power::new::<watt>(0.0),
+            energy: Energy::new::<joule>(0.0),
+            month: None,
+            month_peak: Power::new::<watt>(0.0),
+        }
+    }
+
+    fn interval_start_of(&self, time: Time) -> Time {
+        let interval = self.interval.get::<second>();
+        Time::new::<second>(
+            (time.get::<second>() / interval).floor() * interval,
+        )
+    }
+
+    fn month_of(time: Time) -> Option<(i32, u32)> {
+        DateTime::from_timestamp(time.get::<second>() as i64, 0).map(|x| {
+            let local = x.with_timezone(&Local);
+            (local.year(), local.month())
+        })
+    }
+
+    /// Adds a grid power sample. Export is counted as zero import.
+    /// Returns the average import of the previous interval
+    /// when the sample starts a new interval.
+    pub fn add_sample(&mut self, time: Time, power: Power) -> Option<Power> {
+        let zero = Power::new::<watt>(0.0);
+        let last_time = match self.last_time {
+            Some(x) if time > x => x,
+            Some(_) => return None,
+            None => {
+                self.interval_start = self.interval_start_of(time);
+                self.last_time = Some(time);
+                self.last_power = power.max(zero);
+                return None;
+            }
+        };
+
+        let interval_end = self.interval_start + self.interval;
+        let mut result = None;
+        if time >= interval_end {
+            self.energy += self.last_power * (interval_end - last_time);
+            let average = self.energy / self.interval;
+
+            let month = Self::month_of(self.interval_start);
+            if month != self.month {
+                self.month = month;
+                self.month_peak = zero;
+            }
+            self.month_peak = self.month_peak.max(average);
+
+            // Samples are held, even across missing intervals.
+            self.interval_start = self.interval_start_of(time);
+            self.energy = self.last_power * (time - self.interval_start);
+            result = Some(average);
+        } else {
+            self.energy += self.last_power * (time - last_time);
+        }
+
+        self.last_time = Some(time);
+        self.last_power = power.max(zero);
+        result
+    }
+
+    pub fn month_peak(&self) -> Power {
+        self.month_peak
+    }
+
+    /// Month of the last finished interval as `year * 100 + month`.
+    pub fn month(&self) -> Option<u32> {
+        self.month.map(|(year, month)| year as u32 * 100 + month)
+    }
+
+    /// Restores the month peak after a restart. A peak of another month
+    /// is dropped with the first finished interval.
+    pub fn restore_month_peak(&mut self, month: u32, peak: Power) {
+        self.month = Some(((month / 100) as i32, month % 100));
+        self.month_peak = peak;
+    }
+
+    /// Average import since start of the current interval.
+    pub fn average(&self) -> Power {
+        match self.last_time {
+            Some(x) if x > self.interval_start => {
+                self.energy / (x - self.interval_start)
+            }
+            _ => self.last_power,
+        }
+    }
+
+    /// Remaining time of the current interval.
+    pub fn remaining(&self) -> Time {
+        let now = self.last_time.unwrap_or(self.interval_start);
+        self.interval_start + self.interval - now
+    }
+
+    /// Predicts the interval average assuming that the current
+    /// import stays constant until the end of the interval.
+    pub fn predicted(&self) -> Power {
+        (self.energy + self.last_power * self.remaining()) / self.interval
+    }
+}
+
+pub struct PeakShavingProcessor {
+    base: ProcessorBase,
+    command_input: mpsc::Receiver<Command>,
+    meter_input: watch::Receiver<Model>,
+    switch_output: Arc<SwitchMux>,
+    loads: Vec<SheddableLoad>,
+    load_control: Option<mpsc::Sender<LoadControlCmd>>,
+    limits: DemandLimits,
+    tracker: DemandTracker,
+    control_state: StateStore,
+    shed: Vec<ShedState>,
+    last_shed: Time,
+    discharge: Power,
+}
+
+impl PeakShavingProcessor {
+    pub fn new(
+        base: ProcessorBase,
+        command_input: mpsc::Receiver<Command>,
+        meter_input: watch::Receiver<Model>,
+        switch_output: Arc<SwitchMux>,
+        loads: Vec<SheddableLoad>,
+        load_control: Option<mpsc::Sender<LoadControlCmd>>,
+        options: PeakShavingOptions,
+    ) -> Self {
+        Self {
+            base,
+            command_input,
+            meter_input,
+            switch_output,
+            loads,
+            load_control,
+            tracker: DemandTracker::new(options.limits.interval),
+            limits: options.limits,
+            control_state: options.control_state,
+            shed: Vec::new(),
+            last_shed: Time::new::<second>(0.0),
+            discharge: Power::new::<watt>(0.0),
+        }
+    }
+
+    pub fn logger(&self) -> &Logger {
+        &self.base.logger
+    }
+
+    pub async fn run(&mut self) -> TaskResult {
+        if let Some(values) = self.control_state.restore().await {
+            self.restore(&values);
+        }
+
+        tokio::select! {
+            _ = self.base.canceled.changed() => {
+                return Err(Error::Canceled(self.base.name.clone()));
+            }
+            x = self.command_input.recv() => {
+                if let Some(command) = x {
+                    self.handle_command(command)?;
+                }
+                return Ok(());
+            }
+            x = self.meter_input.changed() => {
+                if let Err(e) = x {
+                    return Err(Error::Bug(
+                        format!("Reading meter input failed: {e}")
+                    ));
+                }
+            }
+        };
+
+        let (time, power) = match *self.meter_input.borrow() {
+            Model::BidirMeter(ref x) => (x.time, x.power),
+            Model::None => return Ok(()),
+            _ => {
+                return Err(Error::Temporary(format!(
+                    "Received invalid model from meter input: {:?}",
+                    *self.meter_input.borrow()
+                )))
+            }
+        };
+
+        if let Some(average) = self.tracker.add_sample(time, power) {
+            debug!(
+                self.base.logger,
+                "Demand interval finished with {} average, month peak is {}",
+                average.into_format_args(watt, Abbreviation),
+                self.tracker
+                    .month_peak()
+                    .into_format_args(watt, Abbreviation),
+            );
+            if average >= self.tracker.month_peak() {
+                self.save_month_peak().await;
+            }
+            // Shed loads are only restored at the start of a new interval
+            // to avoid toggling them during the interval.
+            self.restore_loads().await;
+        }
+
+        let can_shed = self.shed.len() < self.loads.len()
+            && time - self.last_shed >= self.limits.shed_delay;
+        let (discharge, shed) =
+            Self::plan(&self.limits, &self.tracker, self.discharge, can_shed);
+
+        self.set_discharge(discharge).await?;
+        if shed {
+            self.last_shed = time;
+            self.shed_next_load().await;
+        }
+
+        Ok(())
+    }
+
+    /// Applies the month peak which was stored before the last restart.
+    fn restore(&mut self, values: &BTreeMap<String, String>) {
+        let month = self.control_state.parse(values, "month");
+        let peak = self.control_state.parse(values, "month_peak");
+        if let (Some(month), Some(peak)) = (month, peak) {
+            let peak = Power::new::<watt>(peak);
+            info!(
+                self.base.logger,
+                "Restored month peak of {}",
+                peak.into_format_args(watt, Abbreviation),
+            );
+            self.tracker.restore_month_peak(month, peak);
+        }
+    }
+
+    async fn save_month_peak(&self) {
+        if let Some(month) = self.tracker.month() {
+            self.control_state.save("month", &month).await;
+            self.control_state
+                .save("month_peak", &self.tracker.month_peak().get::<watt>())
+                .await;
+        }
+    }
+
+    fn threshold(limits: &DemandLimits, tracker: &DemandTracker) -> Power {
+        let threshold = tracker.month_peak().max(limits.min_peak);
+        match limits.limit {
+            Some(x) => threshold.min(x),
+            None => threshold,
+        }
+    }
+
+    /// Calculates the requested battery discharge power and
+    /// whether another load must be shed.
+    fn plan(
+        limits: &DemandLimits,
+        tracker: &DemandTracker,
+        discharge: Power,
+        can_shed: bool,
+    ) -> (Power, bool) {
+        let zero = Power::new::<watt>(0.0);
+        let threshold = Self::threshold(limits, tracker);
+        let remaining = tracker.remaining().max(Time::new::<second>(1.0));
+        // The power correction which is required to end the interval
+        // exactly at the threshold.
+        let correction =
+            (tracker.predicted() - threshold) * limits.interval / remaining;
+
+        let discharge = (discharge + correction).max(zero);
+        if discharge > limits.max_discharge {
+            (limits.max_discharge, can_shed)
+        } else {
+            (discharge, false)
+        }
+    }
+
+    async fn set_discharge(&mut self, power: Power) -> Result<(), Error> {
+        let load_control = match &self.load_control {
+            Some(x) => x,
+            None => return Ok(()),
+        };
+        if (self.discharge - power).abs() < Power::new::<watt>(1.0) {
+            return Ok(());
+        }
+
+        let (tx, rx) = oneshot::channel();
+        let cmd = LoadControlCmd::SetDischargePower { power, resp: tx };
+        if let Err(e) = load_control.send(cmd).await {
+            return Err(Error::Temporary(format!(
+                "Sending discharge request failed: {e}"
+            )));
+        }
+        rx.await.map_err(|e| {
+            Error::Temporary(format!(
+                "Receiving discharge response failed: {e}"
+            ))
+        })?;
+
+        self.discharge = power;
+        Ok(())
+    }
+
+    async fn shed_next_load(&mut self) {
+        let load = match self.loads.get(self.shed.len()) {
+            Some(x) => x,
+            None => return,
+        };
+
+        let result = match load {
+            SheddableLoad::Switch(id) => {
+                Self::shed_switch(&self.switch_output, *id).await
+            }
+            SheddableLoad::Appliance(_, tx) => {
+                Self::set_appliance(tx, TriState::Off).await
+            }
+        };
+        match result {
+            Ok(state) => {
+                info!(
+                    self.base.logger,
+                    "Shedding load {} to keep demand below {}",
+                    self.shed.len(),
+                    Self::threshold(&self.limits, &self.tracker)
+                        .into_format_args(watt, Abbreviation),
+                );
+                self.shed.push(state);
+            }
+            Err(e) => {
+                warn!(
+                    self.base.logger,
+                    "Shedding load {} failed: {}",
+                    self.shed.len(),
+                    e
+                );
+            }
+        }
+    }
+
+    async fn restore_loads(&mut self) {
+        for (load, state) in self.loads.iter().zip(self.shed.drain(..)) {
+            let result = match (load, state) {
+                (SheddableLoad::Switch(id), ShedState::Switch(x)) => {
+                    self.switch_output.write_val(*id, x).await
+                }
+                (SheddableLoad::Appliance(_, tx), ShedState::Appliance(x)) => {
+                    Self::set_appliance(tx, x).await.map(|_| ())
+                }
+                _ => Err("Load does not match its state".into()),
+            };
+            if let Err(e) = result {
+                warn!(self.base.logger, "Restoring load failed: {}", e);
+            }
+        }
+    }
+
+    async fn shed_switch(
+        switch_mux: &SwitchMux,
+        id: usize,
+    ) -> Result<ShedState, String> {
+        let state = switch_mux.read_val(id).await?;
+        if state {
+            switch_mux.write_val(id, false).await?;
+        }
+        Ok(ShedState::Switch(state))
+    }
+
+    /// Sets a new appliance mode and returns the previous one.
+    async fn set_appliance(
+        tx: &mpsc::Sender<ApplianceCmd>,
+        force_on_off: TriState,
+    ) -> Result<ShedState, String> {
+        let (resp_tx, resp_rx) = oneshot::channel();
+        tx.send(ApplianceCmd::GetForceOnOff { resp: resp_tx })
+            .await
+            .map_err(|e| format!("Sending command failed: {e}"))?;
+        let state = resp_rx
+            .await
+            .map_err(|e| format!("Receiving response failed: {e}"))?;
+
+        let (resp_tx, resp_rx) = oneshot::channel();
+        tx.send(ApplianceCmd::SetForceOnOff {
+            force_on_off,
+            persist: false,
+            resp: resp_tx,
+        })
+        .await
+        .map_err(|e| format!("Sending command failed: {e}"))?;
+        resp_rx
+            .await
+            .map_err(|e| format!("Receiving response failed: {e}"))?;
+
+        Ok(ShedState::Appliance(state.get()))
+    }
+
+    fn handle_command(&mut self, command: Command) -> Result<(), Error> {
+        match command {
+            Command::GetStatus { resp } => {
+                let status = PeakStatus {
+                    month_peak: self.tracker.month_peak(),
+                    average: self.tracker.average(),
+                    predicted: self.tracker.predicted(),
+                    threshold: Self::threshold(&self.limits, &self.tracker),
+                    shed_loads: self.shed.len(),
+                    discharge: self.discharge,
+                };
+                if resp.send(status).is_err() {
+                    return Err(Error::Bug(
+                        "Sending GetStatus response failed!".into(),
+                    ));
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+fn seconds(x: f64) -> Time {
+    Time::new::<second>(x)
+}
+
+#[cfg(test)]
+fn watts(x: f64) -> Power {
+    Power::new::<watt>(x)
+}
+
+#[cfg(test)]
+fn assert_power(expected: f64, actual: Power, msg: &str) {
+    assert!(
+        (actual.get::<watt>() - expected).abs() < 0.01,
+        "{msg}: expected {expected} W, got {} W",
+        actual.get::<watt>()
+    );
+}
+
+#[test]
+fn test_demand_tracker() {
+    // Mid of a month, so that local time zone does not matter.
+    let start = 1_789_000_200.0;
+    let mut tracker = DemandTracker::new(seconds(900.0));
+
+    assert_eq!(None, tracker.add_sample(seconds(start), watts(1000.0)));
+    assert_eq!(
+        None,
+        tracker.add_sample(seconds(start + 300.0), watts(4000.0))
+    );
+    assert_power(1000.0, tracker.average(), "Wrong average");
+    assert_power(3000.0, tracker.predicted(), "Wrong prediction");
+
+    assert_eq!(
+        None,
+        tracker.add_sample(seconds(start + 600.0), watts(-2000.0)),
+    );
+    assert_power(2500.0, tracker.average(), "Wrong average");
+    assert_power(1666.67, tracker.predicted(), "Export was not clamped");
+
+    let average = tracker
+        .add_sample(seconds(start + 960.0), watts(500.0))
+        .expect("Interval did not finish");
+    assert_power(1666.67, average, "Wrong interval average");
+    assert_power(1666.67, tracker.month_peak(), "Wrong month peak");
+    assert_power(0.0, tracker.average(), "Interval was not reset");
+
+    let average = tracker
+        .add_sample(seconds(start + 1800.0), watts(500.0))
+        .expect("Interval did not finish");
+    assert_power(466.67, average, "Wrong interval average");
+    assert_power(1666.67, tracker.month_peak(), "Month peak was lowered");
+
+    // The first interval of the next month resets the peak.
+    let next_month = start + 35.0 * 86400.0;
+    tracker.add_sample(seconds(next_month), watts(100.0));
+    tracker.add_sample(seconds(next_month + 900.0), watts(100.0));
+    assert_power(100.0, tracker.month_peak(), "Month peak was not reset");
+}
+
+#[test]
+fn test_restore_month_peak() {
+    let start = 1_789_000_200.0;
+    let mut tracker = DemandTracker::new(seconds(900.0));
+    tracker.add_sample(seconds(start), watts(1000.0));
+    tracker.add_sample(seconds(start + 900.0), watts(1000.0));
+    let month = tracker.month().expect("Month was not set");
+
+    let mut tracker = DemandTracker::new(seconds(900.0));
+    tracker.restore_month_peak(month, watts(3000.0));
+    tracker.add_sample(seconds(start), watts(1000.0));
+    tracker.add_sample(seconds(start + 900.0), watts(1000.0));
+    assert_power(3000.0, tracker.month_peak(), "Month peak was not kept");
+
+    let mut tracker = DemandTracker::new(seconds(900.0));
+    tracker.restore_month_peak(month - 1, watts(3000.0));
+    tracker.add_sample(seconds(start), watts(1000.0));
+    tracker.add_sample(seconds(start + 900.0), watts(1000.0));
+    assert_power(1000.0, tracker.month_peak(), "Old month peak was kept");
+}
+
+#[test]
+fn test_plan() {
+    let start = 1_789_000_200.0;
+    let limits = DemandLimits {
+        interval: seconds(900.0),
+        limit: Some(watts(5000.0)),
+        min_peak: watts(2000.0),
+        max_discharge: watts(1000.0),
+        shed_delay: seconds(30.0),
+    };
+    let mut tracker = DemandTracker::new(seconds(900.0));
+    tracker.add_sample(seconds(start), watts(1500.0));
+    tracker.add_sample(seconds(start + 450.0), watts(1500.0));
+
+    assert_power(
+        2000.0,
+        PeakShavingProcessor::threshold(&limits, &tracker),
+        "Minimum peak was not used",
+    );
+    let (discharge, shed) =
+        PeakShavingProcessor::plan(&limits, &tracker, watts(0.0), true);
+    assert_power(0.0, discharge, "Discharged below threshold");
+    assert!(!shed, "Shed load below threshold");
+
+    tracker.add_sample(seconds(start + 600.0), watts(3500.0));
+    let (discharge, shed) =
+        PeakShavingProcessor::plan(&limits, &tracker, watts(0.0), true);
+    assert_power(500.0, discharge, "Wrong discharge power");
+    assert!(!shed, "Shed load although battery can handle the peak");
+
+    tracker.add_sample(seconds(start + 750.0), watts(4000.0));
+    let (discharge, shed) =
+        PeakShavingProcessor::plan(&limits, &tracker, watts(500.0), true);
+    assert_power(1000.0, discharge, "Discharge was not limited");
+    assert!(shed, "Did not shed load");
+
+    let (_, shed) =
+        PeakShavingProcessor::plan(&limits, &tracker, watts(500.0), false);
+    assert!(!shed, "Shed load during shed delay");
+
+    tracker.add_sample(seconds(start + 800.0), watts(500.0));
+    let (discharge, _) =
+        PeakShavingProcessor::plan(&limits, &tracker, watts(1000.0), false);
+    assert!(
+        discharge < watts(1000.0),
+        "Discharge was not reduced below threshold"
+    );
+}
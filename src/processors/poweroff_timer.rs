@@ -15,9 +15,10 @@
     You should have received a copy of the GNU Affero General Public License
     along with this program.  If not, see <https://www.gnu.org/licenses/>.
 \******************************************************************************/
-use super::ProcessorBase;
+use super::{Overridable, ProcessorBase, StateStore};
 use crate::{task_group::TaskResult, Error, SwitchMux};
-use slog::{debug, Logger};
+use slog::{debug, info, Logger};
+use std::collections::BTreeMap;
 use std::sync::Arc;
 use std::time::Duration;
 use tokio::sync::{mpsc, oneshot, watch};
@@ -26,11 +27,16 @@ use tokio::sync::{mpsc, oneshot, watch};
 pub enum Command {
     SetOnTime {
         on_time: Duration,
+        /// False for the on time of a single run, e.g. of a deferrable job.
+        /// It only applies until the switch is turned off again.
+        persist: bool,
         resp: oneshot::Sender<()>,
     },
     GetOnTime {
-        resp: oneshot::Sender<Duration>,
+        resp: oneshot::Sender<Overridable<Duration>>,
     },
+    /// Returns to the configured on time.
+    ResetOnTime { resp: oneshot::Sender<Duration> },
 }
 
 pub struct PoweroffTimerProcessor {
@@ -39,7 +45,10 @@ pub struct PoweroffTimerProcessor {
     switch_input: watch::Receiver<bool>,
     switch_output: Arc<SwitchMux>,
     switch_id: usize,
-    on_time: Duration,
+    on_time: Overridable<Duration>,
+    control_state: StateStore,
+    // On time of the current run, which overrides the on time once.
+    run_on_time: Option<Duration>,
     sleep_time: Duration,
 }
 
@@ -51,6 +60,7 @@ impl PoweroffTimerProcessor {
         switch_output: Arc<SwitchMux>,
         switch_id: usize,
         on_time: Duration,
+        control_state: StateStore,
     ) -> Self {
         Self {
             base,
@@ -58,7 +68,9 @@ impl PoweroffTimerProcessor {
             switch_input,
             switch_output,
             switch_id,
-            on_time,
+            on_time: Overridable::new(on_time),
+            control_state,
+            run_on_time: None,
             sleep_time: Duration::from_secs(0),
         }
     }
@@ -68,13 +80,17 @@ impl PoweroffTimerProcessor {
     }
 
     pub async fn run(&mut self) -> TaskResult {
+        if let Some(values) = self.control_state.restore().await {
+            self.restore(&values);
+        }
+
         tokio::select! {
             _ = self.base.canceled.changed() => {
                 return Err(Error::Canceled(self.base.name.clone()));
             }
             x = self.command_input.recv() => {
                 if let Some(command) = x {
-                    self.handle_command(command)?;
+                    self.handle_command(command).await?;
                 }
             }
             _ = tokio::time::sleep(self.sleep_time) => {
@@ -104,7 +120,7 @@ impl PoweroffTimerProcessor {
     }
 
     async fn update_output(&mut self, value: bool) -> Result<(), Error> {
-        match self
+        let result = match self
             .switch_output
             .write_val_raw(self.switch_id, value)
             .await
@@ -118,21 +134,44 @@ impl PoweroffTimerProcessor {
                 self.sleep_time = self.calc_sleep_time(false);
                 Err(e)
             }
+        };
+        if !value || result.is_err() {
+            self.run_on_time = None;
         }
+        result
     }
 
     fn calc_sleep_time(&self, value: bool) -> Duration {
         if value {
-            self.on_time
+            self.run_on_time.unwrap_or_else(|| self.on_time.get())
         } else {
             Duration::from_secs(u64::MAX)
         }
     }
 
-    fn handle_command(&mut self, command: Command) -> Result<(), Error> {
+    /// Applies the on time which was set before the last restart.
+    fn restore(&mut self, values: &BTreeMap<String, String>) {
+        if let Some(x) = self.control_state.parse(values, "on_time") {
+            info!(self.base.logger, "Restored on time of {} s", x);
+            self.on_time.set(Duration::from_secs(x));
+        }
+    }
+
+    async fn handle_command(&mut self, command: Command) -> Result<(), Error> {
         match command {
-            Command::SetOnTime { on_time, resp } => {
-                self.on_time = on_time;
+            Command::SetOnTime {
+                on_time,
+                persist,
+                resp,
+            } => {
+                if persist {
+                    self.on_time.set(on_time);
+                    self.control_state
+                        .save("on_time", &on_time.as_secs())
+                        .await;
+                } else {
+                    self.run_on_time = Some(on_time);
+                }
                 if resp.send(()).is_err() {
                     return Err(Error::Bug(
                         "Sending SetOnTime response failed!".into(),
@@ -146,6 +185,15 @@ impl PoweroffTimerProcessor {
                     ));
                 }
             }
+            Command::ResetOnTime { resp } => {
+                self.on_time.reset();
+                self.control_state.reset("on_time").await;
+                if resp.send(self.on_time.get()).is_err() {
+                    return Err(Error::Bug(
+                        "Sending ResetOnTime response failed!".into(),
+                    ));
+                }
+            }
         }
 
         Ok(())
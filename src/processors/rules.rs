@@ -0,0 +1,415 @@
+/******************************************************************************\
+    empowerd - empowers the offline smart home
+    Copyright (C) 2019 - 2026 Max Maisel
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU Affero General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU Affero General Public License for more details.
+
+    You should have received a copy of the GNU Affero General Public License
+    along with this program.  If not, see <https://www.gnu.org/licenses/>.
+\******************************************************************************/
+use super::{ApplianceCmd, ProcessorBase};
+use crate::{
+    models::SwitchRule,
+    schedule::{Holidays, RuleTarget},
+    settings::Location,
+    task_group::TaskResult,
+    tri_state::TriState,
+    Error, SwitchMux,
+};
+use chrono::{DateTime, Local, TimeZone};
+use diesel_async::{
+    pooled_connection::deadpool::{Object, Pool},
+    AsyncPgConnection,
+};
+use slog::{debug, info, warn, Logger};
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::{mpsc, oneshot};
+
+#[derive(Debug)]
+pub enum Command {
+    SetRule {
+        rule: SwitchRule,
+        resp: oneshot::Sender<Result<SwitchRule, String>>,
+    },
+    DeleteRule {
+        id: i32,
+        resp: oneshot::Sender<Result<(), String>>,
+    },
+    GetRules {
+        resp: oneshot::Sender<Vec<SwitchRule>>,
+    },
+}
+
+pub struct RulesProcessor {
+    base: ProcessorBase,
+    command_input: mpsc::Receiver<Command>,
+    switch_output: Arc<SwitchMux>,
+    appliance_output: BTreeMap<String, mpsc::Sender<ApplianceCmd>>,
+    database: Pool<AsyncPgConnection>,
+    location: Option<Location>,
+    holidays: Holidays,
+    // Rules are loaded from the database on first run.
+    rules: Option<Vec<SwitchRule>>,
+    last_minute: i64,
+}
+
+impl RulesProcessor {
+    pub fn new(
+        base: ProcessorBase,
+        command_input: mpsc::Receiver<Command>,
+        switch_output: Arc<SwitchMux>,
+        appliance_output: BTreeMap<String, mpsc::Sender<ApplianceCmd>>,
+        database: Pool<AsyncPgConnection>,
+        location: Option<Location>,
+        holidays: Holidays,
+    ) -> Self {
+        Self {
+            base,
+            command_input,
+            switch_output,
+            appliance_output,
+            database,
+            location,
+            holidays,
+            rules: None,
+            // Rules of the current minute are applied on the first run.
+            last_minute: Local::now().timestamp().div_euclid(60) - 1,
+        }
+    }
+
+    pub fn logger(&self) -> &Logger {
+        &self.base.logger
+    }
+
+    pub async fn run(&mut self) -> TaskResult {
+        if self.rules.is_none() {
+            let mut conn = self.get_database().await?;
+            self.rules =
+                Some(SwitchRule::all(&mut conn, &self.base.name).await?);
+        }
+
+        // Wake up at the start of the next minute, unless the current
+        // minute was not applied yet.
+        let now = Local::now();
+        let sleep_time = if now.timestamp().div_euclid(60) != self.last_minute {
+            Duration::from_secs(0)
+        } else {
+            Duration::from_millis(
+                60_000 - (now.timestamp_millis().rem_euclid(60_000) as u64),
+            )
+        };
+        tokio::select! {
+            _ = self.base.canceled.changed() => {
+                return Err(Error::Canceled(self.base.name.clone()));
+            }
+            x = self.command_input.recv() => {
+                if let Some(command) = x {
+                    self.handle_command(command).await?;
+                }
+            }
+            _ = tokio::time::sleep(sleep_time) => (),
+        };
+
+        let now = Local::now();
+        let minute = now.timestamp().div_euclid(60);
+        if minute == self.last_minute {
+            return Ok(());
+        }
+        self.last_minute = minute;
+        self.apply_rules(&now).await
+    }
+
+    async fn get_database(&self) -> Result<Object<AsyncPgConnection>, Error> {
+        self.database.get().await.map_err(|e| {
+            Error::Temporary(format!(
+                "Getting database connection from pool failed: {e}",
+            ))
+        })
+    }
+
+    fn rules_mut(&mut self) -> Result<&mut Vec<SwitchRule>, Error> {
+        self.rules
+            .as_mut()
+            .ok_or_else(|| Error::Bug("Rules were not loaded".into()))
+    }
+
+    /// Returns all enabled rules which trigger at the given time
+    /// or an error for rules which can not be evaluated.
+    fn triggered<Tz: TimeZone>(
+        rules: &[SwitchRule],
+        time: &DateTime<Tz>,
+        location: Option<&Location>,
+        holidays: &Holidays,
+    ) -> Vec<Result<SwitchRule, String>> {
+        let date = time.date_naive();
+        rules
+            .iter()
+            .filter(|x| x.enabled && x.calendar.allows(holidays, date))
+            .filter_map(|rule| {
+                let matches = rule
+                    .parsed_schedule()
+                    .and_then(|x| x.matches(time, location));
+                match matches {
+                    Ok(true) => Some(Ok(rule.clone())),
+                    Ok(false) => None,
+                    Err(e) => Some(Err(format!(
+                        "Evaluating rule {} '{}' failed: {}",
+                        rule.id, rule.name, e
+                    ))),
+                }
+            })
+            .collect()
+    }
+
+    async fn apply_rules<Tz: TimeZone>(
+        &self,
+        time: &DateTime<Tz>,
+    ) -> TaskResult {
+        let rules = match &self.rules {
+            Some(x) => x,
+            None => return Ok(()),
+        };
+        let triggered = Self::triggered(
+            rules,
+            time,
+            self.location.as_ref(),
+            &self.holidays,
+        );
+
+        // Rules are applied in order, so later rules override earlier
+        // ones on the same target. A single broken rule must not block
+        // the others.
+        for rule in triggered {
+            let rule = match rule {
+                Ok(x) => x,
+                Err(e) => {
+                    warn!(self.base.logger, "{}", e);
+                    continue;
+                }
+            };
+            debug!(
+                self.base.logger,
+                "Rule {} '{}' sets {} '{}' to {}",
+                rule.id,
+                rule.name,
+                rule.target,
+                rule.target_name,
+                rule.action
+            );
+            if let Err(e) = self.apply_action(&rule).await {
+                warn!(
+                    self.base.logger,
+                    "Applying rule {} '{}' failed: {}", rule.id, rule.name, e
+                );
+            }
+        }
+
+        Ok(())
+    }
+
+    async fn apply_action(&self, rule: &SwitchRule) -> Result<(), String> {
+        match rule.target {
+            RuleTarget::Switch => {
+                let id = self.switch_output.id_by_name(&rule.target_name)?;
+                let value = match rule.action {
+                    TriState::On => true,
+                    TriState::Off => false,
+                    TriState::Auto => {
+                        return Err("Switches do not support Auto".into())
+                    }
+                };
+                self.switch_output.write_val(id, value).await
+            }
+            RuleTarget::Appliance => {
+                let tx = match self.appliance_output.get(&rule.target_name) {
+                    Some(x) => x,
+                    None => {
+                        return Err(format!(
+                            "Appliance '{}' does not exist",
+                            rule.target_name
+                        ))
+                    }
+                };
+                let (resp_tx, resp_rx) = oneshot::channel();
+                tx.send(ApplianceCmd::SetForceOnOff {
+                    force_on_off: rule.action,
+                    persist: true,
+                    resp: resp_tx,
+                })
+                .await
+                .map_err(|e| format!("Sending command failed: {e}"))?;
+                resp_rx
+                    .await
+                    .map_err(|e| format!("Receiving response failed: {e}"))
+            }
+        }
+    }
+
+    fn validate(&self, rule: &SwitchRule) -> Result<(), String> {
+        let schedule = rule.parsed_schedule()?;
+        if schedule.needs_location() && self.location.is_none() {
+            return Err("Location is required for sun times".into());
+        }
+
+        match rule.target {
+            RuleTarget::Switch => {
+                self.switch_output.id_by_name(&rule.target_name)?;
+                if rule.action == TriState::Auto {
+                    return Err("Switches do not support Auto".into());
+                }
+            }
+            RuleTarget::Appliance => {
+                if !self.appliance_output.contains_key(&rule.target_name) {
+                    return Err(format!(
+                        "Appliance '{}' does not exist",
+                        rule.target_name
+                    ));
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    async fn handle_command(&mut self, command: Command) -> Result<(), Error> {
+        match command {
+            Command::SetRule { rule, resp } => {
+                let result = self.set_rule(rule).await;
+                if resp.send(result).is_err() {
+                    return Err(Error::Bug(
+                        "Sending SetRule response failed!".into(),
+                    ));
+                }
+            }
+            Command::DeleteRule { id, resp } => {
+                let result = self.delete_rule(id).await;
+                if resp.send(result).is_err() {
+                    return Err(Error::Bug(
+                        "Sending DeleteRule response failed!".into(),
+                    ));
+                }
+            }
+            Command::GetRules { resp } => {
+                let rules = self.rules_mut()?.clone();
+                if resp.send(rules).is_err() {
+                    return Err(Error::Bug(
+                        "Sending GetRules response failed!".into(),
+                    ));
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Inserts a new rule if its ID is zero, otherwise updates the rule.
+    async fn set_rule(
+        &mut self,
+        mut rule: SwitchRule,
+    ) -> Result<SwitchRule, String> {
+        self.validate(&rule)?;
+        let mut conn = self.get_database().await.map_err(String::from)?;
+        let name = self.base.name.clone();
+        let rules = self.rules_mut().map_err(String::from)?;
+
+        if rule.id == 0 {
+            rule.insert(&mut conn, &name).await.map_err(String::from)?;
+            rules.push(rule.clone());
+        } else {
+            let existing = match rules.iter_mut().find(|x| x.id == rule.id) {
+                Some(x) => x,
+                None => return Err(format!("Rule {} does not exist", rule.id)),
+            };
+            rule.save_changes(&mut conn, &name)
+                .await
+                .map_err(String::from)?;
+            *existing = rule.clone();
+        }
+
+        info!(
+            self.base.logger,
+            "Set rule {} '{}' on {} '{}'",
+            rule.id,
+            rule.name,
+            rule.target,
+            rule.target_name
+        );
+        Ok(rule)
+    }
+
+    async fn delete_rule(&mut self, id: i32) -> Result<(), String> {
+        let rules = self.rules_mut().map_err(String::from)?;
+        match rules.iter().position(|x| x.id == id) {
+            Some(idx) => rules.remove(idx),
+            None => return Err(format!("Rule {id} does not exist")),
+        };
+
+        let mut conn = self.get_database().await.map_err(String::from)?;
+        SwitchRule::delete(&mut conn, id)
+            .await
+            .map_err(String::from)
+    }
+}
+
+#[cfg(test)]
+use crate::schedule::RuleCalendar;
+
+#[cfg(test)]
+fn make_rule(id: i32, schedule: &str, calendar: RuleCalendar) -> SwitchRule {
+    SwitchRule {
+        id,
+        name: format!("rule{id}"),
+        enabled: true,
+        schedule: schedule.into(),
+        calendar,
+        target: RuleTarget::Switch,
+        target_name: "pump".into(),
+        action: TriState::On,
+    }
+}
+
+#[test]
+fn test_triggered() {
+    let holidays = Holidays::new(&["2026-10-19".into()]).unwrap();
+    let monday: DateTime<chrono::Utc> =
+        DateTime::parse_from_rfc3339("2026-10-19T06:30:10Z")
+            .unwrap()
+            .into();
+    let mut rules = vec![
+        make_rule(1, "30 6 * * 1-5", RuleCalendar::NotHoliday),
+        make_rule(2, "30 6 * * *", RuleCalendar::Holiday),
+        make_rule(3, "30 6 * * *", RuleCalendar::Always),
+        make_rule(4, "31 6 * * *", RuleCalendar::Always),
+    ];
+    rules[2].enabled = false;
+
+    let triggered = RulesProcessor::triggered(&rules, &monday, None, &holidays)
+        .into_iter()
+        .map(|x| x.unwrap().id)
+        .collect::<Vec<_>>();
+    assert_eq!(vec![2], triggered, "Wrong rules triggered on holiday");
+
+    let holidays = Holidays::default();
+    let triggered = RulesProcessor::triggered(&rules, &monday, None, &holidays)
+        .into_iter()
+        .map(|x| x.unwrap().id)
+        .collect::<Vec<_>>();
+    assert_eq!(vec![1], triggered, "Wrong rules triggered on workday");
+
+    rules[0].schedule = "sunset".into();
+    let triggered = RulesProcessor::triggered(&rules, &monday, None, &holidays);
+    assert!(
+        triggered.len() == 1 && triggered[0].is_err(),
+        "Sun rule without location did not fail"
+    );
+}
@@ -0,0 +1,226 @@
+/******************************************************************************\
+    empowerd - empowers the offline smart home
+    Copyright (C) 2019 - 2026 Max Maisel
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU Affero General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU Affero General Public License for more details.
+
+    You should have received a copy of the GNU Affero General Public License
+    along with this program.  If not, see <https://www.gnu.org/licenses/>.
+\******************************************************************************/
+use crate::{models::ControlState, Error};
+use diesel_async::{
+    pooled_connection::deadpool::{Object, Pool},
+    AsyncPgConnection,
+};
+use slog::{warn, Logger};
+use std::{collections::BTreeMap, time::Duration};
+
+/// A config value which may be overridden at runtime.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Overridable<T> {
+    default: T,
+    value: Option<T>,
+}
+
+impl<T: Copy> Overridable<T> {
+    pub fn new(default: T) -> Self {
+        Self {
+            default,
+            value: None,
+        }
+    }
+
+    /// Returns the overriding value or the config default.
+    pub fn get(&self) -> T {
+        self.value.unwrap_or(self.default)
+    }
+
+    pub fn is_overridden(&self) -> bool {
+        self.value.is_some()
+    }
+
+    pub fn set(&mut self, value: T) {
+        self.value = Some(value);
+    }
+
+    /// Returns to the config default.
+    pub fn reset(&mut self) {
+        self.value = None;
+    }
+
+    pub fn map<U>(self, f: impl Fn(T) -> U) -> Overridable<U> {
+        Overridable {
+            default: f(self.default),
+            value: self.value.map(f),
+        }
+    }
+}
+
+/// Stores the values which were changed at runtime in the database, so
+/// they survive restarts of the processor and the daemon. Without a
+/// database, all values start with the config default.
+pub struct StateStore {
+    processor: String,
+    database: Option<Pool<AsyncPgConnection>>,
+    logger: Logger,
+    // Values are loaded from the database on first run.
+    loaded: bool,
+}
+
+impl StateStore {
+    const RESTORE_TIMEOUT: Duration = Duration::from_secs(5);
+
+    pub fn new(
+        processor: String,
+        database: Option<Pool<AsyncPgConnection>>,
+        logger: Logger,
+    ) -> Self {
+        Self {
+            processor,
+            database,
+            logger,
+            loaded: false,
+        }
+    }
+
+    async fn get_database(
+        &self,
+    ) -> Result<Option<Object<AsyncPgConnection>>, Error> {
+        let database = match &self.database {
+            Some(x) => x,
+            None => return Ok(None),
+        };
+        database.get().await.map(Some).map_err(|e| {
+            Error::Temporary(format!(
+                "Getting database connection from pool failed: {e}",
+            ))
+        })
+    }
+
+    /// Loads the stored values on the first call. Later calls return None.
+    /// If the database is unavailable, a warning is logged and the config
+    /// defaults are kept, so the processor can still control its outputs.
+    pub async fn restore(&mut self) -> Option<BTreeMap<String, String>> {
+        if self.loaded {
+            return None;
+        }
+        self.loaded = true;
+
+        let result = tokio::time::timeout(Self::RESTORE_TIMEOUT, async {
+            match self.get_database().await? {
+                Some(mut conn) => {
+                    ControlState::all(&mut conn, &self.processor).await
+                }
+                None => Ok(BTreeMap::new()),
+            }
+        })
+        .await
+        .unwrap_or_else(|_| {
+            Err(Error::Temporary("Database request timed out".into()))
+        });
+        match result {
+            Ok(values) => Some(values),
+            Err(e) => {
+                warn!(
+                    self.logger,
+                    "Could not restore stored values, using config \
+                        defaults: {}",
+                    e
+                );
+                None
+            }
+        }
+    }
+
+    /// Stores a value. Failures are only logged, the value still applies
+    /// until the processor is restarted.
+    pub async fn save(&self, key: &str, value: &impl ToString) {
+        let result = match self.get_database().await {
+            Ok(Some(mut conn)) => {
+                ControlState::set(
+                    &mut conn,
+                    &self.processor,
+                    key,
+                    &value.to_string(),
+                )
+                .await
+            }
+            Ok(None) => Ok(()),
+            Err(e) => Err(e),
+        };
+        if let Err(e) = result {
+            warn!(self.logger, "Could not store {}: {}", key, e);
+        }
+    }
+
+    /// Removes a stored value, so the config default applies again.
+    pub async fn reset(&self, key: &str) {
+        let result = match self.get_database().await {
+            Ok(Some(mut conn)) => {
+                ControlState::delete(&mut conn, &self.processor, key).await
+            }
+            Ok(None) => Ok(()),
+            Err(e) => Err(e),
+        };
+        if let Err(e) = result {
+            warn!(self.logger, "Could not reset {}: {}", key, e);
+        }
+    }
+
+    /// Parses a stored value. Invalid values are logged and ignored.
+    pub fn parse<T: std::str::FromStr>(
+        &self,
+        values: &BTreeMap<String, String>,
+        key: &str,
+    ) -> Option<T> {
+        let value = values.get(key)?;
+        match value.parse() {
+            Ok(x) => Some(x),
+            Err(_) => {
+                warn!(self.logger, "Ignoring invalid {} '{}'", key, value);
+                None
+            }
+        }
+    }
+}
+
+#[test]
+fn test_overridable() {
+    let mut value = Overridable::new(10);
+    assert_eq!(10, value.get());
+    assert!(!value.is_overridden());
+
+    value.set(20);
+    assert_eq!(20, value.get());
+    assert!(value.is_overridden());
+    let mapped = value.map(|x| x * 2);
+    assert_eq!(40, mapped.get());
+    assert!(mapped.is_overridden());
+
+    value.reset();
+    assert_eq!(10, value.get());
+    assert!(!value.is_overridden());
+}
+
+#[tokio::test]
+async fn test_restore_without_database() {
+    use diesel_async::pooled_connection::AsyncDieselConnectionManager;
+
+    let manager = AsyncDieselConnectionManager::<AsyncPgConnection>::new(
+        "postgres://empowerd@127.0.0.1:1/empowerd",
+    );
+    let pool = Pool::builder(manager).build().unwrap();
+    let logger = Logger::root(slog::Discard, slog::o!());
+    let mut store = StateStore::new("test".into(), Some(pool), logger);
+
+    assert_eq!(None, store.restore().await);
+    assert!(store.loaded);
+}
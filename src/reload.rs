@@ -0,0 +1,271 @@
+/******************************************************************************\
+    empowerd - empowers the offline smart home
+    Copyright (C) 2019 - 2026 Max Maisel
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU Affero General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU Affero General Public License for more details.
+
+    You should have received a copy of the GNU Affero General Public License
+    along with this program.  If not, see <https://www.gnu.org/licenses/>.
+\******************************************************************************/
+use crate::{
+    processors::poweroff_timer_name,
+    settings::{Gpio, ModbusCoil, ProcessorType, Settings, SinkType},
+};
+use std::collections::BTreeSet;
+
+/// Nodes which must be restarted to apply new settings.
+#[derive(Debug, Default, PartialEq)]
+pub struct Changes {
+    /// Changed, added or removed sources.
+    pub sources: BTreeSet<String>,
+    /// Changed, added or removed processors and the processors which
+    /// depend on changed nodes.
+    pub processors: BTreeSet<String>,
+    /// Changed, added or removed sinks.
+    pub sinks: BTreeSet<String>,
+    /// True if a switch changed. Switch IDs may change, so all processors
+    /// which use switches are restarted.
+    pub switches: bool,
+    /// True if the notifier must be restarted.
+    pub notifications: bool,
+    /// Changed settings which are only applied by restarting the daemon.
+    pub unsupported: Vec<&'static str>,
+}
+
+impl Changes {
+    pub fn is_empty(&self) -> bool {
+        self.sources.is_empty()
+            && self.processors.is_empty()
+            && self.sinks.is_empty()
+            && !self.notifications
+    }
+}
+
+/// Returns the names of all nodes which differ between both lists.
+fn changed<'a, T: PartialEq>(
+    old: &'a [T],
+    new: &'a [T],
+    name: impl Fn(&T) -> &str,
+) -> BTreeSet<String> {
+    let mut changed = BTreeSet::new();
+    for x in old {
+        match new.iter().find(|y| name(y) == name(x)) {
+            Some(y) if x == y => (),
+            _ => {
+                changed.insert(name(x).to_owned());
+            }
+        }
+    }
+    for x in new {
+        if !old.iter().any(|y| name(y) == name(x)) {
+            changed.insert(name(x).to_owned());
+        }
+    }
+    changed
+}
+
+fn is_switch(settings: &Settings, name: &str) -> bool {
+    settings.sinks.iter().any(|x| {
+        x.name == name
+            && matches!(x.variant, SinkType::Gpio(_) | SinkType::ModbusCoil(_))
+    })
+}
+
+/// Returns the names of the poweroff timer processors.
+fn poweroff_timers(settings: &Settings) -> impl Iterator<Item = String> + '_ {
+    settings.sinks.iter().filter_map(|x| match &x.variant {
+        SinkType::Gpio(gpio) if gpio.on_time != Gpio::max_on_time() => {
+            Some(poweroff_timer_name(&x.name))
+        }
+        SinkType::ModbusCoil(coil)
+            if coil.on_time != ModbusCoil::max_on_time() =>
+        {
+            Some(poweroff_timer_name(&x.name))
+        }
+        _ => None,
+    })
+}
+
+fn node_names(settings: &Settings) -> BTreeSet<&str> {
+    settings
+        .sources
+        .iter()
+        .map(|x| x.name.as_str())
+        .chain(settings.processors.iter().map(|x| x.name.as_str()))
+        .collect()
+}
+
+/// Compares the running settings with new ones.
+pub fn diff(old: &Settings, new: &Settings) -> Changes {
+    let sources = changed(&old.sources, &new.sources, |x| &x.name);
+    let mut processors = changed(&old.processors, &new.processors, |x| &x.name);
+    let sinks = changed(&old.sinks, &new.sinks, |x| &x.name);
+    let switches = sinks.iter().any(|x| is_switch(old, x) || is_switch(new, x));
+    let location = old.location != new.location;
+
+    // Processors which use sinks, switches or the location.
+    for p in &new.processors {
+        let restart = match &p.variant {
+            ProcessorType::Debug(x) => sinks.contains(&x.output),
+            ProcessorType::Appliance(x) => {
+                location || sinks.contains(&x.appliance_output)
+            }
+            ProcessorType::LoadControl(_) => location,
+            ProcessorType::DeferrableLoad(_) => switches,
+            ProcessorType::Rules(_) => location || switches,
+            ProcessorType::PeakShaving(_) => switches,
+            ProcessorType::ExportLimit(x) => {
+                matches!(&x.inverter_output, Some(y) if sinks.contains(y))
+            }
+            ProcessorType::AvailablePower(_) => false,
+        };
+        if restart {
+            processors.insert(p.name.clone());
+        }
+    }
+
+    // Processors which send commands to restarted processors.
+    let restarted = |f: fn(&ProcessorType) -> bool| {
+        new.processors
+            .iter()
+            .any(|x| f(&x.variant) && processors.contains(&x.name))
+    };
+    let appliance = restarted(|x| matches!(x, ProcessorType::Appliance(_)));
+    let load_control =
+        restarted(|x| matches!(x, ProcessorType::LoadControl(_)));
+    for p in &new.processors {
+        let restart = match &p.variant {
+            ProcessorType::Rules(_) => appliance,
+            ProcessorType::PeakShaving(_) => appliance || load_control,
+            ProcessorType::ExportLimit(_) => load_control,
+            _ => false,
+        };
+        if restart {
+            processors.insert(p.name.clone());
+        }
+    }
+    if switches {
+        processors.extend(poweroff_timers(old));
+        processors.extend(poweroff_timers(new));
+    }
+
+    let mut unsupported = Vec::new();
+    if old.database != new.database || old.influx != new.influx {
+        unsupported.push("database");
+    }
+    if old.graphql.listen_address != new.graphql.listen_address
+        || old.graphql.tls != new.graphql.tls
+        || old.graphql.key_file != new.graphql.key_file
+        || old.graphql.session_timeout != new.graphql.session_timeout
+    {
+        unsupported.push("graphql");
+    }
+    if old.pid_file != new.pid_file
+        || old.wrk_dir != new.wrk_dir
+        || old.logfile != new.logfile
+        || old.log_level != new.log_level
+    {
+        unsupported.push("daemon");
+    }
+
+    // Notifications may watch any node.
+    let notifications = node_names(old) != node_names(new)
+        || old.notification_channels != new.notification_channels
+        || old.notifications != new.notifications;
+
+    Changes {
+        sources,
+        processors,
+        sinks,
+        switches,
+        notifications,
+        unsupported,
+    }
+}
+
+#[test]
+fn test_diff() {
+    let old: Settings = toml::from_str(
+        r#"
+        [[source]]
+        name = "Meter"
+        series_id = 1
+        type = "Debug"
+        poll_interval = 10
+
+        [[processor]]
+        name = "Heater"
+        type = "Appliance"
+        power_input = "Power"
+        appliance_input = "Meter"
+        appliance_output = "HeaterSink"
+
+        [[processor]]
+        name = "Rules"
+        type = "Rules"
+
+        [[processor]]
+        name = "Power"
+        type = "AvailablePower"
+        battery_input = "Meter"
+        meter_input = "Meter"
+        battery_threshold = 0
+        tau = 10
+
+        [[sink]]
+        name = "HeaterSink"
+        type = "Debug"
+
+        [[sink]]
+        name = "Pump"
+        type = "Gpio"
+        dev = "/dev/gpiochip0"
+        pin_num = 1
+        icon = "Valve"
+        on_time = 60
+        "#,
+    )
+    .unwrap();
+
+    assert_eq!(Changes::default(), diff(&old, &old));
+
+    let mut new = old.clone();
+    new.sinks[0].name = "HeaterOutput".into();
+    if let ProcessorType::Appliance(x) = &mut new.processors[0].variant {
+        x.appliance_output = "HeaterOutput".into();
+    }
+    let changes = diff(&old, &new);
+    assert_eq!(
+        vec!["Heater", "Rules"],
+        changes.processors.iter().collect::<Vec<_>>()
+    );
+    assert_eq!(
+        vec!["HeaterOutput", "HeaterSink"],
+        changes.sinks.iter().collect::<Vec<_>>()
+    );
+    assert!(changes.sources.is_empty());
+    assert!(!changes.switches);
+    assert!(!changes.notifications);
+
+    let mut new = old.clone();
+    if let SinkType::Gpio(x) = &mut new.sinks[1].variant {
+        x.num = 2;
+    }
+    new.graphql.listen_address = "[::]:3001".into();
+    let changes = diff(&old, &new);
+    assert_eq!(
+        vec!["Rules", "_PoweroffTimerProcessor_Pump"],
+        changes.processors.iter().collect::<Vec<_>>()
+    );
+    assert!(changes.switches);
+    assert!(!changes.notifications);
+    assert_eq!(vec!["graphql"], changes.unsupported);
+}
@@ -0,0 +1,738 @@
+/******************************************************************************\
+    empowerd - empowers the offline smart home
+    Copyright (C) 2019 - 2026 Max Maisel
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU Affero General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU Affero General Public License for more details.
+
+    You should have received a copy of the GNU Affero General Public License
+    along with this program.  If not, see <https://www.gnu.org/licenses/>.
+\******************************************************************************/
+use crate::{
+    clock,
+    models::{
+        postgres::{
+            battery::RawBattery, bidir_meter::RawBidirMeter,
+            heatpump::RawHeatpump, simple_meter::RawSimpleMeter,
+        },
+        units::{
+            kilowatt_hour, millisecond, second, watt, Energy, Power, Time,
+        },
+        Battery, BidirMeter, Heatpump, Model, SeriesType, SimpleMeter,
+    },
+    processors::{self, Selection},
+    settings::{ProcessorType, Settings, Source},
+    sinks::simulated::Setpoint,
+    sinks::{ArcSink, DebugSink, SimulatedSink, Sinks},
+    switch_mux::SwitchMux,
+    task_group::TaskGroupBuilder,
+    Error,
+};
+use chrono::{DateTime, Duration, NaiveDate, NaiveDateTime, Utc};
+use diesel_async::AsyncPgConnection;
+use slog::Logger;
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fmt,
+    str::FromStr,
+    sync::Arc,
+};
+use tokio::sync::watch;
+
+/// Number of records which are loaded from the database at once.
+const BATCH_SIZE: i64 = 10000;
+
+/// Parses a time like `2024-05-01`, `2024-05-01 12:00:00` or an RFC 3339
+/// timestamp. Times without offset are UTC like in the database.
+pub fn parse_time(text: &str) -> Result<DateTime<Utc>, String> {
+    if let Ok(x) = DateTime::parse_from_rfc3339(text) {
+        return Ok(x.with_timezone(&Utc));
+    }
+    if let Ok(x) = NaiveDateTime::parse_from_str(text, "%Y-%m-%d %H:%M:%S%.f") {
+        return Ok(x.and_utc());
+    }
+    match NaiveDate::parse_from_str(text, "%Y-%m-%d") {
+        Ok(x) => Ok(x.and_time(Default::default()).and_utc()),
+        Err(_) => Err(format!("Invalid time '{}'", text)),
+    }
+}
+
+/// Returns the timestamp of a record.
+pub fn record_time(record: &Model) -> DateTime<Utc> {
+    let millis = match record.time() {
+        Some(x) => x.get::<millisecond>() as i64,
+        None => 0,
+    };
+    DateTime::from_timestamp_millis(millis).unwrap_or_default()
+}
+
+/// A line of a CSV file.
+struct Row<'a> {
+    header: &'a [&'a str],
+    fields: Vec<&'a str>,
+}
+
+impl Row<'_> {
+    /// Parses a column. Missing columns and empty fields are None.
+    fn value<T: FromStr>(&self, column: &str) -> Result<Option<T>, String> {
+        let field = self
+            .header
+            .iter()
+            .position(|x| *x == column)
+            .and_then(|x| self.fields.get(x))
+            .filter(|x| !x.is_empty());
+        match field {
+            Some(x) => x
+                .parse()
+                .map(Some)
+                .map_err(|_| format!("Invalid {} '{}'", column, x)),
+            None => Ok(None),
+        }
+    }
+
+    fn required<T: FromStr>(&self, column: &str) -> Result<T, String> {
+        self.value(column)?
+            .ok_or_else(|| format!("Missing column {}", column))
+    }
+
+    fn time(&self) -> Result<NaiveDateTime, String> {
+        let time: String = self.required("time")?;
+        parse_time(&time).map(|x| x.naive_utc())
+    }
+}
+
+fn split_csv(line: &str) -> Vec<&str> {
+    line.split(',')
+        .map(|x| x.trim().trim_matches('"'))
+        .collect()
+}
+
+fn parse_row(series: SeriesType, row: &Row) -> Result<Model, String> {
+    let time = row.time()?;
+    let power_w = row.required("power_w")?;
+    let record = match series {
+        SeriesType::Battery => Battery::from(RawBattery {
+            series_id: 0,
+            time,
+            charge_wh: row.required("charge_wh")?,
+            energy_in_wh: row.value("energy_in_wh")?.unwrap_or(0),
+            energy_out_wh: row.value("energy_out_wh")?.unwrap_or(0),
+            power_w,
+        })
+        .into(),
+        SeriesType::BidirMeter => BidirMeter::from(RawBidirMeter {
+            series_id: 0,
+            time,
+            energy_in_wh: row.value("energy_in_wh")?.unwrap_or(0),
+            energy_out_wh: row.value("energy_out_wh")?.unwrap_or(0),
+            power_w,
+        })
+        .into(),
+        SeriesType::Heatpump => Heatpump::from(RawHeatpump {
+            series_id: 0,
+            time,
+            energy_wh: row.value("energy_wh")?.unwrap_or(0),
+            power_w,
+            heat_wh: row.value("heat_wh")?.unwrap_or(0),
+            cold_wh: row.value("cold_wh")?.unwrap_or(0),
+            defrost_wh: row.value("defrost_wh")?.unwrap_or(0),
+            cop_pct: row.value("cop_pct")?.unwrap_or(0),
+            boiler_top_degc_e1: row.value("boiler_top_degc_e1")?,
+            boiler_mid_degc_e1: row.value("boiler_mid_degc_e1")?,
+            boiler_bot_degc_e1: row.value("boiler_bot_degc_e1")?,
+        })
+        .into(),
+        SeriesType::SimpleMeter => SimpleMeter::from(RawSimpleMeter {
+            series_id: 0,
+            time,
+            energy_wh: row.value("energy_wh")?.unwrap_or(0),
+            power_w,
+        })
+        .into(),
+        x => return Err(format!("{:?} series cannot be replayed", x)),
+    };
+    Ok(record)
+}
+
+/// Reads the records of a series from a CSV file with header line. The
+/// columns are named like in the database, only time, power and battery
+/// charge are required. A series can be exported with
+/// `\copy (SELECT * FROM batteries WHERE series_id = 1) TO 'x.csv' CSV HEADER`.
+pub fn read_csv(series: SeriesType, text: &str) -> Result<Vec<Model>, String> {
+    let mut lines = text
+        .lines()
+        .enumerate()
+        .filter(|(_, line)| !line.trim().is_empty());
+    let header = match lines.next() {
+        Some((_, line)) => split_csv(line),
+        None => return Ok(Vec::new()),
+    };
+
+    let mut records = Vec::new();
+    for (idx, line) in lines {
+        let row = Row {
+            header: &header,
+            fields: split_csv(line),
+        };
+        let record = parse_row(series, &row)
+            .map_err(|e| format!("Line {}: {}", idx + 1, e))?;
+        records.push(record);
+    }
+    records.sort_by_key(record_time);
+    Ok(records)
+}
+
+fn into_models<T: Into<Model>>(records: Vec<T>) -> Vec<Model> {
+    records.into_iter().map(|x| x.into()).collect()
+}
+
+/// Loads the records of a series within the given time range from the
+/// database.
+pub async fn load_series(
+    conn: &mut AsyncPgConnection,
+    series: SeriesType,
+    series_id: i32,
+    from: DateTime<Utc>,
+    to: DateTime<Utc>,
+) -> Result<Vec<Model>, Error> {
+    let mut records = Vec::new();
+    let mut first = from.naive_utc();
+    loop {
+        let batch = match series {
+            SeriesType::Battery => into_models(
+                Battery::batch(conn, series_id, first, BATCH_SIZE).await?,
+            ),
+            SeriesType::BidirMeter => into_models(
+                BidirMeter::batch(conn, series_id, first, BATCH_SIZE).await?,
+            ),
+            SeriesType::Heatpump => into_models(
+                Heatpump::batch(conn, series_id, first, BATCH_SIZE).await?,
+            ),
+            SeriesType::SimpleMeter => into_models(
+                SimpleMeter::batch(conn, series_id, first, BATCH_SIZE).await?,
+            ),
+            x => {
+                return Err(Error::InvalidInput(format!(
+                    "{:?} series cannot be replayed",
+                    x
+                )))
+            }
+        };
+
+        let complete = batch.len() < BATCH_SIZE as usize;
+        for record in batch {
+            if record_time(&record) >= to {
+                return Ok(records);
+            }
+            records.push(record);
+        }
+        match records.last() {
+            // Records are stored with a resolution of one second.
+            Some(x) if !complete => {
+                first = (record_time(x) + Duration::seconds(1)).naive_utc()
+            }
+            _ => return Ok(records),
+        }
+    }
+}
+
+/// Energy totals of the grid exchange and the appliances.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Totals {
+    pub grid_import: Energy,
+    pub grid_export: Energy,
+    pub appliances: Energy,
+    /// Appliance energy which was not imported from the grid.
+    pub self_consumed: Energy,
+}
+
+impl Totals {
+    fn new() -> Self {
+        let zero = Energy::new::<kilowatt_hour>(0.0);
+        Self {
+            grid_import: zero,
+            grid_export: zero,
+            appliances: zero,
+            self_consumed: zero,
+        }
+    }
+
+    fn add(&mut self, grid: Power, appliances: Power, duration: Time) {
+        let zero = Power::new::<watt>(0.0);
+        let import = grid.max(zero);
+        self.grid_import += import * duration;
+        self.grid_export += (-grid).max(zero) * duration;
+        self.appliances += appliances * duration;
+        self.self_consumed += (appliances - import.min(appliances)) * duration;
+    }
+
+    /// Returns the share of the appliance energy in percent which was
+    /// covered by local generation or the battery.
+    pub fn self_consumption(&self) -> Option<f64> {
+        if self.appliances > Energy::new::<kilowatt_hour>(0.0) {
+            Some(100.0 * (self.self_consumed / self.appliances).value)
+        } else {
+            None
+        }
+    }
+}
+
+/// Simulation result of an appliance.
+pub struct ApplianceReport {
+    pub name: String,
+    pub energy: Energy,
+    pub recorded_energy: Energy,
+    pub on_time: Time,
+    pub switch_ons: usize,
+    pub setpoints: Vec<Setpoint>,
+}
+
+/// An appliance processor and the sink which records its setpoints.
+struct Appliance {
+    name: String,
+    input: String,
+    sink: Arc<SimulatedSink>,
+    energy: Energy,
+    recorded_energy: Energy,
+    on_time: Time,
+}
+
+impl Appliance {
+    fn report(self) -> ApplianceReport {
+        let setpoints = self.sink.setpoints();
+        let mut on = false;
+        let mut switch_ons = 0;
+        for setpoint in &setpoints {
+            let next = setpoint.power > Power::new::<watt>(0.0);
+            if next && !on {
+                switch_ons += 1;
+            }
+            on = next;
+        }
+
+        ApplianceReport {
+            name: self.name,
+            energy: self.energy,
+            recorded_energy: self.recorded_energy,
+            on_time: self.on_time,
+            switch_ons,
+            setpoints,
+        }
+    }
+}
+
+/// Result of a replay. The simulated grid exchange is the recorded one
+/// corrected by the difference between simulated and recorded appliance
+/// power. The battery is assumed to behave as recorded.
+pub struct Report {
+    pub from: DateTime<Utc>,
+    pub to: DateTime<Utc>,
+    pub records: usize,
+    pub simulated: Totals,
+    pub recorded: Totals,
+    pub appliances: Vec<ApplianceReport>,
+    /// Processors which were not simulated.
+    pub skipped: Vec<String>,
+}
+
+impl Report {
+    /// Returns all setpoint changes of the appliances as CSV.
+    pub fn setpoints_csv(&self) -> String {
+        let mut csv = String::from("time,appliance,power_w\n");
+        for appliance in &self.appliances {
+            for x in &appliance.setpoints {
+                csv += &format!(
+                    "{},{},{}\n",
+                    x.time.naive_utc(),
+                    appliance.name,
+                    x.power.get::<watt>().round()
+                );
+            }
+        }
+        csv
+    }
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fn kwh(x: Energy) -> f64 {
+            x.get::<kilowatt_hour>()
+        }
+        fn percent(x: Option<f64>) -> String {
+            match x {
+                Some(x) => format!("{:.1} %", x),
+                None => "-".into(),
+            }
+        }
+
+        writeln!(
+            f,
+            "Replayed {} records from {} to {}",
+            self.records, self.from, self.to
+        )?;
+        if !self.skipped.is_empty() {
+            writeln!(f, "Not simulated: {}", self.skipped.join(", "))?;
+        }
+        writeln!(f)?;
+        writeln!(f, "{:<18}{:>14}{:>14}", "", "Simulated", "Recorded")?;
+        writeln!(
+            f,
+            "{:<18}{:>10.2} kWh{:>10.2} kWh",
+            "Grid import",
+            kwh(self.simulated.grid_import),
+            kwh(self.recorded.grid_import)
+        )?;
+        writeln!(
+            f,
+            "{:<18}{:>10.2} kWh{:>10.2} kWh",
+            "Grid export",
+            kwh(self.simulated.grid_export),
+            kwh(self.recorded.grid_export)
+        )?;
+        writeln!(
+            f,
+            "{:<18}{:>14}{:>14}",
+            "Self-consumption",
+            percent(self.simulated.self_consumption()),
+            percent(self.recorded.self_consumption())
+        )?;
+
+        writeln!(f)?;
+        writeln!(
+            f,
+            "{:<18}{:>14}{:>14}{:>10}{:>12}",
+            "Appliance", "Simulated", "Recorded", "On time", "Switch-ons"
+        )?;
+        for x in &self.appliances {
+            writeln!(
+                f,
+                "{:<18}{:>10.2} kWh{:>10.2} kWh{:>8.1} h{:>12}",
+                x.name,
+                kwh(x.energy),
+                kwh(x.recorded_energy),
+                x.on_time.get::<second>() / 3600.0,
+                x.switch_ons
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// Returns the input nodes of a processor which can be replayed.
+fn inputs(variant: &ProcessorType) -> Vec<&str> {
+    match variant {
+        ProcessorType::Debug(x) => vec![&x.input],
+        ProcessorType::AvailablePower(x) => {
+            vec![&x.battery_input, &x.meter_input]
+        }
+        ProcessorType::Appliance(x) => vec![&x.power_input, &x.appliance_input],
+        _ => Vec::new(),
+    }
+}
+
+fn power(record: &Model) -> Option<Power> {
+    match record {
+        Model::BidirMeter(x) => Some(x.power),
+        Model::Heatpump(x) => Some(x.power),
+        Model::SimpleMeter(x) => Some(x.power),
+        _ => None,
+    }
+}
+
+/// Lets the processors handle a record. On a current thread runtime, every
+/// processor passes a changed input on within one round of the scheduler.
+async fn settle(rounds: usize) {
+    for _ in 0..rounds {
+        tokio::task::yield_now().await;
+    }
+}
+
+/// Feeds recorded data through the processors of a config with virtual
+/// time. Appliances are replaced by simulated sinks.
+pub struct Replay {
+    settings: Settings,
+    skipped: Vec<String>,
+}
+
+impl Replay {
+    /// Keeps the processors which can be simulated. LoadControl talks to
+    /// the battery inverter, the others need switches or the database.
+    pub fn new(settings: &Settings) -> Result<Self, String> {
+        let mut replay = settings.clone();
+        let mut skipped = Vec::new();
+        replay.processors.retain(|x| match x.variant {
+            ProcessorType::Debug(_)
+            | ProcessorType::AvailablePower(_)
+            | ProcessorType::Appliance(_) => true,
+            _ => {
+                skipped.push(x.name.clone());
+                false
+            }
+        });
+
+        let nodes = replay
+            .sources
+            .iter()
+            .map(|x| x.name.as_str())
+            .chain(replay.processors.iter().map(|x| x.name.as_str()))
+            .collect::<BTreeSet<_>>();
+        for p in &replay.processors {
+            for input in inputs(&p.variant) {
+                if !nodes.contains(input) {
+                    return Err(format!(
+                        "Processor '{}' depends on '{}' which cannot be \
+                        replayed",
+                        p.name, input
+                    ));
+                }
+            }
+        }
+
+        Ok(Self {
+            settings: replay,
+            skipped,
+        })
+    }
+
+    /// Returns the sources which are used by the simulated processors.
+    pub fn sources(&self) -> Result<Vec<(&Source, SeriesType)>, String> {
+        let inputs = self
+            .settings
+            .processors
+            .iter()
+            .flat_map(|x| inputs(&x.variant))
+            .collect::<BTreeSet<_>>();
+
+        let mut sources = Vec::new();
+        for source in &self.settings.sources {
+            if !inputs.contains(source.name.as_str()) {
+                continue;
+            }
+            match SeriesType::from_source(&source.variant) {
+                Some(
+                    x @ (SeriesType::Battery
+                    | SeriesType::BidirMeter
+                    | SeriesType::Heatpump
+                    | SeriesType::SimpleMeter),
+                ) => sources.push((source, x)),
+                _ => {
+                    return Err(format!(
+                        "Source '{}' cannot be replayed",
+                        source.name
+                    ))
+                }
+            }
+        }
+        Ok(sources)
+    }
+
+    /// Creates the sinks of the simulated processors.
+    fn sinks(
+        &self,
+        logger: &Logger,
+    ) -> Result<(Sinks, Vec<Appliance>), String> {
+        let mut sinks = BTreeMap::new();
+        let mut appliances = Vec::new();
+        for p in &self.settings.processors {
+            match &p.variant {
+                ProcessorType::Debug(x) => {
+                    let sink = DebugSink::new(x.output.clone(), logger.clone());
+                    sinks.insert(
+                        x.output.clone(),
+                        ArcSink::Debug(Arc::new(sink)),
+                    );
+                }
+                ProcessorType::Appliance(x) => {
+                    let sink = Arc::new(SimulatedSink::new(
+                        x.appliance_output.clone(),
+                        logger.clone(),
+                    ));
+                    sinks.insert(
+                        x.appliance_output.clone(),
+                        ArcSink::Simulated(sink.clone()),
+                    );
+                    let zero = Energy::new::<kilowatt_hour>(0.0);
+                    appliances.push(Appliance {
+                        name: p.name.clone(),
+                        input: x.appliance_input.clone(),
+                        sink,
+                        energy: zero,
+                        recorded_energy: zero,
+                        on_time: Time::new::<second>(0.0),
+                    });
+                }
+                _ => (),
+            }
+        }
+        let switch_mux = SwitchMux::new(BTreeMap::new())?;
+        sinks
+            .insert("_SwitchMux".into(), ArcSink::SwitchMux(switch_mux.into()));
+        let sinks = Sinks {
+            sinks,
+            switches: Vec::new(),
+        };
+        Ok((sinks, appliances))
+    }
+
+    /// Replays the records of all sources in chronological order. Must run
+    /// on a current thread runtime, so that the processors handle every
+    /// record before the next one.
+    pub async fn run(
+        &self,
+        logger: Logger,
+        series: BTreeMap<String, Vec<Model>>,
+    ) -> Result<Report, String> {
+        let mut events = series
+            .iter()
+            .flat_map(|(name, records)| {
+                records.iter().map(move |x| (record_time(x), name, x))
+            })
+            .collect::<Vec<_>>();
+        events.sort_by_key(|x| x.0);
+        let (from, to) = match (events.first(), events.last()) {
+            (Some(x), Some(y)) => (x.0, y.0),
+            _ => return Err("No records to replay".into()),
+        };
+        clock::set_virtual_time(from);
+
+        let mut nodes = series
+            .keys()
+            .map(|x| (x.clone(), watch::channel(Model::None).0))
+            .collect::<BTreeMap<_, _>>();
+        let (sinks, mut appliances) = self.sinks(&logger)?;
+        let mut tasks = TaskGroupBuilder::new("replay".into(), logger.clone());
+        // The processors stop if their command channels are closed.
+        let _commands = processors::processor_tasks(
+            logger,
+            &self.settings,
+            &mut tasks,
+            &mut nodes,
+            &sinks,
+            None,
+            Selection::All,
+        )?;
+        let mut tasks = tasks.build();
+
+        let meters = self
+            .settings
+            .processors
+            .iter()
+            .filter_map(|x| match &x.variant {
+                ProcessorType::AvailablePower(x) => Some(x.meter_input.clone()),
+                _ => None,
+            })
+            .collect::<BTreeSet<_>>();
+        let rounds = 2 * self.settings.processors.len() + 2;
+        let zero = Power::new::<watt>(0.0);
+        let mut simulated = Totals::new();
+        let mut recorded = Totals::new();
+        let mut last_time = from;
+
+        for (time, source, record) in &events {
+            let duration = Time::new::<millisecond>(
+                (*time - last_time).num_milliseconds() as f64,
+            );
+            let power_of = |name: &String| match nodes.get(name) {
+                Some(x) => power(&x.borrow()).unwrap_or(zero),
+                None => zero,
+            };
+
+            let grid = meters.iter().fold(zero, |sum, x| sum + power_of(x));
+            let mut recorded_power = zero;
+            let mut simulated_power = zero;
+            for x in &mut appliances {
+                let recorded = power_of(&x.input);
+                let simulated = x.sink.power();
+                x.energy += simulated * duration;
+                x.recorded_energy += recorded * duration;
+                if simulated > zero {
+                    x.on_time += duration;
+                }
+                recorded_power += recorded;
+                simulated_power += simulated;
+            }
+            recorded.add(grid, recorded_power, duration);
+            simulated.add(
+                grid - recorded_power + simulated_power,
+                simulated_power,
+                duration,
+            );
+
+            clock::set_virtual_time(*time);
+            if let Some(x) = nodes.get(*source) {
+                x.send_replace((*record).clone());
+            }
+            settle(rounds).await;
+            last_time = *time;
+        }
+
+        tasks.cancel()?;
+        tasks.run().await;
+        for (name, health) in tasks.health() {
+            if let Ok(health) = health.lock() {
+                if let Some(e) = &health.last_error {
+                    return Err(format!("Processor '{}' failed: {}", name, e));
+                }
+            }
+        }
+
+        Ok(Report {
+            from,
+            to,
+            records: events.len(),
+            simulated,
+            recorded,
+            appliances: appliances.into_iter().map(|x| x.report()).collect(),
+            skipped: self.skipped.clone(),
+        })
+    }
+}
+
+#[test]
+fn test_read_csv() {
+    let csv = "series_id,time,charge_wh,energy_in_wh,energy_out_wh,power_w\n\
+        1,2024-05-01 00:00:10,5000,10,20,-300\n\
+        1,2024-05-01T00:00:00Z,4990,,,\"250\"\n";
+    let records = read_csv(SeriesType::Battery, csv).unwrap();
+    assert_eq!(2, records.len());
+    assert_eq!(parse_time("2024-05-01").unwrap(), record_time(&records[0]));
+    match &records[1] {
+        Model::Battery(x) => {
+            assert_eq!(Power::new::<watt>(-300.0), x.power);
+            assert_eq!(Energy::new::<kilowatt_hour>(5.0), x.charge);
+        }
+        x => panic!("Unexpected record {:?}", x),
+    }
+
+    let error = read_csv(SeriesType::BidirMeter, "time,power_w\n\n2024,1\n");
+    assert_eq!(Err("Line 3: Invalid time '2024'".into()), error.map(|_| ()));
+    assert!(read_csv(SeriesType::Weather, "time,power_w\n").is_ok());
+    assert!(read_csv(SeriesType::SimpleMeter, "time\n2024-05-01\n").is_err());
+}
+
+#[test]
+fn test_totals() {
+    let mut totals = Totals::new();
+    let hour = Time::new::<second>(3600.0);
+    // 1 kW appliance with 600 W excess power.
+    totals.add(Power::new::<watt>(400.0), Power::new::<watt>(1000.0), hour);
+    // Appliance is off, 500 W are exported.
+    totals.add(Power::new::<watt>(-500.0), Power::new::<watt>(0.0), hour);
+    // 2 kW appliance, all is imported.
+    totals.add(Power::new::<watt>(2000.0), Power::new::<watt>(2000.0), hour);
+
+    let kwh = |x: Energy| (x.get::<kilowatt_hour>() * 1000.0).round() / 1000.0;
+    assert_eq!(2.4, kwh(totals.grid_import));
+    assert_eq!(0.5, kwh(totals.grid_export));
+    assert_eq!(3.0, kwh(totals.appliances));
+    assert_eq!(0.6, kwh(totals.self_consumed));
+    assert_eq!(20, totals.self_consumption().unwrap().round() as i64);
+    assert_eq!(None, Totals::new().self_consumption());
+}
@@ -0,0 +1,409 @@
+/******************************************************************************\
+    empowerd - empowers the offline smart home
+    Copyright (C) 2019 - 2026 Max Maisel
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU Affero General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU Affero General Public License for more details.
+
+    You should have received a copy of the GNU Affero General Public License
+    along with this program.  If not, see <https://www.gnu.org/licenses/>.
+\******************************************************************************/
+//! Mirrors the GraphQL queries and mutations as REST endpoints for clients
+//! which cannot speak GraphQL. Each endpoint executes a fixed GraphQL
+//! operation, so it shares the resolvers, authorization and audit log.
+use super::{error_response, json_response, read_body};
+use crate::{graphql::websocket::Schema, Context};
+use hyper::{body::Incoming, Method, Request, Response, StatusCode};
+use juniper::Variables;
+use serde_json::{Map, Value};
+
+/// Describes how the request body is passed to the GraphQL operation.
+pub enum Input {
+    None,
+    /// The body is passed as "$input" object. The ID from the path is
+    /// added to it.
+    Object(&'static str),
+    /// The fields of the body are passed as arguments.
+    Arguments,
+}
+
+pub struct Route {
+    pub method: Method,
+    pub path: &'static str,
+    pub summary: &'static str,
+    /// Name of the query or mutation field.
+    pub field: &'static str,
+    pub document: &'static str,
+    pub input: Input,
+    /// True if the field returns a JSON encoded string.
+    pub json: bool,
+    /// True if the endpoint does not require authentication.
+    pub public: bool,
+}
+
+impl Route {
+    const fn get(
+        path: &'static str,
+        summary: &'static str,
+        field: &'static str,
+        document: &'static str,
+    ) -> Self {
+        Self {
+            method: Method::GET,
+            path,
+            summary,
+            field,
+            document,
+            input: Input::None,
+            json: false,
+            public: false,
+        }
+    }
+
+    const fn put(
+        path: &'static str,
+        summary: &'static str,
+        field: &'static str,
+        document: &'static str,
+        type_name: &'static str,
+    ) -> Self {
+        Self {
+            method: Method::PUT,
+            path,
+            summary,
+            field,
+            document,
+            input: Input::Object(type_name),
+            json: false,
+            public: false,
+        }
+    }
+
+    /// Creates a route whose arguments are taken from the path.
+    const fn delete(
+        path: &'static str,
+        summary: &'static str,
+        field: &'static str,
+        document: &'static str,
+    ) -> Self {
+        Self {
+            method: Method::DELETE,
+            path,
+            summary,
+            field,
+            document,
+            input: Input::Arguments,
+            json: false,
+            public: false,
+        }
+    }
+
+    /// Matches the path and extracts the ID parameter if there is one.
+    fn matches(&self, path: &str) -> Option<Option<i32>> {
+        match self.path.strip_suffix("/{id}") {
+            Some(prefix) => {
+                let id = path.strip_prefix(prefix)?.strip_prefix('/')?;
+                Some(Some(id.parse().ok()?))
+            }
+            None => (self.path == path).then_some(None),
+        }
+    }
+}
+
+pub fn routes() -> Vec<Route> {
+    vec![
+        Route {
+            method: Method::POST,
+            input: Input::Arguments,
+            public: true,
+            ..Route::get(
+                "/api/v1/login",
+                "Log in and get a session token",
+                "login",
+                "mutation($username: String!, $password: String!) { \
+                login(username: $username, password: $password) }",
+            )
+        },
+        Route {
+            method: Method::POST,
+            ..Route::get(
+                "/api/v1/logout",
+                "Log out and invalidate the session token",
+                "logout",
+                "mutation { logout }",
+            )
+        },
+        Route {
+            json: true,
+            ..Route::get(
+                "/api/v1/config",
+                "Get the backend config for the UI",
+                "backendConfig",
+                "{ backendConfig }",
+            )
+        },
+        Route::get(
+            "/api/v1/available-powers",
+            "Get all available power controllers",
+            "availablePowers",
+            "{ availablePowers { \
+            id threshold thresholdOverridden power name } }",
+        ),
+        Route::put(
+            "/api/v1/available-powers/{id}",
+            "Set the battery threshold of an available power controller",
+            "setAvailablePower",
+            "mutation($input: InputAvailablePower!) { \
+            setAvailablePower(input: $input) { \
+            id threshold thresholdOverridden power name } }",
+            "InputAvailablePower",
+        ),
+        Route::delete(
+            "/api/v1/available-powers/{id}",
+            "Reset the battery threshold to the config default",
+            "resetAvailablePower",
+            "mutation($id: Int!) { resetAvailablePower(id: $id) { \
+            id threshold thresholdOverridden power name } }",
+        ),
+        Route::get(
+            "/api/v1/appliances",
+            "Get all appliances",
+            "appliances",
+            "{ appliances { id forceOnOff forceOnOffOverridden name } }",
+        ),
+        Route::put(
+            "/api/v1/appliances/{id}",
+            "Force an appliance on or off",
+            "setAppliance",
+            "mutation($input: InputAppliance!) { \
+            setAppliance(input: $input) { \
+            id forceOnOff forceOnOffOverridden name } }",
+            "InputAppliance",
+        ),
+        Route::delete(
+            "/api/v1/appliances/{id}",
+            "Return an appliance to automatic mode",
+            "resetAppliance",
+            "mutation($id: Int!) { resetAppliance(id: $id) { \
+            id forceOnOff forceOnOffOverridden name } }",
+        ),
+        Route::get(
+            "/api/v1/load-control",
+            "Get the grid load control mode",
+            "loadControl",
+            "{ loadControl { chargeMode chargeModeOverridden name } }",
+        ),
+        Route::put(
+            "/api/v1/load-control",
+            "Set the grid load control mode",
+            "setLoadControl",
+            "mutation($input: InputLoadControl!) { \
+            setLoadControl(input: $input) { \
+            chargeMode chargeModeOverridden name } }",
+            "InputLoadControl",
+        ),
+        Route::delete(
+            "/api/v1/load-control",
+            "Reset the grid load control mode to the default",
+            "resetLoadControl",
+            "mutation { resetLoadControl { \
+            chargeMode chargeModeOverridden name } }",
+        ),
+        Route::get(
+            "/api/v1/poweroff-timers",
+            "Get all poweroff timers",
+            "poweroffTimers",
+            "{ poweroffTimers { id onTime onTimeOverridden switchId } }",
+        ),
+        Route::put(
+            "/api/v1/poweroff-timers/{id}",
+            "Set the on time of a poweroff timer",
+            "setPoweroffTimer",
+            "mutation($input: InputPoweroffTimer!) { \
+            setPoweroffTimer(input: $input) { \
+            id onTime onTimeOverridden switchId } }",
+            "InputPoweroffTimer",
+        ),
+        Route::delete(
+            "/api/v1/poweroff-timers/{id}",
+            "Reset the on time of a poweroff timer to the config default",
+            "resetPoweroffTimer",
+            "mutation($id: Int!) { resetPoweroffTimer(id: $id) { \
+            id onTime onTimeOverridden switchId } }",
+        ),
+        Route::get(
+            "/api/v1/switches",
+            "Get all switches",
+            "switches",
+            "{ switches { id open name icon } }",
+        ),
+        Route::put(
+            "/api/v1/switches/{id}",
+            "Open or close a switch",
+            "setSwitch",
+            "mutation($input: InputSwitch!) { \
+            setSwitch(switch: $input) { id open name icon } }",
+            "InputSwitch",
+        ),
+    ]
+}
+
+fn variables(
+    route: &Route,
+    id: Option<i32>,
+    body: &[u8],
+) -> Result<Variables, String> {
+    let mut object = match body.is_empty() {
+        true => Map::new(),
+        false => serde_json::from_slice::<Map<String, Value>>(body)
+            .map_err(|e| format!("Invalid request body: {e}"))?,
+    };
+    if let Some(id) = id {
+        object.insert("id".into(), id.into());
+    }
+
+    let value = match route.input {
+        Input::None => Value::Object(Map::new()),
+        Input::Object(_) => {
+            let mut variables = Map::new();
+            variables.insert("input".into(), Value::Object(object));
+            Value::Object(variables)
+        }
+        Input::Arguments => Value::Object(object),
+    };
+    serde_json::from_value(value).map_err(|e| e.to_string())
+}
+
+fn status(message: &str) -> StatusCode {
+    match message {
+        "Unauthorized!"
+        | "Session expired!"
+        | "Incorrect user or password!" => StatusCode::UNAUTHORIZED,
+        "Forbidden!" => StatusCode::FORBIDDEN,
+        "Internal server error!" => StatusCode::INTERNAL_SERVER_ERROR,
+        x if x.ends_with("not found") => StatusCode::NOT_FOUND,
+        x if x.starts_with("Too many") => StatusCode::TOO_MANY_REQUESTS,
+        _ => StatusCode::BAD_REQUEST,
+    }
+}
+
+async fn execute(
+    route: &Route,
+    id: Option<i32>,
+    body: &[u8],
+    schema: &Schema,
+    ctx: &Context,
+) -> Response<String> {
+    let variables = match variables(route, id, body) {
+        Ok(x) => x,
+        Err(e) => return error_response(StatusCode::BAD_REQUEST, &e),
+    };
+
+    let (value, errors) =
+        match juniper::execute(route.document, None, schema, &variables, ctx)
+            .await
+        {
+            Ok(x) => x,
+            Err(e) => {
+                return error_response(
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    &e.to_string(),
+                )
+            }
+        };
+    if let Some(e) = errors.first() {
+        let message = e.error().message();
+        return error_response(status(message), message);
+    }
+
+    let result = match serde_json::to_value(&value) {
+        Ok(Value::Object(mut x)) => x.remove(route.field).unwrap_or_default(),
+        Ok(_) => Value::Null,
+        Err(e) => {
+            return error_response(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                &e.to_string(),
+            )
+        }
+    };
+    if !route.json {
+        return json_response(StatusCode::OK, &result);
+    }
+    match serde_json::from_str::<Value>(result.as_str().unwrap_or_default()) {
+        Ok(x) => json_response(StatusCode::OK, &x),
+        Err(e) => {
+            error_response(StatusCode::INTERNAL_SERVER_ERROR, &e.to_string())
+        }
+    }
+}
+
+/// Serves all routes below /api/v1.
+pub async fn handle(
+    req: Request<Incoming>,
+    schema: &Schema,
+    ctx: Context,
+) -> Response<String> {
+    let path = req.uri().path().trim_end_matches('/').to_string();
+    let routes = routes();
+    let matching = routes
+        .iter()
+        .filter_map(|x| x.matches(&path).map(|id| (x, id)))
+        .collect::<Vec<_>>();
+    if matching.is_empty() {
+        return error_response(StatusCode::NOT_FOUND, "Not found");
+    }
+    let (route, id) = match matching
+        .into_iter()
+        .find(|(route, _)| route.method == req.method())
+    {
+        Some(x) => x,
+        None => {
+            return error_response(
+                StatusCode::METHOD_NOT_ALLOWED,
+                "Not allowed",
+            )
+        }
+    };
+
+    if !route.public {
+        if let Err(e) = ctx.verify() {
+            return error_response(
+                StatusCode::UNAUTHORIZED,
+                &e.to_string(&ctx.globals.logger),
+            );
+        }
+    }
+
+    let body = match read_body(req).await {
+        Ok(x) => x,
+        Err(e) => return e,
+    };
+    execute(route, id, &body, schema, &ctx).await
+}
+
+#[test]
+fn test_route_matches() {
+    let routes = routes();
+    let switch = routes.iter().find(|x| x.field == "setSwitch").unwrap();
+    assert_eq!(Some(Some(3)), switch.matches("/api/v1/switches/3"));
+    assert_eq!(None, switch.matches("/api/v1/switches"));
+    assert_eq!(None, switch.matches("/api/v1/switches/x"));
+
+    let config = routes.iter().find(|x| x.field == "backendConfig").unwrap();
+    assert_eq!(Some(None), config.matches("/api/v1/config"));
+}
+
+#[test]
+fn test_variables() {
+    let routes = routes();
+    let switch = routes.iter().find(|x| x.field == "setSwitch").unwrap();
+    let variables = variables(switch, Some(2), br#"{"open": true}"#).unwrap();
+    assert_eq!("{id: 2, open: true}", variables["input"].to_string());
+}
@@ -0,0 +1,582 @@
+/******************************************************************************\
+    empowerd - empowers the offline smart home
+    Copyright (C) 2019 - 2026 Max Maisel
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU Affero General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU Affero General Public License for more details.
+
+    You should have received a copy of the GNU Affero General Public License
+    along with this program.  If not, see <https://www.gnu.org/licenses/>.
+\******************************************************************************/
+//! Exposes sources, processors, switches and appliances as entities in the
+//! format of the Home Assistant REST API, so that they can be used with its
+//! RESTful sensor, switch and select integrations.
+use super::{error_response, json_response, read_body};
+use crate::{
+    models::{
+        units::{
+            celsius, hectopascal, meter_per_second, millimeter, percent, ratio,
+            second, watt, watt_hour, Ratio, Temperature,
+        },
+        Model,
+    },
+    processors::ApplianceCmd,
+    session_manager::Role,
+    tri_state::TriState,
+    Context,
+};
+use chrono::{DateTime, Utc};
+use hyper::{body::Incoming, Method, Request, Response, StatusCode};
+use serde::{Deserialize, Serialize};
+use tokio::sync::oneshot;
+
+#[derive(Serialize)]
+struct Attributes {
+    friendly_name: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    device_class: Option<&'static str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    unit_of_measurement: Option<&'static str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    state_class: Option<&'static str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    options: Option<Vec<&'static str>>,
+}
+
+/// State of a Home Assistant entity.
+#[derive(Serialize)]
+pub struct EntityState {
+    entity_id: String,
+    state: String,
+    attributes: Attributes,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    last_updated: Option<DateTime<Utc>>,
+}
+
+/// Request body to change the state of a switch or select entity.
+/// Home Assistant sends either a plain string or a JSON object.
+#[derive(Deserialize)]
+struct InputState {
+    state: String,
+}
+
+/// Sensor metadata which allows Home Assistant to use the sensor in its
+/// energy dashboard without templating.
+struct Quantity {
+    key: &'static str,
+    name: &'static str,
+    device_class: Option<&'static str>,
+    unit: Option<&'static str>,
+    state_class: &'static str,
+}
+
+impl Quantity {
+    const fn new(
+        key: &'static str,
+        name: &'static str,
+        device_class: Option<&'static str>,
+        unit: Option<&'static str>,
+        state_class: &'static str,
+    ) -> Self {
+        Self {
+            key,
+            name,
+            device_class,
+            unit,
+            state_class,
+        }
+    }
+
+    const fn energy(key: &'static str, name: &'static str) -> Self {
+        Self::new(key, name, Some("energy"), Some("Wh"), "total_increasing")
+    }
+
+    const fn temperature(key: &'static str, name: &'static str) -> Self {
+        Self::new(key, name, Some("temperature"), Some("°C"), "measurement")
+    }
+
+    const fn humidity(key: &'static str, name: &'static str) -> Self {
+        Self::new(key, name, Some("humidity"), Some("%"), "measurement")
+    }
+}
+
+const POWER: Quantity =
+    Quantity::new("power", "Power", Some("power"), Some("W"), "measurement");
+const CHARGE: Quantity = Quantity::new(
+    "charge",
+    "Charge",
+    Some("energy_storage"),
+    Some("Wh"),
+    "measurement",
+);
+const RUNTIME: Quantity = Quantity::new(
+    "runtime",
+    "Runtime",
+    Some("duration"),
+    Some("s"),
+    "total_increasing",
+);
+const COP: Quantity = Quantity::new("cop", "COP", None, None, "measurement");
+const RAIN: Quantity = Quantity::new(
+    "rain",
+    "Rain",
+    Some("precipitation"),
+    Some("mm"),
+    "total_increasing",
+);
+const PRESSURE: Quantity = Quantity::new(
+    "pressure",
+    "Pressure",
+    Some("atmospheric_pressure"),
+    Some("hPa"),
+    "measurement",
+);
+const WIND_SPEED: Quantity = Quantity::new(
+    "wind_speed",
+    "Wind speed",
+    Some("wind_speed"),
+    Some("m/s"),
+    "measurement",
+);
+const FORCE_OPTIONS: [&str; 3] = ["auto", "on", "off"];
+
+/// Converts a name into the object ID part of an entity ID.
+fn slug(name: &str) -> String {
+    let mut slug = String::new();
+    for c in name.chars() {
+        if c.is_ascii_alphanumeric() {
+            slug.push(c.to_ascii_lowercase());
+        } else if !slug.is_empty() && !slug.ends_with('_') {
+            slug.push('_');
+        }
+    }
+    slug.trim_end_matches('_').into()
+}
+
+fn temperature(x: Option<Temperature>) -> Option<f64> {
+    x.map(|x| x.get::<celsius>())
+}
+
+fn humidity(x: Option<Ratio>) -> Option<f64> {
+    x.map(|x| x.get::<percent>())
+}
+
+/// Lists the quantities of a record together with their values.
+fn readings(model: &Model) -> Vec<(Quantity, Option<f64>)> {
+    match model {
+        Model::None => Vec::new(),
+        Model::AvailablePower(x) => vec![(POWER, Some(x.power.get::<watt>()))],
+        Model::Battery(x) => vec![
+            (POWER, Some(x.power.get::<watt>())),
+            (CHARGE, Some(x.charge.get::<watt_hour>())),
+            (
+                Quantity::energy("energy_in", "Energy charged"),
+                Some(x.energy_in.get::<watt_hour>()),
+            ),
+            (
+                Quantity::energy("energy_out", "Energy discharged"),
+                Some(x.energy_out.get::<watt_hour>()),
+            ),
+        ],
+        Model::BidirMeter(x) => vec![
+            (POWER, Some(x.power.get::<watt>())),
+            (
+                Quantity::energy("energy_in", "Energy imported"),
+                Some(x.energy_in.get::<watt_hour>()),
+            ),
+            (
+                Quantity::energy("energy_out", "Energy exported"),
+                Some(x.energy_out.get::<watt_hour>()),
+            ),
+        ],
+        Model::Generator(x) => vec![
+            (POWER, Some(x.power.get::<watt>())),
+            (
+                Quantity::energy("energy", "Energy"),
+                Some(x.energy.get::<watt_hour>()),
+            ),
+            (RUNTIME, Some(x.runtime.get::<second>())),
+        ],
+        Model::Heatpump(x) => vec![
+            (POWER, Some(x.power.get::<watt>())),
+            (
+                Quantity::energy("energy", "Energy"),
+                Some(x.energy.get::<watt_hour>()),
+            ),
+            (
+                Quantity::energy("heat", "Heat"),
+                Some(x.heat.get::<watt_hour>()),
+            ),
+            (
+                Quantity::energy("cold", "Cold"),
+                Some(x.cold.get::<watt_hour>()),
+            ),
+            (
+                Quantity::energy("defrost", "Defrost"),
+                Some(x.defrost.get::<watt_hour>()),
+            ),
+            (COP, Some(x.cop.get::<ratio>())),
+            (
+                Quantity::temperature("boiler_top", "Boiler top"),
+                temperature(x.boiler_top),
+            ),
+            (
+                Quantity::temperature("boiler_mid", "Boiler middle"),
+                temperature(x.boiler_mid),
+            ),
+            (
+                Quantity::temperature("boiler_bot", "Boiler bottom"),
+                temperature(x.boiler_bot),
+            ),
+        ],
+        Model::SimpleMeter(x) => vec![
+            (POWER, Some(x.power.get::<watt>())),
+            (
+                Quantity::energy("energy", "Energy"),
+                Some(x.energy.get::<watt_hour>()),
+            ),
+        ],
+        Model::Weather(x) => vec![
+            (
+                Quantity::temperature("temp_in", "Temperature inside"),
+                Some(x.temp_in.get::<celsius>()),
+            ),
+            (
+                Quantity::humidity("hum_in", "Humidity inside"),
+                Some(x.hum_in.get::<percent>()),
+            ),
+            (
+                Quantity::temperature("temp_out", "Temperature outside"),
+                temperature(x.temp_out),
+            ),
+            (
+                Quantity::humidity("hum_out", "Humidity outside"),
+                humidity(x.hum_out),
+            ),
+            (
+                Quantity::temperature("dew_point", "Dew point"),
+                temperature(x.dew_point),
+            ),
+            (RAIN, Some(x.rain_acc.get::<millimeter>())),
+            (PRESSURE, Some(x.baro_sea.get::<hectopascal>())),
+            (WIND_SPEED, x.wind_act.map(|x| x.get::<meter_per_second>())),
+            (
+                Quantity::temperature("temp_x1", "Temperature 1"),
+                temperature(x.temp_x1),
+            ),
+            (
+                Quantity::humidity("hum_x1", "Humidity 1"),
+                humidity(x.hum_x1),
+            ),
+            (
+                Quantity::temperature("temp_x2", "Temperature 2"),
+                temperature(x.temp_x2),
+            ),
+            (
+                Quantity::humidity("hum_x2", "Humidity 2"),
+                humidity(x.hum_x2),
+            ),
+            (
+                Quantity::temperature("temp_x3", "Temperature 3"),
+                temperature(x.temp_x3),
+            ),
+            (
+                Quantity::humidity("hum_x3", "Humidity 3"),
+                humidity(x.hum_x3),
+            ),
+            (
+                Quantity::temperature("temp_x4", "Temperature 4"),
+                temperature(x.temp_x4),
+            ),
+            (
+                Quantity::humidity("hum_x4", "Humidity 4"),
+                humidity(x.hum_x4),
+            ),
+            (
+                Quantity::temperature("temp_x5", "Temperature 5"),
+                temperature(x.temp_x5),
+            ),
+            (
+                Quantity::humidity("hum_x5", "Humidity 5"),
+                humidity(x.hum_x5),
+            ),
+            (
+                Quantity::temperature("temp_x6", "Temperature 6"),
+                temperature(x.temp_x6),
+            ),
+            (
+                Quantity::humidity("hum_x6", "Humidity 6"),
+                humidity(x.hum_x6),
+            ),
+            (
+                Quantity::temperature("temp_x7", "Temperature 7"),
+                temperature(x.temp_x7),
+            ),
+            (
+                Quantity::humidity("hum_x7", "Humidity 7"),
+                humidity(x.hum_x7),
+            ),
+        ],
+    }
+}
+
+/// Returns the reading with the given key, e.g. "charge" or "temp_out", in
+/// the unit of its sensor entity.
+pub fn reading(model: &Model, key: &str) -> Option<f64> {
+    readings(model)
+        .into_iter()
+        .find(|(quantity, _)| quantity.key == key)
+        .and_then(|(_, value)| value)
+}
+
+fn sensors(node: &str, model: &Model) -> Vec<EntityState> {
+    let time = match model {
+        Model::None => return Vec::new(),
+        Model::AvailablePower(x) => x.time,
+        Model::Battery(x) => x.time,
+        Model::BidirMeter(x) => x.time,
+        Model::Generator(x) => x.time,
+        Model::Heatpump(x) => x.time,
+        Model::SimpleMeter(x) => x.time,
+        Model::Weather(x) => x.time,
+    };
+    let last_updated = DateTime::from_timestamp(time.get::<second>() as i64, 0);
+
+    readings(model)
+        .into_iter()
+        .filter_map(|(quantity, value)| {
+            Some(EntityState {
+                entity_id: format!(
+                    "sensor.empowerd_{}_{}",
+                    slug(node),
+                    quantity.key
+                ),
+                state: ((value? * 1000.0).round() / 1000.0).to_string(),
+                attributes: Attributes {
+                    friendly_name: format!("{} {}", node, quantity.name),
+                    device_class: quantity.device_class,
+                    unit_of_measurement: quantity.unit,
+                    state_class: Some(quantity.state_class),
+                    options: None,
+                },
+                last_updated,
+            })
+        })
+        .collect()
+}
+
+fn switch_entity_id(name: &str) -> String {
+    format!("switch.empowerd_{}", slug(name))
+}
+
+fn appliance_entity_id(name: &str) -> String {
+    format!("select.empowerd_{}_force", slug(name))
+}
+
+fn switch_state(name: String, open: Option<bool>) -> EntityState {
+    EntityState {
+        entity_id: switch_entity_id(&name),
+        state: match open {
+            Some(true) => "on",
+            Some(false) => "off",
+            None => "unavailable",
+        }
+        .into(),
+        attributes: Attributes {
+            friendly_name: name,
+            device_class: Some("switch"),
+            unit_of_measurement: None,
+            state_class: None,
+            options: None,
+        },
+        last_updated: None,
+    }
+}
+
+fn appliance_state(name: &str, force_on_off: TriState) -> EntityState {
+    EntityState {
+        entity_id: appliance_entity_id(name),
+        state: force_on_off.to_string().to_lowercase(),
+        attributes: Attributes {
+            friendly_name: format!("{name} force mode"),
+            device_class: None,
+            unit_of_measurement: None,
+            state_class: None,
+            options: Some(FORCE_OPTIONS.to_vec()),
+        },
+        last_updated: None,
+    }
+}
+
+async fn states(ctx: &Context) -> Result<Vec<EntityState>, String> {
+    let globals = &ctx.globals;
+    let mut states = Vec::new();
+
+    for (name, node) in &globals.nodes {
+        states.append(&mut sensors(name, &node.borrow()));
+    }
+
+    let switches = globals.switch_mux.subscribe().borrow().clone();
+    for (id, open) in switches.into_iter().enumerate() {
+        states.push(switch_state(globals.switch_mux.name(id)?, open));
+    }
+
+    for processor in &globals.processor_cmds.appliance {
+        let (tx, rx) = oneshot::channel();
+        let cmd = ApplianceCmd::GetForceOnOff { resp: tx };
+        let force_on_off =
+            processor.issue_command(&globals.logger, cmd, rx).await?;
+        states.push(appliance_state(&processor.name, force_on_off.get()));
+    }
+
+    Ok(states)
+}
+
+fn parse_state(body: &[u8]) -> String {
+    let state = match serde_json::from_slice::<InputState>(body) {
+        Ok(x) => x.state,
+        Err(_) => String::from_utf8_lossy(body).trim().into(),
+    };
+    state.to_lowercase()
+}
+
+async fn set_state(
+    ctx: &Context,
+    entity_id: &str,
+    state: &str,
+) -> Result<Option<EntityState>, Response<String>> {
+    let globals = &ctx.globals;
+    let bad_request =
+        |msg: String| error_response(StatusCode::BAD_REQUEST, &msg);
+    let forbidden = |msg: String| error_response(StatusCode::FORBIDDEN, &msg);
+
+    for id in globals.switch_mux.ids() {
+        let name = globals.switch_mux.name(id).map_err(bad_request)?;
+        if switch_entity_id(&name) != entity_id {
+            continue;
+        }
+        let open = match state {
+            "on" => true,
+            "off" => false,
+            _ => return Err(bad_request(format!("Invalid state '{state}'"))),
+        };
+        ctx.authorize_mutation(Role::Operator, "haSetSwitch", &(id, open))
+            .await
+            .map_err(forbidden)?;
+        globals
+            .switch_mux
+            .write_val(id, open)
+            .await
+            .map_err(bad_request)?;
+        return Ok(Some(switch_state(name, Some(open))));
+    }
+
+    for processor in &globals.processor_cmds.appliance {
+        if appliance_entity_id(&processor.name) != entity_id {
+            continue;
+        }
+        let force_on_off = match state {
+            "auto" => TriState::Auto,
+            "on" => TriState::On,
+            "off" => TriState::Off,
+            _ => return Err(bad_request(format!("Invalid option '{state}'"))),
+        };
+        ctx.authorize_mutation(
+            Role::Operator,
+            "haSetAppliance",
+            &(&processor.name, force_on_off),
+        )
+        .await
+        .map_err(forbidden)?;
+        let (tx, rx) = oneshot::channel();
+        let cmd = ApplianceCmd::SetForceOnOff {
+            force_on_off,
+            persist: true,
+            resp: tx,
+        };
+        processor
+            .issue_command(&globals.logger, cmd, rx)
+            .await
+            .map_err(bad_request)?;
+        return Ok(Some(appliance_state(&processor.name, force_on_off)));
+    }
+
+    Ok(None)
+}
+
+/// Serves GET /api/ha/states, GET /api/ha/states/<entity_id> and
+/// POST /api/ha/states/<entity_id>.
+pub async fn handle(req: Request<Incoming>, ctx: Context) -> Response<String> {
+    let path = req.uri().path().trim_end_matches('/').to_string();
+    let entity_id = match path.strip_prefix("/api/ha/states") {
+        Some("") => None,
+        Some(x) if x.starts_with('/') => Some(x[1..].to_string()),
+        _ => return error_response(StatusCode::NOT_FOUND, "Not found"),
+    };
+
+    if let Err(e) = ctx.verify() {
+        return error_response(
+            StatusCode::UNAUTHORIZED,
+            &e.to_string(&ctx.globals.logger),
+        );
+    }
+
+    match (req.method().clone(), entity_id) {
+        (Method::GET, entity_id) => {
+            let states = match states(&ctx).await {
+                Ok(x) => x,
+                Err(e) => {
+                    return error_response(
+                        StatusCode::INTERNAL_SERVER_ERROR,
+                        &e,
+                    )
+                }
+            };
+            match entity_id {
+                None => json_response(StatusCode::OK, &states),
+                Some(id) => match states.iter().find(|x| x.entity_id == id) {
+                    Some(x) => json_response(StatusCode::OK, x),
+                    None => error_response(
+                        StatusCode::NOT_FOUND,
+                        "Entity not found",
+                    ),
+                },
+            }
+        }
+        (Method::POST, Some(entity_id)) => {
+            let body = match read_body(req).await {
+                Ok(x) => x,
+                Err(e) => return e,
+            };
+            let state = parse_state(&body);
+            match set_state(&ctx, &entity_id, &state).await {
+                Ok(Some(x)) => json_response(StatusCode::OK, &x),
+                Ok(None) => {
+                    error_response(StatusCode::NOT_FOUND, "Entity not found")
+                }
+                Err(e) => e,
+            }
+        }
+        _ => error_response(StatusCode::METHOD_NOT_ALLOWED, "Not allowed"),
+    }
+}
+
+#[test]
+fn test_slug() {
+    assert_eq!("grid_meter", slug("Grid Meter"));
+    assert_eq!("pv_1", slug("_PV-1_"));
+    assert_eq!("w_rme", slug("Wärme"));
+}
+
+#[test]
+fn test_parse_state() {
+    assert_eq!("on", parse_state(b"ON"));
+    assert_eq!("auto", parse_state(br#"{"state": "Auto"}"#));
+}
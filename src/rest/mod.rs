@@ -0,0 +1,74 @@
+/******************************************************************************\
+    empowerd - empowers the offline smart home
+    Copyright (C) 2019 - 2026 Max Maisel
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU Affero General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU Affero General Public License for more details.
+
+    You should have received a copy of the GNU Affero General Public License
+    along with this program.  If not, see <https://www.gnu.org/licenses/>.
+\******************************************************************************/
+//! Serves REST APIs for clients which cannot speak GraphQL.
+use http_body_util::{BodyExt, Limited};
+use hyper::{
+    body::{Bytes, Incoming},
+    header::CONTENT_TYPE,
+    Request, Response, StatusCode,
+};
+use serde::Serialize;
+use serde_json::json;
+
+pub mod api;
+pub mod homeassistant;
+pub mod openapi;
+
+const MAX_BODY_SIZE: usize = 65536;
+
+pub fn json_response<T: Serialize>(
+    status: StatusCode,
+    value: &T,
+) -> Response<String> {
+    let body = match serde_json::to_string(value) {
+        Ok(x) => x,
+        Err(e) => {
+            return error_response(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                &format!("Serializing response failed: {e}"),
+            )
+        }
+    };
+    let mut response = Response::new(body);
+    *response.status_mut() = status;
+    response
+        .headers_mut()
+        .insert(CONTENT_TYPE, "application/json".parse().unwrap());
+    response
+}
+
+pub fn error_response(status: StatusCode, message: &str) -> Response<String> {
+    let mut response = Response::new(json!({ "message": message }).to_string());
+    *response.status_mut() = status;
+    response
+        .headers_mut()
+        .insert(CONTENT_TYPE, "application/json".parse().unwrap());
+    response
+}
+
+pub async fn read_body(
+    req: Request<Incoming>,
+) -> Result<Bytes, Response<String>> {
+    match Limited::new(req.into_body(), MAX_BODY_SIZE).collect().await {
+        Ok(x) => Ok(x.to_bytes()),
+        Err(e) => Err(error_response(
+            StatusCode::BAD_REQUEST,
+            &format!("Reading request body failed: {e}"),
+        )),
+    }
+}
@@ -0,0 +1,269 @@
+/******************************************************************************\
+    empowerd - empowers the offline smart home
+    Copyright (C) 2019 - 2026 Max Maisel
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU Affero General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU Affero General Public License for more details.
+
+    You should have received a copy of the GNU Affero General Public License
+    along with this program.  If not, see <https://www.gnu.org/licenses/>.
+\******************************************************************************/
+//! Generates the OpenAPI description of the REST API from the route table
+//! and the introspected GraphQL schema.
+use super::{
+    api::{routes, Input, Route},
+    error_response, json_response,
+};
+use crate::{graphql::websocket::Schema, Context};
+use hyper::{Response, StatusCode};
+use juniper::IntrospectionFormat;
+use serde_json::{json, Map, Value};
+use std::collections::BTreeSet;
+
+struct Types<'a> {
+    types: Vec<&'a Value>,
+    /// Names of the types referenced by the routes.
+    used: BTreeSet<String>,
+}
+
+impl<'a> Types<'a> {
+    fn new(schema: &'a Value) -> Self {
+        let types = schema["types"]
+            .as_array()
+            .map(|x| x.iter().collect())
+            .unwrap_or_default();
+        Self {
+            types,
+            used: BTreeSet::new(),
+        }
+    }
+
+    fn get(&self, name: &str) -> Option<&'a Value> {
+        self.types.iter().find(|x| x["name"] == name).copied()
+    }
+
+    fn field(&self, type_name: &str, field: &str) -> Option<&'a Value> {
+        self.get(type_name)?["fields"]
+            .as_array()?
+            .iter()
+            .find(|x| x["name"] == field)
+    }
+
+    /// Converts a GraphQL type reference into a JSON schema.
+    fn reference(&mut self, kind: &Value) -> Value {
+        let name = kind["name"].as_str().unwrap_or_default();
+        match kind["kind"].as_str().unwrap_or_default() {
+            "NON_NULL" => self.reference(&kind["ofType"]),
+            "LIST" => json!({
+                "type": "array",
+                "items": self.reference(&kind["ofType"]),
+            }),
+            "SCALAR" => scalar(name),
+            _ => {
+                self.used.insert(name.into());
+                json!({ "$ref": format!("#/components/schemas/{name}") })
+            }
+        }
+    }
+
+    /// Converts fields or arguments into an object schema.
+    fn object(&mut self, fields: &Value, skip: Option<&str>) -> Value {
+        let mut properties = Map::new();
+        let mut required = Vec::new();
+        for field in fields.as_array().into_iter().flatten() {
+            let name = field["name"].as_str().unwrap_or_default();
+            if Some(name) == skip {
+                continue;
+            }
+            let mut schema = self.reference(&field["type"]);
+            if let Some(description) = field["description"].as_str() {
+                schema["description"] = description.into();
+            }
+            if field["type"]["kind"] == "NON_NULL" {
+                required.push(name);
+            }
+            properties.insert(name.into(), schema);
+        }
+        json!({
+            "type": "object",
+            "properties": properties,
+            "required": required,
+        })
+    }
+
+    fn schema(&mut self, name: &str) -> Value {
+        let kind = match self.get(name) {
+            Some(x) => x,
+            None => return json!({}),
+        };
+        let mut schema = match kind["kind"].as_str().unwrap_or_default() {
+            "ENUM" => json!({
+                "type": "string",
+                "enum": kind["enumValues"]
+                    .as_array()
+                    .into_iter()
+                    .flatten()
+                    .map(|x| x["name"].clone())
+                    .collect::<Vec<_>>(),
+            }),
+            "INPUT_OBJECT" => self.object(&kind["inputFields"], None),
+            _ => self.object(&kind["fields"], None),
+        };
+        if let Some(description) = kind["description"].as_str() {
+            schema["description"] = description.into();
+        }
+        schema
+    }
+}
+
+fn scalar(name: &str) -> Value {
+    match name {
+        "Int" => json!({ "type": "integer", "format": "int32" }),
+        "Float" => json!({ "type": "number", "format": "double" }),
+        "Boolean" => json!({ "type": "boolean" }),
+        "DateTime" => json!({ "type": "string", "format": "date-time" }),
+        _ => json!({ "type": "string" }),
+    }
+}
+
+fn operation(route: &Route, types: &mut Types) -> Value {
+    let root = match route.document.starts_with("mutation") {
+        true => "Mutation",
+        false => "Query",
+    };
+    let field = types.field(root, route.field);
+    let response = match route.json {
+        true => json!({ "type": "object" }),
+        false => match field {
+            Some(x) => types.reference(&x["type"]),
+            None => json!({}),
+        },
+    };
+
+    let mut operation = json!({
+        "summary": route.summary,
+        "operationId": route.field,
+        "responses": {
+            "200": {
+                "description": "Success",
+                "content": { "application/json": { "schema": response } },
+            },
+            "default": {
+                "description": "Error",
+                "content": {
+                    "application/json": {
+                        "schema": { "$ref": "#/components/schemas/Error" },
+                    },
+                },
+            },
+        },
+    });
+    if let Some(description) = field.and_then(|x| x["description"].as_str()) {
+        operation["description"] = description.into();
+    }
+    if route.public {
+        operation["security"] = json!([]);
+    }
+    if route.path.ends_with("/{id}") {
+        operation["parameters"] = json!([{
+            "name": "id",
+            "in": "path",
+            "required": true,
+            "schema": scalar("Int"),
+        }]);
+    }
+
+    // The ID is passed in the path.
+    let skip = route.path.ends_with("/{id}").then_some("id");
+    let body = match route.input {
+        Input::None => None,
+        Input::Object(type_name) => types
+            .get(type_name)
+            .map(|x| types.object(&x["inputFields"], skip)),
+        Input::Arguments => field.map(|x| types.object(&x["args"], skip)),
+    };
+    if let Some(body) = body.filter(|x| x["properties"] != json!({})) {
+        operation["requestBody"] = json!({
+            "required": true,
+            "content": { "application/json": { "schema": body } },
+        });
+    }
+    operation
+}
+
+/// Builds the OpenAPI document from the introspection result.
+fn document(schema: &Value) -> Value {
+    let mut types = Types::new(schema);
+    let mut paths = Map::new();
+    for route in routes() {
+        let operation = operation(&route, &mut types);
+        let path = paths.entry(route.path).or_insert_with(|| json!({}));
+        path[route.method.as_str().to_lowercase()] = operation;
+    }
+
+    // Referenced types may reference further types.
+    let mut schemas = Map::new();
+    while let Some(name) = types
+        .used
+        .iter()
+        .find(|x| !schemas.contains_key(*x))
+        .cloned()
+    {
+        let schema = types.schema(&name);
+        schemas.insert(name, schema);
+    }
+    schemas.insert(
+        "Error".into(),
+        json!({
+            "type": "object",
+            "properties": { "message": { "type": "string" } },
+            "required": ["message"],
+        }),
+    );
+
+    json!({
+        "openapi": "3.0.3",
+        "info": {
+            "title": "empowerd",
+            "version": env!("CARGO_PKG_VERSION"),
+        },
+        "paths": paths,
+        "components": {
+            "schemas": schemas,
+            "securitySchemes": {
+                "bearer": { "type": "http", "scheme": "bearer" },
+            },
+        },
+        "security": [{ "bearer": [] }],
+    })
+}
+
+/// Serves the OpenAPI document at "/openapi.json".
+pub fn handle(schema: &Schema, ctx: &Context) -> Response<String> {
+    let introspection = match juniper::introspect(
+        schema,
+        ctx,
+        IntrospectionFormat::default(),
+    ) {
+        Ok((value, _)) => serde_json::to_value(&value),
+        Err(e) => {
+            return error_response(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                &e.to_string(),
+            )
+        }
+    };
+    match introspection {
+        Ok(x) => json_response(StatusCode::OK, &document(&x["__schema"])),
+        Err(e) => {
+            error_response(StatusCode::INTERNAL_SERVER_ERROR, &e.to_string())
+        }
+    }
+}
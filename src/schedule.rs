@@ -0,0 +1,429 @@
+/******************************************************************************\
+    empowerd - empowers the offline smart home
+    Copyright (C) 2019 - 2026 Max Maisel
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU Affero General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU Affero General Public License for more details.
+
+    You should have received a copy of the GNU Affero General Public License
+    along with this program.  If not, see <https://www.gnu.org/licenses/>.
+\******************************************************************************/
+use crate::{seasonal::sunrise_and_sunset, settings::Location};
+use chrono::{DateTime, Datelike, Duration, NaiveDate, TimeZone, Timelike};
+use std::str::FromStr;
+
+/// A set of allowed values of a single cron field stored as bitmask.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+struct CronField {
+    mask: u64,
+    any: bool,
+}
+
+impl CronField {
+    fn parse(field: &str, min: u32, max: u32) -> Result<Self, String> {
+        let mut mask = 0u64;
+        for part in field.split(',') {
+            let (range, step) = match part.split_once('/') {
+                Some((range, step)) => {
+                    let step = step
+                        .parse::<u32>()
+                        .map_err(|_| format!("Invalid step '{step}'"))?;
+                    if step == 0 {
+                        return Err("Step must not be zero".into());
+                    }
+                    (range, step)
+                }
+                None => (part, 1),
+            };
+
+            let (start, stop) = if range == "*" {
+                (min, max)
+            } else if let Some((start, stop)) = range.split_once('-') {
+                (Self::parse_value(start)?, Self::parse_value(stop)?)
+            } else {
+                let value = Self::parse_value(range)?;
+                // "5/10" means every 10 starting at 5.
+                (value, if step > 1 { max } else { value })
+            };
+
+            if start < min || stop > max || start > stop {
+                return Err(format!(
+                    "Value '{part}' is out of range {min}-{max}"
+                ));
+            }
+            for value in (start..=stop).step_by(step as usize) {
+                mask |= 1 << value;
+            }
+        }
+
+        Ok(Self {
+            mask,
+            any: field == "*",
+        })
+    }
+
+    fn parse_value(value: &str) -> Result<u32, String> {
+        value
+            .parse::<u32>()
+            .map_err(|_| format!("Invalid value '{value}'"))
+    }
+
+    fn contains(&self, value: u32) -> bool {
+        self.mask & (1 << value) != 0
+    }
+}
+
+/// Cron like "minute hour day-of-month month day-of-week" expression.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct CronSpec {
+    minutes: CronField,
+    hours: CronField,
+    days: CronField,
+    months: CronField,
+    weekdays: CronField,
+}
+
+impl CronSpec {
+    pub fn matches<Tz: TimeZone>(&self, time: &DateTime<Tz>) -> bool {
+        let day = self.days.contains(time.day());
+        let weekday = self
+            .weekdays
+            .contains(time.weekday().num_days_from_sunday());
+        // Like cron, match either day field if both are restricted.
+        let day_matches = match (self.days.any, self.weekdays.any) {
+            (false, false) => day || weekday,
+            _ => day && weekday,
+        };
+
+        day_matches
+            && self.minutes.contains(time.minute())
+            && self.hours.contains(time.hour())
+            && self.months.contains(time.month())
+    }
+}
+
+impl FromStr for CronSpec {
+    type Err = String;
+
+    fn from_str(input: &str) -> Result<Self, Self::Err> {
+        let fields = input.split_whitespace().collect::<Vec<_>>();
+        if fields.len() != 5 {
+            return Err(format!(
+                "Cron expression '{input}' must have five fields"
+            ));
+        }
+
+        let mut weekdays = CronField::parse(fields[4], 0, 7)?;
+        // Sunday is either 0 or 7.
+        if weekdays.contains(7) {
+            weekdays.mask |= 1;
+        }
+
+        Ok(Self {
+            minutes: CronField::parse(fields[0], 0, 59)?,
+            hours: CronField::parse(fields[1], 0, 23)?,
+            days: CronField::parse(fields[2], 1, 31)?,
+            months: CronField::parse(fields[3], 1, 12)?,
+            weekdays,
+        })
+    }
+}
+
+/// Time of day at which a rule is triggered.
+/// This is either a cron expression like "30 6 * * 1-5" or a time relative
+/// to sunrise or sunset in minutes like "sunrise+30" or "sunset-15".
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum Schedule {
+    Cron(CronSpec),
+    Sunrise(i64),
+    Sunset(i64),
+}
+
+impl Schedule {
+    pub fn needs_location(&self) -> bool {
+        !matches!(self, Schedule::Cron(_))
+    }
+
+    /// Checks if the schedule triggers within the minute of the given time.
+    pub fn matches<Tz: TimeZone>(
+        &self,
+        time: &DateTime<Tz>,
+        location: Option<&Location>,
+    ) -> Result<bool, String> {
+        let (offset, sunrise) = match self {
+            Schedule::Cron(x) => return Ok(x.matches(time)),
+            Schedule::Sunrise(x) => (*x, true),
+            Schedule::Sunset(x) => (*x, false),
+        };
+
+        let location = match location {
+            Some(x) => x,
+            None => return Err("Location is required for sun times".into()),
+        };
+        let utc = time.to_utc();
+        let offset = Duration::minutes(offset);
+        // Use a fixed time of day so that the calculated sun times
+        // do not change during the day.
+        let noon = match (utc - offset).date_naive().and_hms_opt(12, 0, 0) {
+            Some(x) => x.and_utc(),
+            None => return Err("Invalid date".into()),
+        };
+        let event = match sunrise_and_sunset(
+            noon,
+            location.latitude,
+            location.longitude,
+        )? {
+            Some((rise, set)) => {
+                if sunrise {
+                    rise
+                } else {
+                    set
+                }
+            }
+            None => return Ok(false),
+        } + offset;
+
+        Ok(event.timestamp().div_euclid(60) == utc.timestamp().div_euclid(60))
+    }
+}
+
+impl FromStr for Schedule {
+    type Err = String;
+
+    fn from_str(input: &str) -> Result<Self, Self::Err> {
+        let input = input.trim();
+        for (name, sunrise) in [("sunrise", true), ("sunset", false)] {
+            if let Some(offset) = input.strip_prefix(name) {
+                let offset = match offset.trim() {
+                    "" => 0,
+                    x => x.trim_start_matches('+').trim().parse().map_err(
+                        |_| format!("Invalid offset in schedule '{input}'"),
+                    )?,
+                };
+                return Ok(if sunrise {
+                    Schedule::Sunrise(offset)
+                } else {
+                    Schedule::Sunset(offset)
+                });
+            }
+        }
+
+        Ok(Schedule::Cron(input.parse()?))
+    }
+}
+
+/// A list of holidays. Dates are either given as "YYYY-MM-DD" for
+/// single days or "MM-DD" for holidays which repeat every year.
+#[derive(Clone, Debug, Default)]
+pub struct Holidays {
+    dates: Vec<NaiveDate>,
+    yearly: Vec<(u32, u32)>,
+}
+
+impl Holidays {
+    pub fn new(input: &[String]) -> Result<Self, String> {
+        let mut holidays = Self::default();
+        for date in input {
+            if let Ok(x) = NaiveDate::parse_from_str(date, "%Y-%m-%d") {
+                holidays.dates.push(x);
+                continue;
+            }
+
+            let parsed = date.split_once('-').and_then(|(month, day)| {
+                let month = month.parse::<u32>().ok()?;
+                let day = day.parse::<u32>().ok()?;
+                // Check the date in a leap year.
+                NaiveDate::from_ymd_opt(2000, month, day).map(|_| (month, day))
+            });
+            match parsed {
+                Some(x) => holidays.yearly.push(x),
+                None => return Err(format!("Invalid holiday date '{date}'")),
+            }
+        }
+
+        Ok(holidays)
+    }
+
+    pub fn contains(&self, date: NaiveDate) -> bool {
+        self.dates.contains(&date)
+            || self.yearly.contains(&(date.month(), date.day()))
+    }
+}
+
+/// Restricts rules to holidays or non-holidays.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, juniper::GraphQLEnum)]
+pub enum RuleCalendar {
+    Always,
+    Holiday,
+    NotHoliday,
+}
+
+impl RuleCalendar {
+    pub fn allows(&self, holidays: &Holidays, date: NaiveDate) -> bool {
+        match self {
+            RuleCalendar::Always => true,
+            RuleCalendar::Holiday => holidays.contains(date),
+            RuleCalendar::NotHoliday => !holidays.contains(date),
+        }
+    }
+}
+
+impl std::fmt::Display for RuleCalendar {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let name = match self {
+            RuleCalendar::Always => "Always",
+            RuleCalendar::Holiday => "Holiday",
+            RuleCalendar::NotHoliday => "NotHoliday",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+impl FromStr for RuleCalendar {
+    type Err = String;
+
+    fn from_str(input: &str) -> Result<Self, Self::Err> {
+        match input {
+            "Always" => Ok(RuleCalendar::Always),
+            "Holiday" => Ok(RuleCalendar::Holiday),
+            "NotHoliday" => Ok(RuleCalendar::NotHoliday),
+            _ => Err(format!("Invalid calendar '{input}'")),
+        }
+    }
+}
+
+/// Kind of device which is controlled by a rule.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, juniper::GraphQLEnum)]
+pub enum RuleTarget {
+    Switch,
+    Appliance,
+}
+
+impl std::fmt::Display for RuleTarget {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let name = match self {
+            RuleTarget::Switch => "Switch",
+            RuleTarget::Appliance => "Appliance",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+impl FromStr for RuleTarget {
+    type Err = String;
+
+    fn from_str(input: &str) -> Result<Self, Self::Err> {
+        match input {
+            "Switch" => Ok(RuleTarget::Switch),
+            "Appliance" => Ok(RuleTarget::Appliance),
+            _ => Err(format!("Invalid rule target '{input}'")),
+        }
+    }
+}
+
+#[cfg(test)]
+use chrono::Utc;
+
+#[cfg(test)]
+fn utc(input: &str) -> DateTime<Utc> {
+    DateTime::parse_from_rfc3339(input).unwrap().into()
+}
+
+#[test]
+fn test_cron() {
+    let cron: CronSpec = "30 6 * * 1-5".parse().unwrap();
+    assert!(
+        cron.matches(&utc("2026-10-19T06:30:00Z")),
+        "Cron did not match on monday"
+    );
+    assert!(
+        cron.matches(&utc("2026-10-19T06:30:59Z")),
+        "Cron did not match within the minute"
+    );
+    assert!(
+        !cron.matches(&utc("2026-10-19T06:31:00Z")),
+        "Cron matched wrong minute"
+    );
+    assert!(
+        !cron.matches(&utc("2026-10-18T06:30:00Z")),
+        "Cron matched on sunday"
+    );
+
+    let cron: CronSpec = "*/15 8-18/2 1,15 * 0".parse().unwrap();
+    assert!(
+        cron.matches(&utc("2026-10-15T10:45:00Z")),
+        "Step did not match"
+    );
+    assert!(
+        cron.matches(&utc("2026-10-18T08:00:00Z")),
+        "Weekday did not match with restricted day of month"
+    );
+    assert!(
+        !cron.matches(&utc("2026-10-15T09:45:00Z")),
+        "Hour step matched wrong hour"
+    );
+
+    let cron: CronSpec = "0 0 * * 7".parse().unwrap();
+    assert!(
+        cron.matches(&utc("2026-10-18T00:00:00Z")),
+        "Sunday as 7 did not match"
+    );
+
+    assert!("0 0 * *".parse::<CronSpec>().is_err());
+    assert!("60 0 * * *".parse::<CronSpec>().is_err());
+    assert!("0 0 0 * *".parse::<CronSpec>().is_err());
+    assert!("*/0 0 * * *".parse::<CronSpec>().is_err());
+}
+
+#[test]
+fn test_sun_schedule() {
+    let location = Location {
+        latitude: 50.0,
+        longitude: 10.0,
+    };
+    let sunrise: Schedule = "sunrise".parse().unwrap();
+    let sunset: Schedule = "sunset + 30".parse().unwrap();
+    assert_eq!(Schedule::Sunset(30), sunset);
+    assert_eq!(Ok(Schedule::Sunrise(-15)), "sunrise-15".parse());
+
+    let (rise, set) = sunrise_and_sunset(
+        utc("2026-06-21T12:00:00Z"),
+        location.latitude,
+        location.longitude,
+    )
+    .unwrap()
+    .unwrap();
+    assert_eq!(Ok(true), sunrise.matches(&rise, Some(&location)));
+    assert_eq!(
+        Ok(false),
+        sunrise.matches(&(rise + Duration::minutes(1)), Some(&location))
+    );
+    assert_eq!(
+        Ok(true),
+        sunset.matches(&(set + Duration::minutes(30)), Some(&location))
+    );
+    assert!(sunrise.matches(&rise, None).is_err());
+}
+
+#[test]
+fn test_holidays() {
+    let holidays =
+        Holidays::new(&["2026-04-05".into(), "12-25".into()]).unwrap();
+    let easter = NaiveDate::from_ymd_opt(2026, 4, 5).unwrap();
+    let christmas = NaiveDate::from_ymd_opt(2030, 12, 25).unwrap();
+    let workday = NaiveDate::from_ymd_opt(2026, 4, 7).unwrap();
+
+    assert!(holidays.contains(easter));
+    assert!(holidays.contains(christmas));
+    assert!(!holidays.contains(workday));
+    assert!(RuleCalendar::NotHoliday.allows(&holidays, workday));
+    assert!(!RuleCalendar::Holiday.allows(&holidays, workday));
+    assert!(Holidays::new(&["13-01".into()]).is_err());
+}
@@ -15,7 +15,8 @@
     You should have received a copy of the GNU Affero General Public License
     along with this program.  If not, see <https://www.gnu.org/licenses/>.
 \******************************************************************************/
-use chrono::{DateTime, Duration, Local, Utc};
+use crate::clock;
+use chrono::{DateTime, Duration, Utc};
 use spa::{sunrise_and_set, FloatOps, SunriseAndSet};
 
 pub struct StdFloatOps;
@@ -48,6 +49,25 @@ impl FloatOps for StdFloatOps {
     }
 }
 
+/// Sunrise and sunset time of a day.
+pub type SunTimes = (DateTime<Utc>, DateTime<Utc>);
+
+/// Calculates sunrise and sunset of the day containing the given time.
+/// Returns None during polar day or polar night.
+pub fn sunrise_and_sunset(
+    time: DateTime<Utc>,
+    latitude: f64,
+    longitude: f64,
+) -> Result<Option<SunTimes>, String> {
+    match sunrise_and_set::<StdFloatOps>(time, latitude, longitude) {
+        Ok(SunriseAndSet::Daylight(sunrise, sunset)) => {
+            Ok(Some((sunrise, sunset)))
+        }
+        Ok(SunriseAndSet::PolarDay) | Ok(SunriseAndSet::PolarNight) => Ok(None),
+        Err(e) => Err(format!("Calculating sunrise and sunset failed: {e:?}")),
+    }
+}
+
 pub struct SeasonalBuilder {
     latitude: f64,
     longitude: f64,
@@ -145,7 +165,7 @@ impl Seasonal {
         (deviation as f64 + self.offset) * self.gain
     }
     pub fn current_correction(&self) -> f64 {
-        self.calc_correction(DateTime::<Utc>::from(Local::now()))
+        self.calc_correction(clock::now())
     }
 }
 
@@ -15,24 +15,103 @@
     You should have received a copy of the GNU Affero General Public License
     along with this program.  If not, see <https://www.gnu.org/licenses/>.
 \******************************************************************************/
-use base64::prelude::{Engine, BASE64_STANDARD_NO_PAD};
+use base64::prelude::{Engine, BASE64_STANDARD_NO_PAD, BASE64_URL_SAFE_NO_PAD};
 use hmac::{Hmac, Mac};
 use jwt::SignWithKey;
 use jwt::VerifyWithKey;
 use ring::rand::{SecureRandom, SystemRandom};
 use serde::{Deserialize, Serialize};
-use sha2::Sha256;
+use sha2::{Digest, Sha256};
 use slog::{error, Logger};
 use std::collections::HashMap;
+use std::fs;
+use std::io::{self, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
 use std::sync::Mutex;
 use std::time::{SystemTime, UNIX_EPOCH};
 
+/// Permission level of an API user. Each role includes the permissions of
+/// all lower roles.
+#[derive(
+    Clone,
+    Copy,
+    Debug,
+    Default,
+    Deserialize,
+    Eq,
+    Ord,
+    PartialEq,
+    PartialOrd,
+    Serialize,
+    juniper::GraphQLEnum,
+)]
+pub enum Role {
+    /// Can read all data.
+    #[default]
+    Viewer,
+    /// Can additionally control switches, appliances and jobs.
+    Operator,
+    /// Can additionally change thresholds and rules.
+    Admin,
+}
+
+impl std::fmt::Display for Role {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let name = match self {
+            Role::Viewer => "Viewer",
+            Role::Operator => "Operator",
+            Role::Admin => "Admin",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+impl std::str::FromStr for Role {
+    type Err = String;
+
+    fn from_str(input: &str) -> Result<Self, Self::Err> {
+        match input {
+            "Viewer" => Ok(Role::Viewer),
+            "Operator" => Ok(Role::Operator),
+            "Admin" => Ok(Role::Admin),
+            _ => Err(format!("Invalid role '{input}'")),
+        }
+    }
+}
+
+/// Owner of a session.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Identity {
+    pub user: String,
+    pub role: Role,
+}
+
+impl Identity {
+    /// Checks that the identity has at least the given role.
+    pub fn require(&self, role: Role) -> Result<(), AuthError> {
+        if self.role < role {
+            return Err(AuthError::new_custom(
+                format!(
+                    "User '{}' with role {} requires role {}",
+                    self.user, self.role, role
+                ),
+                "Forbidden!".into(),
+            ));
+        }
+        return Ok(());
+    }
+}
+
 #[derive(Clone, Debug, Deserialize, Serialize)]
 struct Session {
     pub id: String,
     pub valid_until: u64,
+    pub user: String,
+    pub role: Role,
 }
 
+#[derive(Debug)]
 pub struct AuthError {
     message: Option<String>,
     user_message: Option<String>,
@@ -80,35 +159,253 @@ macro_rules! auth_error(
     };
 );
 
+/// Prefix of long-lived API tokens which distinguishes them from sessions.
+pub const API_TOKEN_PREFIX: &str = "emp_";
+
 #[derive(Debug)]
 pub struct SessionManager {
     rand: SystemRandom,
     lifetime: u64,
     // TODO: trace content of sessions
     sessions: Mutex<HashMap<String, Session>>,
+    api_tokens: Mutex<HashMap<String, Identity>>,
+    // Hashes of destroyed session tokens which must not be restored,
+    // mapped to the expiry time of the token.
+    revoked: Mutex<HashMap<String, u64>>,
     key: Hmac<Sha256>,
+    // Revoked tokens are stored next to a persistent key.
+    revoked_file: Option<PathBuf>,
 }
 
 impl SessionManager {
-    pub fn new(lifetime: u64) -> Result<SessionManager, String> {
-        let mut key: [u8; 32] = [0; 32];
+    /// Creates a session manager. Sessions are signed with the key from
+    /// the given file so that they survive restarts. A missing key file
+    /// is created. Otherwise, a random key is used. Destroyed sessions are
+    /// stored in a file next to the key.
+    pub fn new(
+        lifetime: u64,
+        key_file: Option<&Path>,
+    ) -> Result<SessionManager, String> {
         let rand = SystemRandom::new();
-        if let Err(e) = rand.fill(&mut key) {
-            return Err(format!("Could not get random key: {}", e));
-        }
+        let key = match key_file {
+            Some(path) => Self::load_key(&rand, path)?,
+            None => Self::random_key(&rand)?,
+        };
 
         let key = Hmac::new_from_slice(&key)
             .map_err(|e| format!("Could not create HMAC: {}", e))?;
 
+        let revoked_file = key_file.map(|path| {
+            let mut name = path.file_name().unwrap_or_default().to_owned();
+            name.push(".revoked");
+            path.with_file_name(name)
+        });
+        let revoked = match &revoked_file {
+            Some(path) => Self::load_revoked(path)?,
+            None => HashMap::new(),
+        };
+
         return Ok(SessionManager {
             rand: rand,
             lifetime: lifetime,
             sessions: Mutex::new(HashMap::new()),
+            api_tokens: Mutex::new(HashMap::new()),
+            revoked: Mutex::new(revoked),
             key: key,
+            revoked_file,
         });
     }
 
-    pub fn register(&self) -> Result<String, AuthError> {
+    fn now() -> Result<u64, AuthError> {
+        return Ok(SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_err(|e| auth_error!("Get system time failed: {}", e))?
+            .as_secs());
+    }
+
+    /// Reads the revoked tokens. Each line contains the hash of a token and
+    /// its expiry time. Expired tokens are skipped.
+    fn load_revoked(path: &Path) -> Result<HashMap<String, u64>, String> {
+        let content = match fs::read_to_string(path) {
+            Ok(x) => x,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                return Ok(HashMap::new())
+            }
+            Err(e) => {
+                return Err(format!(
+                    "Could not read revoked sessions {}: {}",
+                    path.display(),
+                    e
+                ))
+            }
+        };
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_err(|e| format!("Get system time failed: {}", e))?
+            .as_secs();
+
+        let mut revoked = HashMap::new();
+        for line in content.lines() {
+            let (hash, valid_until) = match line.split_once(' ') {
+                Some((hash, x)) => match x.parse::<u64>() {
+                    Ok(x) => (hash, x),
+                    Err(_) => continue,
+                },
+                None => continue,
+            };
+            if valid_until >= now {
+                revoked.insert(hash.to_string(), valid_until);
+            }
+        }
+        return Ok(revoked);
+    }
+
+    /// Replaces the file of revoked tokens by writing a temporary file and
+    /// renaming it.
+    fn save_revoked(
+        path: &Path,
+        revoked: &HashMap<String, u64>,
+    ) -> Result<(), AuthError> {
+        let mut content = String::new();
+        for (hash, valid_until) in revoked {
+            content.push_str(&format!("{} {}\n", hash, valid_until));
+        }
+
+        let mut name = path.file_name().unwrap_or_default().to_owned();
+        name.push(".tmp");
+        let tmp = path.with_file_name(name);
+        let _ = fs::remove_file(&tmp);
+        fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .mode(0o600)
+            .open(&tmp)
+            .and_then(|mut x| x.write_all(content.as_bytes()))
+            .and_then(|_| fs::rename(&tmp, path))
+            .map_err(|e| {
+                auth_error!(
+                    "Could not write revoked sessions {}: {}",
+                    path.display(),
+                    e
+                )
+            })
+    }
+
+    fn random_key(rand: &SystemRandom) -> Result<Vec<u8>, String> {
+        let mut key = vec![0; 32];
+        if let Err(e) = rand.fill(&mut key) {
+            return Err(format!("Could not get random key: {}", e));
+        }
+        return Ok(key);
+    }
+
+    fn load_key(rand: &SystemRandom, path: &Path) -> Result<Vec<u8>, String> {
+        match fs::read(path) {
+            Ok(key) => {
+                if key.len() < 32 {
+                    return Err(format!(
+                        "Key file {} must contain at least 32 bytes",
+                        path.display()
+                    ));
+                }
+                return Ok(key);
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                let key = Self::random_key(rand)?;
+                fs::OpenOptions::new()
+                    .write(true)
+                    .create_new(true)
+                    .mode(0o600)
+                    .open(path)
+                    .and_then(|mut x| x.write_all(&key))
+                    .map_err(|e| {
+                        format!(
+                            "Could not write key file {}: {}",
+                            path.display(),
+                            e
+                        )
+                    })?;
+                return Ok(key);
+            }
+            Err(e) => {
+                return Err(format!(
+                    "Could not read key file {}: {}",
+                    path.display(),
+                    e
+                ))
+            }
+        }
+    }
+
+    /// Generates a new API token. Only its hash should be stored.
+    pub fn generate_api_token(&self) -> Result<String, AuthError> {
+        let mut raw: [u8; 30] = [0; 30];
+        if let Err(e) = self.rand.fill(&mut raw) {
+            return Err(auth_error!("Could not generate API token: {}", e));
+        }
+        return Ok(format!(
+            "{}{}",
+            API_TOKEN_PREFIX,
+            BASE64_URL_SAFE_NO_PAD.encode(raw)
+        ));
+    }
+
+    pub fn hash_api_token(token: &str) -> String {
+        return BASE64_STANDARD_NO_PAD.encode(Sha256::digest(token));
+    }
+
+    /// Allows requests with the API token with the given hash.
+    pub fn add_api_token(
+        &self,
+        token_hash: String,
+        identity: Identity,
+    ) -> Result<(), AuthError> {
+        match self.api_tokens.lock() {
+            Ok(mut x) => {
+                x.insert(token_hash, identity);
+                return Ok(());
+            }
+            Err(e) => {
+                return Err(auth_error!("Locking API tokens failed: {}", e))
+            }
+        }
+    }
+
+    pub fn remove_api_token(&self, token_hash: &str) -> Result<(), AuthError> {
+        match self.api_tokens.lock() {
+            Ok(mut x) => {
+                x.remove(token_hash);
+                return Ok(());
+            }
+            Err(e) => {
+                return Err(auth_error!("Locking API tokens failed: {}", e))
+            }
+        }
+    }
+
+    fn verify_api_token(&self, token: &str) -> Result<Identity, AuthError> {
+        let token_hash = Self::hash_api_token(token);
+        match self.api_tokens.lock() {
+            Ok(x) => match x.get(&token_hash) {
+                Some(identity) => return Ok(identity.clone()),
+                None => {
+                    return Err(AuthError::new_custom(
+                        "Could not find API token".into(),
+                        "Unauthorized!".into(),
+                    ))
+                }
+            },
+            Err(e) => {
+                return Err(auth_error!("Locking API tokens failed: {}", e))
+            }
+        }
+    }
+
+    pub fn register(
+        &self,
+        user: &str,
+        role: Role,
+    ) -> Result<String, AuthError> {
         let mut raw_id: [u8; 30] = [0; 30];
         if let Err(e) = self.rand.fill(&mut raw_id) {
             return Err(auth_error!("Could not generate session ID: {}", e));
@@ -126,6 +423,8 @@ impl SessionManager {
         let session = Session {
             id: BASE64_STANDARD_NO_PAD.encode(raw_id),
             valid_until: valid_until,
+            user: user.into(),
+            role,
         };
 
         // XXX: needs session.clone() because sign_with_key consumes object
@@ -155,56 +454,110 @@ impl SessionManager {
         });
     }
 
-    pub fn verify(&self, token: &str) -> Result<(), AuthError> {
+    /// Verifies the session and extends it by the session lifetime.
+    pub fn verify(&self, token: &str) -> Result<Identity, AuthError> {
+        self.lookup(token, true)
+    }
+
+    /// Checks that the session is still valid without extending it, e.g.
+    /// for long running subscriptions.
+    pub fn check(&self, token: &str) -> Result<Identity, AuthError> {
+        self.lookup(token, false)
+    }
+
+    fn lookup(
+        &self,
+        token: &str,
+        refresh: bool,
+    ) -> Result<Identity, AuthError> {
+        if token.starts_with(API_TOKEN_PREFIX) {
+            return self.verify_api_token(token);
+        }
+
         let requested_session = self.verify_token(token)?;
+        let now = Self::now()?;
+        let revoked = match self.revoked.lock() {
+            Ok(x) => x.contains_key(&Self::hash_api_token(token)),
+            Err(e) => {
+                return Err(auth_error!("Locking sessions failed: {}", e))
+            }
+        };
 
         match self.sessions.lock() {
-            Ok(mut x) => match x.get_mut(&requested_session.id) {
-                Some(session) => {
-                    let now = SystemTime::now()
-                        .duration_since(UNIX_EPOCH)
-                        .map_err(|e| {
-                            auth_error!("Get system time failed: {}", e)
-                        })?
-                        .as_secs();
-                    if session.valid_until < now {
-                        x.remove(&requested_session.id);
-                        return Err(AuthError::new_user(
-                            "Session expired!".into(),
-                        ));
-                    }
-                    match now.checked_add(self.lifetime) {
-                        Some(x) => session.valid_until = x,
-                        None => {
-                            return Err(auth_error!(
-                                "Could not generate timestamp"
-                            ))
-                        }
-                    };
+            Ok(mut x) => {
+                // Sessions signed with a persistent key are restored after a
+                // restart until the expiry time from the token is reached.
+                if self.revoked_file.is_some()
+                    && !revoked
+                    && requested_session.valid_until >= now
+                    && !x.contains_key(&requested_session.id)
+                {
+                    x.insert(
+                        requested_session.id.clone(),
+                        requested_session.clone(),
+                    );
                 }
-                None => {
-                    return Err(AuthError::new_custom(
-                        "Could not find session".into(),
-                        "Unauthorized!".into(),
-                    ))
+
+                match x.get_mut(&requested_session.id) {
+                    Some(session) => {
+                        if session.valid_until < now {
+                            x.remove(&requested_session.id);
+                            return Err(AuthError::new_user(
+                                "Session expired!".into(),
+                            ));
+                        }
+                        if refresh {
+                            match now.checked_add(self.lifetime) {
+                                Some(x) => session.valid_until = x,
+                                None => {
+                                    return Err(auth_error!(
+                                        "Could not generate timestamp"
+                                    ))
+                                }
+                            };
+                        }
+                        return Ok(Identity {
+                            user: session.user.clone(),
+                            role: session.role,
+                        });
+                    }
+                    None => {
+                        return Err(AuthError::new_custom(
+                            "Could not find session".into(),
+                            "Unauthorized!".into(),
+                        ))
+                    }
                 }
-            },
+            }
             Err(e) => {
                 return Err(auth_error!("Locking sessions failed: {}", e))
             }
         }
-        return Ok(());
     }
 
+    /// Verifies the session and checks that its owner has at least the
+    /// given role.
+    pub fn authorize(
+        &self,
+        token: &str,
+        role: Role,
+    ) -> Result<Identity, AuthError> {
+        let identity = self.verify(token)?;
+        identity.require(role)?;
+        return Ok(identity);
+    }
+
+    /// Destroys a session. With a persistent key, sessions from before a
+    /// restart are restored first and the token is revoked permanently.
     pub fn destroy(&self, token: &str) -> Result<(), AuthError> {
         let requested_session = self.verify_token(token)?;
+        if self.revoked_file.is_some() {
+            self.check(token)?;
+        }
+        let now = Self::now()?;
 
         match self.sessions.lock() {
             Ok(mut x) => {
-                let now = SystemTime::now()
-                    .duration_since(UNIX_EPOCH)
-                    .map_err(|e| auth_error!("Get system time failed: {}", e))?
-                    .as_secs();
                 x.retain(|_k, v| v.valid_until > now);
 
                 if x.remove(&requested_session.id).is_none() {
@@ -218,6 +571,98 @@ impl SessionManager {
                 return Err(auth_error!("Locking sessions failed: {}", e))
             }
         }
+
+        if let Some(path) = &self.revoked_file {
+            match self.revoked.lock() {
+                Ok(mut x) => {
+                    x.retain(|_k, v| *v >= now);
+                    x.insert(
+                        Self::hash_api_token(token),
+                        requested_session.valid_until,
+                    );
+                    Self::save_revoked(path, &x)?;
+                }
+                Err(e) => {
+                    return Err(auth_error!("Locking sessions failed: {}", e))
+                }
+            }
+        }
         return Ok(());
     }
 }
+
+#[test]
+fn test_authorize() {
+    let manager = SessionManager::new(300, None).unwrap();
+    let token = manager.register("alice", Role::Operator).unwrap();
+
+    let identity = manager.verify(&token).unwrap();
+    assert_eq!("alice", identity.user);
+    assert_eq!(Role::Operator, identity.role);
+
+    assert!(manager.authorize(&token, Role::Viewer).is_ok());
+    assert!(manager.authorize(&token, Role::Operator).is_ok());
+    assert!(manager.authorize(&token, Role::Admin).is_err());
+    assert_eq!("alice", manager.check(&token).unwrap().user);
+
+    manager.destroy(&token).unwrap();
+    assert!(manager.authorize(&token, Role::Viewer).is_err());
+    assert!(manager.check(&token).is_err());
+}
+
+#[test]
+fn test_persistent_key() {
+    let path = std::env::temp_dir()
+        .join(format!("empowerd-test-{}.key", std::process::id()));
+    let revoked = path.with_extension("key.revoked");
+    let _ = fs::remove_file(&path);
+    let _ = fs::remove_file(&revoked);
+
+    let manager = SessionManager::new(300, Some(&path)).unwrap();
+    let token = manager.register("alice", Role::Admin).unwrap();
+    let logout = manager.register("bob", Role::Admin).unwrap();
+    let later = manager.register("carol", Role::Admin).unwrap();
+    manager.destroy(&logout).unwrap();
+
+    let restarted = SessionManager::new(300, Some(&path)).unwrap();
+    assert_eq!("alice", restarted.verify(&token).unwrap().user);
+    assert!(restarted.verify(&logout).is_err());
+    assert!(restarted.destroy(&logout).is_err());
+    // Sessions from before the restart can be destroyed as well.
+    restarted.destroy(&later).unwrap();
+    assert!(restarted.verify(&later).is_err());
+
+    let restarted = SessionManager::new(300, Some(&path)).unwrap();
+    assert!(restarted.verify(&logout).is_err());
+    assert!(restarted.verify(&later).is_err());
+    assert_eq!("alice", restarted.verify(&token).unwrap().user);
+    assert!(SessionManager::new(300, None)
+        .unwrap()
+        .verify(&token)
+        .is_err());
+
+    fs::remove_file(&path).unwrap();
+    fs::remove_file(&revoked).unwrap();
+}
+
+#[test]
+fn test_api_token() {
+    let manager = SessionManager::new(300, None).unwrap();
+    let token = manager.generate_api_token().unwrap();
+    assert!(token.starts_with(API_TOKEN_PREFIX));
+    assert!(manager.verify(&token).is_err());
+
+    let hash = SessionManager::hash_api_token(&token);
+    let identity = Identity {
+        user: "alice".into(),
+        role: Role::Operator,
+    };
+    manager
+        .add_api_token(hash.clone(), identity.clone())
+        .unwrap();
+    assert_eq!(identity, manager.verify(&token).unwrap());
+    assert!(manager.authorize(&token, Role::Admin).is_err());
+
+    manager.remove_api_token(&hash).unwrap();
+    assert!(manager.verify(&token).is_err());
+}
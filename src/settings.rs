@@ -22,9 +22,16 @@ use std::{
     path::{Path, PathBuf},
 };
 
-use clap::Parser;
+use clap::{Parser, Subcommand};
 use serde::{Deserialize, Serialize};
 
+use crate::{
+    config_file,
+    graph::{GraphFormat, NodeGraph},
+    schedule::Holidays,
+    session_manager::Role,
+};
+
 /// Defines the command line arguments.
 #[derive(Debug, Parser)]
 #[command(version, about)]
@@ -38,10 +45,22 @@ struct Cli {
     /// Test config and exit
     #[clap(short)]
     test: bool,
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Debug, Subcommand)]
+enum Command {
+    /// Print the node graph of the config and exit
+    Graph {
+        /// Output format
+        #[clap(value_enum, short, long, default_value("dot"))]
+        format: GraphFormat,
+    },
 }
 
 /// Defines the database location and credentials.
-#[derive(Clone, Deserialize)]
+#[derive(Clone, Deserialize, PartialEq)]
 #[serde(default)]
 pub struct Database {
     /// Database address and port
@@ -76,7 +95,7 @@ impl Default for Database {
 }
 
 /// Defines GraphQL API location and credentials.
-#[derive(Clone, Deserialize)]
+#[derive(Clone, Deserialize, PartialEq)]
 #[serde(default)]
 pub struct GraphQL {
     /// API server listen address and port
@@ -87,6 +106,29 @@ pub struct GraphQL {
     pub username: String,
     /// Argon2 hashed password of the API user.
     pub hashed_password: String,
+    /// File with the session signing key. Sessions survive restarts
+    /// if this is set. The file is created if it does not exist.
+    pub key_file: Option<String>,
+    /// Serve HTTPS instead of plain HTTP if set.
+    pub tls: Option<Tls>,
+    /// Bearer token required to read /metrics. It is public if unset.
+    pub metrics_token: Option<String>,
+}
+
+/// Defines the TLS certificates of the GraphQL server.
+/// They are reloaded on SIGHUP.
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+pub struct Tls {
+    /// PEM encoded certificate chain
+    pub cert_file: String,
+    /// PEM encoded private key
+    pub key_file: String,
+    /// PEM encoded CA certificates to verify optional client certificates
+    pub client_ca_file: Option<String>,
+    /// Permission level of clients authenticated by a certificate.
+    /// The certificate's common name is used as user name.
+    #[serde(default)]
+    pub client_role: Role,
 }
 
 impl Debug for GraphQL {
@@ -96,6 +138,12 @@ impl Debug for GraphQL {
             .field("session_timeout", &self.session_timeout)
             .field("username", &self.username)
             .field("hashed_password", &"**SECRET**")
+            .field("key_file", &self.key_file)
+            .field("tls", &self.tls)
+            .field(
+                "metrics_token",
+                &self.metrics_token.as_ref().map(|_| "**SECRET**"),
+            )
             .finish()
     }
 }
@@ -107,6 +155,9 @@ impl Default for GraphQL {
             session_timeout: 300,
             username: "user".into(),
             hashed_password: "!".into(),
+            key_file: None,
+            tls: None,
+            metrics_token: None,
         }
     }
 }
@@ -114,27 +165,27 @@ impl Default for GraphQL {
 /// Defines the geographical location of the system.
 /// This is required if seasonal corrections are used to calculate
 /// current day length.
-#[derive(Clone, Debug, Deserialize)]
+#[derive(Clone, Debug, Deserialize, PartialEq)]
 pub struct Location {
     pub latitude: f64,
     pub longitude: f64,
 }
 
 /// Dummy data source for debugging.
-#[derive(Clone, Debug, Deserialize)]
+#[derive(Clone, Debug, Deserialize, PartialEq)]
 pub struct DebugSource {
     pub poll_interval: u64,
 }
 
 /// Physical model of a battery storage.
-#[derive(Clone, Debug, Deserialize)]
+#[derive(Clone, Debug, Deserialize, PartialEq)]
 pub struct BatteryModel {
     pub capacity: f64,
     pub threshold: f64,
 }
 
 /// SMA SunnyBoyStorage inverter Modbus data source parameters.
-#[derive(Clone, Debug, Deserialize)]
+#[derive(Clone, Debug, Deserialize, PartialEq)]
 pub struct SunnyBoyStorage {
     /// Device IP address and port
     pub address: String,
@@ -145,7 +196,7 @@ pub struct SunnyBoyStorage {
 }
 
 /// SMA SunnyIsland inverter Modbus data source parameters.
-#[derive(Clone, Debug, Deserialize)]
+#[derive(Clone, Debug, Deserialize, PartialEq)]
 pub struct SunnyIsland {
     /// Device IP address and port
     pub address: String,
@@ -156,13 +207,13 @@ pub struct SunnyIsland {
 }
 
 /// Physical model of a solar power source.
-#[derive(Clone, Debug, Deserialize)]
+#[derive(Clone, Debug, Deserialize, PartialEq)]
 pub struct SolarModel {
     pub peak_power: f64,
 }
 
 /// Generic Sunspec Modbus compatible inverter data source parameters.
-#[derive(Clone, Debug, Deserialize)]
+#[derive(Clone, Debug, Deserialize, PartialEq)]
 pub struct SunspecSolar {
     /// Device IP address and port
     pub address: String,
@@ -175,7 +226,7 @@ pub struct SunspecSolar {
 }
 
 /// Senertec Dachs MSR-S generator REST-API data source parameters.
-#[derive(Clone, Deserialize)]
+#[derive(Clone, Deserialize, PartialEq)]
 pub struct DachsMsrS {
     /// Device IP address and port
     pub address: String,
@@ -196,7 +247,7 @@ impl Debug for DachsMsrS {
 }
 
 /// Keba KeContact wallbox JSON data source parameters.
-#[derive(Clone, Debug, Deserialize)]
+#[derive(Clone, Debug, Deserialize, PartialEq)]
 pub struct KeContact {
     /// Device IP address and port
     pub address: String,
@@ -205,14 +256,14 @@ pub struct KeContact {
 }
 
 /// Physical model of a heatpump.
-#[derive(Clone, Debug, Deserialize)]
+#[derive(Clone, Debug, Deserialize, PartialEq)]
 pub struct HeatpumpModel {
     pub peak_cop: f64,
     pub peak_heat: f64,
 }
 
 /// Lambda heat pump Modbus data source parameters.
-#[derive(Clone, Debug, Deserialize)]
+#[derive(Clone, Debug, Deserialize, PartialEq)]
 pub struct LambdaHeatPump {
     /// Device IP address and port
     pub address: String,
@@ -227,13 +278,13 @@ pub struct LambdaHeatPump {
 }
 
 /// Power consumption model.
-#[derive(Clone, Debug, Deserialize)]
+#[derive(Clone, Debug, Deserialize, PartialEq)]
 pub struct ConsumptionModel {
     pub peak_power: f64,
 }
 
 /// SMA energy meter Speedwire data source parameters.
-#[derive(Clone, Debug, Deserialize)]
+#[derive(Clone, Debug, Deserialize, PartialEq)]
 pub struct SmaMeter {
     /// Local IP address which will receive broadcast messages.
     pub bind_address: Ipv4Addr,
@@ -249,7 +300,7 @@ pub struct SmaMeter {
 
 /// Generic SML (smart meter language) compatible energy meter data
 /// source parameters.
-#[derive(Clone, Debug, Deserialize)]
+#[derive(Clone, Debug, Deserialize, PartialEq)]
 pub struct SmlMeter {
     /// Serial TTY device path
     pub device: String,
@@ -262,7 +313,7 @@ pub struct SmlMeter {
 }
 
 /// SMA SonnyBoy inverter Speedwire data source parameters.
-#[derive(Clone, Deserialize)]
+#[derive(Clone, Deserialize, PartialEq)]
 pub struct SunnyBoySpeedwire {
     /// Device IP address without port
     pub address: Ipv4Addr,
@@ -284,7 +335,7 @@ impl Debug for SunnyBoySpeedwire {
     }
 }
 
-#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
 #[serde(default)]
 pub struct WeatherLabels {
     x1: Option<String>,
@@ -297,7 +348,7 @@ pub struct WeatherLabels {
 }
 
 /// Bresser 6 in 1 USB weather station data source.
-#[derive(Clone, Debug, Deserialize)]
+#[derive(Clone, Debug, Deserialize, PartialEq)]
 pub struct Bresser6in1 {
     /// Data acquisition poll interval
     pub poll_interval: u64,
@@ -307,7 +358,7 @@ pub struct Bresser6in1 {
 }
 
 /// Common type for handling different data sources.
-#[derive(Clone, Debug, Deserialize)]
+#[derive(Clone, Debug, Deserialize, PartialEq)]
 #[serde(tag = "type")]
 pub enum SourceType {
     Debug(DebugSource),
@@ -323,8 +374,39 @@ pub enum SourceType {
     Bresser6in1(Bresser6in1),
 }
 
+impl SourceType {
+    pub fn poll_interval(&self) -> u64 {
+        match self {
+            SourceType::Debug(x) => x.poll_interval,
+            SourceType::SunnyBoyStorage(x) => x.poll_interval,
+            SourceType::SunnyIsland(x) => x.poll_interval,
+            SourceType::SunspecSolar(x) => x.poll_interval,
+            SourceType::DachsMsrS(x) => x.poll_interval,
+            SourceType::KeContact(x) => x.poll_interval,
+            SourceType::LambdaHeatPump(x) => x.poll_interval,
+            SourceType::SmaMeter(x) => x.poll_interval,
+            SourceType::SmlMeter(x) => x.poll_interval,
+            SourceType::SunnyBoySpeedwire(x) => x.poll_interval,
+            SourceType::Bresser6in1(x) => x.poll_interval,
+        }
+    }
+}
+
+/// Restart behaviour of a source or processor after it failed.
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq)]
+pub enum RestartPolicy {
+    /// Restarts the task after one second, also if it fails repeatedly.
+    Always,
+    /// Restarts the task with exponential backoff. Restarts are suspended
+    /// by a circuit breaker if the task fails repeatedly.
+    #[default]
+    OnFailure,
+    /// Keeps the task stopped.
+    Never,
+}
+
 /// Defines a data source node.
-#[derive(Clone, Debug, Deserialize)]
+#[derive(Clone, Debug, Deserialize, PartialEq)]
 pub struct Source {
     /// Name of the data source.
     pub name: String,
@@ -333,13 +415,16 @@ pub struct Source {
     /// If true, this source is only used for displaying data in the UI.
     #[serde(default)]
     pub archived: bool,
+    /// Restart behaviour after the source failed.
+    #[serde(default)]
+    pub restart: RestartPolicy,
     /// The individual data source parameters.
     #[serde(flatten)]
     pub variant: SourceType,
 }
 
 /// Applies an offset correction to data based on the current day length.
-#[derive(Clone, Debug, Deserialize)]
+#[derive(Clone, Debug, Deserialize, PartialEq)]
 pub struct Seasonal {
     /// Current day length offset in hours.
     /// This constant is added to the calculated day length before
@@ -352,8 +437,31 @@ pub struct Seasonal {
     pub phase: i64,
 }
 
+/// Closed loop PID controller parameters.
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+pub struct PidController {
+    /// Proportional gain.
+    pub kp: f64,
+    /// Optional integral time in seconds.
+    pub ti: Option<f64>,
+    /// Optional derivative time in seconds.
+    pub td: Option<f64>,
+    /// Derivative filter time constant in seconds.
+    #[serde(default)]
+    pub tf: f64,
+    /// Maximum controller output in watt.
+    #[serde(default = "PidController::default_max_power")]
+    pub max_power: f64,
+}
+
+impl PidController {
+    pub fn default_max_power() -> f64 {
+        230.0 * 16.0 // maximum power of one phase
+    }
+}
+
 /// Dummy processor for debug porposes.
-#[derive(Clone, Debug, Deserialize)]
+#[derive(Clone, Debug, Deserialize, PartialEq)]
 pub struct DebugProcessor {
     pub input: String,
     pub output: String,
@@ -367,7 +475,7 @@ impl DebugProcessor {
 
 /// Calculates the currently available power based on grid exchange power
 /// and battery charge.
-#[derive(Clone, Debug, Deserialize)]
+#[derive(Clone, Debug, Deserialize, PartialEq)]
 pub struct AvailablePowerProcessor {
     /// Name of the battery source node to use as input.
     pub battery_input: String,
@@ -377,16 +485,23 @@ pub struct AvailablePowerProcessor {
     pub battery_threshold: f64,
     /// Output power lowpass filter time constant.
     pub tau: f64,
+    /// No power is available while an input is silent for X seconds.
+    #[serde(default = "AvailablePowerProcessor::default_input_timeout")]
+    pub input_timeout: u64,
 }
 
 impl AvailablePowerProcessor {
     fn has_source(&self, source: &str) -> bool {
         self.meter_input == source || self.battery_input == source
     }
+
+    pub fn default_input_timeout() -> u64 {
+        60
+    }
 }
 
 /// Controls the power consumption of an appliance.
-#[derive(Clone, Debug, Deserialize)]
+#[derive(Clone, Debug, Deserialize, PartialEq)]
 pub struct ApplianceProcessor {
     /// Name of available power input node for the appliance.
     /// Can either be an AvailablePowerProcessor or another ApplianceProcessor.
@@ -402,6 +517,14 @@ pub struct ApplianceProcessor {
     pub retransmit_interval: u64,
     /// Optional seasonal correction for the appliance.
     pub seasonal: Option<Seasonal>,
+    /// Optional closed loop controller for the appliance power.
+    /// Without it, the appliance power is set to the measured
+    /// appliance power plus the available power.
+    pub controller: Option<PidController>,
+    /// The appliance is switched off while the power input is silent
+    /// for X seconds, unless it is forced on.
+    #[serde(default = "ApplianceProcessor::default_input_timeout")]
+    pub input_timeout: u64,
 }
 
 impl ApplianceProcessor {
@@ -412,12 +535,16 @@ impl ApplianceProcessor {
     pub fn default_retransmit_interval() -> u64 {
         86400 // one day
     }
+
+    pub fn default_input_timeout() -> u64 {
+        120
+    }
 }
 
 /// Basic SMA Speedwire energy meter grid exchange load controller.
 /// Allows to draw a small constant load from the grid when battery
 /// charge depletes.
-#[derive(Clone, Debug, Deserialize)]
+#[derive(Clone, Debug, Deserialize, PartialEq)]
 pub struct LoadControlProcessor {
     /// Local IP address which will receive broadcast messages.
     pub bind_addr: Ipv4Addr,
@@ -447,36 +574,174 @@ pub struct LoadControlProcessor {
     pub charge_power: f64,
     /// Optional seasonal correction.
     pub seasonal: Option<Seasonal>,
+    /// No grid power is requested while the battery input is silent
+    /// for X seconds.
+    #[serde(default = "LoadControlProcessor::default_input_timeout")]
+    pub input_timeout: u64,
 }
 
 impl LoadControlProcessor {
     fn has_source(&self, source: &str) -> bool {
         self.battery_input == source
     }
+
+    pub fn default_input_timeout() -> u64 {
+        120
+    }
+}
+
+/// Schedules deferrable one-shot loads on switch channels.
+/// Jobs are registered through the API and started when enough power is
+/// available or when they would otherwise miss their deadline.
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+pub struct DeferrableLoadProcessor {
+    /// Name of the available power input node.
+    pub power_input: String,
+    /// Pending jobs are re-evaluated every X seconds.
+    #[serde(default = "DeferrableLoadProcessor::default_check_interval")]
+    pub check_interval: u64,
+}
+
+impl DeferrableLoadProcessor {
+    fn has_source(&self, source: &str) -> bool {
+        self.power_input == source
+    }
+
+    pub fn default_check_interval() -> u64 {
+        60
+    }
+}
+
+/// Switches channels and sets appliance modes at scheduled times.
+/// Rules are edited through the API.
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+pub struct RulesProcessor {
+    /// Holidays as "YYYY-MM-DD" or as "MM-DD" for every year.
+    #[serde(default)]
+    pub holidays: Vec<String>,
+}
+
+/// A load which is shed by the peak shaving processor.
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(tag = "type")]
+pub enum PeakShavingLoad {
+    Switch { name: String },
+    Appliance { name: String },
+}
+
+/// Limits the 15 minute average grid import for demand charge tariffs.
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+pub struct PeakShavingProcessor {
+    /// Name of the grid meter input node.
+    pub meter_input: String,
+    /// Length of a demand interval in seconds.
+    #[serde(default = "PeakShavingProcessor::default_interval")]
+    pub interval: u64,
+    /// Hard limit for the interval average import in watt.
+    pub limit: Option<f64>,
+    /// Interval averages below this value in watt are never shaved.
+    pub min_peak: f64,
+    /// Maximum battery discharge power in watt which is requested from
+    /// the LoadControl processor.
+    #[serde(default)]
+    pub battery_discharge: f64,
+    /// Minimum time between shedding two loads in seconds.
+    #[serde(default = "PeakShavingProcessor::default_shed_delay")]
+    pub shed_delay: u64,
+    /// Loads which are shed in the given order.
+    #[serde(default)]
+    pub loads: Vec<PeakShavingLoad>,
+}
+
+impl PeakShavingProcessor {
+    fn has_source(&self, source: &str) -> bool {
+        self.meter_input == source
+    }
+
+    pub fn default_interval() -> u64 {
+        900
+    }
+
+    pub fn default_shed_delay() -> u64 {
+        30
+    }
+}
+
+/// Keeps grid export below a limit by curtailing an inverter
+/// or charging the battery.
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+pub struct ExportLimitProcessor {
+    /// Name of the grid meter input node.
+    pub meter_input: String,
+    /// Name of an optional SunspecInverter sink which is curtailed.
+    pub inverter_output: Option<String>,
+    /// Maximum battery charge power in watt which is requested from
+    /// the LoadControl processor.
+    #[serde(default)]
+    pub battery_charge: f64,
+    /// Maximum grid export in watt. Zero disables export completely.
+    pub limit: f64,
+    /// Proportional gain of the controller.
+    #[serde(default = "ExportLimitProcessor::default_kp")]
+    pub kp: f64,
+    /// Integral gain of the controller per second.
+    #[serde(default = "ExportLimitProcessor::default_ki")]
+    pub ki: f64,
+    /// Fail-safe is entered when the meter is silent for X seconds.
+    #[serde(default = "ExportLimitProcessor::default_meter_timeout")]
+    pub meter_timeout: u64,
+    /// Inverter power limit in watt during fail-safe.
+    #[serde(default)]
+    pub fail_safe_power: f64,
+}
+
+impl ExportLimitProcessor {
+    fn has_source(&self, source: &str) -> bool {
+        self.meter_input == source
+    }
+
+    pub fn default_kp() -> f64 {
+        0.3
+    }
+
+    pub fn default_ki() -> f64 {
+        0.5
+    }
+
+    pub fn default_meter_timeout() -> u64 {
+        10
+    }
 }
 
 /// Common type for handling different data processors.
-#[derive(Clone, Debug, Deserialize)]
+#[derive(Clone, Debug, Deserialize, PartialEq)]
 #[serde(tag = "type")]
 pub enum ProcessorType {
     Debug(DebugProcessor),
     AvailablePower(AvailablePowerProcessor),
     Appliance(ApplianceProcessor),
     LoadControl(LoadControlProcessor),
+    DeferrableLoad(DeferrableLoadProcessor),
+    Rules(RulesProcessor),
+    PeakShaving(PeakShavingProcessor),
+    ExportLimit(ExportLimitProcessor),
 }
 
 /// Defines a data processor node.
-#[derive(Clone, Debug, Deserialize)]
+#[derive(Clone, Debug, Deserialize, PartialEq)]
 pub struct Processor {
     /// Name of the data processor.
     pub name: String,
+    /// Restart behaviour after the processor failed.
+    #[serde(default)]
+    pub restart: RestartPolicy,
     /// The individual data processor parameters.
     #[serde(flatten)]
     pub variant: ProcessorType,
 }
 
 /// Available icons for the Web-UI.
-#[derive(Clone, Debug, Deserialize)]
+#[derive(Clone, Debug, Deserialize, PartialEq)]
 pub enum Icon {
     Power,
     Valve,
@@ -493,7 +758,7 @@ impl std::fmt::Display for Icon {
 }
 
 /// GPIO data sink parameters.
-#[derive(Clone, Debug, Deserialize)]
+#[derive(Clone, Debug, Deserialize, PartialEq)]
 pub struct Gpio {
     /// Icon name for the Web-UI.
     pub icon: Icon,
@@ -515,7 +780,7 @@ impl Gpio {
 }
 
 /// Modbus coil data sink parameters.
-#[derive(Clone, Debug, Deserialize)]
+#[derive(Clone, Debug, Deserialize, PartialEq)]
 pub struct ModbusCoil {
     /// Icon name for the Web-UI.
     pub icon: Icon,
@@ -539,14 +804,14 @@ impl ModbusCoil {
 }
 
 /// Lambda heat pump Modbus data sink parameters.
-#[derive(Clone, Debug, Deserialize)]
+#[derive(Clone, Debug, Deserialize, PartialEq)]
 pub struct LambdaHeatPumpSink {
     /// Device IP address and port
     pub address: String,
 }
 
 /// Keba KeContact wallbox JSON data sink parameters.
-#[derive(Clone, Debug, Deserialize)]
+#[derive(Clone, Debug, Deserialize, PartialEq)]
 pub struct KeContactSink {
     /// Device IP address and port
     pub address: String,
@@ -554,8 +819,29 @@ pub struct KeContactSink {
     pub phases: u8,
 }
 
+/// SunSpec inverter power limit data sink parameters.
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+pub struct SunspecInverterSink {
+    /// Device IP address and port
+    pub address: String,
+    /// Optional modbus device ID. This is only required for some devices.
+    pub modbus_id: Option<u8>,
+    /// Nominal inverter power in watt.
+    pub nominal_power: f64,
+    /// The inverter reverts the power limit if it is not refreshed
+    /// within this time in seconds.
+    #[serde(default = "SunspecInverterSink::default_revert_timeout")]
+    pub revert_timeout: u16,
+}
+
+impl SunspecInverterSink {
+    pub fn default_revert_timeout() -> u16 {
+        60
+    }
+}
+
 /// Common type for handling different data sinks.
-#[derive(Clone, Debug, Deserialize)]
+#[derive(Clone, Debug, Deserialize, PartialEq)]
 #[serde(tag = "type")]
 pub enum SinkType {
     Debug,
@@ -563,18 +849,219 @@ pub enum SinkType {
     ModbusCoil(ModbusCoil),
     KeContact(KeContactSink),
     LambdaHeatPump(LambdaHeatPumpSink),
+    SunspecInverter(SunspecInverterSink),
 }
 
 /// Defines a data sink node.
-#[derive(Clone, Debug, Deserialize)]
+#[derive(Clone, Debug, Deserialize, PartialEq)]
 pub struct Sink {
     pub name: String,
     #[serde(flatten)]
     pub variant: SinkType,
 }
 
+/// Encryption of SMTP connections.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+pub enum SmtpTls {
+    /// Plain text, only for servers on the local network.
+    None,
+    /// Upgrade the connection with STARTTLS, usually on port 587.
+    #[default]
+    StartTls,
+    /// Implicit TLS, usually on port 465.
+    Tls,
+}
+
+/// Sends notifications as e-mail.
+#[derive(Clone, Deserialize, PartialEq)]
+pub struct SmtpChannel {
+    pub host: String,
+    #[serde(default = "SmtpChannel::default_port")]
+    pub port: u16,
+    #[serde(default)]
+    pub tls: SmtpTls,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    /// Sender address.
+    pub from: String,
+    /// Recipient addresses.
+    pub to: Vec<String>,
+}
+
+impl SmtpChannel {
+    fn default_port() -> u16 {
+        587
+    }
+}
+
+impl Debug for SmtpChannel {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("SmtpChannel")
+            .field("host", &self.host)
+            .field("port", &self.port)
+            .field("tls", &self.tls)
+            .field("username", &self.username)
+            .field("password", &self.password.as_ref().map(|_| "**SECRET**"))
+            .field("from", &self.from)
+            .field("to", &self.to)
+            .finish()
+    }
+}
+
+/// Sends notifications to an HTTP endpoint.
+#[derive(Clone, Deserialize, PartialEq)]
+pub struct HttpChannel {
+    /// Topic URL for ntfy, server URL for Gotify or the webhook URL.
+    pub url: String,
+    /// Access token for ntfy, application token for Gotify or bearer token
+    /// for webhooks.
+    pub token: Option<String>,
+}
+
+impl Debug for HttpChannel {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("HttpChannel")
+            .field("url", &self.url)
+            .field("token", &self.token.as_ref().map(|_| "**SECRET**"))
+            .finish()
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(tag = "type")]
+pub enum ChannelType {
+    Smtp(SmtpChannel),
+    Ntfy(HttpChannel),
+    Gotify(HttpChannel),
+    /// Posts the notification as JSON.
+    Webhook(HttpChannel),
+}
+
+/// Time range in local time where only urgent notifications are sent.
+/// Other notifications are held back until the quiet hours end.
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+pub struct QuietHours {
+    /// Start time as "HH:MM".
+    pub start: String,
+    /// End time as "HH:MM".
+    pub end: String,
+}
+
+/// Defines a channel which delivers notifications.
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+pub struct NotificationChannel {
+    pub name: String,
+    pub quiet_hours: Option<QuietHours>,
+    #[serde(flatten)]
+    pub variant: ChannelType,
+}
+
+/// Alarms if a reading of a node is above or below a limit.
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+pub struct ThresholdNotification {
+    pub node: String,
+    /// Name of the reading, e.g. "charge" or "temp_out".
+    pub field: String,
+    pub above: Option<f64>,
+    pub below: Option<f64>,
+}
+
+/// Alarms if a reading of a node increases by more than a limit within
+/// a time window, e.g. the defrost energy of a heat pump.
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+pub struct IncreaseNotification {
+    pub node: String,
+    /// Name of the reading, e.g. "defrost" or "rain".
+    pub field: String,
+    pub above: f64,
+    /// Time window in seconds.
+    pub window: u64,
+}
+
+/// Alarms if a node did not produce a new record for some time.
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+pub struct StaleNotification {
+    pub node: String,
+    /// Maximum age of the latest record in seconds.
+    pub max_age: u64,
+}
+
+/// Alarms if a source or processor reports errors.
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+pub struct ErrorsNotification {
+    pub node: String,
+    /// Minimum number of errors within the time window.
+    #[serde(default = "ErrorsNotification::default_count")]
+    pub count: u64,
+    /// Time window in seconds.
+    #[serde(default = "ErrorsNotification::default_window")]
+    pub window: u64,
+}
+
+impl ErrorsNotification {
+    fn default_count() -> u64 {
+        1
+    }
+
+    fn default_window() -> u64 {
+        3600
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(tag = "type")]
+pub enum NotificationType {
+    Threshold(ThresholdNotification),
+    Increase(IncreaseNotification),
+    Stale(StaleNotification),
+    Errors(ErrorsNotification),
+}
+
+/// Defines an alarm rule. A notification is sent when the alarm becomes
+/// active and when it is resolved.
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+pub struct Notification {
+    pub name: String,
+    /// Names of the channels to notify.
+    pub channels: Vec<String>,
+    /// Repeats the notification after this many seconds while the alarm
+    /// is active.
+    pub repeat: Option<u64>,
+    /// Urgent notifications are also sent during quiet hours.
+    #[serde(default)]
+    pub urgent: bool,
+    #[serde(flatten)]
+    pub variant: NotificationType,
+}
+
+/// Returns the location of a section in the config file for error
+/// messages, e.g. "[[processor]] #2 'charging'".
+pub fn config_location(section: &str, index: usize, name: &str) -> String {
+    format!("[[{}]] #{} '{}'", section, index + 1, name)
+}
+
+/// Collects the validation errors of a config file.
+#[derive(Default)]
+struct Errors {
+    /// Location of the currently validated section.
+    location: String,
+    errors: Vec<String>,
+}
+
+impl Errors {
+    fn push(&mut self, error: impl fmt::Display) {
+        self.errors.push(format!("{}: {}", self.location, error));
+    }
+
+    fn check(&mut self, valid: bool, error: impl fmt::Display) {
+        if !valid {
+            self.push(error);
+        }
+    }
+}
+
 /// Overall settings for the empower-daemon.
-#[derive(Clone, Debug, Deserialize)]
+#[derive(Clone, Debug, Deserialize, PartialEq)]
 #[serde(default)]
 pub struct Settings {
     /// Detach from controlling terminal after start.
@@ -599,6 +1086,16 @@ pub struct Settings {
     pub processors: Vec<Processor>,
     #[serde(rename = "sink")]
     pub sinks: Vec<Sink>,
+    #[serde(rename = "notification_channel")]
+    pub notification_channels: Vec<NotificationChannel>,
+    #[serde(rename = "notification")]
+    pub notifications: Vec<Notification>,
+    /// Path of the loaded config file.
+    #[serde(skip)]
+    pub config_path: PathBuf,
+    /// Print the node graph in this format and exit.
+    #[serde(skip)]
+    pub export_graph: Option<GraphFormat>,
 }
 
 impl Default for Settings {
@@ -617,6 +1114,10 @@ impl Default for Settings {
             sources: Vec::new(),
             processors: Vec::new(),
             sinks: Vec::new(),
+            notification_channels: Vec::new(),
+            notifications: Vec::new(),
+            config_path: PathBuf::new(),
+            export_graph: None,
         }
     }
 }
@@ -630,75 +1131,291 @@ impl Settings {
                 ProcessorType::AvailablePower(x) => x.has_source(source),
                 ProcessorType::Appliance(x) => x.has_source(source),
                 ProcessorType::LoadControl(x) => x.has_source(source),
+                ProcessorType::DeferrableLoad(x) => x.has_source(source),
+                ProcessorType::Rules(_) => false,
+                ProcessorType::PeakShaving(x) => x.has_source(source),
+                ProcessorType::ExportLimit(x) => x.has_source(source),
             }
         })
     }
 
-    /// Loads settings from config file.
+    /// Loads settings from config file and its include files.
     pub fn load_from_file(filename: &Path) -> Result<Settings, String> {
-        let toml = std::fs::read_to_string(filename).map_err(|e| {
-            format!(
-                "Could not read config file '{}': {}",
-                &filename.display(),
-                e
-            )
-        })?;
-
-        toml::from_str(&toml)
+        toml::Value::Table(config_file::read(filename)?)
+            .try_into()
             .map_err(|e| format!("Could not parse config: {}", e))
     }
 
     /// Validates a node name.
     fn validate_name<'a>(
+        errors: &mut Errors,
         names: &mut BTreeSet<&'a str>,
         name: &'a str,
-    ) -> Result<(), String> {
+    ) {
         if names.contains(&name) {
-            return Err(format!("Duplicate name in config file: '{}'", &name));
+            errors.push(format!("Duplicate name '{}'", &name));
         } else if name.starts_with('_') {
-            return Err(format!("Names must not start with '_': '{}'", &name));
+            errors.push(format!("Names must not start with '_': '{}'", &name));
         }
         names.insert(name);
-        Ok(())
     }
 
-    /// Validates series IDs
-    fn validate_id(ids: &mut BTreeSet<i32>, id: i32) -> Result<(), String> {
-        if ids.contains(&id) {
-            return Err(format!(
-                "Duplicate series_id in config file: '{}'",
-                id
-            ));
+    fn validate_source(errors: &mut Errors, source: &Source) {
+        let poll_interval = source.variant.poll_interval();
+        errors.check(poll_interval > 0, "'poll_interval' must be positive");
+        if let SourceType::LambdaHeatPump(x) = &source.variant {
+            if x.oversample_factor == 0 {
+                errors.push("'oversample_factor' must be positive");
+            } else if poll_interval % x.oversample_factor != 0 {
+                errors.push("'oversample_factor' must divide 'poll_interval'");
+            }
+        }
+    }
+
+    fn validate_seasonal(
+        &self,
+        errors: &mut Errors,
+        seasonal: &Option<Seasonal>,
+    ) {
+        errors.check(
+            seasonal.is_none() || self.location.is_some(),
+            "'seasonal' requires a 'location' section",
+        );
+    }
+
+    fn validate_processor(&self, errors: &mut Errors, processor: &Processor) {
+        match &processor.variant {
+            ProcessorType::Debug(_) => (),
+            ProcessorType::AvailablePower(x) => {
+                errors.check(x.tau > 0.0, "'tau' must be positive");
+                errors.check(
+                    x.input_timeout > 0,
+                    "'input_timeout' must be positive",
+                );
+            }
+            ProcessorType::Appliance(x) => {
+                errors.check(
+                    x.retransmit_interval > 0,
+                    "'retransmit_interval' must be positive",
+                );
+                errors.check(
+                    x.input_timeout > 0,
+                    "'input_timeout' must be positive",
+                );
+                self.validate_seasonal(errors, &x.seasonal);
+                if let Some(pid) = &x.controller {
+                    errors.check(pid.kp >= 0.0, "'kp' must not be negative");
+                    errors.check(
+                        pid.max_power > 0.0,
+                        "'max_power' must be positive",
+                    );
+                    errors.check(
+                        !matches!(pid.ti, Some(x) if x <= 0.0),
+                        "'ti' must be positive",
+                    );
+                    errors.check(
+                        !matches!(pid.td, Some(x) if x <= 0.0),
+                        "'td' must be positive",
+                    );
+                    errors.check(pid.tf >= 0.0, "'tf' must not be negative");
+                }
+            }
+            ProcessorType::LoadControl(x) => {
+                errors.check(
+                    x.num_points > 1,
+                    "'num_points' must be greater than 1",
+                );
+                errors.check(
+                    x.battery_empty_cap < x.battery_threshold_cap,
+                    "'battery_empty_cap' must be smaller than \
+                    'battery_threshold_cap'",
+                );
+                errors.check(
+                    x.hysteresis_cap >= 0.0,
+                    "'hysteresis_cap' must not be negative",
+                );
+                errors.check(
+                    x.input_timeout > 0,
+                    "'input_timeout' must be positive",
+                );
+                self.validate_seasonal(errors, &x.seasonal);
+            }
+            ProcessorType::DeferrableLoad(x) => {
+                errors.check(
+                    x.check_interval > 0,
+                    "'check_interval' must be positive",
+                );
+            }
+            ProcessorType::Rules(x) => {
+                if let Err(e) = Holidays::new(&x.holidays) {
+                    errors.push(format!("'holidays': {e}"));
+                }
+            }
+            ProcessorType::PeakShaving(x) => {
+                errors.check(x.interval > 0, "'interval' must be positive");
+                errors.check(
+                    !matches!(x.limit, Some(x) if x <= 0.0),
+                    "'limit' must be positive",
+                );
+                errors.check(
+                    x.min_peak >= 0.0,
+                    "'min_peak' must not be negative",
+                );
+                errors.check(
+                    x.battery_discharge >= 0.0,
+                    "'battery_discharge' must not be negative",
+                );
+            }
+            ProcessorType::ExportLimit(x) => {
+                errors.check(x.limit >= 0.0, "'limit' must not be negative");
+                errors.check(
+                    x.battery_charge >= 0.0,
+                    "'battery_charge' must not be negative",
+                );
+                errors.check(
+                    x.meter_timeout > 0,
+                    "'meter_timeout' must be positive",
+                );
+                errors.check(
+                    x.inverter_output.is_some() || x.battery_charge > 0.0,
+                    "'inverter_output' or 'battery_charge' is required",
+                );
+            }
+        }
+    }
+
+    fn validate_sink(errors: &mut Errors, sink: &Sink) {
+        match &sink.variant {
+            SinkType::Debug | SinkType::LambdaHeatPump(_) => (),
+            SinkType::Gpio(x) => {
+                errors.check(x.on_time > 0, "'on_time' must be positive");
+            }
+            SinkType::ModbusCoil(x) => {
+                errors.check(x.on_time > 0, "'on_time' must be positive");
+            }
+            SinkType::KeContact(x) => {
+                errors.check(
+                    x.phases == 1 || x.phases == 3,
+                    "'phases' must be 1 or 3",
+                );
+            }
+            SinkType::SunspecInverter(x) => {
+                errors.check(
+                    x.nominal_power > 0.0,
+                    "'nominal_power' must be positive",
+                );
+            }
+        }
+    }
+
+    fn validate_notification(
+        errors: &mut Errors,
+        channels: &BTreeSet<&str>,
+        notification: &Notification,
+    ) {
+        for channel in &notification.channels {
+            errors.check(
+                channels.contains(channel.as_str()),
+                format!("Unknown channel '{}'", channel),
+            );
+        }
+        match &notification.variant {
+            NotificationType::Threshold(x) => errors.check(
+                x.above.is_some() || x.below.is_some(),
+                "'above' or 'below' is required",
+            ),
+            NotificationType::Increase(x) => {
+                errors.check(x.window > 0, "'window' must be positive")
+            }
+            NotificationType::Stale(x) => {
+                errors.check(x.max_age > 0, "'max_age' must be positive")
+            }
+            NotificationType::Errors(x) => {
+                errors.check(x.count > 0, "'count' must be positive");
+                errors.check(x.window > 0, "'window' must be positive");
+            }
         }
-        ids.insert(id);
-        Ok(())
     }
 
-    /// Validates complete settings.
+    /// Validates complete settings and reports all errors at once.
     fn validate(&self) -> Result<(), String> {
+        let mut errors = Errors::default();
         let mut names = BTreeSet::new();
         let mut ids = BTreeSet::new();
 
-        for source in &self.sources {
-            Self::validate_name(&mut names, &source.name)?;
-            Self::validate_id(&mut ids, source.series_id)?;
+        for (i, source) in self.sources.iter().enumerate() {
+            errors.location = config_location("source", i, &source.name);
+            Self::validate_name(&mut errors, &mut names, &source.name);
+            if !ids.insert(source.series_id) {
+                errors.push(format!(
+                    "Duplicate series_id '{}'",
+                    source.series_id
+                ));
+            }
+            Self::validate_source(&mut errors, source);
+        }
+
+        let mut load_controls = 0;
+        let mut battery_controls = 0;
+        for (i, processor) in self.processors.iter().enumerate() {
+            errors.location = config_location("processor", i, &processor.name);
+            Self::validate_name(&mut errors, &mut names, &processor.name);
+            if let ProcessorType::LoadControl(_) = processor.variant {
+                load_controls += 1;
+                errors.check(
+                    load_controls == 1,
+                    "Only one LoadControl processor is supported",
+                );
+            }
+            // The battery power requests of several processors would
+            // overwrite each other in the LoadControl processor.
+            let controls_battery = match &processor.variant {
+                ProcessorType::PeakShaving(x) => x.battery_discharge > 0.0,
+                ProcessorType::ExportLimit(x) => x.battery_charge > 0.0,
+                _ => false,
+            };
+            if controls_battery {
+                battery_controls += 1;
+                errors.check(
+                    battery_controls == 1,
+                    "Only one processor may control the battery power",
+                );
+            }
+            self.validate_processor(&mut errors, processor);
         }
 
-        for processor in &self.processors {
-            Self::validate_name(&mut names, &processor.name)?;
+        for (i, sink) in self.sinks.iter().enumerate() {
+            errors.location = config_location("sink", i, &sink.name);
+            Self::validate_name(&mut errors, &mut names, &sink.name);
+            Self::validate_sink(&mut errors, sink);
         }
 
-        for sink in &self.sinks {
-            Self::validate_name(&mut names, &sink.name)?;
+        let mut channels = BTreeSet::new();
+        for (i, channel) in self.notification_channels.iter().enumerate() {
+            errors.location =
+                config_location("notification_channel", i, &channel.name);
+            Self::validate_name(&mut errors, &mut channels, &channel.name);
+        }
+        for (i, notification) in self.notifications.iter().enumerate() {
+            errors.location =
+                config_location("notification", i, &notification.name);
+            Self::validate_notification(&mut errors, &channels, notification);
         }
 
-        Ok(())
+        let mut errors = errors.errors;
+        errors.extend_from_slice(NodeGraph::new(self).errors());
+        match errors.len() {
+            0 => Ok(()),
+            1 => Err(errors.remove(0)),
+            n => Err(format!("{} errors:\n{}", n, errors.join("\n"))),
+        }
     }
 
     /// Loads settings from command line arguments and config file.
     pub fn load() -> Result<Settings, String> {
         let options = Cli::parse();
         let mut settings = Settings::load_from_file(&options.config_path)?;
+        settings.config_path = options.config_path;
 
         if options.nodaemonize {
             settings.daemonize = false;
@@ -707,8 +1424,124 @@ impl Settings {
             settings.daemonize = false;
             settings.test_cfg = true;
         }
+        if let Some(Command::Graph { format }) = options.command {
+            settings.export_graph = Some(format);
+        }
 
         settings.validate()?;
         Ok(settings)
     }
+
+    /// Loads the config file again. Command line arguments are kept.
+    pub fn reload(&self) -> Result<Settings, String> {
+        let mut settings = Settings::load_from_file(&self.config_path)?;
+        settings.config_path = self.config_path.clone();
+        settings.daemonize = self.daemonize;
+        settings.test_cfg = self.test_cfg;
+
+        settings.validate()?;
+        Ok(settings)
+    }
+
+    /// Parses and validates the content of a config file which would be
+    /// stored at the given path. Include files are read from disk.
+    pub fn parse(toml: &str, path: &Path) -> Result<Settings, String> {
+        let mut settings: Settings =
+            toml::Value::Table(config_file::parse(toml, path)?)
+                .try_into()
+                .map_err(|e| format!("Could not parse config: {}", e))?;
+        settings.config_path = path.to_path_buf();
+
+        settings.validate()?;
+        Ok(settings)
+    }
+}
+
+#[test]
+fn test_validate() {
+    let settings: Settings = toml::from_str(
+        r#"
+        [[source]]
+        name = "Heatpump"
+        series_id = 1
+        type = "LambdaHeatPump"
+        address = "127.0.0.1:502"
+        poll_interval = 10
+        oversample_factor = 3
+
+        [[source]]
+        name = "Heatpump"
+        series_id = 1
+        type = "Debug"
+        poll_interval = 0
+
+        [[source]]
+        name = "Meter"
+        series_id = 2
+        type = "SmlMeter"
+        device = "/dev/ttyUSB0"
+        baud = 9600
+        poll_interval = 10
+
+        [[source]]
+        name = "Storage"
+        series_id = 3
+        type = "SunnyBoyStorage"
+        address = "127.0.0.1:502"
+        poll_interval = 10
+
+        [[processor]]
+        name = "Battery"
+        type = "LoadControl"
+        bind_addr = "0.0.0.0"
+        meter_susy_id = 0
+        meter_serial = 0
+        ctrl_serial = 0
+        battery_input = "Storage"
+        battery_empty_cap = 0.1
+        battery_threshold_cap = 0.2
+        hysteresis_cap = 0.05
+        basic_load = 300
+        min_grid_power = 0
+        num_points = 10
+        charge_power = 0
+
+        [[processor]]
+        name = "Peak"
+        type = "PeakShaving"
+        meter_input = "Meter"
+        interval = 0
+        min_peak = 4000
+        battery_discharge = 2000
+
+        [[processor]]
+        name = "Export"
+        type = "ExportLimit"
+        meter_input = "Meter"
+        battery_charge = 3000
+        limit = 0
+
+        [[sink]]
+        name = "Wallbox"
+        type = "KeContact"
+        address = "127.0.0.1:7090"
+        phases = 2
+        "#,
+    )
+    .unwrap();
+
+    assert_eq!(
+        Err("7 errors:\n\
+            [[source]] #1 'Heatpump': 'oversample_factor' must divide \
+            'poll_interval'\n\
+            [[source]] #2 'Heatpump': Duplicate name 'Heatpump'\n\
+            [[source]] #2 'Heatpump': Duplicate series_id '1'\n\
+            [[source]] #2 'Heatpump': 'poll_interval' must be positive\n\
+            [[processor]] #2 'Peak': 'interval' must be positive\n\
+            [[processor]] #3 'Export': Only one processor may control \
+            the battery power\n\
+            [[sink]] #1 'Wallbox': 'phases' must be 1 or 3"
+            .to_string()),
+        settings.validate()
+    );
 }
@@ -16,6 +16,8 @@
     along with this program.  If not, see <https://www.gnu.org/licenses/>.
 \******************************************************************************/
 use crate::{
+    models::units::{watt, Power},
+    reload::Changes,
     settings::{Gpio, ModbusCoil, Settings, SinkType},
     switch_mux::{SwitchArgs, SwitchType},
     SwitchMux,
@@ -29,12 +31,16 @@ pub mod gpio_switch;
 pub mod ke_contact;
 pub mod lambda_heat_pump;
 pub mod modbus_switch;
+pub mod simulated;
+pub mod sunspec_inverter;
 
 pub use debug::DebugSink;
 pub use gpio_switch::GpioSwitch;
 pub use ke_contact::KeContactSink;
 pub use lambda_heat_pump::LambdaHeatPumpSink;
 pub use modbus_switch::ModbusSwitch;
+pub use simulated::SimulatedSink;
+pub use sunspec_inverter::SunspecInverterSink;
 
 #[derive(Clone)]
 pub enum ArcSink {
@@ -42,6 +48,8 @@ pub enum ArcSink {
     SwitchMux(Arc<SwitchMux>),
     LambdaHeatPump(Arc<LambdaHeatPumpSink>),
     KeContact(Arc<KeContactSink>),
+    SunspecInverter(Arc<SunspecInverterSink>),
+    Simulated(Arc<SimulatedSink>),
 }
 
 impl fmt::Display for ArcSink {
@@ -51,26 +59,65 @@ impl fmt::Display for ArcSink {
             ArcSink::SwitchMux(_) => "SwitchMux",
             ArcSink::LambdaHeatPump(_) => "LambdaHeatPump",
             ArcSink::KeContact(_) => "KeContact",
+            ArcSink::SunspecInverter(_) => "SunspecInverter",
+            ArcSink::Simulated(_) => "Simulated",
         };
         write!(f, "{}", name)
     }
 }
 
+#[derive(Clone)]
 pub struct SwitchProcCreateInfo {
     pub name: String,
     pub channel: watch::Receiver<bool>,
     pub on_time: u64,
 }
 
+/// All sink nodes and the channels of switches which are turned off by
+/// a poweroff timer.
+#[derive(Clone)]
+pub struct Sinks {
+    pub sinks: BTreeMap<String, ArcSink>,
+    pub switches: Vec<SwitchProcCreateInfo>,
+}
+
+impl Sinks {
+    pub fn switch_mux(&self) -> Result<Arc<SwitchMux>, String> {
+        match self.sinks.get("_SwitchMux") {
+            Some(ArcSink::SwitchMux(x)) => Ok(x.clone()),
+            Some(_) => Err("SwitchMux has invalid type".into()),
+            None => Err("Could not find SwitchMux sink".into()),
+        }
+    }
+}
+
 pub fn make_sinks(
     logger: Logger,
     settings: &Settings,
-) -> Result<(BTreeMap<String, ArcSink>, Vec<SwitchProcCreateInfo>), String> {
+) -> Result<Sinks, String> {
+    update_sinks(logger, settings, None)
+}
+
+/// Creates the sinks for new settings. Unchanged sinks are taken from the
+/// running ones. The running SwitchMux is reconfigured if a switch changed.
+pub fn update_sinks(
+    logger: Logger,
+    settings: &Settings,
+    running: Option<(&Sinks, &Changes)>,
+) -> Result<Sinks, String> {
     let mut sinks = BTreeMap::new();
     let mut switches = BTreeMap::<SwitchType, Vec<SwitchArgs>>::new();
     let mut switch_proc_info = Vec::new();
 
     for sink in &settings.sinks {
+        if let Some((running, changes)) = running {
+            if !changes.sinks.contains(&sink.name) {
+                if let Some(x) = running.sinks.get(&sink.name) {
+                    sinks.insert(sink.name.clone(), x.clone());
+                    continue;
+                }
+            }
+        }
         match &sink.variant {
             SinkType::Debug => {
                 let obj = DebugSink::new(sink.name.clone(), logger.clone());
@@ -165,14 +212,43 @@ pub fn make_sinks(
                     ArcSink::KeContact(Arc::new(obj)),
                 );
             }
+            SinkType::SunspecInverter(setting) => {
+                let obj = SunspecInverterSink::new(
+                    sink.name.clone(),
+                    setting.address.clone(),
+                    setting.modbus_id,
+                    Power::new::<watt>(setting.nominal_power),
+                    setting.revert_timeout,
+                    logger.clone(),
+                )?;
+                sinks.insert(
+                    sink.name.clone(),
+                    ArcSink::SunspecInverter(Arc::new(obj)),
+                );
+            }
         }
     }
 
-    let switch_mux = SwitchMux::new(switches)
-        .map_err(|e| format!("Could not create SwitchMux: {}", e))?;
-    sinks.insert(
-        "_SwitchMux".into(),
-        ArcSink::SwitchMux(Arc::new(switch_mux)),
-    );
-    Ok((sinks, switch_proc_info))
+    let switch_mux = match running {
+        Some((running, changes)) if !changes.switches => {
+            switch_proc_info = running.switches.clone();
+            running.switch_mux()?
+        }
+        Some((running, _)) => {
+            let switch_mux = running.switch_mux()?;
+            switch_mux.reconfigure(switches).map_err(|e| {
+                format!("Could not reconfigure SwitchMux: {}", e)
+            })?;
+            switch_mux
+        }
+        None => Arc::new(
+            SwitchMux::new(switches)
+                .map_err(|e| format!("Could not create SwitchMux: {}", e))?,
+        ),
+    };
+    sinks.insert("_SwitchMux".into(), ArcSink::SwitchMux(switch_mux));
+    Ok(Sinks {
+        sinks,
+        switches: switch_proc_info,
+    })
 }
@@ -0,0 +1,94 @@
+/******************************************************************************\
+    empowerd - empowers the offline smart home
+    Copyright (C) 2019 - 2026 Max Maisel
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU Affero General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU Affero General Public License for more details.
+
+    You should have received a copy of the GNU Affero General Public License
+    along with this program.  If not, see <https://www.gnu.org/licenses/>.
+\******************************************************************************/
+use crate::{
+    clock,
+    models::units::{watt, Power},
+};
+use chrono::{DateTime, Utc};
+use slog::{debug, Logger};
+use std::sync::Mutex;
+
+/// A change of the power of a simulated appliance.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Setpoint {
+    pub time: DateTime<Utc>,
+    pub power: Power,
+}
+
+/// Stands in for an appliance during a replay and records its setpoints.
+pub struct SimulatedSink {
+    name: String,
+    setpoints: Mutex<Vec<Setpoint>>,
+    logger: Logger,
+}
+
+impl SimulatedSink {
+    pub fn new(name: String, logger: Logger) -> Self {
+        Self {
+            name,
+            setpoints: Mutex::new(Vec::new()),
+            logger,
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Records the power if it changed. Returns true if the appliance is on.
+    pub fn set_available_power(&self, power: Power) -> Result<bool, String> {
+        let power = power.max(Power::new::<watt>(0.0));
+        let mut setpoints = self
+            .setpoints
+            .lock()
+            .map_err(|e| format!("Recording setpoint failed: {e}"))?;
+        if !matches!(setpoints.last(), Some(x) if x.power == power) {
+            debug!(
+                self.logger,
+                "Setting {} to {} W",
+                self.name,
+                power.get::<watt>()
+            );
+            setpoints.push(Setpoint {
+                time: clock::now(),
+                power,
+            });
+        }
+        Ok(power > Power::new::<watt>(0.0))
+    }
+
+    /// Returns the latest setpoint.
+    pub fn power(&self) -> Power {
+        let setpoints = match self.setpoints.lock() {
+            Ok(x) => x,
+            Err(e) => e.into_inner(),
+        };
+        match setpoints.last() {
+            Some(x) => x.power,
+            None => Power::new::<watt>(0.0),
+        }
+    }
+
+    /// Returns all recorded setpoints in chronological order.
+    pub fn setpoints(&self) -> Vec<Setpoint> {
+        match self.setpoints.lock() {
+            Ok(x) => x.clone(),
+            Err(e) => e.into_inner().clone(),
+        }
+    }
+}
@@ -0,0 +1,88 @@
+/******************************************************************************\
+    empowerd - empowers the offline smart home
+    Copyright (C) 2019 - 2026 Max Maisel
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU Affero General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU Affero General Public License for more details.
+
+    You should have received a copy of the GNU Affero General Public License
+    along with this program.  If not, see <https://www.gnu.org/licenses/>.
+\******************************************************************************/
+use crate::misc::parse_socketaddr_with_default;
+use crate::models::units::{watt, Power};
+use slog::{debug, Logger};
+use std::time::Duration;
+use sunspec_client::SunspecClient;
+use tokio::sync::Mutex;
+
+pub struct SunspecInverterSink {
+    name: String,
+    client: Mutex<SunspecClient>,
+    nominal_power: Power,
+    revert_timeout: u16,
+    logger: Logger,
+}
+
+impl SunspecInverterSink {
+    pub fn new(
+        name: String,
+        address: String,
+        id: Option<u8>,
+        nominal_power: Power,
+        revert_timeout: u16,
+        logger: Logger,
+    ) -> Result<Self, String> {
+        let address = parse_socketaddr_with_default(&address, 502)?;
+        let client = SunspecClient::new(address, id, Some(logger.clone()));
+
+        Ok(Self {
+            name,
+            client: Mutex::new(client),
+            nominal_power,
+            revert_timeout,
+            logger,
+        })
+    }
+
+    pub fn nominal_power(&self) -> Power {
+        self.nominal_power
+    }
+
+    /// The power limit must be written again within this interval,
+    /// otherwise the inverter reverts it.
+    pub fn refresh_interval(&self) -> Option<Duration> {
+        match self.revert_timeout {
+            0 => None,
+            x => Some(Duration::from_secs(x as u64) / 2),
+        }
+    }
+
+    pub async fn set_power_limit(&self, power: Power) -> Result<(), String> {
+        let percent = (power / self.nominal_power).value * 100.0;
+        debug!(
+            self.logger,
+            "Limiting inverter power to {} W ({:.1} %)",
+            power.get::<watt>(),
+            percent
+        );
+
+        let mut client = self.client.lock().await;
+        let mut context = client.open().await?;
+        if client.models().is_empty() {
+            client.introspect(&mut context).await?;
+        }
+        client
+            .set_power_limit(&mut context, percent, self.revert_timeout)
+            .await
+            .map_err(|e| {
+                format!("Setting power limit for {} failed: {}", self.name, e)
+            })
+    }
+}
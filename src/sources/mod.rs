@@ -16,22 +16,17 @@
     along with this program.  If not, see <https://www.gnu.org/licenses/>.
 \******************************************************************************/
 use crate::{
-    models::{run_migrations, Model},
-    settings::{Settings, SourceType},
-    task_group::{
-        task_loop, TaskGroup, TaskGroupBuilder, TaskState, TaskTiming,
-    },
+    models::Model,
+    settings::{RestartPolicy, Settings, SourceType},
+    task_group::{task_loop, TaskGroupBuilder, TaskState, TaskTiming},
     Error,
 };
 use diesel_async::{
-    pooled_connection::{
-        deadpool::{Object, Pool},
-        AsyncDieselConnectionManager,
-    },
+    pooled_connection::deadpool::{Object, Pool},
     AsyncPgConnection,
 };
 use slog::{debug, trace, Logger};
-use std::collections::BTreeMap;
+use std::collections::{BTreeMap, BTreeSet};
 use std::time::{Duration, SystemTime};
 use tokio::sync::watch;
 
@@ -108,17 +103,18 @@ impl SourceBaseBuilder {
         self
     }
 
-    pub fn add_processor(
+    /// Publishes the records of this source to processors and GraphQL
+    /// subscriptions. An existing channel is reused, so restarted sources
+    /// keep their subscribers.
+    pub fn add_output(
         mut self,
         name: &str,
-        settings: &Settings,
-        outputs: &mut BTreeMap<String, watch::Receiver<Model>>,
+        outputs: &mut BTreeMap<String, watch::Sender<Model>>,
     ) -> Self {
-        if settings.has_processor(name) {
-            let (tx, rx) = watch::channel(Model::None);
-            self.processors = Some(tx);
-            outputs.insert(name.into(), rx);
-        }
+        let tx = outputs
+            .entry(name.into())
+            .or_insert_with(|| watch::channel(Model::None).0);
+        self.processors = Some(tx.clone());
         self
     }
 
@@ -183,35 +179,27 @@ impl SourceBase {
     }
 }
 
+/// Creates the polling tasks of all sources or only of the selected ones.
 pub fn polling_tasks(
     logger: Logger,
     settings: &Settings,
-) -> Result<(TaskGroup, BTreeMap<String, watch::Receiver<Model>>), String> {
-    let tasks = TaskGroupBuilder::new("sources".into(), logger.clone());
-    let mut outputs = BTreeMap::<String, watch::Receiver<Model>>::new();
-
-    let pg_url = format!(
-        "postgres://{}:{}@{}/{}",
-        settings.database.user,
-        settings.database.password,
-        settings.database.url,
-        settings.database.name,
-    );
-    tokio::task::block_in_place(|| run_migrations(&pg_url))?;
-    let pool_cfg =
-        AsyncDieselConnectionManager::<AsyncPgConnection>::new(pg_url);
-    let database = Pool::builder(pool_cfg).build().map_err(|e| {
-        format!("Building database connection pool failed: {e}")
-    })?;
-
+    database: Pool<AsyncPgConnection>,
+    tasks: &mut TaskGroupBuilder,
+    outputs: &mut BTreeMap<String, watch::Sender<Model>>,
+    selected: Option<&BTreeSet<String>>,
+) -> Result<(), String> {
     for source in &settings.sources {
-        if source.archived {
+        if source.archived
+            || matches!(selected, Some(x) if !x.contains(&source.name))
+        {
             continue;
         }
+        let name = source.name.as_str();
+        let supervisor = tasks.supervisor(name, source.restart);
 
         let base_builder = SourceBaseBuilder::new(
             database.clone(),
-            tasks.cancel_rx(),
+            tasks.cancel_rx(name),
             logger.clone(),
         );
 
@@ -222,10 +210,10 @@ pub fn polling_tasks(
                         .name(source.name.clone())
                         .series_id(source.series_id)
                         .interval(Duration::from_secs(setting.poll_interval))
-                        .add_processor(&source.name, settings, &mut outputs)
+                        .add_output(&source.name, outputs)
                         .build(),
                 );
-                tasks.add_task(task_loop!(source));
+                tasks.add_task(name, task_loop!(source, supervisor));
             }
             SourceType::SunnyIsland(setting) => {
                 let mut source = SunnyStorageSource::new(
@@ -233,12 +221,12 @@ pub fn polling_tasks(
                         .name(source.name.clone())
                         .series_id(source.series_id)
                         .interval(Duration::from_secs(setting.poll_interval))
-                        .add_processor(&source.name, settings, &mut outputs)
+                        .add_output(&source.name, outputs)
                         .build(),
                     "sunny_island",
                     setting.address.clone(),
                 )?;
-                tasks.add_task(task_loop!(source));
+                tasks.add_task(name, task_loop!(source, supervisor));
             }
             SourceType::SunnyBoyStorage(setting) => {
                 let mut source = SunnyStorageSource::new(
@@ -246,12 +234,12 @@ pub fn polling_tasks(
                         .name(source.name.clone())
                         .series_id(source.series_id)
                         .interval(Duration::from_secs(setting.poll_interval))
-                        .add_processor(&source.name, settings, &mut outputs)
+                        .add_output(&source.name, outputs)
                         .build(),
                     "sunny_boy_storage",
                     setting.address.clone(),
                 )?;
-                tasks.add_task(task_loop!(source));
+                tasks.add_task(name, task_loop!(source, supervisor));
             }
             SourceType::SunspecSolar(setting) => {
                 let mut source = SunspecSolarSource::new(
@@ -259,12 +247,12 @@ pub fn polling_tasks(
                         .name(source.name.clone())
                         .series_id(source.series_id)
                         .interval(Duration::from_secs(setting.poll_interval))
-                        .add_processor(&source.name, settings, &mut outputs)
+                        .add_output(&source.name, outputs)
                         .build(),
                     setting.address.clone(),
                     setting.modbus_id,
                 )?;
-                tasks.add_task(task_loop!(source));
+                tasks.add_task(name, task_loop!(source, supervisor));
             }
             SourceType::DachsMsrS(setting) => {
                 let mut source = DachsMsrSSource::new(
@@ -272,12 +260,12 @@ pub fn polling_tasks(
                         .name(source.name.clone())
                         .series_id(source.series_id)
                         .interval(Duration::from_secs(setting.poll_interval))
-                        .add_processor(&source.name, settings, &mut outputs)
+                        .add_output(&source.name, outputs)
                         .build(),
                     setting.address.clone(),
                     setting.password.clone(),
                 );
-                tasks.add_task(task_loop!(source));
+                tasks.add_task(name, task_loop!(source, supervisor));
             }
             SourceType::KeContact(setting) => {
                 let mut source = KeContactSource::new(
@@ -285,11 +273,11 @@ pub fn polling_tasks(
                         .name(source.name.clone())
                         .series_id(source.series_id)
                         .interval(Duration::from_secs(setting.poll_interval))
-                        .add_processor(&source.name, settings, &mut outputs)
+                        .add_output(&source.name, outputs)
                         .build(),
                     setting.address.clone(),
                 )?;
-                tasks.add_task(task_loop!(source));
+                tasks.add_task(name, task_loop!(source, supervisor));
             }
             SourceType::LambdaHeatPump(setting) => {
                 let mut source = LambdaHeatPumpSource::new(
@@ -298,11 +286,11 @@ pub fn polling_tasks(
                         .series_id(source.series_id)
                         .interval(Duration::from_secs(setting.poll_interval))
                         .oversample_factor(setting.oversample_factor)
-                        .add_processor(&source.name, settings, &mut outputs)
+                        .add_output(&source.name, outputs)
                         .build(),
                     setting.address.clone(),
                 )?;
-                tasks.add_task(task_loop!(source));
+                tasks.add_task(name, task_loop!(source, supervisor));
             }
             SourceType::SmaMeter(setting) => {
                 let mut source = SmaMeterSource::new(
@@ -310,13 +298,13 @@ pub fn polling_tasks(
                         .name(source.name.clone())
                         .series_id(source.series_id)
                         .interval(Duration::from_secs(setting.poll_interval))
-                        .add_processor(&source.name, settings, &mut outputs)
+                        .add_output(&source.name, outputs)
                         .build(),
                     setting.bind_address,
                     setting.susy_id,
                     setting.serial,
                 )?;
-                tasks.add_task(task_loop!(source));
+                tasks.add_task(name, task_loop!(source, supervisor));
             }
             SourceType::SmlMeter(setting) => {
                 let mut source = SmlMeterSource::new(
@@ -324,12 +312,12 @@ pub fn polling_tasks(
                         .name(source.name.clone())
                         .series_id(source.series_id)
                         .interval(Duration::from_secs(setting.poll_interval))
-                        .add_processor(&source.name, settings, &mut outputs)
+                        .add_output(&source.name, outputs)
                         .build(),
                     setting.device.clone(),
                     setting.baud,
                 )?;
-                tasks.add_task(task_loop!(source));
+                tasks.add_task(name, task_loop!(source, supervisor));
             }
             SourceType::SunnyBoySpeedwire(setting) => {
                 let mut source = SunnyBoySpeedwireSource::new(
@@ -337,12 +325,12 @@ pub fn polling_tasks(
                         .name(source.name.clone())
                         .series_id(source.series_id)
                         .interval(Duration::from_secs(setting.poll_interval))
-                        .add_processor(&source.name, settings, &mut outputs)
+                        .add_output(&source.name, outputs)
                         .build(),
                     setting.password.clone(),
                     setting.address,
                 )?;
-                tasks.add_task(task_loop!(source));
+                tasks.add_task(name, task_loop!(source, supervisor));
             }
             SourceType::Bresser6in1(setting) => {
                 let mut source = Bresser6in1Source::new(
@@ -350,26 +338,27 @@ pub fn polling_tasks(
                         .name(source.name.clone())
                         .series_id(source.series_id)
                         .interval(Duration::from_secs(setting.poll_interval))
-                        .add_processor(&source.name, settings, &mut outputs)
+                        .add_output(&source.name, outputs)
                         .build(),
                 );
-                tasks.add_task(task_loop!(source));
+                tasks.add_task(name, task_loop!(source, supervisor));
             }
         }
     }
 
-    if !tasks.has_tasks() {
+    if selected.is_none() && !tasks.has_tasks() {
         debug!(logger, "No sources enabled, using dummy");
         let mut dummy = DummySource::new(
-            SourceBaseBuilder::new(database, tasks.cancel_rx(), logger)
+            SourceBaseBuilder::new(database, tasks.cancel_rx("dummy"), logger)
                 .name("dummy".into())
                 .interval(Duration::from_secs(86400))
                 .build(),
         );
-        tasks.add_task(task_loop!(dummy));
+        let supervisor = tasks.supervisor("dummy", RestartPolicy::default());
+        tasks.add_task("dummy", task_loop!(dummy, supervisor));
     }
 
-    Ok((tasks.build(), outputs))
+    Ok(())
 }
 
 fn sleep_duration(
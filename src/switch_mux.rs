@@ -21,8 +21,11 @@ use crate::{
 };
 use async_trait::async_trait;
 use std::{
-    collections::BTreeMap, fmt::Debug, net::SocketAddr, path::PathBuf,
-    sync::Arc,
+    collections::BTreeMap,
+    fmt::Debug,
+    net::SocketAddr,
+    path::PathBuf,
+    sync::{Arc, RwLock},
 };
 use tokio::sync::watch;
 
@@ -46,83 +49,136 @@ pub enum SwitchType {
     Modbus { addr: SocketAddr, id: u8 },
 }
 
-#[derive(Debug)]
+#[derive(Clone, Debug)]
 struct Channel {
     pub name: String,
     pub icon: Icon,
     pub idx: usize,
     pub proc: Option<watch::Sender<bool>>,
+    pub typ: SwitchType,
     pub switch: Arc<dyn SwitchGroup>,
 }
 
 #[derive(Debug)]
 pub struct SwitchMux {
-    channels: Vec<Channel>,
+    channels: RwLock<Vec<Channel>>,
+    state: watch::Sender<Vec<Option<bool>>>,
 }
 
 impl SwitchMux {
     pub fn new(
         config: BTreeMap<SwitchType, Vec<SwitchArgs>>,
     ) -> Result<Self, String> {
+        let channels = Self::make_channels(config, &[])?;
+        let (state, _) = watch::channel(vec![None; channels.len()]);
+        Ok(Self {
+            channels: RwLock::new(channels),
+            state,
+        })
+    }
+
+    /// Creates the channels. Existing switch groups of the same type are
+    /// reused, so their outputs are not reset.
+    fn make_channels(
+        config: BTreeMap<SwitchType, Vec<SwitchArgs>>,
+        existing: &[Channel],
+    ) -> Result<Vec<Channel>, String> {
         // TODO: keep order from cfg file
 
         let mut channels = Vec::new();
         for (typ, args) in config {
-            match typ {
-                SwitchType::Gpio { dev } => {
-                    let switch = Arc::new(GpioSwitch::new(
-                        &dev,
+            let switch: Arc<dyn SwitchGroup> = match existing
+                .iter()
+                .find(|x| x.typ == typ)
+            {
+                Some(x) => x.switch.clone(),
+                None => match &typ {
+                    SwitchType::Gpio { dev } => Arc::new(GpioSwitch::new(
+                        dev,
                         &args.iter().map(|x| x.num as u32).collect::<Vec<_>>(),
-                    )?);
-                    for arg in args {
-                        channels.push(Channel {
-                            name: arg.name,
-                            icon: arg.icon,
-                            idx: arg.num,
-                            proc: arg.proc,
-                            switch: switch.clone(),
-                        });
-                    }
-                }
-                SwitchType::Modbus { addr, id } => {
-                    let switch = Arc::new(ModbusSwitch::new(addr, id));
-                    for arg in args {
-                        channels.push(Channel {
-                            name: arg.name,
-                            icon: arg.icon,
-                            idx: arg.num,
-                            proc: arg.proc,
-                            switch: switch.clone(),
-                        });
+                    )?),
+                    SwitchType::Modbus { addr, id } => {
+                        Arc::new(ModbusSwitch::new(*addr, *id))
                     }
-                }
+                },
+            };
+            for arg in args {
+                channels.push(Channel {
+                    name: arg.name,
+                    icon: arg.icon,
+                    idx: arg.num,
+                    proc: arg.proc,
+                    typ: typ.clone(),
+                    switch: switch.clone(),
+                });
             }
         }
+        Ok(channels)
+    }
+
+    /// Applies a new configuration. Switch groups with unchanged channel
+    /// numbers are kept and the last known state is kept for channels with
+    /// an unchanged name.
+    pub fn reconfigure(
+        &self,
+        config: BTreeMap<SwitchType, Vec<SwitchArgs>>,
+    ) -> Result<(), String> {
+        let mut channels = self.channels.write().map_err(|e| e.to_string())?;
+        let old_names =
+            channels.iter().map(|x| x.name.clone()).collect::<Vec<_>>();
+        let mut old_nums = BTreeMap::<SwitchType, Vec<usize>>::new();
+        for channel in channels.iter() {
+            old_nums
+                .entry(channel.typ.clone())
+                .or_default()
+                .push(channel.idx);
+        }
 
-        Ok(Self { channels })
+        // Other switch groups are dropped first because they must release
+        // their lines before they can be requested again.
+        let mut existing = std::mem::take(&mut *channels);
+        existing.retain(|channel| {
+            let nums = config
+                .get(&channel.typ)
+                .map(|args| args.iter().map(|x| x.num).collect::<Vec<_>>());
+            nums.as_ref() == old_nums.get(&channel.typ)
+        });
+
+        *channels = Self::make_channels(config, &existing)?;
+        self.state.send_modify(|state| {
+            *state = channels
+                .iter()
+                .map(|x| {
+                    old_names
+                        .iter()
+                        .position(|name| name == &x.name)
+                        .and_then(|id| state.get(id).copied().flatten())
+                })
+                .collect();
+        });
+        Ok(())
     }
 
-    fn get_channel(&self, id: usize) -> Result<&Channel, String> {
-        self.channels
+    fn get_channel(&self, id: usize) -> Result<Channel, String> {
+        let channels = self.channels.read().map_err(|e| e.to_string())?;
+        channels
             .get(id)
+            .cloned()
             .ok_or(format!("Channel {id} not found"))
     }
 
     pub fn len(&self) -> usize {
-        self.channels.len()
+        self.channels.read().map_or(0, |x| x.len())
     }
 
     pub fn ids(&self) -> Vec<usize> {
-        return self
-            .channels
-            .iter()
-            .enumerate()
-            .map(|(idx, _channel)| idx)
-            .collect();
+        return (0..self.len()).collect();
     }
 
     pub fn id_by_name(&self, name: &str) -> Result<usize, String> {
         self.channels
+            .read()
+            .map_err(|e| e.to_string())?
             .iter()
             .position(|x| x.name == name)
             .ok_or(format!("Switch with name '{}' does not exist.", name))
@@ -130,7 +186,7 @@ impl SwitchMux {
 
     pub fn name(&self, id: usize) -> Result<String, String> {
         let channel = self.get_channel(id)?;
-        Ok(channel.name.clone())
+        Ok(channel.name)
     }
 
     pub fn icon(&self, id: usize) -> Result<String, String> {
@@ -140,11 +196,29 @@ impl SwitchMux {
 
     pub async fn read_val(&self, id: usize) -> Result<bool, String> {
         let channel = self.get_channel(id)?;
-        channel
+        let val = channel
             .switch
             .read_val(channel.idx)
             .await
-            .map_err(|e| e.to_string())
+            .map_err(|e| e.to_string())?;
+        self.update_state(id, val);
+        Ok(val)
+    }
+
+    /// Subscribes to the last known state of all channels.
+    pub fn subscribe(&self) -> watch::Receiver<Vec<Option<bool>>> {
+        self.state.subscribe()
+    }
+
+    fn update_state(&self, id: usize, val: bool) {
+        self.state
+            .send_if_modified(|state| match state.get_mut(id) {
+                Some(x) if *x != Some(val) => {
+                    *x = Some(val);
+                    true
+                }
+                _ => false,
+            });
     }
 
     pub async fn write_val(&self, id: usize, val: bool) -> Result<(), String> {
@@ -153,6 +227,7 @@ impl SwitchMux {
             proc.send_replace(val);
         } else {
             channel.switch.write_val(channel.idx, val).await?;
+            self.update_state(id, val);
         }
 
         Ok(())
@@ -164,6 +239,8 @@ impl SwitchMux {
         val: bool,
     ) -> Result<(), String> {
         let channel = self.get_channel(id)?;
-        channel.switch.write_val(channel.idx, val).await
+        channel.switch.write_val(channel.idx, val).await?;
+        self.update_state(id, val);
+        Ok(())
     }
 }
@@ -15,13 +15,29 @@
     You should have received a copy of the GNU Affero General Public License
     along with this program.  If not, see <https://www.gnu.org/licenses/>.
 \******************************************************************************/
-use crate::Error;
-use futures::stream::FuturesUnordered;
-use futures::StreamExt;
-use slog::{debug, error, info, Logger};
+use crate::{settings::RestartPolicy, Error};
+use chrono::{DateTime, Utc};
+use futures::future::select_all;
+use slog::{debug, error, info, warn, Logger};
+use std::collections::{BTreeMap, VecDeque};
+use std::sync::{atomic::AtomicU64, Arc, Mutex};
+use std::time::{Duration, Instant};
 use tokio::sync::watch;
 use tokio::task::JoinHandle;
 
+/// Time a stopped task gets to finish before it is aborted.
+const STOP_TIMEOUT: Duration = Duration::from_secs(10);
+/// Delay before the first restart of a failed task.
+const BASE_BACKOFF: Duration = Duration::from_secs(1);
+/// Upper limit of the exponential backoff.
+const MAX_BACKOFF: Duration = Duration::from_secs(300);
+/// Failures within this window are used for backoff and circuit breaker.
+const FAILURE_WINDOW: Duration = Duration::from_secs(600);
+/// The circuit breaker opens after this many failures within the window.
+const BREAKER_FAILURES: usize = 5;
+/// Time the circuit breaker stays open before the task is tried again.
+const BREAKER_COOLDOWN: Duration = Duration::from_secs(1800);
+
 #[derive(Debug, Default)]
 pub struct TaskTiming {
     pub now: u64,
@@ -42,20 +58,200 @@ pub enum TaskState {
     Canceled,
 }
 
+/// Number of temporary errors of a task.
+pub type ErrorCounter = Arc<AtomicU64>;
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq, juniper::GraphQLEnum)]
+pub enum TaskStatus {
+    /// The task is running.
+    Running,
+    /// The task failed and waits for its restart.
+    Backoff,
+    /// The task failed repeatedly and is suspended by the circuit breaker.
+    CircuitOpen,
+    /// The task failed and is not restarted.
+    Failed,
+}
+
+/// Supervision state of a task.
+#[derive(Clone, Debug)]
+pub struct TaskHealth {
+    pub status: TaskStatus,
+    pub restarts: u64,
+    /// Time of the latest successful run.
+    pub last_success: Option<DateTime<Utc>>,
+    /// Number of errors since the latest successful run.
+    pub consecutive_failures: u64,
+    pub last_error: Option<String>,
+    /// Time of the latest status change.
+    pub since: DateTime<Utc>,
+}
+
+impl Default for TaskHealth {
+    fn default() -> Self {
+        Self {
+            status: TaskStatus::Running,
+            restarts: 0,
+            last_success: None,
+            consecutive_failures: 0,
+            last_error: None,
+            since: Utc::now(),
+        }
+    }
+}
+
+/// Shared supervision state of a task.
+pub type HealthRef = Arc<Mutex<TaskHealth>>;
+
+/// Restarts a failed task according to its restart policy.
+pub struct Supervisor {
+    name: String,
+    policy: RestartPolicy,
+    errors: ErrorCounter,
+    health: HealthRef,
+    canceled: watch::Receiver<TaskState>,
+    failures: VecDeque<Instant>,
+    half_open: bool,
+}
+
+impl Supervisor {
+    fn set_status(&self, status: TaskStatus, error: Option<String>) {
+        if let Ok(mut health) = self.health.lock() {
+            if health.status != status {
+                health.since = Utc::now();
+            }
+            health.status = status;
+            if error.is_some() {
+                health.consecutive_failures += 1;
+                health.last_error = error;
+            }
+        }
+    }
+
+    /// Counts a temporary error.
+    pub fn temporary(&self, error: &str) {
+        self.errors
+            .fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+        self.set_status(TaskStatus::Running, Some(error.into()));
+    }
+
+    /// Marks a completed run, which closes the circuit breaker.
+    pub fn succeeded(&mut self, logger: &Logger) {
+        if self.half_open {
+            info!(logger, "Task '{}' recovered", self.name);
+            self.half_open = false;
+            self.failures.clear();
+        }
+        if let Ok(mut health) = self.health.lock() {
+            if health.status != TaskStatus::Running {
+                health.since = Utc::now();
+            }
+            health.status = TaskStatus::Running;
+            health.last_success = Some(Utc::now());
+            health.consecutive_failures = 0;
+        }
+    }
+
+    /// Returns the delay before the failed task is restarted or None if it
+    /// stays stopped.
+    fn backoff(&mut self, now: Instant) -> (TaskStatus, Option<Duration>) {
+        match self.policy {
+            RestartPolicy::Never => return (TaskStatus::Failed, None),
+            RestartPolicy::Always => {
+                return (TaskStatus::Backoff, Some(BASE_BACKOFF))
+            }
+            RestartPolicy::OnFailure => (),
+        }
+
+        while matches!(self.failures.front(),
+            Some(x) if now.duration_since(*x) > FAILURE_WINDOW)
+        {
+            self.failures.pop_front();
+        }
+        self.failures.push_back(now);
+
+        if self.half_open || self.failures.len() >= BREAKER_FAILURES {
+            self.half_open = true;
+            return (TaskStatus::CircuitOpen, Some(BREAKER_COOLDOWN));
+        }
+        let exp = self.failures.len().saturating_sub(1) as u32;
+        let delay = BASE_BACKOFF.saturating_mul(2u32.saturating_pow(exp));
+        (TaskStatus::Backoff, Some(delay.min(MAX_BACKOFF)))
+    }
+
+    /// Handles a failure of the task. Returns the error if the task is not
+    /// restarted or if it was canceled while waiting.
+    pub async fn failed(
+        &mut self,
+        error: Error,
+        logger: &Logger,
+    ) -> Result<(), Error> {
+        if let Error::Canceled(_) = error {
+            return Err(error);
+        }
+
+        let (status, delay) = self.backoff(Instant::now());
+        self.set_status(status, Some(format!("{:?}", error)));
+        let delay = match delay {
+            Some(x) => x,
+            None => {
+                error!(logger, "Task '{}' failed: {:?}", self.name, error);
+                return Err(error);
+            }
+        };
+        match status {
+            TaskStatus::CircuitOpen => error!(
+                logger,
+                "Task '{}' failed repeatedly, suspended for {}s: {:?}",
+                self.name,
+                delay.as_secs(),
+                error
+            ),
+            _ => warn!(
+                logger,
+                "Task '{}' failed, restarting in {}s: {:?}",
+                self.name,
+                delay.as_secs(),
+                error
+            ),
+        }
+
+        tokio::select! {
+            _ = tokio::time::sleep(delay) => (),
+            _ = self.canceled.changed() => {
+                return Err(Error::Canceled(self.name.clone()));
+            }
+        }
+        if let Ok(mut health) = self.health.lock() {
+            health.restarts += 1;
+        }
+        self.set_status(TaskStatus::Running, None);
+        Ok(())
+    }
+}
+
 macro_rules! task_loop {
-    ($source:expr) => {
+    ($source:expr, $supervisor:expr) => {{
+        let mut supervisor: crate::task_group::Supervisor = $supervisor;
         tokio::task::spawn(async move {
             loop {
                 match $source.run().await {
-                    Ok(()) => (),
+                    Ok(()) => supervisor.succeeded($source.logger()),
                     Err(crate::Error::Temporary(e)) => {
+                        supervisor.temporary(&e);
                         slog::error!($source.logger(), "{}", e)
                     }
-                    Err(e) => return e,
+                    Err(e) => {
+                        if let Err(e) =
+                            supervisor.failed(e, $source.logger()).await
+                        {
+                            return e;
+                        }
+                    }
                 }
             }
         })
-    };
+    }};
 }
 
 pub(crate) use task_loop;
@@ -63,30 +259,55 @@ pub(crate) use task_loop;
 pub struct TaskGroupBuilder {
     name: String,
     logger: Logger,
-    tasks: FuturesUnordered<JoinHandle<Error>>,
-    cancel_tx: watch::Sender<TaskState>,
-    cancel_rx: watch::Receiver<TaskState>,
+    tasks: BTreeMap<String, JoinHandle<Error>>,
+    cancel: BTreeMap<String, watch::Sender<TaskState>>,
+    errors: BTreeMap<String, ErrorCounter>,
+    health: BTreeMap<String, HealthRef>,
 }
 
 impl TaskGroupBuilder {
     pub fn new(name: String, logger: Logger) -> Self {
-        let (cancel_tx, cancel_rx) =
-            watch::channel(TaskState::Running(TaskTiming::default()));
         Self {
             name,
             logger,
-            tasks: FuturesUnordered::new(),
-            cancel_tx,
-            cancel_rx,
+            tasks: BTreeMap::new(),
+            cancel: BTreeMap::new(),
+            errors: BTreeMap::new(),
+            health: BTreeMap::new(),
         }
     }
 
-    pub fn add_task(&self, task: JoinHandle<Error>) {
-        self.tasks.push(task);
+    pub fn add_task(&mut self, name: &str, task: JoinHandle<Error>) {
+        self.tasks.insert(name.into(), task);
+    }
+
+    /// Creates the supervisor of the task with the given name.
+    pub fn supervisor(
+        &mut self,
+        name: &str,
+        policy: RestartPolicy,
+    ) -> Supervisor {
+        let health = HealthRef::default();
+        self.health.insert(name.into(), health.clone());
+        Supervisor {
+            name: name.into(),
+            policy,
+            errors: self.errors.entry(name.into()).or_default().clone(),
+            health,
+            canceled: self.cancel_rx(name),
+            failures: VecDeque::new(),
+            half_open: false,
+        }
     }
 
-    pub fn cancel_rx(&self) -> watch::Receiver<TaskState> {
-        self.cancel_rx.clone()
+    /// Creates the channel which cancels the task with the given name.
+    pub fn cancel_rx(&mut self, name: &str) -> watch::Receiver<TaskState> {
+        self.cancel
+            .entry(name.into())
+            .or_insert_with(|| {
+                watch::channel(TaskState::Running(TaskTiming::default())).0
+            })
+            .subscribe()
     }
 
     pub fn has_tasks(&self) -> bool {
@@ -94,68 +315,175 @@ impl TaskGroupBuilder {
     }
 
     pub fn build(self) -> TaskGroup {
-        TaskGroup::new(self.name, self.logger, self.tasks, self.cancel_tx)
+        TaskGroup {
+            name: self.name,
+            logger: self.logger,
+            tasks: self.tasks,
+            cancel: self.cancel,
+            errors: self.errors,
+            health: self.health,
+            canceled: false,
+        }
     }
 }
 
 pub struct TaskGroup {
     name: String,
     logger: Logger,
-    tasks: FuturesUnordered<JoinHandle<Error>>,
-    cancel: watch::Sender<TaskState>,
+    tasks: BTreeMap<String, JoinHandle<Error>>,
+    cancel: BTreeMap<String, watch::Sender<TaskState>>,
+    errors: BTreeMap<String, ErrorCounter>,
+    health: BTreeMap<String, HealthRef>,
+    canceled: bool,
 }
 
 impl TaskGroup {
-    pub fn new(
-        name: String,
-        logger: Logger,
-        tasks: FuturesUnordered<JoinHandle<Error>>,
-        cancel: watch::Sender<TaskState>,
-    ) -> Self {
-        Self {
-            name,
-            logger,
-            tasks,
-            cancel,
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Gets the error counters of all tasks by name.
+    pub fn errors(&self) -> BTreeMap<String, ErrorCounter> {
+        self.errors.clone()
+    }
+
+    /// Gets the supervision state of all tasks by name.
+    pub fn health(&self) -> BTreeMap<String, HealthRef> {
+        self.health.clone()
+    }
+
+    /// Creates a builder for tasks which are later merged into this group.
+    /// Error counters of existing tasks are kept for restarted tasks.
+    pub fn builder(&self) -> TaskGroupBuilder {
+        TaskGroupBuilder {
+            errors: self.errors.clone(),
+            ..TaskGroupBuilder::new(self.name.clone(), self.logger.clone())
         }
     }
 
-    pub fn name(&self) -> &str {
-        &self.name
+    /// Moves the tasks of another group into this one.
+    pub fn merge(&mut self, other: TaskGroup) {
+        self.tasks.extend(other.tasks);
+        self.cancel.extend(other.cancel);
+        self.errors.extend(other.errors);
+        self.health.extend(other.health);
     }
 
-    pub async fn run(&mut self) -> Result<(), ()> {
-        while let Some(join_result) = self.tasks.next().await {
-            let result = match join_result {
-                Ok(x) => x,
-                Err(e) => {
-                    error!(self.logger, "Join task failed: {}", e);
-                    return Err(());
+    /// Cancels a single task and waits until it has finished.
+    pub async fn stop(&mut self, name: &str) {
+        if let Some(cancel) = self.cancel.remove(name) {
+            cancel.send_replace(TaskState::Canceled);
+        }
+        let mut task = match self.tasks.remove(name) {
+            Some(x) => x,
+            None => return,
+        };
+        if tokio::time::timeout(STOP_TIMEOUT, &mut task).await.is_err() {
+            warn!(self.logger, "Task '{}' did not stop, abort it", name);
+            task.abort();
+            let _ = task.await;
+        }
+        debug!(self.logger, "Task '{}' was stopped", name);
+    }
+
+    /// Stops a task which no longer exists and drops its error counter.
+    pub async fn remove(&mut self, name: &str) {
+        self.stop(name).await;
+        self.errors.remove(name);
+        self.health.remove(name);
+    }
+
+    /// Waits for the tasks until the group was canceled. Failed tasks are
+    /// marked as failed, the other tasks keep running.
+    pub async fn run(&mut self) {
+        loop {
+            if self.tasks.is_empty() {
+                if self.canceled {
+                    return;
                 }
+                // Tasks may be added again by a reload.
+                std::future::pending::<()>().await;
+            }
+
+            let (join_result, idx, _) =
+                select_all(self.tasks.values_mut()).await;
+            let name = match self.tasks.keys().nth(idx).cloned() {
+                Some(x) => x,
+                None => continue,
             };
+            self.tasks.remove(&name);
+            self.cancel.remove(&name);
 
-            if let Error::Canceled(name) = result {
-                debug!(self.logger, "Task '{}' was canceled", name);
-            } else {
-                error!(self.logger, "Task failed: {:?}", result);
-                return Err(());
+            let error = match join_result {
+                Ok(Error::Canceled(_)) => {
+                    debug!(self.logger, "Task '{}' was canceled", name);
+                    continue;
+                }
+                // The supervisor logged the error already.
+                Ok(e) => format!("{:?}", e),
+                Err(e) => {
+                    error!(self.logger, "Task '{}' panicked: {}", name, e);
+                    e.to_string()
+                }
+            };
+            if let Some(Ok(mut health)) =
+                self.health.get(&name).map(|x| x.lock())
+            {
+                health.status = TaskStatus::Failed;
+                health.last_error = Some(error);
+                health.since = Utc::now();
             }
         }
-
-        Ok(())
     }
 
     pub fn cancel(&mut self) -> Result<(), String> {
-        if self.cancel.is_closed() {
-            return Ok(());
+        self.canceled = true;
+        for cancel in self.cancel.values() {
+            cancel.send_replace(TaskState::Canceled);
         }
+        info!(self.logger, "Task group '{}' canceled", self.name);
+        Ok(())
+    }
+}
 
-        match self.cancel.send(TaskState::Canceled) {
-            Ok(_) => {
-                info!(self.logger, "Task group '{}' canceled", self.name);
-                Ok(())
-            }
-            Err(e) => Err(e.to_string()),
-        }
+#[test]
+fn test_backoff() {
+    let logger = Logger::root(slog::Discard, slog::o!());
+    let mut tasks = TaskGroupBuilder::new("test".into(), logger);
+    let now = Instant::now();
+
+    let mut supervisor = tasks.supervisor("never", RestartPolicy::Never);
+    assert_eq!((TaskStatus::Failed, None), supervisor.backoff(now));
+
+    let mut supervisor = tasks.supervisor("always", RestartPolicy::Always);
+    for _ in 0..10 {
+        let (status, delay) = supervisor.backoff(now);
+        assert_eq!((TaskStatus::Backoff, Some(BASE_BACKOFF)), (status, delay));
+    }
+
+    let mut supervisor =
+        tasks.supervisor("on_failure", RestartPolicy::OnFailure);
+    for secs in [1, 2, 4, 8] {
+        assert_eq!(
+            (TaskStatus::Backoff, Some(Duration::from_secs(secs))),
+            supervisor.backoff(now)
+        );
     }
+    assert_eq!(
+        (TaskStatus::CircuitOpen, Some(BREAKER_COOLDOWN)),
+        supervisor.backoff(now)
+    );
+    // A failure after the cooldown opens the breaker again.
+    let later = now + BREAKER_COOLDOWN;
+    assert_eq!(
+        (TaskStatus::CircuitOpen, Some(BREAKER_COOLDOWN)),
+        supervisor.backoff(later)
+    );
+    // A successful run closes it and old failures are forgotten.
+    supervisor.succeeded(&Logger::root(slog::Discard, slog::o!()));
+    let later = later + FAILURE_WINDOW * 2;
+    assert_eq!(
+        (TaskStatus::Backoff, Some(BASE_BACKOFF)),
+        supervisor.backoff(later)
+    );
 }
@@ -22,3 +22,27 @@ pub enum TriState {
     Off,
     Auto,
 }
+
+impl std::fmt::Display for TriState {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let name = match self {
+            TriState::On => "On",
+            TriState::Off => "Off",
+            TriState::Auto => "Auto",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+impl std::str::FromStr for TriState {
+    type Err = String;
+
+    fn from_str(input: &str) -> Result<Self, Self::Err> {
+        match input {
+            "On" => Ok(TriState::On),
+            "Off" => Ok(TriState::Off),
+            "Auto" => Ok(TriState::Auto),
+            _ => Err(format!("Invalid state '{input}'")),
+        }
+    }
+}
@@ -149,6 +149,7 @@ impl UiConfig {
                 SinkType::ModbusCoil(_) => config.controls = true,
                 SinkType::KeContact(_) => config.controls = true,
                 SinkType::LambdaHeatPump(_) => config.controls = true,
+                SinkType::SunspecInverter(_) => (),
             }
         }
 
@@ -0,0 +1,293 @@
+/******************************************************************************\
+    empowerd - empowers the offline smart home
+    Copyright (C) 2019 - 2026 Max Maisel
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU Affero General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU Affero General Public License for more details.
+
+    You should have received a copy of the GNU Affero General Public License
+    along with this program.  If not, see <https://www.gnu.org/licenses/>.
+\******************************************************************************/
+//! Runs empowerd against simulated devices and a temporary PostgreSQL
+//! database. The test needs the PostgreSQL server binaries in the PATH or
+//! PG_BINDIR, a non-root user and the free ports 7090 and 127.0.80.80:8080:
+//! `cargo test --test e2e -- --ignored`
+
+use diesel_async::{AsyncConnection, AsyncPgConnection};
+use empowerd_sim::{
+    dachs::DachsState, keba::KebaState, speedwire::MeterReading, DachsServer,
+    Daemon, EnergyMeterEmitter, KebaResponder, ModbusServer, RegisterMap,
+    TempPostgres,
+};
+use libempowerd::models::{
+    units::{joule, kilowatt_hour, watt_hour},
+    Battery, BidirMeter, Generator, Heatpump, SimpleMeter,
+};
+use sma_proto::SmaEndpoint;
+use std::{
+    net::{Ipv4Addr, SocketAddr},
+    time::{Duration, Instant},
+};
+
+const DACHS_ADDR: &str = "127.0.80.80";
+const METER_SUSY_ID: u16 = 270;
+const METER_SERIAL: u32 = 1234567;
+
+struct Devices {
+    battery: ModbusServer,
+    solar: ModbusServer,
+    heatpump: ModbusServer,
+    wallbox: KebaResponder,
+    _generator: DachsServer,
+    meter: EnergyMeterEmitter,
+}
+
+impl Devices {
+    async fn start() -> Self {
+        let local: SocketAddr = "127.0.0.1:0".parse().unwrap();
+        Self {
+            battery: ModbusServer::start(
+                local,
+                RegisterMap::sunny_island(1000, 800, 50, 10000),
+            )
+            .await
+            .unwrap(),
+            solar: ModbusServer::start(
+                local,
+                RegisterMap::sunspec_inverter(123456),
+            )
+            .await
+            .unwrap(),
+            heatpump: ModbusServer::start(
+                local,
+                RegisterMap::lambda_heat_pump(70000),
+            )
+            .await
+            .unwrap(),
+            wallbox: KebaResponder::start(
+                local,
+                KebaState {
+                    e_total: 55000,
+                    ..Default::default()
+                },
+            )
+            .await
+            .unwrap(),
+            _generator: DachsServer::start(
+                format!("{DACHS_ADDR}:8080").parse().unwrap(),
+                "secret",
+                DachsState {
+                    total_energy: 1234.0,
+                    runtime: 2.0,
+                },
+            )
+            .await
+            .unwrap(),
+            meter: EnergyMeterEmitter::start(
+                Ipv4Addr::LOCALHOST,
+                SmaEndpoint {
+                    susy_id: METER_SUSY_ID,
+                    serial: METER_SERIAL,
+                },
+                Duration::from_millis(100),
+                MeterReading {
+                    energy_in: 3600000,
+                    ..Default::default()
+                },
+            )
+            .unwrap(),
+        }
+    }
+
+    /// Lets the meter report a grid export of 3 kW.
+    fn export(&self, interval: Duration) {
+        self.meter.update(|x| {
+            x.power_out = 3000;
+            x.energy_out += (3000.0 * interval.as_secs_f64()) as u64;
+        });
+    }
+
+    fn config(&self, postgres: &TempPostgres) -> String {
+        format!(
+            r#"
+daemonize = false
+log_level = "debug"
+
+[database]
+url = "{database}"
+name = "empowerd"
+user = "postgres"
+password = "postgres"
+
+[graphql]
+listen_address = "127.0.0.1:{graphql_port}"
+
+[[source]]
+name = "battery"
+series_id = 1
+type = "SunnyIsland"
+address = "{battery}"
+poll_interval = 1
+
+[[source]]
+name = "generator"
+series_id = 2
+type = "DachsMsrS"
+address = "{DACHS_ADDR}"
+password = "secret"
+poll_interval = 1
+
+[[source]]
+name = "wallbox"
+series_id = 3
+type = "KeContact"
+address = "{wallbox}"
+poll_interval = 1
+
+[[source]]
+name = "heatpump"
+series_id = 4
+type = "LambdaHeatPump"
+address = "{heatpump}"
+poll_interval = 1
+oversample_factor = 1
+
+[[source]]
+name = "meter"
+series_id = 5
+type = "SmaMeter"
+bind_address = "127.0.0.1"
+susy_id = {METER_SUSY_ID}
+serial = {METER_SERIAL}
+poll_interval = 1
+
+[[source]]
+name = "solar"
+series_id = 6
+type = "SunspecSolar"
+address = "{solar}"
+modbus_id = 126
+poll_interval = 1
+
+[[processor]]
+name = "power"
+type = "AvailablePower"
+battery_input = "battery"
+meter_input = "meter"
+battery_threshold = 1000
+tau = 1
+
+[[processor]]
+name = "heatpumpproc"
+type = "Appliance"
+power_input = "power"
+appliance_input = "heatpump"
+appliance_output = "heatpumpsink"
+
+[[sink]]
+name = "heatpumpsink"
+type = "LambdaHeatPump"
+address = "{heatpump}"
+"#,
+            database = postgres.address(),
+            graphql_port = empowerd_sim::free_port().unwrap(),
+            battery = self.battery.local_addr(),
+            wallbox = self.wallbox.local_addr(),
+            heatpump = self.heatpump.local_addr(),
+            solar = self.solar.local_addr(),
+        )
+    }
+
+    fn available_power(&self) -> i16 {
+        self.heatpump
+            .holding(RegisterMap::LAMBDA_AVAILABLE_POWER, 1)
+            .unwrap()[0] as i16
+    }
+}
+
+/// Returns true if the latest records of all sources were stored.
+async fn records_stored(conn: &mut AsyncPgConnection) -> bool {
+    let battery = match Battery::last(conn, 1).await {
+        Ok(x) => x,
+        Err(_) => return false,
+    };
+    assert_eq!(5000.0, battery.charge.get::<watt_hour>());
+    assert_eq!(1000.0, battery.energy_in.get::<watt_hour>());
+
+    let generator = match Generator::last(conn, 2).await {
+        Ok(x) => x,
+        Err(_) => return false,
+    };
+    assert_eq!(1234.0, generator.energy.get::<kilowatt_hour>());
+
+    let wallbox = match SimpleMeter::last(conn, 3).await {
+        Ok(x) => x,
+        Err(_) => return false,
+    };
+    assert_eq!(5500.0, wallbox.energy.get::<watt_hour>());
+
+    if Heatpump::last(conn, 4).await.is_err() {
+        return false;
+    }
+
+    let meter = match BidirMeter::last(conn, 5).await {
+        Ok(x) => x,
+        Err(_) => return false,
+    };
+    assert_eq!(3600000.0, meter.energy_in.get::<joule>());
+
+    let solar = match SimpleMeter::last(conn, 6).await {
+        Ok(x) => x,
+        Err(_) => return false,
+    };
+    assert_eq!(123456.0, solar.energy.get::<watt_hour>());
+
+    true
+}
+
+#[tokio::test(flavor = "multi_thread")]
+#[ignore = "needs PostgreSQL server binaries and a non-root user"]
+async fn test_simulated_devices() {
+    let postgres = TempPostgres::start("empowerd").unwrap();
+    let devices = Devices::start().await;
+    let mut daemon = Daemon::start(
+        env!("CARGO_BIN_EXE_empowerd"),
+        &devices.config(&postgres),
+    )
+    .unwrap();
+
+    let mut conn = None;
+    let mut stored = false;
+    let interval = Duration::from_millis(100);
+    let deadline = Instant::now() + Duration::from_secs(60);
+    while !(stored && devices.available_power() > 0) {
+        if let Some(code) = daemon.exit_code().unwrap() {
+            panic!("empowerd exited with {code}:\n{}", daemon.log());
+        }
+        if Instant::now() > deadline {
+            panic!(
+                "Timed out, stored records: {stored}, available power: {}\n{}",
+                devices.available_power(),
+                daemon.log()
+            );
+        }
+
+        tokio::time::sleep(interval).await;
+        devices.export(interval);
+        if conn.is_none() {
+            conn = AsyncPgConnection::establish(&postgres.url("empowerd"))
+                .await
+                .ok();
+        }
+        if let Some(conn) = &mut conn {
+            stored = stored || records_stored(conn).await;
+        }
+    }
+}